@@ -0,0 +1,586 @@
+use {
+    serde_json::Value,
+    std::{
+        collections::{BTreeMap, BTreeSet, HashMap},
+        env, fs,
+        io::Read,
+        path::PathBuf,
+        process::{Command, Stdio},
+        sync::{
+            atomic::{AtomicU64, AtomicU8, Ordering},
+            Mutex,
+        },
+        thread::{self, JoinHandle},
+        time::Duration,
+    },
+};
+
+pub const BACKENDS: [&str; 3] = ["trans", "LibreTranslate", "Offline"];
+const LIBRETRANSLATE: &str = "http://localhost:5000";
+const TIMEOUT: u64 = 30;
+pub const CANCELLED: &str = "Cancelled";
+
+static SELECTED: AtomicU8 = AtomicU8::new(0);
+// Bumped by every request, those started before are stale
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+// LibreTranslate (code, name) pairs by server URL, fetched once
+static CODES: Mutex<BTreeMap<String, Vec<(String, String)>>> = Mutex::new(BTreeMap::new());
+
+pub trait TranslationBackend: Send {
+    /// Language names offered in `FROM` and `TO`.
+    fn languages(&self) -> Result<Vec<String>, String>;
+    /// Translates `text` between two of those languages, `voice` reads it aloud where supported.
+    fn translate(&self, voice: bool, from: &str, to: &str, text: &str) -> Result<String, String>;
+    /// The name of the language `text` is written in.
+    fn detect(&self, text: &str) -> Result<String, String>;
+}
+
+/// Starts a request, returning its generation.
+pub fn next() -> u64 {
+    GENERATION.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// Whether a newer request was started since `generation`.
+pub fn stale(generation: u64) -> bool {
+    GENERATION.load(Ordering::SeqCst) != generation
+}
+
+pub fn selected() -> u8 {
+    SELECTED.load(Ordering::Relaxed)
+}
+
+pub fn select(idx: u8) {
+    SELECTED.store(idx.min(BACKENDS.len() as u8 - 1), Ordering::Relaxed);
+}
+
+pub fn current() -> Box<dyn TranslationBackend> {
+    match selected() {
+        1 => Box::new(LibreTranslate::from_env()),
+        2 => Box::new(Offline {
+            dir: crate::config().join("dict"),
+        }),
+        _ => Box::new(Trans),
+    }
+}
+
+/// The [translate-shell](https://github.com/soimort/translate-shell) CLI.
+pub struct Trans;
+
+fn read(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut bytes).ok();
+        }
+        String::from_utf8_lossy(&bytes).to_string()
+    })
+}
+
+impl Trans {
+    // Runs trans, killing it once a newer request starts
+    fn run(args: &[&str]) -> Result<String, String> {
+        let generation = GENERATION.load(Ordering::SeqCst);
+        let mut child = Command::new("trans")
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|error| format!("trans: {error}"))?;
+        let (stdout, stderr) = (read(child.stdout.take()), read(child.stderr.take()));
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(|error| error.to_string())? {
+                break status;
+            }
+            if stale(generation) {
+                child.kill().ok();
+                child.wait().ok();
+                return Err(CANCELLED.to_string());
+            }
+            thread::sleep(Duration::from_millis(20));
+        };
+        match status.success() {
+            true => Ok(stdout.join().unwrap_or_default()),
+            false => Err(stderr.join().unwrap_or_default()),
+        }
+    }
+}
+
+impl TranslationBackend for Trans {
+    fn languages(&self) -> Result<Vec<String>, String> {
+        if !cfg!(target_family = "unix") {
+            return Err("trans needs a Unix shell".to_string());
+        }
+        let run = Command::new("trans")
+            .arg("-list-languages-english")
+            .output()
+            .map_err(|error| format!("trans: {error}"))?;
+        match run.status.success() {
+            true => Ok(String::from_utf8_lossy(&run.stdout)
+                .lines()
+                .map(str::to_string)
+                .collect()),
+            false => Err(String::from_utf8_lossy(&run.stderr).to_string()),
+        }
+    }
+
+    fn translate(&self, voice: bool, from: &str, to: &str, text: &str) -> Result<String, String> {
+        let text = text.trim().replace("\n\n", "\n");
+        let mut args = vec!["-join-sentence", "-no-ansi", "-indent", "2"];
+        for option in [
+            "-show-languages",
+            "-show-original",
+            "-show-original-dictionary",
+            "-show-prompt-message",
+            "-show-alternatives",
+            "-show-translation-phonetics",
+        ] {
+            args.extend([option, "n"]);
+        }
+        args.extend(["-from", from, "-to", to]);
+        if text.split_whitespace().count() != 1 {
+            args.push("-brief");
+        }
+        if voice {
+            args.push("-speak");
+        }
+        args.push(&text);
+        Trans::run(&args)
+    }
+
+    // -identify prints the language's own name first, then a Name line in English
+    fn detect(&self, text: &str) -> Result<String, String> {
+        let output = Trans::run(&["-identify", "-no-ansi", text.trim()])?;
+        output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("Name"))
+            .or(output.lines().map(str::trim).find(|line| !line.is_empty()))
+            .map(|name| name.trim().to_string())
+            .ok_or("Couldn't detect the language".to_string())
+    }
+}
+
+/// A LibreTranslate-compatible HTTP API, at `$LIBRETRANSLATE_URL` with `$LIBRETRANSLATE_API_KEY`.
+pub struct LibreTranslate {
+    url: String,
+    key: Option<String>,
+}
+
+impl LibreTranslate {
+    pub fn new(url: &str, key: Option<String>) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            key,
+        }
+    }
+
+    fn from_env() -> Self {
+        Self::new(
+            &env::var("LIBRETRANSLATE_URL").unwrap_or(LIBRETRANSLATE.to_string()),
+            env::var("LIBRETRANSLATE_API_KEY").ok(),
+        )
+    }
+
+    fn agent() -> ureq::Agent {
+        ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(TIMEOUT))
+            .build()
+    }
+
+    fn error(error: ureq::Error) -> String {
+        match error {
+            ureq::Error::Status(code, response) => response
+                .into_json::<Value>()
+                .ok()
+                .and_then(|json| json["error"].as_str().map(str::to_string))
+                .unwrap_or(format!("HTTP {code}")),
+            error => error.to_string(),
+        }
+    }
+
+    // Not locked while fetching, two requests racing only fetch twice
+    fn codes(&self) -> Result<Vec<(String, String)>, String> {
+        if let Some(codes) = CODES.lock().unwrap().get(&self.url) {
+            return Ok(codes.clone());
+        }
+        let json: Value = Self::agent()
+            .get(&format!("{}/languages", self.url))
+            .call()
+            .map_err(Self::error)?
+            .into_json()
+            .map_err(|error| error.to_string())?;
+        let codes: Vec<(String, String)> = json
+            .as_array()
+            .ok_or("unexpected /languages response")?
+            .iter()
+            .filter_map(|lang| {
+                Some((
+                    lang["code"].as_str()?.to_string(),
+                    lang["name"].as_str()?.to_string(),
+                ))
+            })
+            .collect();
+        CODES
+            .lock()
+            .unwrap()
+            .insert(self.url.clone(), codes.clone());
+        Ok(codes)
+    }
+
+    fn name(&self, code: &str) -> Result<String, String> {
+        self.codes()?
+            .into_iter()
+            .find(|(lang, _)| lang == code)
+            .map(|(_, name)| name)
+            .ok_or(format!("{code} isn't supported"))
+    }
+
+    fn code(&self, name: &str) -> Result<String, String> {
+        self.codes()?
+            .into_iter()
+            .find(|(code, lang)| lang == name || code == name)
+            .map(|(code, _)| code)
+            .ok_or(format!("{name} isn't supported"))
+    }
+}
+
+impl TranslationBackend for LibreTranslate {
+    fn languages(&self) -> Result<Vec<String>, String> {
+        Ok(self.codes()?.into_iter().map(|(_, name)| name).collect())
+    }
+
+    fn translate(&self, _: bool, from: &str, to: &str, text: &str) -> Result<String, String> {
+        let mut body = serde_json::json!({
+            "q": text,
+            "source": self.code(from)?,
+            "target": self.code(to)?,
+            "format": "text",
+        });
+        if let Some(key) = &self.key {
+            body["api_key"] = Value::from(key.as_str());
+        }
+        let json: Value = Self::agent()
+            .post(&format!("{}/translate", self.url))
+            .send_json(body)
+            .map_err(Self::error)?
+            .into_json()
+            .map_err(|error| error.to_string())?;
+        json["translatedText"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or("unexpected /translate response".to_string())
+    }
+
+    // The most confident of the candidates /detect returns
+    fn detect(&self, text: &str) -> Result<String, String> {
+        let mut body = serde_json::json!({ "q": text });
+        if let Some(key) = &self.key {
+            body["api_key"] = Value::from(key.as_str());
+        }
+        let json: Value = Self::agent()
+            .post(&format!("{}/detect", self.url))
+            .send_json(body)
+            .map_err(Self::error)?
+            .into_json()
+            .map_err(|error| error.to_string())?;
+        let code = json
+            .as_array()
+            .and_then(|candidates| {
+                candidates.iter().max_by(|a, b| {
+                    let confidence = |json: &Value| json["confidence"].as_f64().unwrap_or_default();
+                    confidence(a).total_cmp(&confidence(b))
+                })
+            })
+            .and_then(|best| best["language"].as_str())
+            .ok_or("Couldn't detect the language")?;
+        self.name(code)
+    }
+}
+
+/// Word lists in `~/.config/FlDialect/dict`, one `From-To.tsv` per language pair
+/// with a `source<TAB>translation` entry per line.
+pub struct Offline {
+    pub dir: PathBuf,
+}
+
+impl Offline {
+    fn pairs(&self) -> Vec<(String, String)> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "tsv"))
+            .filter_map(|path| {
+                let stem = path.file_stem()?.to_string_lossy().to_string();
+                let (from, to) = stem.split_once('-')?;
+                Some((from.to_string(), to.to_string()))
+            })
+            .collect()
+    }
+
+    // Entries are keyed in lowercase, a reversed pair's file is read backwards
+    fn dictionary(&self, from: &str, to: &str) -> Result<HashMap<String, String>, String> {
+        let (file, reversed) = match self.dir.join(format!("{from}-{to}.tsv")) {
+            file if file.exists() => (file, false),
+            _ => (self.dir.join(format!("{to}-{from}.tsv")), true),
+        };
+        let text = fs::read_to_string(&file)
+            .map_err(|_| format!("No dictionary for {from} to {to} in {}", self.dir.display()))?;
+        Ok(text
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(source, target)| match reversed {
+                true => (target.trim().to_lowercase(), source.trim().to_string()),
+                false => (source.trim().to_lowercase(), target.trim().to_string()),
+            })
+            .collect())
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+impl TranslationBackend for Offline {
+    fn languages(&self) -> Result<Vec<String>, String> {
+        let languages: BTreeSet<String> = self
+            .pairs()
+            .into_iter()
+            .flat_map(|(from, to)| [from, to])
+            .collect();
+        match languages.is_empty() {
+            true => Err(format!("No dictionaries in {}", self.dir.display())),
+            false => Ok(languages.into_iter().collect()),
+        }
+    }
+
+    // The whole text if it's an entry, otherwise word by word, unknown words are kept
+    fn translate(&self, _: bool, from: &str, to: &str, text: &str) -> Result<String, String> {
+        let dictionary = self.dictionary(from, to)?;
+        if let Some(target) = dictionary.get(&text.trim().to_lowercase()) {
+            return Ok(target.clone());
+        }
+        let mut result = String::new();
+        let mut word = String::new();
+        let lookup = |word: &mut String, result: &mut String| {
+            if !word.is_empty() {
+                match dictionary.get(&word.to_lowercase()) {
+                    Some(target) if word.starts_with(char::is_uppercase) => {
+                        result.push_str(&capitalize(target))
+                    }
+                    Some(target) => result.push_str(target),
+                    None => result.push_str(word),
+                }
+                word.clear();
+            }
+        };
+        for ch in text.chars() {
+            match ch.is_alphabetic() || ch == '\'' {
+                true => word.push(ch),
+                false => {
+                    lookup(&mut word, &mut result);
+                    result.push(ch);
+                }
+            }
+        }
+        lookup(&mut word, &mut result);
+        Ok(result)
+    }
+
+    // The language most of the words are entries of
+    fn detect(&self, text: &str) -> Result<String, String> {
+        let words: Vec<String> = text
+            .split(|ch: char| !(ch.is_alphabetic() || ch == '\''))
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        let mut best = (0, None);
+        for (from, to) in self.pairs() {
+            for (source, target) in [(&from, &to), (&to, &from)] {
+                let dictionary = self.dictionary(source, target)?;
+                let found = words
+                    .iter()
+                    .filter(|word| dictionary.contains_key(*word))
+                    .count();
+                if found > best.0 {
+                    best = (found, Some(source.clone()));
+                }
+            }
+        }
+        best.1.ok_or("Couldn't detect the language".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{
+            io::{BufRead, BufReader, Write},
+            net::TcpListener,
+            process,
+        },
+    };
+
+    // Answers like a LibreTranslate server: English and German, translations are
+    // "q:source>target" and "fail" is refused
+    fn stub() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_string();
+                let mut length = 0;
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+                let (status, response) = match path.as_str() {
+                    "/languages" => (
+                        "200 OK",
+                        serde_json::json!([
+                            { "code": "en", "name": "English" },
+                            { "code": "de", "name": "German" },
+                        ]),
+                    ),
+                    "/translate" if request["q"] == "fail" => {
+                        ("400 Bad Request", serde_json::json!({ "error": "Refused" }))
+                    }
+                    "/translate" => (
+                        "200 OK",
+                        serde_json::json!({ "translatedText": format!(
+                            "{}:{}>{}",
+                            request["q"].as_str().unwrap(),
+                            request["source"].as_str().unwrap(),
+                            request["target"].as_str().unwrap(),
+                        )}),
+                    ),
+                    "/detect" => (
+                        "200 OK",
+                        serde_json::json!([
+                            { "language": "en", "confidence": 10.0 },
+                            { "language": "de", "confidence": 90.0 },
+                        ]),
+                    ),
+                    _ => ("404 Not Found", serde_json::json!({})),
+                };
+                let response = response.to_string();
+                let mut stream = stream;
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                    response.len()
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn libretranslate() {
+        let backend = LibreTranslate::new(&format!("{}/", stub()), None);
+        assert_eq!(
+            backend.languages(),
+            Ok(vec!["English".to_string(), "German".to_string()])
+        );
+        assert_eq!(
+            backend.translate(false, "English", "de", "Hello"),
+            Ok("Hello:en>de".to_string())
+        );
+        assert_eq!(backend.detect("Hallo"), Ok("German".to_string()));
+        assert_eq!(
+            backend.translate(false, "English", "French", "Hello"),
+            Err("French isn't supported".to_string())
+        );
+        assert_eq!(
+            backend.translate(false, "English", "German", "fail"),
+            Err("Refused".to_string())
+        );
+        // another server gets its own languages
+        let other = LibreTranslate::new("http://127.0.0.1:9", None);
+        assert!(other.languages().is_err());
+    }
+
+    fn dictionaries(name: &str) -> Offline {
+        let dir = env::temp_dir().join(format!("fldialect-{name}-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("English-German.tsv"),
+            "# English\tGerman\nhello\thallo\nworld\tWelt\ngood morning\tguten Morgen\n",
+        )
+        .unwrap();
+        fs::write(dir.join("English-French.tsv"), "hello\tbonjour\n").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        Offline { dir }
+    }
+
+    #[test]
+    fn offline_translate() {
+        let offline = dictionaries("translate");
+        assert_eq!(
+            offline.languages(),
+            Ok(vec![
+                "English".to_string(),
+                "French".to_string(),
+                "German".to_string()
+            ])
+        );
+        let translate = |from, to, text| offline.translate(false, from, to, text);
+        assert_eq!(
+            translate("English", "German", " Good morning\n"),
+            Ok("guten Morgen".to_string())
+        );
+        assert_eq!(
+            translate("English", "German", "Hello, world!"),
+            Ok("Hallo, Welt!".to_string())
+        );
+        assert_eq!(
+            translate("German", "English", "Welt hallo"),
+            Ok("World hello".to_string())
+        );
+        assert_eq!(
+            translate("English", "German", "hello there"),
+            Ok("hallo there".to_string())
+        );
+        assert!(translate("French", "German", "bonjour").is_err());
+        fs::remove_dir_all(&offline.dir).ok();
+    }
+
+    #[test]
+    fn offline_detect() {
+        let offline = dictionaries("detect");
+        assert_eq!(offline.detect("Hallo Welt"), Ok("German".to_string()));
+        assert_eq!(offline.detect("hello world"), Ok("English".to_string()));
+        assert!(offline.detect("xyz").is_err());
+        let empty = Offline {
+            dir: offline.dir.join("missing"),
+        };
+        assert!(empty.languages().is_err());
+        fs::remove_dir_all(&offline.dir).ok();
+    }
+}
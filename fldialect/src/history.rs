@@ -0,0 +1,536 @@
+use {
+    fltk::{
+        app,
+        browser::HoldBrowser,
+        button::{Button, ButtonType},
+        dialog::{alert_default, FileChooser, FileChooserType},
+        enums::CallbackTrigger,
+        frame::Frame,
+        group::Flex,
+        input::Input,
+        menu::MenuButton,
+        misc::InputChoice,
+        prelude::{BrowserExt, ButtonExt, DisplayExt, GroupExt, InputExt, WidgetBase, WidgetExt},
+        text::TextEditor,
+    },
+    serde_json::{json, Value},
+    std::{
+        cell::RefCell,
+        fs,
+        path::PathBuf,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+pub const SIDEBAR: &str = "Sidebar";
+const HISTORY: &str = "History";
+const SEARCH: &str = "Search";
+const STARRED: &str = "Favourites only";
+const STAR: &str = "Favourite";
+const RERUN: &str = "Translate again";
+// Favourites are kept beyond this
+const LIMIT: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub from: String,
+    pub to: String,
+    pub source: String,
+    pub target: String,
+    // seconds since the epoch
+    pub time: u64,
+    pub starred: bool,
+    // added by a live translation in this run, the next one may replace it
+    pub live: bool,
+}
+
+thread_local! {
+    // newest first
+    static ENTRIES: RefCell<Vec<Entry>> = RefCell::new(load());
+    // browser line -> entry
+    static SHOWN: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+fn file() -> PathBuf {
+    crate::config().join("history.json")
+}
+
+fn load() -> Vec<Entry> {
+    let Some(json) = fs::read_to_string(file())
+        .ok()
+        .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+    else {
+        return Vec::new();
+    };
+    json.as_array()
+        .map(|entries| {
+            entries
+                .iter()
+                .filter_map(|entry| {
+                    Some(Entry {
+                        from: entry["from"].as_str()?.to_string(),
+                        to: entry["to"].as_str()?.to_string(),
+                        source: entry["source"].as_str()?.to_string(),
+                        target: entry["target"].as_str()?.to_string(),
+                        time: entry["time"].as_u64().unwrap_or_default(),
+                        starred: entry["starred"].as_bool().unwrap_or_default(),
+                        live: false,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn save() {
+    let json = ENTRIES.with(|entries| {
+        Value::from(
+            entries
+                .borrow()
+                .iter()
+                .map(|entry| {
+                    json!({
+                        "from": entry.from,
+                        "to": entry.to,
+                        "source": entry.source,
+                        "target": entry.target,
+                        "time": entry.time,
+                        "starred": entry.starred,
+                    })
+                })
+                .collect::<Vec<Value>>(),
+        )
+    });
+    if let Err(error) = fs::write(file(), json.to_string()) {
+        eprintln!("\x1b[31m{error}\x1b[0m");
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+// YYYY-MM-DD HH:MM:SS in UTC
+pub fn date(time: u64) -> String {
+    let (days, secs) = ((time / 86400) as i64, time % 86400);
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Records a translation, translating the same text again only updates its entry.
+pub fn add(from: &str, to: &str, source: &str, target: &str) {
+    crate::history::insert(from, to, source, target, false);
+}
+
+// Puts entry first, it takes over the star of an entry for the same text it replaces
+fn record(entries: &mut Vec<Entry>, mut entry: Entry) {
+    if let Some(idx) = entries
+        .iter()
+        .position(|old| old.from == entry.from && old.to == entry.to && old.source == entry.source)
+    {
+        entry.starred = entries.remove(idx).starred;
+    }
+    entries.insert(0, entry);
+    while entries.len() > LIMIT {
+        match entries.iter().rposition(|entry| !entry.starred) {
+            Some(idx) => entries.remove(idx),
+            None => break,
+        };
+    }
+}
+
+fn insert(from: &str, to: &str, source: &str, target: &str, live: bool) {
+    let entry = Entry {
+        from: from.to_string(),
+        to: to.to_string(),
+        source: source.to_string(),
+        target: target.to_string(),
+        time: now(),
+        starred: false,
+        live,
+    };
+    ENTRIES.with(|entries| crate::history::record(&mut entries.borrow_mut(), entry));
+    save();
+    // the indices moved, nothing stays selected
+    SHOWN.with(|shown| shown.borrow_mut().clear());
+    crate::history::fill();
+}
+
+/// Like `add`, for live translations: an unstarred newest entry of the same pair
+/// that was added live and whose source was typed on to this one's (or cut back)
+/// is dropped first.
+pub fn replace(from: &str, to: &str, source: &str, target: &str) {
+    ENTRIES.with(|entries| {
+        crate::history::drop_live(&mut entries.borrow_mut(), from, to, source);
+    });
+    crate::history::insert(from, to, source, target, true);
+}
+
+fn drop_live(entries: &mut Vec<Entry>, from: &str, to: &str, source: &str) {
+    if entries.first().is_some_and(|entry| {
+        entry.live
+            && !entry.starred
+            && entry.from == from
+            && entry.to == to
+            && (source.starts_with(&entry.source) || entry.source.starts_with(source))
+    }) {
+        entries.remove(0);
+    }
+}
+
+fn matches(entry: &Entry, query: &str) -> bool {
+    query.is_empty()
+        || [&entry.source, &entry.target, &entry.from, &entry.to]
+            .iter()
+            .any(|text| text.to_lowercase().contains(query))
+}
+
+fn label(entry: &Entry) -> String {
+    const WIDTH: usize = 40;
+    let line = entry.source.lines().next().unwrap_or_default();
+    let mut short: String = line.chars().take(WIDTH).collect();
+    if short.len() < line.len() {
+        short.push('…');
+    }
+    format!(
+        "@.{} {} → {}: {short}",
+        if entry.starred { "★" } else { "☆" },
+        entry.from,
+        entry.to,
+    )
+}
+
+/// Lists the entries matching the search box, newest first.
+pub fn fill() {
+    let (Some(mut browser), Some(search), Some(starred)) = (
+        app::widget_from_id::<HoldBrowser>(HISTORY),
+        app::widget_from_id::<Input>(SEARCH),
+        app::widget_from_id::<Button>(STARRED),
+    ) else {
+        return;
+    };
+    let query = search.value().to_lowercase();
+    let selected = crate::history::selected();
+    browser.clear();
+    let shown: Vec<usize> = ENTRIES.with(|entries| {
+        entries
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| {
+                (!starred.value() || entry.starred) && crate::history::matches(entry, &query)
+            })
+            .map(|(idx, entry)| {
+                browser.add(&crate::history::label(entry));
+                idx
+            })
+            .collect()
+    });
+    if let Some(line) = selected.and_then(|idx| shown.iter().position(|shown| *shown == idx)) {
+        browser.select(line as i32 + 1);
+    }
+    SHOWN.with(move |lines| *lines.borrow_mut() = shown);
+}
+
+fn selected() -> Option<usize> {
+    let browser = app::widget_from_id::<HoldBrowser>(HISTORY)?;
+    let line = (browser.value() as usize).checked_sub(1)?;
+    SHOWN.with(|shown| shown.borrow().get(line).copied())
+}
+
+fn entry(idx: usize) -> Option<Entry> {
+    ENTRIES.with(|entries| entries.borrow().get(idx).cloned())
+}
+
+// Puts an entry back into the editors and language choices, without starting
+// a live translation of the restored source
+fn show(entry: &Entry) {
+    let live = crate::request::live();
+    crate::request::set_live(false);
+    for (label, value) in [(crate::FROM, &entry.from), (crate::TO, &entry.to)] {
+        app::widget_from_id::<InputChoice>(label)
+            .unwrap()
+            .set_value(value);
+    }
+    for (label, value) in [
+        (crate::SOURCE, &entry.source),
+        (crate::TARGET, &entry.target),
+    ] {
+        app::widget_from_id::<TextEditor>(label)
+            .unwrap()
+            .buffer()
+            .unwrap()
+            .set_text(value);
+    }
+    crate::request::set_live(live);
+    crate::rename();
+}
+
+fn star(_: &mut Button) {
+    if let Some(idx) = crate::history::selected() {
+        ENTRIES.with(|entries| {
+            if let Some(entry) = entries.borrow_mut().get_mut(idx) {
+                entry.starred = !entry.starred;
+            }
+        });
+        save();
+        crate::history::fill();
+    }
+}
+
+fn rerun(_: &mut Button) {
+    if let Some(entry) = crate::history::selected().and_then(crate::history::entry) {
+        crate::history::show(&entry);
+        app::widget_from_id::<Button>(crate::TRANSLATE)
+            .unwrap()
+            .do_callback();
+    }
+}
+
+fn visible() -> Vec<Entry> {
+    SHOWN.with(|shown| {
+        shown
+            .borrow()
+            .iter()
+            .filter_map(|idx| crate::history::entry(*idx))
+            .collect()
+    })
+}
+
+fn export(filter: &str, text: String) {
+    if text.is_empty() {
+        alert_default("History is empty.");
+        return;
+    }
+    let mut dialog = FileChooser::new(
+        std::env::var("HOME").unwrap(),
+        filter,
+        FileChooserType::Create,
+        "Export ...",
+    );
+    dialog.show();
+    while dialog.shown() {
+        app::wait();
+    }
+    if dialog.count() > 0 {
+        if let Some(file) = dialog.value(1) {
+            if let Err(error) = fs::write(&file, text) {
+                alert_default(&error.to_string());
+            }
+        };
+    };
+}
+
+/// The entries listed in the sidebar as CSV with a header row.
+pub fn csv(_: &mut MenuButton) {
+    let text = crate::history::csv_text(&crate::history::visible());
+    crate::history::export("*.csv", text);
+}
+
+fn csv_text(entries: &[Entry]) -> String {
+    let quote = |field: &str| format!("\"{}\"", field.replace('"', "\"\""));
+    let rows: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            [
+                crate::history::date(entry.time),
+                entry.from.clone(),
+                entry.to.clone(),
+                entry.source.clone(),
+                entry.target.clone(),
+                entry.starred.to_string(),
+            ]
+            .map(|field| quote(&field))
+            .join(",")
+        })
+        .collect();
+    match rows.is_empty() {
+        true => String::new(),
+        false => format!(
+            "time,from,to,source,target,favourite\n{}\n",
+            rows.join("\n")
+        ),
+    }
+}
+
+/// The entries listed in the sidebar as an Anki import file: source on the front,
+/// translation on the back, tagged with the language pair.
+pub fn anki(_: &mut MenuButton) {
+    let text = crate::history::anki_text(&crate::history::visible());
+    crate::history::export("*.txt", text);
+}
+
+fn anki_text(entries: &[Entry]) -> String {
+    let field = |text: &str| text.trim().replace(['\t', '\n'], " ");
+    let tag = |lang: &str| lang.replace(' ', "_");
+    let rows: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "{}\t{}\t{}-{}{}",
+                field(&entry.source),
+                field(&entry.target),
+                tag(&entry.from),
+                tag(&entry.to),
+                if entry.starred { " favourite" } else { "" },
+            )
+        })
+        .collect();
+    match rows.is_empty() {
+        true => String::new(),
+        false => format!(
+            "#separator:tab\n#html:false\n#tags column:3\n{}\n",
+            rows.join("\n")
+        ),
+    }
+}
+
+pub fn sidebar() -> Flex {
+    let mut element = Flex::default_fill().column().with_id(SIDEBAR);
+    let mut search = Input::default().with_id(SEARCH);
+    search.set_tooltip(SEARCH);
+    search.set_trigger(CallbackTrigger::Changed);
+    search.set_callback(|_| crate::history::fill());
+    element.fixed(&search, crate::HEIGHT);
+    let mut browser = HoldBrowser::default().with_id(HISTORY);
+    browser.set_tooltip(HISTORY);
+    browser.set_callback(|_| {
+        if let Some(entry) = crate::history::selected().and_then(crate::history::entry) {
+            crate::history::show(&entry);
+        }
+    });
+    let mut bar = Flex::default();
+    crate::button(STARRED, "@#search", &mut bar)
+        .with_type(ButtonType::Toggle)
+        .set_callback(|_| crate::history::fill());
+    Frame::default();
+    crate::button(STAR, "★", &mut bar).set_callback(crate::history::star);
+    crate::button(RERUN, "@#redo", &mut bar).set_callback(crate::history::rerun);
+    bar.end();
+    bar.set_pad(0);
+    element.fixed(&bar, crate::HEIGHT);
+    element.end();
+    element.set_pad(crate::SPACE);
+    crate::history::fill();
+    element
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(from: &str, to: &str, source: &str, live: bool) -> Entry {
+        Entry {
+            from: from.to_string(),
+            to: to.to_string(),
+            source: source.to_string(),
+            target: source.to_uppercase(),
+            time: 0,
+            starred: false,
+            live,
+        }
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(date(0), "1970-01-01 00:00:00");
+        assert_eq!(date(1709210096), "2024-02-29 12:34:56");
+        assert_eq!(date(951868799), "2000-02-29 23:59:59");
+        assert_eq!(date(951868800), "2000-03-01 00:00:00");
+        assert_eq!(date(4107542400), "2100-03-01 00:00:00");
+    }
+
+    #[test]
+    fn exports() {
+        let mut quoted = entry("English", "Old English", "say \"hi\",\nthen\tgo", false);
+        quoted.starred = true;
+        let entries = [quoted, entry("German", "English", " hallo ", false)];
+        assert_eq!(
+            csv_text(&entries),
+            "time,from,to,source,target,favourite\n\
+             \"1970-01-01 00:00:00\",\"English\",\"Old English\",\"say \"\"hi\"\",\nthen\tgo\",\
+             \"SAY \"\"HI\"\",\nTHEN\tGO\",\"true\"\n\
+             \"1970-01-01 00:00:00\",\"German\",\"English\",\" hallo \",\" HALLO \",\"false\"\n"
+        );
+        assert_eq!(
+            anki_text(&entries),
+            "#separator:tab\n#html:false\n#tags column:3\n\
+             say \"hi\", then go\tSAY \"HI\", THEN GO\tEnglish-Old_English favourite\n\
+             hallo\tHALLO\tGerman-English\n"
+        );
+        assert_eq!(csv_text(&[]), "");
+        assert_eq!(anki_text(&[]), "");
+    }
+
+    #[test]
+    fn live_entries() {
+        let mut entries = vec![entry("English", "German", "hel", true)];
+        // typed on
+        drop_live(&mut entries, "English", "German", "hello");
+        assert!(entries.is_empty());
+        // another pair, an unrelated text, a starred or a finished entry stay
+        for (first, source) in [
+            (entry("English", "French", "hel", true), "hello"),
+            (entry("English", "German", "bye", true), "hello"),
+            (entry("English", "German", "hel", false), "hello"),
+        ] {
+            let mut entries = vec![first];
+            drop_live(&mut entries, "English", "German", source);
+            assert_eq!(entries.len(), 1);
+        }
+        let mut starred = entry("English", "German", "hello", true);
+        starred.starred = true;
+        let mut entries = vec![starred];
+        // cut back
+        drop_live(&mut entries, "English", "German", "hell");
+        assert_eq!(entries.len(), 1);
+        // only the newest entry is looked at
+        let mut entries = vec![
+            entry("English", "German", "x", false),
+            entry("English", "German", "hel", true),
+        ];
+        drop_live(&mut entries, "English", "German", "hello");
+        assert_eq!(entries.len(), 2);
+    }
+
+    #[test]
+    fn recording() {
+        let mut entries = vec![];
+        record(&mut entries, entry("English", "German", "a", false));
+        record(&mut entries, entry("English", "German", "b", false));
+        entries[1].starred = true;
+        // the same text again moves up and keeps its star
+        record(&mut entries, entry("English", "German", "a", false));
+        let sources: Vec<(&str, bool)> = entries
+            .iter()
+            .map(|e| (e.source.as_str(), e.starred))
+            .collect();
+        assert_eq!(sources, [("a", true), ("b", false)]);
+        // the oldest unstarred entries go past the limit
+        for i in 0..LIMIT {
+            record(
+                &mut entries,
+                entry("English", "German", &i.to_string(), false),
+            );
+        }
+        assert_eq!(entries.len(), LIMIT);
+        assert!(entries.iter().any(|e| e.source == "a"));
+        assert!(!entries.iter().any(|e| e.source == "b"));
+    }
+}
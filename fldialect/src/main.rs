@@ -21,9 +21,19 @@ use {
         window::Window,
     },
     fltk_theme::{color_themes, ColorTheme},
-    std::{env, fs, path::Path, process::Command, thread},
+    std::{
+        env, fs,
+        path::PathBuf,
+        process::Command,
+        sync::mpsc::{self, TryRecvError},
+        thread,
+    },
 };
 
+mod backend;
+mod history;
+mod request;
+
 const NAME: &str = "FlDialect";
 const DIAL: &str = "Spinner";
 const FROM: &str = "From";
@@ -31,6 +41,7 @@ const TO: &str = "To";
 const SOURCE: &str = "Source";
 const TARGET: &str = "Target";
 const PAGE: &str = "Page";
+const BODY: &str = "Body";
 const HERO: &str = "Hero";
 const FOOTER: &str = "Footer";
 const FONTS: &str = "Fonts";
@@ -49,9 +60,11 @@ fn main() {
 }
 
 fn app() {
-    app::GlobalState::new(crate::list());
     let app = app::App::default();
     let (mut window, params) = crate::window();
+    backend::select(params[6]);
+    request::set_live(params[7] != 0);
+    app::GlobalState::new(Vec::<String>::new());
     {
         let mut page = Flex::default_fill().column().with_id(crate::PAGE);
         {
@@ -72,14 +85,25 @@ fn app() {
             page.fixed(&header, HEIGHT);
         }
         {
+            let mut body = Flex::default_fill().with_id(crate::BODY); //BODY
+            let mut sidebar = history::sidebar();
+            body.fixed(&sidebar, 0);
+            sidebar.hide();
             let mut hero = Flex::default_fill().column().with_id(crate::HERO); //HERO
             crate::text(crate::SOURCE);
             hero.fixed(&crate::handle(crate::HANDLE), SPACE);
             crate::text(crate::TARGET);
             hero.end();
+            app::widget_from_id::<TextEditor>(crate::SOURCE)
+                .unwrap()
+                .buffer()
+                .unwrap()
+                .add_modify_callback(request::edited);
             hero.handle(crate::resize);
             hero.set_pad(0);
             hero.set_margin(0);
+            body.end();
+            body.set_pad(0);
         }
         {
             let mut footer = Flex::default_fill().with_id(crate::FOOTER); //FOOTER
@@ -116,6 +140,7 @@ fn app() {
     app::widget_from_id::<Counter>(crate::SIZE)
         .unwrap()
         .do_callback();
+    crate::backend(params[6], false);
     app.run().unwrap();
 }
 
@@ -222,7 +247,12 @@ fn input(tooltip: &'static str) {
     element.input().set_trigger(CallbackTrigger::Changed);
     element.input().set_callback(move |input| {
         choice.clear();
-        for lang in app::GlobalState::<Vec<String>>::get().with(|languages| languages.clone()) {
+        let mut languages =
+            app::GlobalState::<Vec<String>>::get().with(|languages| languages.clone());
+        if tooltip == crate::FROM {
+            languages.insert(0, request::AUTO.to_string());
+        }
+        for lang in languages {
             if lang
                 .to_lowercase()
                 .starts_with(&input.value().to_lowercase())
@@ -256,6 +286,44 @@ fn menu() -> MenuButton {
         crate::hide,
     );
     element.at(idx).unwrap().set();
+    element.add(
+        "&View/&History\t",
+        Shortcut::Ctrl | 'h',
+        MenuFlag::Toggle,
+        crate::sidebar,
+    );
+    let idx: i32 = element.add(
+        "&View/&Live Translation\t",
+        Shortcut::Ctrl | 'l',
+        MenuFlag::Toggle,
+        crate::live,
+    );
+    if request::live() {
+        element.at(idx).unwrap().set();
+    }
+    element.add(
+        "E&xport/&CSV...\t",
+        Shortcut::None,
+        MenuFlag::Normal,
+        history::csv,
+    );
+    element.add(
+        "E&xport/&Anki...\t",
+        Shortcut::None,
+        MenuFlag::Normal,
+        history::anki,
+    );
+    for (ord, name) in backend::BACKENDS.iter().enumerate() {
+        let idx: i32 = element.add(
+            &format!("&Backend/&{name}\t"),
+            Shortcut::None,
+            MenuFlag::Radio,
+            move |_| crate::backend(ord as u8, true),
+        );
+        if ord as u8 == backend::selected() {
+            element.at(idx).unwrap().set();
+        }
+    }
     element.add(
         "@#circle  T&ranslate",
         Shortcut::Ctrl | 'r',
@@ -364,20 +432,94 @@ fn hide(_: &mut MenuButton) {
     page.redraw();
 }
 
+fn sidebar(_: &mut MenuButton) {
+    let mut body = app::widget_from_id::<Flex>(crate::BODY).unwrap();
+    let mut sidebar = app::widget_from_id::<Flex>(history::SIDEBAR).unwrap();
+    if sidebar.visible() {
+        body.fixed(&sidebar, 0);
+        body.set_pad(0);
+        sidebar.hide();
+    } else {
+        body.fixed(&sidebar, WIDTH * 2 + HEIGHT);
+        body.set_pad(SPACE);
+        sidebar.show();
+    };
+    body.redraw();
+}
+
+fn live(_: &mut MenuButton) {
+    request::set_live(!request::live());
+    if request::live() {
+        request::start(true);
+    }
+}
+
 fn rename() {
+    let from = app::widget_from_id::<InputChoice>(crate::FROM)
+        .unwrap()
+        .value()
+        .unwrap_or_default();
     app::first_window().unwrap().set_label(&format!(
         "Translate from {} to {} - {NAME}",
-        app::widget_from_id::<InputChoice>(crate::FROM)
-            .unwrap()
-            .value()
-            .unwrap(),
+        match (from == request::AUTO, request::detected()) {
+            (true, Some(detected)) => format!("{from} ({detected})"),
+            _ => from,
+        },
         app::widget_from_id::<InputChoice>(crate::TO)
             .unwrap()
             .value()
-            .unwrap(),
+            .unwrap_or_default(),
     ));
 }
 
+// Languages are listed in the background, a server can take a while to answer.
+// Errors are only printed at startup.
+fn backend(idx: u8, alert: bool) {
+    backend::select(idx);
+    let current = backend::current();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        sender.send(current.languages()).ok();
+    });
+    app::add_timeout3(0.02, move |handle| {
+        // switched again meanwhile
+        if backend::selected() != idx {
+            return;
+        }
+        match receiver.try_recv() {
+            Ok(languages) => crate::languages(languages, alert),
+            Err(TryRecvError::Empty) => app::repeat_timeout3(0.02, handle),
+            Err(TryRecvError::Disconnected) => {}
+        }
+    });
+}
+
+fn languages(languages: Result<Vec<String>, String>, alert: bool) {
+    let languages = match languages {
+        Ok(languages) => languages,
+        Err(error) if alert => {
+            alert_default(&error);
+            Vec::new()
+        }
+        Err(error) => {
+            eprintln!("\x1b[31m{error}\x1b[0m");
+            Vec::new()
+        }
+    };
+    app::GlobalState::<Vec<String>>::get().with(move |state| *state = languages.clone());
+    for label in [crate::FROM, crate::TO] {
+        let mut element = app::widget_from_id::<InputChoice>(label).unwrap();
+        let value = element.value().unwrap_or_default();
+        element.input().set_value("");
+        element.input().do_callback();
+        match app::GlobalState::<Vec<String>>::get().with(move |state| state.contains(&value)) {
+            true => element.set_value(&value),
+            false => element.set_value_index(0),
+        }
+    }
+    crate::rename();
+}
+
 fn resize(flex: &mut Flex, event: Event) -> bool {
     if event == Event::Resize {
         flex.set_type(match flex.width() < flex.height() {
@@ -395,48 +537,22 @@ fn resize(flex: &mut Flex, event: Event) -> bool {
 fn switch(_: &mut Button) {
     let mut from = app::widget_from_id::<InputChoice>(crate::FROM).unwrap();
     let mut to = app::widget_from_id::<InputChoice>(crate::TO).unwrap();
-    if from.value() != to.value() {
-        let temp = from.value().unwrap();
-        from.set_value(&to.value().unwrap());
+    let mut temp = from.value().unwrap_or_default();
+    if temp == request::AUTO {
+        match request::detected() {
+            Some(detected) => temp = detected,
+            None => return,
+        }
+    }
+    if temp != to.value().unwrap_or_default() {
+        from.set_value(&to.value().unwrap_or_default());
         to.set_value(&temp);
         crate::rename();
     }
 }
 
-fn translate(button: &mut Button) {
-    let from = app::widget_from_id::<InputChoice>(crate::FROM)
-        .unwrap()
-        .value()
-        .unwrap();
-    let to = app::widget_from_id::<InputChoice>(crate::TO)
-        .unwrap()
-        .value()
-        .unwrap();
-    let source = app::widget_from_id::<TextEditor>(crate::SOURCE)
-        .unwrap()
-        .buffer()
-        .unwrap()
-        .text();
-    if from != to && !source.is_empty() {
-        button.deactivate();
-        let voice = app::widget_from_id::<Button>(crate::SPEAK).unwrap().value();
-        let handler = thread::spawn(move || -> String { crate::run(voice, from, to, source) });
-        while !handler.is_finished() {
-            app::wait();
-            app::sleep(0.02);
-            app::widget_from_id::<Dial>(crate::DIAL)
-                .unwrap()
-                .do_callback();
-        }
-        if let Ok(msg) = handler.join() {
-            app::widget_from_id::<TextEditor>(TARGET)
-                .unwrap()
-                .buffer()
-                .unwrap()
-                .set_text(&msg);
-            button.activate();
-        };
-    };
+fn translate(_: &mut Button) {
+    request::start(false);
 }
 
 fn window() -> (Window, Vec<u8>) {
@@ -464,18 +580,19 @@ fn window() -> (Window, Vec<u8>) {
   <rect width="254" height="93" id="rect26" style="fill:url(#linearGradient8)"/>
   <path d="m 72,11.5 -60.5,0 0,78.5 m 0,-43 44.5,0 m 27.5,-44 0,78.5 51.5,0 m -25,-70 70,0 m -33.5,0 0,78.5 m 45,-87 0,87 m 71,-101 -57.75,57.75 57.75,57.75" id="path28" style="fill:none;stroke:#ffffff;stroke-width:17"/>
 </svg>"#;
-    const DEFAULT: [u8; 6] = [
+    const DEFAULT: [u8; 8] = [
         1,   // [0] window_width * U8 +
         105, // [1] window_width_fract
         2,   // [2] window_height * U8 +
         130, // [3] window_height_fract
         1,   // [4] footer_font
         14,  // [5] footer_size
+        0,   // [6] backend
+        0,   // [7] live
     ];
     const U8: i32 = 255;
-    const CONFIG: &str = "/.config/";
-    let file = env::var("HOME").unwrap() + CONFIG + NAME;
-    let params: Vec<u8> = if Path::new(&file).exists() {
+    let file = crate::config().join("params");
+    let params: Vec<u8> = if file.exists() {
         if let Ok(value) = fs::read(&file) {
             if value.len() == DEFAULT.len() {
                 value
@@ -515,6 +632,8 @@ fn window() -> (Window, Vec<u8>) {
                     (window.height() % U8) as u8,
                     app::widget_from_id::<Choice>(crate::FONTS).unwrap().value() as u8,
                     app::widget_from_id::<Counter>(crate::SIZE).unwrap().value() as u8,
+                    backend::selected(),
+                    request::live() as u8,
                 ],
             )
             .unwrap();
@@ -533,63 +652,16 @@ fn font(font: &mut Choice) {
     }
 }
 
-fn run(voice: bool, from: String, to: String, word: String) -> String {
-    let run = Command::new("trans")
-        .args([
-            "-join-sentence",
-            "-no-ansi",
-            "-show-languages",
-            "n",
-            "-show-original",
-            "n",
-            "-show-original-dictionary",
-            "n",
-            "-show-original-dictionary",
-            "n",
-            "-show-prompt-message",
-            "n",
-            "-show-alternatives",
-            "n",
-            "-show-translation-phonetics",
-            "n",
-            "-indent",
-            "2",
-            "-from",
-            &from,
-            "-to",
-            &to,
-            match word.split_whitespace().count() {
-                1 => "",
-                _ => "-brief",
-            },
-            if voice { "-speak" } else { "" },
-            &word.trim().replace("\n\n", "\n"),
-        ])
-        .output()
-        .expect("failed to execute bash");
-    String::from_utf8_lossy(match run.status.success() {
-        true => &run.stdout,
-        false => &run.stderr,
-    })
-    .to_string()
-}
-
-fn list() -> Vec<String> {
-    if cfg!(target_family = "unix") {
-        let run = Command::new("trans")
-            .arg("-list-languages-english")
-            .output()
-            .expect("failed to execute bash");
-        match run.status.success() {
-            true => String::from_utf8_lossy(&run.stdout)
-                .lines()
-                .map(str::to_string)
-                .collect::<Vec<String>>(),
-            false => panic!("\x1b[31m{}\x1b[0m", String::from_utf8_lossy(&run.stderr)),
-        }
-    } else {
-        Vec::from(["no way".to_string()])
+// ~/.config/FlDialect, older versions kept the window params in a file of that name
+fn config() -> PathBuf {
+    let dir = PathBuf::from(env::var("HOME").unwrap())
+        .join(".config")
+        .join(NAME);
+    if dir.is_file() {
+        fs::remove_file(&dir).ok();
     }
+    fs::create_dir_all(&dir).ok();
+    dir
 }
 
 fn once() -> bool {
@@ -0,0 +1,230 @@
+use {
+    crate::{
+        backend::{self, TranslationBackend},
+        history,
+    },
+    fltk::{
+        app,
+        button::Button,
+        dialog::alert_default,
+        misc::InputChoice,
+        prelude::{ButtonExt, DisplayExt, WidgetExt},
+        text::TextEditor,
+        valuator::Dial,
+    },
+    std::{
+        cell::RefCell,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            mpsc::{self, TryRecvError},
+        },
+        thread,
+    },
+};
+
+pub const AUTO: &str = "Auto";
+// Typing pauses this long before a live translation
+const DELAY: f64 = 0.6;
+const TICK: f64 = 0.02;
+
+static LIVE: AtomicBool = AtomicBool::new(false);
+static EDITS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // the language Auto detected last
+    static DETECTED: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+// from, to, source, target
+type Translation = (String, String, String, String);
+
+pub fn live() -> bool {
+    LIVE.load(Ordering::Relaxed)
+}
+
+pub fn set_live(flag: bool) {
+    LIVE.store(flag, Ordering::Relaxed);
+}
+
+pub fn detected() -> Option<String> {
+    DETECTED.with(|detected| Some(detected.borrow().clone()).filter(|name| !name.is_empty()))
+}
+
+fn finish(result: Result<Translation, String>, auto: bool, live: bool) {
+    app::widget_from_id::<Button>(crate::TRANSLATE)
+        .unwrap()
+        .activate();
+    match result {
+        Ok((from, to, source, target)) => {
+            app::widget_from_id::<TextEditor>(crate::TARGET)
+                .unwrap()
+                .buffer()
+                .unwrap()
+                .set_text(&target);
+            if auto {
+                DETECTED.with(|detected| *detected.borrow_mut() = from.clone());
+                crate::rename();
+            }
+            match live {
+                _ if from == to => {}
+                true => history::replace(&from, &to, &source, &target),
+                false => history::add(&from, &to, &source, &target),
+            }
+        }
+        Err(error) if error == backend::CANCELLED => {}
+        Err(error) if live => eprintln!("\x1b[31m{error}\x1b[0m"),
+        Err(error) => alert_default(&error),
+    }
+}
+
+// Starts a request, None when there's nothing to translate. Either way the
+// pending request goes stale.
+fn begin(from: &str, to: &str, source: &str) -> Option<u64> {
+    let generation = backend::next();
+    (from != to && !source.trim().is_empty()).then_some(generation)
+}
+
+// Runs on the request's thread, a text Auto finds to be in `to` already is kept as is
+fn translate(
+    backend: &dyn TranslationBackend,
+    voice: bool,
+    from: String,
+    to: String,
+    source: String,
+) -> Result<Translation, String> {
+    let from = match from == AUTO {
+        true => backend.detect(&source)?,
+        false => from,
+    };
+    let target = match from == to {
+        true => source.clone(),
+        false => backend.translate(voice, &from, &to, &source)?,
+    };
+    Ok((from, to, source, target))
+}
+
+/// Translates `SOURCE` in a background thread, starting another request makes this
+/// one stale. Live requests don't speak and only print errors.
+pub fn start(live: bool) {
+    let from = app::widget_from_id::<InputChoice>(crate::FROM)
+        .unwrap()
+        .value()
+        .unwrap_or_default();
+    let to = app::widget_from_id::<InputChoice>(crate::TO)
+        .unwrap()
+        .value()
+        .unwrap_or_default();
+    let source = app::widget_from_id::<TextEditor>(crate::SOURCE)
+        .unwrap()
+        .buffer()
+        .unwrap()
+        .text();
+    let mut translate_button = app::widget_from_id::<Button>(crate::TRANSLATE).unwrap();
+    let generation = match begin(&from, &to, &source) {
+        Some(generation) => generation,
+        None => {
+            // the stale request's timeout won't reactivate it
+            translate_button.activate();
+            return;
+        }
+    };
+    let voice = !live && app::widget_from_id::<Button>(crate::SPEAK).unwrap().value();
+    let auto = from == AUTO;
+    translate_button.deactivate();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let result = translate(&*backend::current(), voice, from, to, source);
+        sender.send(result).ok();
+    });
+    app::add_timeout3(TICK, move |handle| {
+        if backend::stale(generation) {
+            return;
+        }
+        match receiver.try_recv() {
+            Ok(result) => crate::request::finish(result, auto, live),
+            Err(TryRecvError::Empty) => {
+                app::widget_from_id::<Dial>(crate::DIAL)
+                    .unwrap()
+                    .do_callback();
+                app::repeat_timeout3(TICK, handle);
+            }
+            Err(TryRecvError::Disconnected) => {
+                crate::request::finish(Err("Translation failed.".to_string()), auto, live)
+            }
+        }
+    });
+}
+
+/// `SOURCE`'s modify callback, in live mode translates once typing pauses.
+pub fn edited(_: i32, inserted: i32, deleted: i32, _: i32, _: &str) {
+    if live() && (inserted > 0 || deleted > 0) {
+        let edits = EDITS.fetch_add(1, Ordering::SeqCst) + 1;
+        app::add_timeout3(DELAY, move |_| {
+            if EDITS.load(Ordering::SeqCst) == edits {
+                crate::request::start(true);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::sync::atomic::AtomicU32};
+
+    #[test]
+    fn requests_go_stale() {
+        let first = begin("English", "German", "Hello").unwrap();
+        assert!(!backend::stale(first));
+        let second = begin("English", "German", "Hello world").unwrap();
+        assert!(backend::stale(first));
+        assert!(!backend::stale(second));
+        // nothing to translate, the pending request is dropped all the same
+        assert_eq!(begin("English", "German", " \n"), None);
+        assert!(backend::stale(second));
+        let third = begin("English", "German", "Hello").unwrap();
+        assert_eq!(begin("German", "German", "Hallo"), None);
+        assert!(backend::stale(third));
+    }
+
+    // Detects German and translates to "from>to:text"
+    #[derive(Default)]
+    struct Stub {
+        translated: AtomicU32,
+    }
+
+    impl TranslationBackend for Stub {
+        fn languages(&self) -> Result<Vec<String>, String> {
+            Ok(vec!["English".to_string(), "German".to_string()])
+        }
+        fn translate(&self, _: bool, from: &str, to: &str, text: &str) -> Result<String, String> {
+            self.translated.fetch_add(1, Ordering::Relaxed);
+            Ok(format!("{from}>{to}:{text}"))
+        }
+        fn detect(&self, _: &str) -> Result<String, String> {
+            Ok("German".to_string())
+        }
+    }
+
+    #[test]
+    fn auto_detection() {
+        let stub = Stub::default();
+        let run = |from: &str, to: &str| {
+            translate(&stub, false, from.into(), to.into(), "Hallo".into()).unwrap()
+        };
+        assert_eq!(
+            run(AUTO, "English"),
+            (
+                "German".to_string(),
+                "English".to_string(),
+                "Hallo".to_string(),
+                "German>English:Hallo".to_string()
+            )
+        );
+        assert_eq!(stub.translated.load(Ordering::Relaxed), 1);
+        // already in the target language, the backend isn't asked
+        assert_eq!(run(AUTO, "German").3, "Hallo");
+        assert_eq!(stub.translated.load(Ordering::Relaxed), 1);
+        assert_eq!(run("English", "German").3, "English>German:Hallo");
+        assert_eq!(stub.translated.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,83 @@
+// A language server speaking just enough LSP over stdio for the client tests in src/lsp:
+// every line containing FIXME gets a diagnostic, definitions point at the document's
+// first line and hovers echo the position.
+use serde_json::{json, Value};
+use std::io::{self, BufReader};
+
+#[path = "../src/lsp/rpc.rs"]
+mod rpc;
+
+fn diagnostics(uri: &Value, text: &str) -> Value {
+    let diagnostics: Vec<Value> = text
+        .lines()
+        .enumerate()
+        .filter_map(|(line, s)| {
+            let character = s.find("FIXME")?;
+            Some(json!({
+                "range": {
+                    "start": { "line": line, "character": character },
+                    "end": { "line": line, "character": character + 5 },
+                },
+                "message": "FIXME left in",
+            }))
+        })
+        .collect();
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+fn main() -> io::Result<()> {
+    let mut stdin = BufReader::new(io::stdin());
+    let mut stdout = io::stdout();
+    while let Some(msg) = rpc::read_message(&mut stdin)? {
+        let params = &msg["params"];
+        let doc = &params["textDocument"];
+        let result = match msg["method"].as_str().unwrap_or_default() {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                }
+            }),
+            "textDocument/didOpen" => {
+                let text = doc["text"].as_str().unwrap_or_default();
+                rpc::write_message(&mut stdout, &diagnostics(&doc["uri"], text))?;
+                continue;
+            }
+            "textDocument/didChange" => {
+                let text = params["contentChanges"][0]["text"]
+                    .as_str()
+                    .unwrap_or_default();
+                rpc::write_message(&mut stdout, &diagnostics(&doc["uri"], text))?;
+                continue;
+            }
+            "textDocument/definition" => json!({
+                "uri": doc["uri"],
+                "range": {
+                    "start": { "line": 0, "character": 0 },
+                    "end": { "line": 0, "character": 0 },
+                },
+            }),
+            "textDocument/hover" => json!({
+                "contents": format!(
+                    "{}:{}",
+                    params["position"]["line"], params["position"]["character"]
+                ),
+            }),
+            "exit" => break,
+            _ => Value::Null,
+        };
+        // notifications don't get an answer
+        if !msg["id"].is_null() {
+            rpc::write_message(
+                &mut stdout,
+                &json!({ "jsonrpc": "2.0", "id": msg["id"], "result": result }),
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -1,4 +1,9 @@
-use crate::state::STATE;
+use crate::{
+    commands::{self, Command},
+    cursors, dialogs, editing, fbr, git, gui, outline, preview, script, session,
+    state::{self, CloseChoice, STATE},
+    swap, tasks,
+};
 use fltk::{enums::*, prelude::*, *};
 use std::{fs, path::PathBuf};
 
@@ -8,22 +13,129 @@ fn nfc_get_file(mode: dialog::NativeFileChooserType) -> PathBuf {
     nfc.filename()
 }
 
-fn find() {
+// Returns false if the user cancelled or the file couldn't be written
+fn save_as(id: usize) -> bool {
+    let c = nfc_get_file(dialog::NativeFileChooserType::BrowseSaveFile);
+    if c.as_os_str().is_empty() {
+        return false;
+    }
+    STATE.with(move |s| s.save_as(id, c.clone()))
+}
+
+// Saves buffer id, untitled buffers go through Save as...
+fn save(id: usize) -> bool {
+    let untitled = STATE.with(move |s| s.map.get(&id).map_or(false, |b| b.current_file.is_none()));
+    if untitled {
+        save_as(id)
+    } else {
+        STATE.with(move |s| s.save(id))
+    }
+}
+
+pub fn find(with_replace: bool) {
     let mut dlg: window::Window = app::widget_from_id("find").unwrap();
+    let mut row: group::Flex = app::widget_from_id("replace_row").unwrap();
+    let main_win = app::first_window().unwrap();
+    let h = if with_replace {
+        row.show();
+        80
+    } else {
+        row.hide();
+        45
+    };
+    dlg.resize(
+        main_win.x() + main_win.w() - dlg.w(),
+        main_win.y() + 60,
+        dlg.w(),
+        h,
+    );
+    dlg.show();
+    let mut input: input::Input = app::widget_from_id("find_input").unwrap();
+    input.take_focus().ok();
+}
+
+pub fn palette(mode: dialogs::PaletteMode) {
+    let mut dlg: window::Window = app::widget_from_id("palette").unwrap();
     let main_win = app::first_window().unwrap();
-    dlg.resize(main_win.x() + main_win.w() - 300, dlg.y() + 30, 300, 50);
+    dialogs::load_palette(mode);
+    dlg.resize(
+        main_win.x() + (main_win.w() - dlg.w()) / 2,
+        main_win.y() + 60,
+        dlg.w(),
+        dlg.h(),
+    );
     dlg.show();
+    let mut input: input::Input = app::widget_from_id("palette_input").unwrap();
+    input.take_focus().ok();
 }
 
-fn replace() {
-    let mut dlg: window::Window = app::widget_from_id("replace").unwrap();
+pub fn find_in_files() {
+    let mut dlg: window::Window = app::widget_from_id("find_in_files").unwrap();
     let main_win = app::first_window().unwrap();
-    dlg.resize(main_win.x() + main_win.w() - 300, dlg.y() + 30, 300, 80);
+    dlg.resize(
+        main_win.x() + (main_win.w() - dlg.w()) / 2,
+        main_win.y() + (main_win.h() - dlg.h()) / 2,
+        dlg.w(),
+        dlg.h(),
+    );
     dlg.show();
 }
 
+pub fn show_diff() {
+    let current = STATE.with(|s| s.current_file().zip(s.buf()));
+    let (path, buf) = match current {
+        Some(current) => current,
+        None => return,
+    };
+    match git::diff(&path, &buf.text()) {
+        Some(lines) if lines.is_empty() => dialog::message_default("No changes against HEAD."),
+        Some(lines) => {
+            let mut dlg: window::Window = app::widget_from_id("diff_dialog").unwrap();
+            let mut b: browser::Browser = app::widget_from_id("diff_browser").unwrap();
+            b.clear();
+            for line in lines {
+                let color = match line.chars().next() {
+                    Some('+') => "@C2",
+                    Some('-') => "@C1",
+                    Some('@') => "@C4",
+                    _ => "",
+                };
+                b.add(&format!("{}@.{}", color, line));
+            }
+            dlg.set_label(&format!("Diff: {}", path.display()));
+            dlg.show();
+        }
+        None => dialog::alert_default(&format!("{} is not in a git repository.", path.display())),
+    }
+}
+
+fn ask_save(name: &str) -> CloseChoice {
+    match dialog::choice2_default(
+        &format!("Save changes to {} before closing?", name),
+        "Save",
+        "Discard",
+        "Cancel",
+    ) {
+        Some(0) => CloseChoice::Save,
+        Some(1) => CloseChoice::Discard,
+        _ => CloseChoice::Cancel,
+    }
+}
+
+// Prompts for every modified buffer among ids, returns false if closing should be aborted.
+// The dialogs run their own event loop, so STATE must not be held while they're shown.
+pub fn guard_close(ids: Vec<usize>) -> bool {
+    let dirty = STATE.with(move |s| s.dirty(&ids));
+    match state::confirm_close(&dirty, ask_save) {
+        Some(to_save) => to_save.into_iter().all(save),
+        None => false,
+    }
+}
+
 pub fn win_cb(_: &mut window::Window) {
-    if app::event() == Event::Close {
+    if app::event() == Event::Close && guard_close(STATE.with(|s| s.ids())) {
+        STATE.with(|s| session::save(s));
+        swap::discard_all();
         app::quit();
     }
 }
@@ -33,184 +145,312 @@ pub fn editor_cb(_e: &mut text::TextEditor) {
 }
 
 pub fn new_file() {
-    let dlg = dialog::input_default("Enter file name", "");
-    if let Some(f) = dlg {
-        fs::File::create(f).ok();
-    }
+    STATE.with(|s| s.append(None));
 }
 
+// Creates the directory next to the file browser's selection
 pub fn new_dir() {
     let dlg = dialog::input_default("Enter directory name", "");
     if let Some(f) = dlg {
-        fs::create_dir(f).ok();
+        let dir = fbr::selected_dir();
+        if fs::create_dir(dir.join(f)).is_ok() {
+            fbr::refresh(&dir);
+        }
     }
 }
 
-pub fn menu_cb(m: &mut impl MenuExt) {
-    if let Ok(mpath) = m.item_pathname(None) {
-        match mpath.as_str() {
-            "&File/New File...\t" => {
-                new_file();
-            }
-            "&File/New Dir...\t" => {
-                new_dir();
-            }
-            "&File/Open...\t" => {
-                let c = nfc_get_file(dialog::NativeFileChooserType::BrowseFile);
-                if c.exists() {
-                    STATE.with(move |s| {
-                        s.append(Some(c.canonicalize().unwrap()));
-                    });
-                }
-            }
-            "&File/Save\t" => {
-                STATE.with(|s| {
-                    if let Some(id) = s.current_id() {
-                        let e = s.map.get(&id).unwrap();
-                        let modified = e.modified;
-                        if let Some(current_file) = e.current_file.as_ref() {
-                            if modified && current_file.exists() {
-                                fs::write(current_file, e.buf.text()).ok();
-                                s.was_modified(false);
-                            }
-                        }
-                    }
-                });
-            }
-            "&File/Save as...\t" => {
-                let c = nfc_get_file(dialog::NativeFileChooserType::BrowseSaveFile);
-                if c.exists() {
-                    STATE.with(move |s| {
-                        if let Some(buf) = s.buf().as_ref() {
-                            fs::write(&c, buf.text()).expect("Failed to write to file!");
-                            s.was_modified(false);
-                        }
-                    });
-                }
-            }
-            "&File/Save All\t" => {
-                STATE.with(|s| {
-                    for v in s.map.values_mut() {
-                        if v.modified && v.current_file.as_ref().unwrap().exists() {
-                            fs::write(v.current_file.as_ref().unwrap(), v.buf.text()).ok();
-                            v.modified = true;
-                        }
-                    }
-                });
-            }
-            "&File/Quit\t" => app::quit(),
-            "/Undo\t" | "&Edit/Undo\t" => STATE.with(|s| {
-                if let Some(e) = s.current_editor() {
-                    e.undo()
-                }
-            }),
-            "/Redo\t" | "&Edit/Redo\t" => STATE.with(|s| {
-                if let Some(e) = s.current_editor() {
-                    e.redo()
-                }
-            }),
-            "/Cut\t" | "&Edit/Cut\t" => STATE.with(|s| {
-                if let Some(e) = s.current_editor() {
-                    e.cut()
-                }
-            }),
-            "/Copy\t" | "&Edit/Copy\t" => STATE.with(|s| {
-                if let Some(e) = s.current_editor() {
-                    e.copy()
-                }
-            }),
-            "/Paste\t" | "&Edit/Paste\t" => STATE.with(|s| {
-                if let Some(e) = s.current_editor() {
-                    e.paste()
-                }
-            }),
-            "/Find\t" | "&Edit/Find\t" => find(),
-            "/Replace\t" | "&Edit/Replace\t" => replace(),
-            "&View/File browser\t" => {
-                let mut item = m.at(m.value()).unwrap();
-                let fbr: group::Group = app::widget_from_id("fbr_group").unwrap();
-                let mut parent = group::Flex::from_dyn_widget(&fbr.parent().unwrap()).unwrap();
-                if !item.value() {
-                    parent.fixed(&fbr, 1);
-                    item.clear();
-                } else {
-                    parent.fixed(&fbr, 180);
-                    item.set();
-                }
-                app::redraw();
-            }
-            "&View/Terminal\t" => {
-                let mut item = m.at(m.value()).unwrap();
-                let term: group::Group = app::widget_from_id("term_group").unwrap();
-                let mut parent = group::Flex::from_dyn_widget(&term.parent().unwrap()).unwrap();
-                if !item.value() {
-                    parent.fixed(&term, 1);
-                    item.clear();
-                } else {
-                    parent.fixed(&term, 160);
-                    item.set();
+pub fn reveal_file() {
+    if let Some(p) = STATE.with(|s| s.current_file()) {
+        let visible = app::widget_from_id::<group::Group>("fbr_group").map(|w| w.w() > 1);
+        if visible == Some(false) {
+            toggle_fbr();
+        }
+        fbr::reveal(&p);
+    }
+}
+
+pub fn open() {
+    let c = nfc_get_file(dialog::NativeFileChooserType::BrowseFile);
+    if c.exists() {
+        STATE.with(move |s| {
+            s.append(Some(c.canonicalize().unwrap()));
+        });
+    }
+}
+
+pub fn save_current() {
+    let current = STATE.with(|s| {
+        s.current_id()
+            .filter(|_| s.modified() || s.current_file().is_none())
+    });
+    if let Some(id) = current {
+        save(id);
+    }
+}
+
+pub fn save_current_as() {
+    if let Some(id) = STATE.with(|s| s.current_id()) {
+        save_as(id);
+    }
+}
+
+pub fn save_all() {
+    STATE.with(|s| {
+        for (id, _) in s.dirty(&s.ids()) {
+            s.save(id);
+        }
+    });
+}
+
+pub fn toggle_autosave() {
+    swap::set_autosave(!swap::autosave());
+}
+
+pub fn save_all_and_quit() {
+    save_all();
+    quit();
+}
+
+pub fn quit() {
+    app::handle_main(Event::Close).unwrap();
+}
+
+// Runs f on the focused editor
+pub fn edit(f: fn(&text::TextEditor)) {
+    if let Some(e) = STATE.with(|s| s.current_editor()) {
+        f(&e);
+    }
+}
+
+// The focused editor unless it shows a large or binary file
+fn writable_editor() -> Option<text::TextEditor> {
+    STATE.with(|s| {
+        s.current_editor()
+            .filter(|e| !s.read_only(e.as_widget_ptr() as usize))
+    })
+}
+
+// Like edit, for the commands that change the buffer
+pub fn edit_writable(f: fn(&text::TextEditor)) {
+    if let Some(e) = writable_editor() {
+        f(&e);
+    }
+}
+
+// Undo/redo apply to the buffer as a whole, so the extra cursors are dropped
+pub fn undo() {
+    if let Some(mut e) = writable_editor() {
+        cursors::clear(&mut e);
+        e.undo();
+    }
+}
+
+pub fn redo() {
+    if let Some(mut e) = writable_editor() {
+        cursors::clear(&mut e);
+        e.redo();
+    }
+}
+
+// Splits the visible tab side by side (or stacked with column), the new pane shows the
+// focused buffer. Splitting again only changes the direction.
+pub fn split(column: bool) {
+    let tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
+    let mut edrow = match tabs.value() {
+        Some(edrow) => group::Flex::from_dyn_widget(&edrow).unwrap(),
+        None => return,
+    };
+    edrow.set_type(if column {
+        group::FlexType::Column
+    } else {
+        group::FlexType::Row
+    });
+    STATE.with({
+        let mut edrow = edrow.clone();
+        move |s| {
+            if s.current_pane().is_none() {
+                if let Some(id) = s.current_id() {
+                    s.open_pane(&mut edrow, id);
                 }
-                app::redraw();
             }
-            "&Help/About\t" => {
-                dialog::message_title("About");
-                dialog::message_default("A minimal text editor written using fltk-rs!")
-            }
-            _ => unreachable!(),
         }
+    });
+    if let Some(first) = edrow.child(0) {
+        edrow.fixed(&first, 0);
     }
+    edrow.layout();
+    edrow.redraw();
 }
 
-pub fn tab_close_cb(g: &mut impl GroupExt) {
-    if app::callback_reason() == CallbackReason::Closed {
-        let ed = text::TextEditor::from_dyn_widget(&g.child(0).unwrap()).unwrap();
-        let edid = ed.as_widget_ptr() as usize;
-        let buf = ed.buffer().unwrap();
-        let mut parent = g.parent().unwrap();
-        parent.remove(g);
-        unsafe {
-            text::TextBuffer::delete(buf);
+pub fn close_split() {
+    STATE.with(|s| {
+        if let Some(pane) = s.current_pane() {
+            s.close_pane(pane);
         }
-        STATE.with(move |s| s.map.remove(&edid));
-        parent.set_damage(true);
+    });
+}
+
+pub fn add_next_occurrence() {
+    if let Some(mut e) = STATE.with(|s| s.current_editor()) {
+        cursors::add_next_occurrence(&mut e);
+    }
+}
+
+pub fn toggle_comment() {
+    if let Some(mut e) = writable_editor() {
+        cursors::clear(&mut e);
+        editing::toggle_comment(&mut e);
+    }
+}
+
+// Shows or hides a side panel and keeps its View menu checkbox in sync
+fn toggle_panel(cmd: Command, id: &str, size: i32) {
+    let w: group::Group = match app::widget_from_id(id) {
+        Some(w) => w,
+        None => return,
+    };
+    let mut parent = group::Flex::from_dyn_widget(&w.parent().unwrap()).unwrap();
+    // hidden panels are collapsed to 1px along the parent's direction
+    let visible = w.w() > 1 && w.h() > 1;
+    parent.fixed(&w, if visible { 1 } else { size });
+    if let Some(mut item) = commands::menu_item(cmd) {
+        if visible {
+            item.clear();
+        } else {
+            item.set();
+        }
+    }
+    app::redraw();
+}
+
+pub fn toggle_fbr() {
+    toggle_panel(Command::ToggleFileBrowser, "fbr_group", 180);
+}
+
+pub fn toggle_term() {
+    toggle_panel(Command::ToggleTerminal, "term_group", 160);
+}
+
+pub fn toggle_output() {
+    toggle_panel(Command::ToggleOutput, "output_group", 140);
+}
+
+pub fn toggle_outline() {
+    toggle_panel(Command::ToggleOutline, "outline_group", 200);
+    outline::update();
+}
+
+pub fn toggle_preview() {
+    toggle_panel(Command::TogglePreview, "preview_group", 320);
+    preview::update();
+}
+
+pub fn show_output() {
+    let visible = app::widget_from_id::<group::Group>("output_group").map(|w| w.h() > 1);
+    if visible == Some(false) {
+        toggle_output();
     }
 }
 
 #[cfg(feature = "term")]
-pub fn tab_splitter_cb(f: &mut frame::Frame, ev: Event) -> bool {
-    let mut parent = group::Flex::from_dyn_widget(&f.parent().unwrap()).unwrap();
-    let term = app::widget_from_id::<group::Group>("term_group").unwrap();
-    match ev {
-        Event::Push => true,
-        Event::Drag => {
-            parent.fixed(&term, parent.h() + parent.y() - app::event_y());
-            app::redraw();
-            true
+pub fn show_term() {
+    let visible = app::widget_from_id::<group::Group>("term_group").map(|w| w.h() > 1);
+    if visible == Some(false) {
+        toggle_term();
+    }
+}
+
+// Saves the current file, then runs it with the command for its extension
+pub fn run_file() {
+    save_current();
+    let current = STATE.with(|s| s.current_file().zip(Some(s.current_dir.clone())));
+    if let Some((path, dir)) = current {
+        match tasks::runner(&dir, &path) {
+            Some(task) => tasks::run(task),
+            None => dialog::alert_default(&format!("Don't know how to run {}", path.display())),
         }
-        Event::Enter => {
-            f.window().unwrap().set_cursor(Cursor::NS);
-            true
+    }
+}
+
+pub fn run_script() {
+    let c = nfc_get_file(dialog::NativeFileChooserType::BrowseFile);
+    if c.exists() {
+        script::run_file(&c);
+    }
+}
+
+pub fn save_macro() {
+    let script = match script::last_macro() {
+        Some(s) => s,
+        None => {
+            dialog::alert_default("No macro was recorded");
+            return;
         }
-        Event::Leave => {
-            f.window().unwrap().set_cursor(Cursor::Arrow);
-            true
+    };
+    let c = nfc_get_file(dialog::NativeFileChooserType::BrowseSaveFile);
+    if c.as_os_str().is_empty() {
+        return;
+    }
+    if let Err(e) = fs::write(&c, script + "\n") {
+        dialog::alert_default(&format!("{}: {}", c.display(), e));
+    }
+}
+
+pub fn about() {
+    dialog::message_title("About");
+    dialog::message_default("A minimal text editor written using fltk-rs!")
+}
+
+pub fn tab_close_cb(g: &mut impl GroupExt) {
+    if app::callback_reason() == CallbackReason::Closed {
+        let ed = text::TextEditor::from_dyn_widget(&g.child(0).unwrap()).unwrap();
+        let edid = ed.as_widget_ptr() as usize;
+        if guard_close(vec![edid]) {
+            close_tab(edid);
         }
-        _ => false,
     }
 }
 
-pub fn fbr_splitter_cb(f: &mut frame::Frame, ev: Event) -> bool {
+// Closes the tab of editor id without asking, unsaved changes are dropped
+pub fn close_tab(edid: usize) {
+    let ed = text::TextEditor::from_dyn_widget_ptr(edid as *mut _).unwrap();
+    let g = ed.parent().unwrap();
+    STATE.with({
+        let g = g.clone();
+        move |s| s.close_panes_of(edid, &g)
+    });
+    let buf = ed.buffer().unwrap();
+    let mut parent = g.parent().unwrap();
+    parent.remove(&g);
+    unsafe {
+        text::TextBuffer::delete(buf);
+    }
+    STATE.with(move |s| {
+        if let Some(mybuf) = s.map.remove(&edid) {
+            gui::detach_file(edid, mybuf.current_file.as_deref());
+        }
+        swap::discard(edid);
+    });
+    parent.set_damage(true);
+}
+
+// Shared by the splitters, resize gets the splitter's parent while dragging
+fn splitter(
+    f: &mut frame::Frame,
+    ev: Event,
+    cursor: Cursor,
+    resize: impl FnOnce(&mut group::Flex),
+) -> bool {
     let mut parent = group::Flex::from_dyn_widget(&f.parent().unwrap()).unwrap();
-    let fbr: group::Group = app::widget_from_id("fbr_group").unwrap();
     match ev {
         Event::Push => true,
         Event::Drag => {
-            parent.fixed(&fbr, app::event_x());
+            resize(&mut parent);
             app::redraw();
             true
         }
         Event::Enter => {
-            f.window().unwrap().set_cursor(Cursor::WE);
+            f.window().unwrap().set_cursor(cursor);
             true
         }
         Event::Leave => {
@@ -220,3 +460,39 @@ pub fn fbr_splitter_cb(f: &mut frame::Frame, ev: Event) -> bool {
         _ => false,
     }
 }
+
+#[cfg(feature = "term")]
+pub fn tab_splitter_cb(f: &mut frame::Frame, ev: Event) -> bool {
+    splitter(f, ev, Cursor::NS, |parent| {
+        let term = app::widget_from_id::<group::Group>("term_group").unwrap();
+        parent.fixed(&term, parent.h() + parent.y() - app::event_y());
+    })
+}
+
+pub fn fbr_splitter_cb(f: &mut frame::Frame, ev: Event) -> bool {
+    splitter(f, ev, Cursor::WE, |parent| {
+        let fbr: group::Group = app::widget_from_id("fbr_group").unwrap();
+        parent.fixed(&fbr, app::event_x());
+    })
+}
+
+pub fn preview_splitter_cb(f: &mut frame::Frame, ev: Event) -> bool {
+    splitter(f, ev, Cursor::WE, |parent| {
+        let preview: group::Group = app::widget_from_id("preview_group").unwrap();
+        parent.fixed(&preview, parent.x() + parent.w() - app::event_x());
+    })
+}
+
+// Sits between a tab's editor and its split pane, the editor gets the fixed size
+pub fn pane_splitter_cb(f: &mut frame::Frame, ev: Event) -> bool {
+    let row = f.h() > f.w();
+    splitter(f, ev, if row { Cursor::WE } else { Cursor::NS }, |parent| {
+        let ed = parent.child(0).unwrap();
+        let size = if row {
+            app::event_x() - parent.x()
+        } else {
+            app::event_y() - parent.y()
+        };
+        parent.fixed(&ed, size.max(40));
+    })
+}
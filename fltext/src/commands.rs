@@ -0,0 +1,722 @@
+use crate::{cbs, dialogs::PaletteMode, fbr, script, tasks, utils};
+use fltk::{
+    enums::{Color, Key, Shortcut},
+    menu::{MenuFlag, MenuItem},
+    prelude::*,
+    utils::oncelock::Lazy,
+};
+use std::{collections::HashMap, fs};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Command {
+    NewFile,
+    NewDir,
+    Rename,
+    Duplicate,
+    Delete,
+    Open,
+    QuickOpen,
+    Save,
+    SaveAs,
+    SaveAll,
+    ToggleAutosave,
+    SaveAllAndQuit,
+    Quit,
+    Undo,
+    Redo,
+    Cut,
+    Copy,
+    Paste,
+    Find,
+    Replace,
+    AddNextOccurrence,
+    ToggleComment,
+    FindInFiles,
+    CommandPalette,
+    GoToSymbol,
+    ToggleFileBrowser,
+    ToggleTerminal,
+    ToggleOutput,
+    ToggleOutline,
+    TogglePreview,
+    RevealFile,
+    ShowDiff,
+    SplitRight,
+    SplitDown,
+    CloseSplit,
+    RunTask,
+    RunFile,
+    StopTask,
+    RunScript,
+    RecordMacro,
+    PlayMacro,
+    SaveMacro,
+    About,
+}
+
+pub struct Info {
+    // used in the keymap file
+    pub name: &'static str,
+    pub menu: &'static str,
+    pub label: &'static str,
+    pub icon: Option<&'static str>,
+    pub shortcut: Shortcut,
+    pub flag: MenuFlag,
+    pub handler: fn(),
+}
+
+#[allow(clippy::too_many_arguments)]
+fn info(
+    name: &'static str,
+    menu: &'static str,
+    icon: Option<&'static str>,
+    label: &'static str,
+    shortcut: Shortcut,
+    flag: MenuFlag,
+    handler: fn(),
+) -> Info {
+    Info {
+        name,
+        menu,
+        label,
+        icon,
+        shortcut,
+        flag,
+        handler,
+    }
+}
+
+impl Command {
+    // In menu order
+    pub const ALL: &'static [Command] = &[
+        Command::NewFile,
+        Command::NewDir,
+        Command::Open,
+        Command::QuickOpen,
+        Command::Save,
+        Command::SaveAs,
+        Command::SaveAll,
+        Command::ToggleAutosave,
+        Command::SaveAllAndQuit,
+        Command::Quit,
+        Command::Undo,
+        Command::Redo,
+        Command::Cut,
+        Command::Copy,
+        Command::Paste,
+        Command::Find,
+        Command::Replace,
+        Command::AddNextOccurrence,
+        Command::ToggleComment,
+        Command::FindInFiles,
+        Command::CommandPalette,
+        Command::GoToSymbol,
+        Command::ToggleFileBrowser,
+        Command::ToggleTerminal,
+        Command::ToggleOutput,
+        Command::ToggleOutline,
+        Command::TogglePreview,
+        Command::RevealFile,
+        Command::ShowDiff,
+        Command::SplitRight,
+        Command::SplitDown,
+        Command::CloseSplit,
+        Command::RunTask,
+        Command::RunFile,
+        Command::StopTask,
+        Command::RunScript,
+        Command::RecordMacro,
+        Command::PlayMacro,
+        Command::SaveMacro,
+        Command::About,
+    ];
+
+    pub const EDIT: &'static [Command] = &[
+        Command::Undo,
+        Command::Redo,
+        Command::Cut,
+        Command::Copy,
+        Command::Paste,
+        Command::Find,
+        Command::Replace,
+        Command::AddNextOccurrence,
+        Command::ToggleComment,
+    ];
+
+    // Rename, Duplicate and Delete act on the file browser's selection and only show up there
+    pub const FILE_BROWSER: &'static [Command] = &[
+        Command::NewFile,
+        Command::NewDir,
+        Command::Rename,
+        Command::Duplicate,
+        Command::Delete,
+    ];
+
+    pub fn info(self) -> Info {
+        use Command::*;
+        use MenuFlag::{MenuDivider, Normal, Toggle};
+        let ctrl = Shortcut::Ctrl;
+        let ctrl_shift = Shortcut::Ctrl | Shortcut::Shift;
+        let none = Shortcut::None;
+        match self {
+            NewFile => info(
+                "new_file",
+                "&File",
+                Some("@#filenew"),
+                "&New File...",
+                ctrl | 'n',
+                Normal,
+                cbs::new_file,
+            ),
+            NewDir => info(
+                "new_dir",
+                "&File",
+                Some("@#fileopen"),
+                "New Dir...",
+                ctrl_shift | 'n',
+                Normal,
+                cbs::new_dir,
+            ),
+            Rename => info(
+                "rename",
+                "&File",
+                None,
+                "Rename...",
+                none,
+                Normal,
+                fbr::rename_selected,
+            ),
+            Duplicate => info(
+                "duplicate",
+                "&File",
+                None,
+                "Duplicate",
+                none,
+                Normal,
+                fbr::duplicate_selected,
+            ),
+            Delete => info(
+                "delete",
+                "&File",
+                None,
+                "Move to Trash",
+                none,
+                Normal,
+                fbr::delete_selected,
+            ),
+            Open => info(
+                "open",
+                "&File",
+                Some("@#fileopen"),
+                "&Open...",
+                ctrl | 'o',
+                Normal,
+                cbs::open,
+            ),
+            QuickOpen => info(
+                "quick_open",
+                "&File",
+                None,
+                "Quick Open...",
+                ctrl | 'p',
+                Normal,
+                || cbs::palette(PaletteMode::Files),
+            ),
+            Save => info(
+                "save",
+                "&File",
+                Some("@#filesave"),
+                "&Save",
+                ctrl | 's',
+                Normal,
+                cbs::save_current,
+            ),
+            SaveAs => info(
+                "save_as",
+                "&File",
+                Some("@#filesaveas"),
+                "Save as...",
+                ctrl_shift | 'w',
+                Normal,
+                cbs::save_current_as,
+            ),
+            SaveAll => info(
+                "save_all",
+                "&File",
+                Some("@#filesaveas"),
+                "Save All",
+                none,
+                Normal,
+                cbs::save_all,
+            ),
+            ToggleAutosave => info(
+                "toggle_autosave",
+                "&File",
+                None,
+                "Autosave on Focus Loss",
+                none,
+                Toggle,
+                cbs::toggle_autosave,
+            ),
+            SaveAllAndQuit => info(
+                "save_all_and_quit",
+                "&File",
+                Some("@#filesaveas"),
+                "Save all and quit",
+                none,
+                MenuDivider,
+                cbs::save_all_and_quit,
+            ),
+            Quit => info(
+                "quit",
+                "&File",
+                Some("@#1+"),
+                "Quit",
+                ctrl | 'q',
+                Normal,
+                cbs::quit,
+            ),
+            Undo => info(
+                "undo",
+                "&Edit",
+                Some("@#undo"),
+                "&Undo",
+                ctrl | 'z',
+                Normal,
+                cbs::undo,
+            ),
+            Redo => info(
+                "redo",
+                "&Edit",
+                Some("@#redo"),
+                "&Redo",
+                ctrl | 'y',
+                MenuDivider,
+                cbs::redo,
+            ),
+            Cut => info("cut", "&Edit", None, "Cut", ctrl | 'x', Normal, || {
+                cbs::edit_writable(|e| e.cut())
+            }),
+            Copy => info("copy", "&Edit", None, "Copy", ctrl | 'c', Normal, || {
+                cbs::edit(|e| e.copy())
+            }),
+            Paste => info(
+                "paste",
+                "&Edit",
+                None,
+                "Paste",
+                ctrl | 'v',
+                MenuDivider,
+                || cbs::edit(|e| e.paste()),
+            ),
+            Find => info(
+                "find",
+                "&Edit",
+                Some("@#search"),
+                "&Find",
+                ctrl | 'f',
+                Normal,
+                || cbs::find(false),
+            ),
+            Replace => info(
+                "replace",
+                "&Edit",
+                None,
+                "Replace",
+                ctrl | 'h',
+                Normal,
+                || cbs::find(true),
+            ),
+            AddNextOccurrence => info(
+                "add_next_occurrence",
+                "&Edit",
+                None,
+                "Add Next Occurrence",
+                ctrl | 'd',
+                Normal,
+                cbs::add_next_occurrence,
+            ),
+            ToggleComment => info(
+                "toggle_comment",
+                "&Edit",
+                None,
+                "Toggle Comment",
+                ctrl | '/',
+                Normal,
+                cbs::toggle_comment,
+            ),
+            FindInFiles => info(
+                "find_in_files",
+                "&Edit",
+                None,
+                "Find in Files...",
+                ctrl_shift | 'f',
+                Normal,
+                cbs::find_in_files,
+            ),
+            CommandPalette => info(
+                "command_palette",
+                "&View",
+                None,
+                "Command Palette...",
+                ctrl_shift | 'p',
+                MenuDivider,
+                || cbs::palette(PaletteMode::Commands),
+            ),
+            GoToSymbol => info(
+                "go_to_symbol",
+                "&View",
+                None,
+                "Go to Symbol...",
+                ctrl_shift | 'o',
+                Normal,
+                || cbs::palette(PaletteMode::Symbols),
+            ),
+            ToggleFileBrowser => info(
+                "toggle_file_browser",
+                "&View",
+                None,
+                "File browser",
+                none,
+                Toggle,
+                cbs::toggle_fbr,
+            ),
+            ToggleTerminal => info(
+                "toggle_terminal",
+                "&View",
+                None,
+                "Terminal",
+                none,
+                Toggle,
+                cbs::toggle_term,
+            ),
+            RevealFile => info(
+                "reveal_file",
+                "&View",
+                None,
+                "Reveal in File Browser",
+                none,
+                Normal,
+                cbs::reveal_file,
+            ),
+            ToggleOutput => info(
+                "toggle_output",
+                "&View",
+                None,
+                "Output",
+                none,
+                Toggle,
+                cbs::toggle_output,
+            ),
+            ToggleOutline => info(
+                "toggle_outline",
+                "&View",
+                None,
+                "Outline",
+                none,
+                Toggle,
+                cbs::toggle_outline,
+            ),
+            TogglePreview => info(
+                "toggle_preview",
+                "&View",
+                None,
+                "Markdown Preview",
+                ctrl_shift | 'm',
+                Toggle,
+                cbs::toggle_preview,
+            ),
+            ShowDiff => info(
+                "show_diff",
+                "&View",
+                None,
+                "Show diff",
+                none,
+                MenuDivider,
+                cbs::show_diff,
+            ),
+            SplitRight => info(
+                "split_right",
+                "&View",
+                None,
+                "Split Right",
+                ctrl | '\\',
+                Normal,
+                || cbs::split(false),
+            ),
+            SplitDown => info(
+                "split_down",
+                "&View",
+                None,
+                "Split Down",
+                none,
+                Normal,
+                || cbs::split(true),
+            ),
+            CloseSplit => info(
+                "close_split",
+                "&View",
+                None,
+                "Close Split",
+                none,
+                MenuDivider,
+                cbs::close_split,
+            ),
+            RunTask => info(
+                "run_task",
+                "&Run",
+                None,
+                "Run Task...",
+                ctrl_shift | 'b',
+                Normal,
+                || cbs::palette(PaletteMode::Tasks),
+            ),
+            RunFile => info(
+                "run_file",
+                "&Run",
+                None,
+                "Run Current File",
+                none | Key::F5,
+                Normal,
+                cbs::run_file,
+            ),
+            StopTask => info(
+                "stop_task",
+                "&Run",
+                None,
+                "Stop",
+                Shortcut::Shift | Key::F5,
+                MenuDivider,
+                tasks::stop,
+            ),
+            RunScript => info(
+                "run_script",
+                "&Run",
+                None,
+                "Run Script...",
+                none,
+                Normal,
+                cbs::run_script,
+            ),
+            RecordMacro => info(
+                "record_macro",
+                "&Run",
+                None,
+                "Start/Stop Recording Macro",
+                ctrl_shift | 'r',
+                Normal,
+                script::toggle_recording,
+            ),
+            PlayMacro => info(
+                "play_macro",
+                "&Run",
+                None,
+                "Play Macro",
+                ctrl_shift | 'e',
+                Normal,
+                script::play_macro,
+            ),
+            SaveMacro => info(
+                "save_macro",
+                "&Run",
+                None,
+                "Save Macro as Script...",
+                none,
+                Normal,
+                cbs::save_macro,
+            ),
+            About => info("about", "&Help", None, "About", none, Normal, cbs::about),
+        }
+    }
+
+    pub fn available(self) -> bool {
+        self != Command::ToggleTerminal || cfg!(feature = "term")
+    }
+
+    pub fn run(self) {
+        (self.info().handler)()
+    }
+
+    pub fn shortcut(self) -> Shortcut {
+        let info = self.info();
+        KEYMAP.get(info.name).copied().unwrap_or(info.shortcut)
+    }
+
+    // Label as shown in a menu, header is the submenu path ("&File/") or empty for popups
+    pub fn menu_label(self, header: &str) -> String {
+        let info = self.info();
+        match info.icon {
+            Some(icon) => format!("{}{}  {}\t", header, icon, info.label),
+            None => format!("{}{}\t", header, info.label),
+        }
+    }
+
+    // Full path in the main menu, e.g. for MenuExt::find_item
+    pub fn menu_path(self) -> String {
+        self.menu_label(&format!("{}/", self.info().menu))
+    }
+
+    // "File: Save as...", as listed in the command palette
+    pub fn title(self) -> String {
+        let info = self.info();
+        format!("{}: {}", info.menu, info.label).replace('&', "")
+    }
+}
+
+// Adds cmds to a menu, with_menus puts each one under its "&File"-like submenu
+pub fn add_to_menu(m: &mut impl MenuExt, cmds: &[Command], with_menus: bool) {
+    for cmd in cmds.iter().copied().filter(|c| c.available()) {
+        let header = if with_menus {
+            format!("{}/", cmd.info().menu)
+        } else {
+            String::new()
+        };
+        let idx = m.add(
+            &cmd.menu_label(&header),
+            cmd.shortcut(),
+            cmd.info().flag,
+            move |_| cmd.run(),
+        );
+        if cmd == Command::Quit {
+            m.at(idx)
+                .unwrap()
+                .set_label_color(Color::from_hex(0xdc322f));
+        }
+    }
+}
+
+pub fn menu_item(cmd: Command) -> Option<MenuItem> {
+    fltk::app::widget_from_id::<fltk::menu::SysMenuBar>("menu")?.find_item(&cmd.menu_path())
+}
+
+// "Ctrl+Shift+P", "Alt+F4", "" unbinds
+fn parse_shortcut(s: &str) -> Option<Shortcut> {
+    let mut sc = Shortcut::None;
+    if s.is_empty() {
+        return Some(sc);
+    }
+    let mut parts: Vec<_> = s.split('+').map(str::trim).collect();
+    let key = parts.pop()?;
+    for m in parts {
+        sc = sc
+            | match m.to_ascii_lowercase().as_str() {
+                "ctrl" => Shortcut::Ctrl,
+                "shift" => Shortcut::Shift,
+                "alt" => Shortcut::Alt,
+                "meta" | "cmd" | "super" => Shortcut::Meta,
+                _ => return None,
+            };
+    }
+    let lower = key.to_ascii_lowercase();
+    let named = match lower.as_str() {
+        "enter" => Some(Key::Enter),
+        "tab" => Some(Key::Tab),
+        "escape" | "esc" => Some(Key::Escape),
+        "delete" => Some(Key::Delete),
+        "backspace" => Some(Key::BackSpace),
+        "insert" => Some(Key::Insert),
+        "home" => Some(Key::Home),
+        "end" => Some(Key::End),
+        "pageup" => Some(Key::PageUp),
+        "pagedown" => Some(Key::PageDown),
+        "up" => Some(Key::Up),
+        "down" => Some(Key::Down),
+        "left" => Some(Key::Left),
+        "right" => Some(Key::Right),
+        f if f.len() > 1 && f.starts_with('f') => match f[1..].parse::<i32>() {
+            Ok(n @ 1..=12) => Some(Key::from_i32(Key::F1.bits() + n - 1)),
+            _ => return None,
+        },
+        _ => None,
+    };
+    match named {
+        Some(k) => Some(sc | k),
+        None => {
+            let mut chars = lower.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some(sc | c),
+                _ => None,
+            }
+        }
+    }
+}
+
+// Shortcut overrides from ~/.config/fltext/keymap.toml, keyed by Info::name:
+//
+// save_as = "Ctrl+Shift+S"
+// quick_open = "Ctrl+P"
+// replace = ""
+fn load_keymap() -> HashMap<&'static str, Shortcut> {
+    let table = utils::config_dir()
+        .and_then(|dir| fs::read_to_string(dir.join("keymap.toml")).ok())
+        .and_then(|s| s.parse::<toml::Table>().ok());
+    table.map(|table| keymap(&table)).unwrap_or_default()
+}
+
+// The file browser's commands count as well, anything else is reported
+fn keymap(table: &toml::Table) -> HashMap<&'static str, Shortcut> {
+    let mut map = HashMap::new();
+    for (name, v) in table {
+        let cmd = Command::ALL
+            .iter()
+            .chain(Command::FILE_BROWSER)
+            .find(|cmd| cmd.info().name == name);
+        let name = match cmd {
+            Some(cmd) => cmd.info().name,
+            None => {
+                eprintln!("Unknown command {} in keymap.toml", name);
+                continue;
+            }
+        };
+        match v.as_str().and_then(parse_shortcut) {
+            Some(sc) => {
+                map.insert(name, sc);
+            }
+            None => eprintln!("Invalid shortcut {} for {}", v, name),
+        }
+    }
+    map
+}
+
+static KEYMAP: Lazy<HashMap<&'static str, Shortcut>> = Lazy::new(load_keymap);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shortcuts() {
+        let ctrl_shift = Shortcut::Ctrl | Shortcut::Shift;
+        assert_eq!(parse_shortcut("Ctrl+Shift+O"), Some(ctrl_shift | 'o'));
+        assert_eq!(parse_shortcut("ctrl + shift + o"), Some(ctrl_shift | 'o'));
+        assert_eq!(parse_shortcut("Ctrl+p"), Some(Shortcut::Ctrl | 'p'));
+        assert_eq!(parse_shortcut("Ctrl+P"), Some(Shortcut::Ctrl | 'p'));
+        assert_eq!(parse_shortcut("Alt+F4"), Some(Shortcut::Alt | Key::F4));
+        assert_eq!(parse_shortcut("F12"), Some(Shortcut::None | Key::F12));
+        assert_eq!(
+            parse_shortcut("Cmd+Esc"),
+            Some(Shortcut::Meta | Key::Escape)
+        );
+        assert_eq!(parse_shortcut("Ctrl+/"), Some(Shortcut::Ctrl | '/'));
+        // unbinds
+        assert_eq!(parse_shortcut(""), Some(Shortcut::None));
+        for invalid in ["F13", "F0", "Ctrl+", "Hyper+A", "Ctrl+Shift+Foo", "Ctrl+ab"] {
+            assert_eq!(parse_shortcut(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn keymaps() {
+        let table: toml::Table = "save_as = \"Ctrl+Alt+S\"\nrename = \"F2\"\nreplace = \"\"\n\
+                                  no_such_command = \"Ctrl+Q\"\nquick_open = \"Ctrl+Nope\"\nfind = 1\n"
+            .parse()
+            .unwrap();
+        let map = keymap(&table);
+        let mut names: Vec<&str> = map.keys().copied().collect();
+        names.sort();
+        assert_eq!(names, ["rename", "replace", "save_as"]);
+        assert_eq!(map["save_as"], Shortcut::Ctrl | Shortcut::Alt | 's');
+        assert_eq!(map["rename"], Shortcut::None | Key::F2);
+        assert_eq!(map["replace"], Shortcut::None);
+    }
+}
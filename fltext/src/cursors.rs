@@ -0,0 +1,564 @@
+use fltk::{
+    app, draw,
+    enums::*,
+    prelude::*,
+    text::{PositionType, TextBuffer, TextEditor},
+};
+use std::{cell::RefCell, collections::HashMap};
+
+// The editor's own insert position and selection act as the primary caret,
+// only the additional ones are kept here
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Caret {
+    anchor: i32,
+    pos: i32,
+}
+
+impl Caret {
+    fn at(pos: i32) -> Self {
+        Self { anchor: pos, pos }
+    }
+    fn start(&self) -> i32 {
+        self.anchor.min(self.pos)
+    }
+    fn end(&self) -> i32 {
+        self.anchor.max(self.pos)
+    }
+}
+
+#[derive(Default)]
+struct Cursors {
+    extra: Vec<Caret>,
+    // Alt+drag start and whether the mouse moved since
+    drag: Option<(i32, bool)>,
+}
+
+thread_local! {
+    // Keyed by editor id like State::map
+    static CURSORS: RefCell<HashMap<usize, Cursors>> = RefCell::new(HashMap::new());
+}
+
+enum Edit {
+    Insert(String),
+    // one line per caret when the counts match, like a column paste
+    Paste(String),
+    Backspace,
+    Delete,
+}
+
+fn with<T>(ed: &TextEditor, f: impl FnOnce(&mut Cursors) -> T) -> T {
+    let id = ed.as_widget_ptr() as usize;
+    CURSORS.with(|c| f(c.borrow_mut().entry(id).or_default()))
+}
+
+fn extra(ed: &TextEditor) -> Vec<Caret> {
+    with(ed, |c| c.extra.clone())
+}
+
+fn set_extra(ed: &mut TextEditor, extra: Vec<Caret>) {
+    with(ed, |c| c.extra = extra);
+    ed.redraw();
+}
+
+pub fn clear(ed: &mut TextEditor) {
+    if !extra(ed).is_empty() {
+        set_extra(ed, vec![]);
+    }
+}
+
+pub fn remove(id: usize) {
+    CURSORS.with(|c| c.borrow_mut().remove(&id));
+}
+
+fn primary(ed: &TextEditor, buf: &TextBuffer) -> Caret {
+    let pos = ed.insert_position();
+    match buf.selection_position() {
+        Some((start, end)) if start != end => Caret {
+            anchor: if pos == start { end } else { start },
+            pos: if pos == start { start } else { end },
+        },
+        _ => Caret::at(pos),
+    }
+}
+
+fn set_primary(ed: &mut TextEditor, buf: &mut TextBuffer, caret: Caret) {
+    if caret.anchor == caret.pos {
+        buf.unselect();
+    } else {
+        buf.select(caret.start(), caret.end());
+    }
+    ed.set_insert_position(caret.pos);
+    ed.show_insert_position();
+}
+
+// Carets are kept as offsets and the buffer can change under them, by a reload, a
+// split pane or a script. Clamps pos to the text and moves it back to a char boundary.
+fn snap(text: &str, pos: i32) -> usize {
+    let mut pos = pos.clamp(0, text.len() as i32) as usize;
+    while !text.is_char_boundary(pos) {
+        pos -= 1;
+    }
+    pos
+}
+
+fn snapped(text: &str, c: Caret) -> Caret {
+    Caret {
+        anchor: snap(text, c.anchor) as i32,
+        pos: snap(text, c.pos) as i32,
+    }
+}
+
+fn prev_boundary(text: &str, pos: usize) -> usize {
+    text[..pos]
+        .chars()
+        .next_back()
+        .map_or(pos, |c| pos - c.len_utf8())
+}
+
+fn next_boundary(text: &str, pos: usize) -> usize {
+    text[pos..]
+        .chars()
+        .next()
+        .map_or(pos, |c| pos + c.len_utf8())
+}
+
+fn line_of(ed: &TextEditor, pos: i32) -> i32 {
+    ed.count_lines(0, pos, true)
+}
+
+// Position of the col-th character of the line starting at line_start, clamped to the line end
+fn column_pos(buf: &TextBuffer, line_start: i32, col: usize) -> i32 {
+    let line = buf
+        .text_range(line_start, buf.line_end(line_start))
+        .unwrap_or_default();
+    line_start + line.char_indices().nth(col).map_or(line.len(), |(i, _)| i) as i32
+}
+
+fn column_of(buf: &TextBuffer, pos: i32) -> usize {
+    buf.text_range(buf.line_start(pos), pos)
+        .unwrap_or_default()
+        .chars()
+        .count()
+}
+
+// The span of text to replace for the edit, its new contents and where the carets end up,
+// the primary one flagged. None when there's nothing to do.
+fn plan(
+    text: &str,
+    carets: &[(Caret, bool)],
+    edit: &Edit,
+) -> Option<(usize, usize, String, Vec<(i32, bool)>)> {
+    let mut carets: Vec<(Caret, bool)> = carets
+        .iter()
+        .map(|(c, is_primary)| (snapped(text, *c), *is_primary))
+        .collect();
+    carets.sort_by_key(|(c, _)| c.start());
+    let pasted: Vec<&str> = match edit {
+        Edit::Paste(s) => s.lines().collect(),
+        _ => vec![],
+    };
+    let mut ops = vec![];
+    let mut last_end = 0;
+    for (idx, (c, is_primary)) in carets.iter().enumerate() {
+        let (mut start, mut end) = (c.start() as usize, c.end() as usize);
+        if start == end {
+            match edit {
+                Edit::Backspace => start = prev_boundary(text, start),
+                Edit::Delete => end = next_boundary(text, end),
+                _ => (),
+            }
+        }
+        // carets whose ranges run into each other share the edit
+        start = start.max(last_end);
+        end = end.max(start);
+        last_end = end;
+        let insert = match edit {
+            Edit::Insert(s) => s.as_str(),
+            Edit::Paste(_) if pasted.len() == carets.len() => pasted[idx],
+            Edit::Paste(s) => s.as_str(),
+            _ => "",
+        };
+        ops.push((start, end, insert, *is_primary));
+    }
+    let span_start = ops.first()?.0;
+    let span_end = ops.last()?.1;
+    let mut new = String::new();
+    let mut cursor = span_start;
+    let mut positions = vec![];
+    for (start, end, insert, is_primary) in ops {
+        new.push_str(&text[cursor..start]);
+        new.push_str(insert);
+        positions.push(((span_start + new.len()) as i32, is_primary));
+        cursor = end;
+    }
+    if span_start == span_end && new.is_empty() {
+        return None;
+    }
+    Some((span_start, span_end, new, positions))
+}
+
+// Applies the edit at every caret with a single buffer replace, so that it's one undo step
+fn apply(ed: &mut TextEditor, edit: Edit) {
+    let mut buf = match ed.buffer() {
+        Some(buf) => buf,
+        None => return,
+    };
+    let text = buf.text();
+    let mut carets: Vec<(Caret, bool)> = extra(ed).into_iter().map(|c| (c, false)).collect();
+    carets.push((primary(ed, &buf), true));
+    let (span_start, span_end, new, positions) = match plan(&text, &carets, &edit) {
+        Some(plan) => plan,
+        None => return,
+    };
+    buf.unselect();
+    buf.replace(span_start as i32, span_end as i32, &new);
+    let mut extra: Vec<Caret> = vec![];
+    for (pos, is_primary) in positions {
+        if is_primary {
+            ed.set_insert_position(pos);
+        } else if !extra.iter().any(|c| c.pos == pos) {
+            extra.push(Caret::at(pos));
+        }
+    }
+    let primary = ed.insert_position();
+    extra.retain(|c| c.pos != primary);
+    set_extra(ed, extra);
+    ed.show_insert_position();
+    // marks the buffer as modified like regular typing does
+    ed.do_callback();
+}
+
+fn move_all(ed: &mut TextEditor, key: Key) {
+    let mut buf = match ed.buffer() {
+        Some(buf) => buf,
+        None => return,
+    };
+    let text = buf.text();
+    let step = |c: Caret| -> Caret {
+        let c = snapped(&text, c);
+        let pos = match key {
+            Key::Left if c.anchor != c.pos => c.start(),
+            Key::Right if c.anchor != c.pos => c.end(),
+            Key::Left => prev_boundary(&text, c.pos as usize) as i32,
+            Key::Right => next_boundary(&text, c.pos as usize) as i32,
+            Key::Home => buf.line_start(c.pos),
+            Key::End => buf.line_end(c.pos),
+            Key::Up | Key::Down => {
+                let col = column_of(&buf, c.pos);
+                let line_start = buf.line_start(c.pos);
+                if key == Key::Up {
+                    if line_start == 0 {
+                        return Caret::at(0);
+                    }
+                    column_pos(&buf, buf.line_start(line_start - 1), col)
+                } else {
+                    let line_end = buf.line_end(c.pos);
+                    if line_end >= buf.length() {
+                        return Caret::at(line_end);
+                    }
+                    column_pos(&buf, line_end + 1, col)
+                }
+            }
+            _ => c.pos,
+        };
+        Caret::at(pos)
+    };
+    let primary = step(primary(ed, &buf));
+    let mut moved: Vec<Caret> = vec![];
+    for c in extra(ed).into_iter().map(step) {
+        if c != primary && !moved.contains(&c) {
+            moved.push(c);
+        }
+    }
+    set_primary(ed, &mut buf, primary);
+    set_extra(ed, moved);
+}
+
+// Alt+click toggles a caret, the new one becomes the primary
+fn add_caret(ed: &mut TextEditor, pos: i32) {
+    let mut buf = match ed.buffer() {
+        Some(buf) => buf,
+        None => return,
+    };
+    let mut extra = extra(ed);
+    if let Some(idx) = extra.iter().position(|c| c.pos == pos) {
+        extra.remove(idx);
+    } else {
+        let old = primary(ed, &buf);
+        if old.pos != pos {
+            extra.push(old);
+            set_primary(ed, &mut buf, Caret::at(pos));
+        }
+    }
+    set_extra(ed, extra);
+}
+
+// Alt+drag selects the same columns on every line between start and pos
+fn column_select(ed: &mut TextEditor, start: i32, pos: i32) {
+    let mut buf = match ed.buffer() {
+        Some(buf) => buf,
+        None => return,
+    };
+    let (from_col, to_col) = (column_of(&buf, start), column_of(&buf, pos));
+    let (first, last) = (line_of(ed, start), line_of(ed, pos));
+    let mut extra = vec![];
+    let mut primary = Caret::at(pos);
+    for line in first.min(last)..=first.max(last) {
+        let line_start = buf.skip_lines(0, line);
+        let c = Caret {
+            anchor: column_pos(&buf, line_start, from_col),
+            pos: column_pos(&buf, line_start, to_col),
+        };
+        if line == last {
+            primary = c;
+        } else {
+            extra.push(c);
+        }
+    }
+    set_primary(ed, &mut buf, primary);
+    set_extra(ed, extra);
+}
+
+// Ctrl+D: selects the word under the cursor, then adds the next occurrence
+// of the selection as a new caret
+pub fn add_next_occurrence(ed: &mut TextEditor) {
+    let mut buf = match ed.buffer() {
+        Some(buf) => buf,
+        None => return,
+    };
+    let current = primary(ed, &buf);
+    if current.anchor == current.pos {
+        let (start, end) = (buf.word_start(current.pos), buf.word_end(current.pos));
+        if start < end {
+            set_primary(
+                ed,
+                &mut buf,
+                Caret {
+                    anchor: start,
+                    pos: end,
+                },
+            );
+        }
+        return;
+    }
+    let mut extra = extra(ed);
+    if let Some(next) = next_occurrence(&buf.text(), current, &extra) {
+        extra.push(current);
+        set_primary(ed, &mut buf, next);
+        set_extra(ed, extra);
+    }
+}
+
+// The next occurrence of current's selection after all the carets that isn't selected
+// yet, wrapping around to the start
+fn next_occurrence(text: &str, current: Caret, extra: &[Caret]) -> Option<Caret> {
+    let current = snapped(text, current);
+    let needle = &text[current.start() as usize..current.end() as usize];
+    let taken = |start: usize| {
+        start == current.start() as usize || extra.iter().any(|c| c.start() as usize == start)
+    };
+    let from = extra
+        .iter()
+        .map(|c| snap(text, c.end()))
+        .chain(Some(current.end() as usize))
+        .max()?;
+    let start = text[from..]
+        .match_indices(needle)
+        .map(|(i, _)| from + i)
+        .chain(text.match_indices(needle).map(|(i, _)| i))
+        .find(|start| !taken(*start))? as i32;
+    Some(Caret {
+        anchor: start,
+        pos: start + needle.len() as i32,
+    })
+}
+
+fn key(ed: &mut TextEditor) -> bool {
+    let state = app::event_state();
+    if state.contains(EventState::Ctrl)
+        || state.contains(EventState::Alt)
+        || state.contains(EventState::Meta)
+    {
+        return false;
+    }
+    match app::event_key() {
+        Key::Escape => clear(ed),
+        Key::BackSpace => apply(ed, Edit::Backspace),
+        Key::Delete => apply(ed, Edit::Delete),
+        Key::Enter | Key::KPEnter => apply(ed, Edit::Insert("\n".to_string())),
+        Key::Tab => apply(ed, Edit::Insert("\t".to_string())),
+        k @ (Key::Left | Key::Right | Key::Up | Key::Down | Key::Home | Key::End) => {
+            move_all(ed, k)
+        }
+        _ => {
+            let text = app::event_text();
+            if text.is_empty() || text.chars().any(char::is_control) {
+                return false;
+            }
+            apply(ed, Edit::Insert(text));
+        }
+    }
+    true
+}
+
+pub fn handle(ed: &mut TextEditor, ev: Event) -> bool {
+    match ev {
+        Event::Push if app::event_mouse_button() == app::MouseButton::Left => {
+            if app::is_event_alt() {
+                let pos = ed.xy_to_position(app::event_x(), app::event_y(), PositionType::Cursor);
+                with(ed, |c| c.drag = Some((pos, false)));
+                ed.take_focus().ok();
+                true
+            } else {
+                clear(ed);
+                false
+            }
+        }
+        Event::Drag => match with(ed, |c| c.drag) {
+            Some((start, _)) => {
+                let pos = ed.xy_to_position(app::event_x(), app::event_y(), PositionType::Cursor);
+                with(ed, |c| c.drag = Some((start, true)));
+                column_select(ed, start, pos);
+                true
+            }
+            None => false,
+        },
+        Event::Released => match with(ed, |c| c.drag.take()) {
+            Some((start, moved)) => {
+                if !moved {
+                    add_caret(ed, start);
+                }
+                true
+            }
+            None => false,
+        },
+        Event::KeyDown if !extra(ed).is_empty() => key(ed),
+        Event::Paste if !extra(ed).is_empty() => {
+            apply(ed, Edit::Paste(app::event_text()));
+            true
+        }
+        _ => false,
+    }
+}
+
+// Draws the extra carets and outlines their selections, see gui::build_editor
+pub fn draw(ed: &mut TextEditor) {
+    let extra = extra(ed);
+    if extra.is_empty() {
+        return;
+    }
+    draw::set_font(ed.text_font(), ed.text_size());
+    let lh = draw::height();
+    let top = crate::gui::top_line(ed);
+    let rows = ed.h() / lh + 1;
+    draw::push_clip(ed.x(), ed.y(), ed.w(), ed.h());
+    for c in extra {
+        let line = line_of(ed, c.pos);
+        if line < top || line > top + rows {
+            continue;
+        }
+        let (x, y) = ed.position_to_xy(c.pos);
+        if c.anchor != c.pos && line_of(ed, c.anchor) == line {
+            let (ax, _) = ed.position_to_xy(c.anchor);
+            draw::set_draw_color(ed.selection_color());
+            draw::draw_rect(x.min(ax), y, (x - ax).abs(), lh);
+        }
+        draw::set_draw_color(ed.cursor_color());
+        draw::draw_rectf(x, y, 2, lh);
+    }
+    draw::pop_clip();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sel(anchor: i32, pos: i32) -> Caret {
+        Caret { anchor, pos }
+    }
+
+    // The text after the edit and where the carets went, the primary one first
+    fn edited(text: &str, carets: &[(Caret, bool)], edit: Edit) -> (String, Vec<i32>) {
+        match plan(text, carets, &edit) {
+            Some((start, end, new, positions)) => {
+                let mut text = text.to_string();
+                text.replace_range(start..end, &new);
+                let mut positions: Vec<(i32, bool)> = positions;
+                positions.sort_by_key(|(_, is_primary)| !is_primary);
+                (text, positions.into_iter().map(|(pos, _)| pos).collect())
+            }
+            None => (text.to_string(), vec![]),
+        }
+    }
+
+    #[test]
+    fn insert_at_every_caret() {
+        let carets = [(Caret::at(4), true), (Caret::at(1), false)];
+        assert_eq!(
+            edited("ab\ncd", &carets, Edit::Insert("x".into())),
+            ("axb\ncxd".into(), vec![6, 2])
+        );
+        let carets = [(Caret::at(0), true), (Caret::at(3), false)];
+        assert_eq!(
+            edited("ab\ncd", &carets, Edit::Paste("1\n2".into())),
+            ("1ab\n2cd".into(), vec![1, 5])
+        );
+    }
+
+    #[test]
+    fn overlapping_carets_merge() {
+        // both backspaces take one character each, the carets end up together
+        let carets = [(Caret::at(2), true), (Caret::at(1), false)];
+        assert_eq!(
+            edited("abc", &carets, Edit::Backspace),
+            ("c".into(), vec![0, 0])
+        );
+        // a caret inside another's selection only inserts once the selection is gone
+        let carets = [(sel(0, 3), true), (Caret::at(2), false)];
+        assert_eq!(
+            edited("abcd", &carets, Edit::Insert("X".into())),
+            ("XXd".into(), vec![1, 2])
+        );
+        let carets = [(Caret::at(0), true), (Caret::at(0), false)];
+        assert_eq!(
+            edited("ab", &carets, Edit::Backspace),
+            ("ab".into(), vec![])
+        );
+    }
+
+    #[test]
+    fn stale_carets_are_snapped() {
+        // inside é and past the end, as left behind by a reload
+        let carets = [
+            (Caret::at(0), true),
+            (Caret::at(1), false),
+            (Caret::at(10), false),
+        ];
+        assert_eq!(
+            edited("éa", &carets, Edit::Delete),
+            ("a".into(), vec![0, 0, 1])
+        );
+        let carets = [(sel(10, 1), true)];
+        assert_eq!(
+            edited("éa", &carets, Edit::Insert("x".into())),
+            ("x".into(), vec![1])
+        );
+    }
+
+    #[test]
+    fn next_occurrences() {
+        let text = "foo bar foo foo";
+        assert_eq!(next_occurrence(text, sel(0, 3), &[]), Some(sel(8, 11)));
+        assert_eq!(
+            next_occurrence(text, sel(8, 11), &[sel(0, 3)]),
+            Some(sel(12, 15))
+        );
+        // wraps around past the last one
+        assert_eq!(next_occurrence(text, sel(12, 15), &[]), Some(sel(0, 3)));
+        assert_eq!(
+            next_occurrence(text, sel(12, 15), &[sel(0, 3), sel(8, 11)]),
+            None
+        );
+        assert_eq!(next_occurrence(text, sel(4, 7), &[sel(40, 40)]), None);
+    }
+}
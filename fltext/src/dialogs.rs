@@ -1,9 +1,165 @@
 #![allow(dead_code)]
 
-use crate::state::STATE;
+use crate::{commands::Command, gui, outline, search, state::STATE, tasks, theme};
 use fltk::{prelude::*, *};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
+use std::{
+    ops::Range,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+};
+
+// Matches are painted with gui::MATCH_STYLE, the styles they replaced are kept
+// so they can be put back when the query changes or the bar is closed
+const MAX_MARKS: usize = 10_000;
+
+#[derive(Default)]
+struct Marks {
+    sbuf: Option<text::TextBuffer>,
+    saved: Vec<(usize, String)>,
+}
+
+impl Marks {
+    fn clear(&mut self) {
+        if let Some(mut sbuf) = self.sbuf.take() {
+            let mut styles = sbuf.text();
+            for (start, old) in self.saved.drain(..) {
+                let end = start + old.len();
+                // skip ranges that were restyled by an edit in the meantime
+                if end <= styles.len()
+                    && styles[start..end]
+                        .bytes()
+                        .all(|c| c == gui::MATCH_STYLE as u8)
+                {
+                    styles.replace_range(start..end, &old);
+                }
+            }
+            sbuf.set_text(&styles);
+        }
+    }
+    fn set(
+        &mut self,
+        ed: &mut text::TextEditor,
+        buf: &mut text::TextBuffer,
+        ranges: &[Range<usize>],
+    ) {
+        self.clear();
+        let id = ed.as_widget_ptr() as usize;
+        let id = STATE.with(move |s| s.buffer_id(id));
+        gui::ensure_style_buffer(id, ed, buf);
+        if let Some(mut sbuf) = ed.style_buffer() {
+            let mut styles = sbuf.text();
+            for r in ranges.iter().take(MAX_MARKS) {
+                if r.end <= styles.len() {
+                    self.saved.push((r.start, styles[r.clone()].to_string()));
+                    styles.replace_range(r.clone(), &gui::MATCH_STYLE.to_string().repeat(r.len()));
+                }
+            }
+            sbuf.set_text(&styles);
+            self.sbuf = Some(sbuf);
+        }
+        ed.redraw();
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Step {
+    // first match at or after the selection start, used while typing
+    Here,
+    Next,
+    Prev,
+}
+
+fn current() -> Option<(text::TextEditor, text::TextBuffer)> {
+    STATE.with(|s| s.current_editor().zip(s.buf()))
+}
+
+// current() unless it's a read-only view
+fn writable() -> Option<(text::TextEditor, text::TextBuffer)> {
+    current().filter(|(ed, _)| {
+        let id = ed.as_widget_ptr() as usize;
+        !STATE.with(move |s| s.read_only(id))
+    })
+}
+
+fn selection(ed: &text::TextEditor, buf: &text::TextBuffer) -> Range<usize> {
+    match buf.selection_position() {
+        Some((start, end)) => start as usize..end as usize,
+        None => ed.insert_position() as usize..ed.insert_position() as usize,
+    }
+}
+
+// Selects the match the step lands on and returns the "3 of 17" label
+fn goto(m: &search::Matcher, step: Step, marks: &mut Marks) -> String {
+    let (mut ed, mut buf) = match current() {
+        Some(v) => v,
+        None => return String::new(),
+    };
+    let text = buf.text();
+    let matches = m.find_iter(&text);
+    marks.set(&mut ed, &mut buf, &matches);
+    if matches.is_empty() {
+        return "No results".to_string();
+    }
+    let sel = selection(&ed, &buf);
+    let idx = match step {
+        Step::Here => matches.iter().position(|r| r.start >= sel.start),
+        Step::Next => matches.iter().position(|r| r.start >= sel.end && *r != sel),
+        Step::Prev => matches
+            .iter()
+            .rposition(|r| r.end <= sel.start && *r != sel),
+    }
+    .unwrap_or(match step {
+        Step::Prev => matches.len() - 1,
+        _ => 0,
+    });
+    let r = &matches[idx];
+    buf.select(r.start as i32, r.end as i32);
+    ed.set_insert_position(r.end as i32);
+    ed.show_insert_position();
+    format!("{} of {}", idx + 1, matches.len())
+}
+
+fn replace_current(m: &search::Matcher, rep: &str) {
+    if let Some((ed, mut buf)) = writable() {
+        let sel = selection(&ed, &buf);
+        let text = buf.text();
+        if m.find_iter(&text).contains(&sel) {
+            let new = m.expand(&text, sel.clone(), rep);
+            buf.replace(sel.start as i32, sel.end as i32, &new);
+            buf.select(sel.start as i32, (sel.start + new.len()) as i32);
+            STATE.with(|s| s.was_modified(true));
+        }
+    }
+}
+
+fn replace_all(m: &search::Matcher, rep: &str) -> usize {
+    if let Some((_, mut buf)) = writable() {
+        let text = buf.text();
+        let matches = m.find_iter(&text);
+        if let (Some(first), Some(last)) = (matches.first(), matches.last()) {
+            // a single edit keeps undo and re-highlighting cheap
+            let new = m.replace_matches(&text, &matches, rep);
+            buf.replace(first.start as i32, last.end as i32, &new);
+            STATE.with(|s| s.was_modified(true));
+        }
+        matches.len()
+    } else {
+        0
+    }
+}
+
+fn toggle(label: &str, tooltip: &str) -> button::ToggleButton {
+    let mut b = button::ToggleButton::default().with_label(label);
+    b.set_selection_color(b.color().lighter());
+    b.set_tooltip(tooltip);
+    b
+}
 
 pub struct FindDialog {
     win: window::Window,
@@ -11,167 +167,716 @@ pub struct FindDialog {
 
 impl FindDialog {
     pub fn new() -> Self {
-        let idx = Rc::from(RefCell::from(0));
-        let mut win = window::Window::new(0, 0, 300, 50, "Find").with_id("find");
+        let marks = Rc::from(RefCell::from(Marks::default()));
+        let mut win = window::Window::new(0, 0, 420, 80, "Find").with_id("find");
         win.set_border(false);
-        let mut row = group::Flex::default_fill();
-        row.set_margin(10);
+        let mut col = group::Flex::default_fill().column();
+        col.set_margin(5);
+        let mut row = group::Flex::default().row();
         let f = frame::Frame::default().with_label("Find:");
-        row.fixed(&f, 30);
-        let mut i = input::Input::default();
-        i.set_trigger(enums::CallbackTrigger::EnterKeyAlways);
-        let mut reg = button::ToggleButton::default().with_label(".*");
-        reg.set_selection_color(reg.color().lighter());
-        reg.set_tooltip("Use regex");
+        row.fixed(&f, 60);
+        let mut search = input::Input::default().with_id("find_input");
+        search
+            .set_trigger(enums::CallbackTrigger::Changed | enums::CallbackTrigger::EnterKeyAlways);
+        let mut reg = toggle(".*", "Use regex");
         row.fixed(&reg, 30);
-        let mut b = button::Button::default().with_label("Next");
-        i.set_callback({
-            move |i| {
-                let val = i.value();
-                let reg_val = reg.value();
-                if reg_val && regex::Regex::new(&val).is_err() {
-                    i.set_text_color(enums::Color::Red);
-                    return;
+        let mut case = toggle("Aa", "Match case");
+        case.set_value(true);
+        row.fixed(&case, 30);
+        let mut word = toggle("W", "Whole word");
+        row.fixed(&word, 30);
+        let mut count = frame::Frame::default();
+        row.fixed(&count, 70);
+        let mut prev = button::Button::default().with_label("@<");
+        prev.set_tooltip("Previous (Shift+Enter)");
+        row.fixed(&prev, 30);
+        let mut next = button::Button::default().with_label("@>");
+        next.set_tooltip("Next (Enter)");
+        row.fixed(&next, 30);
+        row.end();
+        let mut row = group::Flex::default().row().with_id("replace_row");
+        let f = frame::Frame::default().with_label("Replace:");
+        row.fixed(&f, 60);
+        let mut replace = input::Input::default();
+        replace.set_tooltip("Regex replacements may use capture groups, e.g. $1");
+        let mut one = button::Button::default().with_label("Replace");
+        row.fixed(&one, 70);
+        let mut all = button::Button::default().with_label("All");
+        row.fixed(&all, 40);
+        row.end();
+        col.end();
+        win.end();
+
+        let matcher = {
+            let search = search.clone();
+            let (reg, case, word) = (reg.clone(), case.clone(), word.clone());
+            move || {
+                let m =
+                    search::Matcher::new(&search.value(), reg.value(), case.value(), word.value());
+                let mut search = search.clone();
+                if m.is_none() && !search.value().is_empty() {
+                    search.set_text_color(enums::Color::Red);
                 } else {
-                    i.set_text_color(enums::Color::Foreground);
+                    search.set_text_color(enums::Color::Foreground);
                 }
-                if !val.is_empty() {
-                    STATE.with({
-                        let idx = idx.clone();
-                        move |s| {
-                            if let Some(buf) = s.buf().as_mut() {
-                                let text = buf.text();
-                                if reg_val {
-                                    if let Ok(re) = regex::Regex::new(&val) {
-                                        let v: Vec<_> =
-                                            re.find_iter(&text).map(|m| m.range()).collect();
-                                        if !v.is_empty() {
-                                            let mut idx = idx.borrow_mut();
-                                            let curr = &v[*idx];
-                                            let mut ed: text::TextEditor =
-                                                s.current_editor().unwrap();
-                                            buf.select(curr.start as i32, curr.end as i32);
-                                            ed.scroll(
-                                                ed.count_lines(0, curr.start as i32, true),
-                                                0,
-                                            );
-                                            *idx += 1;
-                                            if *idx == v.len() {
-                                                *idx = 0;
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    let v: Vec<_> = text.match_indices(&val).collect();
-                                    if !v.is_empty() {
-                                        let mut idx = idx.borrow_mut();
-                                        let curr = v[*idx];
-                                        let mut ed: text::TextEditor = s.current_editor().unwrap();
-                                        buf.select(curr.0 as i32, (curr.0 + val.len()) as i32);
-                                        ed.scroll(ed.count_lines(0, curr.0 as i32, true), 0);
-                                        *idx += 1;
-                                        if *idx == v.len() {
-                                            *idx = 0;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    });
+                search.redraw();
+                m
+            }
+        };
+        let step = {
+            let marks = marks.clone();
+            let matcher = matcher.clone();
+            let mut count = count.clone();
+            move |step| {
+                match matcher() {
+                    Some(m) => count.set_label(&goto(&m, step, &mut marks.borrow_mut())),
+                    None => {
+                        marks.borrow_mut().clear();
+                        count.set_label("");
+                    }
+                }
+                app::redraw();
+            }
+        };
+        search.set_callback({
+            let mut step = step.clone();
+            move |_| {
+                if app::event_key() == enums::Key::Enter {
+                    step(if app::is_event_shift() {
+                        Step::Prev
+                    } else {
+                        Step::Next
+                    })
+                } else {
+                    step(Step::Here)
                 }
             }
         });
-        b.set_callback(move |_| i.do_callback());
-        row.fixed(&b, 60);
-        row.end();
-        win.end();
-        win.handle(|win, ev| match ev {
-            enums::Event::Hide => {
-                win.hide();
-                true
+        for b in [&mut reg, &mut case, &mut word] {
+            b.set_callback({
+                let mut step = step.clone();
+                move |_| step(Step::Here)
+            });
+        }
+        prev.set_callback({
+            let mut step = step.clone();
+            move |_| step(Step::Prev)
+        });
+        next.set_callback({
+            let mut step = step.clone();
+            move |_| step(Step::Next)
+        });
+        one.set_callback({
+            let matcher = matcher.clone();
+            let replace = replace.clone();
+            let mut step = step.clone();
+            move |_| {
+                if let Some(m) = matcher() {
+                    replace_current(&m, &replace.value());
+                    step(Step::Next);
+                }
             }
-            enums::Event::Close => {
-                win.hide();
-                true
+        });
+        all.set_callback({
+            let marks = marks.clone();
+            move |_| {
+                if let Some(m) = matcher() {
+                    marks.borrow_mut().clear();
+                    let n = replace_all(&m, &replace.value());
+                    count.set_label(&format!("{} replaced", n));
+                }
             }
-            _ => false,
         });
+        win.handle(move |_, ev| {
+            if ev == enums::Event::Hide {
+                marks.borrow_mut().clear();
+            }
+            false
+        });
+        Self { win }
+    }
+}
+
+pub struct ImageDialog {
+    win: window::Window,
+}
+
+impl ImageDialog {
+    pub fn new() -> Self {
+        let mut win = window::Window::default()
+            .with_size(400, 300)
+            .with_id("image_dialog");
+        let mut f = frame::Frame::default_fill();
+        win.end();
+        win.resize_callback(move |_win, _, _, w, h| f.resize(0, 0, w, h));
         Self { win }
     }
 }
 
-pub struct ReplaceDialog {
+const PALETTE_MAX: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteMode {
+    Commands,
+    Files,
+    Tasks,
+    Symbols,
+}
+
+enum PaletteAction {
+    Command(Command),
+    Theme(String),
+    File(PathBuf),
+    Task(tasks::Task),
+    // 0-based line in the current editor
+    Symbol(usize),
+}
+
+thread_local! {
+    static PALETTE: RefCell<Vec<(String, PaletteAction)>> = RefCell::new(vec![]);
+    // browser line -> palette entry
+    static SHOWN: RefCell<Vec<usize>> = RefCell::new(vec![]);
+    // bumped whenever the palette is loaded, a file listing still running for an
+    // older one stops
+    static LISTING: Cell<u32> = Cell::new(0);
+}
+
+fn commands() -> Vec<(String, PaletteAction)> {
+    let mut v: Vec<_> = Command::ALL
+        .iter()
+        .filter(|cmd| cmd.available())
+        .map(|cmd| (cmd.title(), PaletteAction::Command(*cmd)))
+        .collect();
+    for t in theme::list() {
+        v.push((format!("Theme: {}", t.name), PaletteAction::Theme(t.name)));
+    }
+    v.extend(
+        task_list()
+            .into_iter()
+            .map(|(label, task)| (format!("Task: {}", label), task)),
+    );
+    v
+}
+
+fn task_list() -> Vec<(String, PaletteAction)> {
+    let root = STATE.with(|s| s.current_dir.clone());
+    tasks::load(&root)
+        .into_iter()
+        .map(|t| {
+            (
+                format!("{} ({})", t.name, t.command),
+                PaletteAction::Task(t),
+            )
+        })
+        .collect()
+}
+
+fn symbols() -> Vec<(String, PaletteAction)> {
+    outline::current_symbols()
+        .into_iter()
+        .map(|sym| {
+            (
+                format!("{}  :{}", sym.label(), sym.line + 1),
+                PaletteAction::Symbol(sym.line),
+            )
+        })
+        .collect()
+}
+
+// Walks the working directory in a background thread, files are added to the
+// palette as they're found so large trees don't block the UI
+fn list_files(gen: u32) {
+    let root = STATE.with(|s| s.current_dir.clone());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn({
+        let root = root.clone();
+        move || {
+            for p in search::walk(&root) {
+                if tx.send(p).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    app::add_timeout3(0.05, move |handle| {
+        let win: window::Window = app::widget_from_id("palette").unwrap();
+        if LISTING.with(Cell::get) != gen || !win.shown() {
+            return;
+        }
+        let mut found = vec![];
+        let done = loop {
+            match rx.try_recv() {
+                Ok(p) => found.push(p),
+                Err(mpsc::TryRecvError::Empty) => break false,
+                Err(mpsc::TryRecvError::Disconnected) => break true,
+            }
+        };
+        if !found.is_empty() {
+            PALETTE.with(|palette| {
+                palette.borrow_mut().extend(found.into_iter().map(|p| {
+                    let label = p.strip_prefix(&root).unwrap_or(&p).display().to_string();
+                    (label, PaletteAction::File(p))
+                }))
+            });
+            let input: input::Input = app::widget_from_id("palette_input").unwrap();
+            filter_palette(&input.value(), true);
+        }
+        if !done {
+            app::repeat_timeout3(0.05, handle);
+        }
+    });
+}
+
+pub fn load_palette(mode: PaletteMode) {
+    let gen = LISTING.with(|l| {
+        l.set(l.get() + 1);
+        l.get()
+    });
+    let entries = match mode {
+        PaletteMode::Commands => commands(),
+        PaletteMode::Files => vec![],
+        PaletteMode::Tasks => task_list(),
+        PaletteMode::Symbols => symbols(),
+    };
+    PALETTE.with(|p| *p.borrow_mut() = entries);
+    let mut input: input::Input = app::widget_from_id("palette_input").unwrap();
+    input.set_value("");
+    filter_palette("", false);
+    if mode == PaletteMode::Files {
+        list_files(gen);
+    }
+}
+
+// keep_selection is for entries arriving while the user picks, typing selects the best match
+fn filter_palette(query: &str, keep_selection: bool) {
+    let mut b: browser::HoldBrowser = app::widget_from_id("palette_browser").unwrap();
+    let selected = match keep_selection {
+        true => SHOWN.with(|s| {
+            let line = (b.value() as usize).checked_sub(1)?;
+            s.borrow().get(line).copied()
+        }),
+        false => None,
+    };
+    b.clear();
+    let mut scored: Vec<(i32, usize)> = PALETTE.with(|p| {
+        p.borrow()
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, (label, _))| Some((search::fuzzy_score(query, label)?, idx)))
+            .collect()
+    });
+    scored.sort_by_key(|(score, idx)| (-score, *idx));
+    scored.truncate(PALETTE_MAX);
+    PALETTE.with(|p| {
+        let p = p.borrow();
+        for (_, idx) in &scored {
+            b.add(&format!("@.{}", p[*idx].0));
+        }
+    });
+    let line = selected
+        .and_then(|selected| scored.iter().position(|(_, idx)| *idx == selected))
+        .map_or(1, |line| line as i32 + 1);
+    SHOWN.with(|s| *s.borrow_mut() = scored.into_iter().map(|(_, idx)| idx).collect());
+    if b.size() > 0 {
+        b.select(line);
+    }
+}
+
+fn run_palette(line: i32) {
+    // 0 when nothing matched
+    let idx = match (line as usize)
+        .checked_sub(1)
+        .and_then(|line| SHOWN.with(|s| s.borrow().get(line).copied()))
+    {
+        Some(idx) => idx,
+        None => return,
+    };
+    let action = PALETTE.with(|p| match &p.borrow()[idx].1 {
+        PaletteAction::Command(cmd) => PaletteAction::Command(*cmd),
+        PaletteAction::Theme(name) => PaletteAction::Theme(name.clone()),
+        PaletteAction::File(path) => PaletteAction::File(path.clone()),
+        PaletteAction::Task(task) => PaletteAction::Task(task.clone()),
+        PaletteAction::Symbol(line) => PaletteAction::Symbol(*line),
+    });
+    let mut win: window::Window = app::widget_from_id("palette").unwrap();
+    win.hide();
+    match action {
+        PaletteAction::Command(cmd) => cmd.run(),
+        PaletteAction::Theme(name) => gui::select_theme(&name),
+        PaletteAction::File(path) => STATE.with(move |s| s.append(Some(path.clone()))),
+        PaletteAction::Task(task) => tasks::run(task),
+        PaletteAction::Symbol(line) => outline::jump(line),
+    }
+}
+
+pub struct PaletteDialog {
     win: window::Window,
 }
 
-impl ReplaceDialog {
+impl PaletteDialog {
     pub fn new() -> Self {
-        let mut win = window::Window::new(0, 0, 300, 80, "Replace").with_id("replace");
+        let mut win = window::Window::new(0, 0, 500, 300, "Palette").with_id("palette");
         win.set_border(false);
         let mut col = group::Flex::default_fill().column();
         col.set_margin(5);
-        let mut row = group::Flex::default();
-        let f = frame::Frame::default().with_label("Search:");
+        let mut input = input::Input::default().with_id("palette_input");
+        input.set_trigger(enums::CallbackTrigger::Changed);
+        col.fixed(&input, 30);
+        let mut b = browser::HoldBrowser::default().with_id("palette_browser");
+        col.end();
+        win.end();
+        input.set_callback(|i| filter_palette(&i.value(), false));
+        input.handle({
+            let mut b = b.clone();
+            move |_, ev| {
+                if ev != enums::Event::KeyDown {
+                    return false;
+                }
+                match app::event_key() {
+                    enums::Key::Up => {
+                        if b.value() > 1 {
+                            b.select(b.value() - 1);
+                        }
+                        true
+                    }
+                    enums::Key::Down => {
+                        if b.value() < b.size() {
+                            b.select(b.value() + 1);
+                        }
+                        true
+                    }
+                    enums::Key::Enter | enums::Key::KPEnter => {
+                        run_palette(b.value());
+                        true
+                    }
+                    _ => false,
+                }
+            }
+        });
+        b.set_callback(|b| {
+            if app::event_clicks() {
+                run_palette(b.value());
+            }
+        });
+        Self { win }
+    }
+}
+
+pub struct DiffDialog {
+    win: window::Window,
+}
+
+impl DiffDialog {
+    pub fn new() -> Self {
+        let mut win = window::Window::default()
+            .with_size(600, 400)
+            .with_label("Diff")
+            .with_id("diff_dialog");
+        let mut b = browser::Browser::default_fill().with_id("diff_browser");
+        b.set_text_font(enums::Font::Courier);
+        win.end();
+        win.make_resizable(true);
+        Self { win }
+    }
+}
+
+type Target = (PathBuf, usize, Range<usize>);
+
+#[derive(Default)]
+struct FindInFiles {
+    gen: u32,
+    cancel: Option<Arc<AtomicBool>>,
+    // one entry per browser line
+    targets: Vec<Option<Target>>,
+    changes: Vec<search::FileChange>,
+}
+
+impl FindInFiles {
+    fn reset(&mut self, b: &mut browser::HoldBrowser) -> u32 {
+        if let Some(cancel) = self.cancel.take() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        self.gen += 1;
+        self.targets.clear();
+        self.changes.clear();
+        b.clear();
+        self.gen
+    }
+    fn push(&mut self, b: &mut browser::HoldBrowser, line: &str, target: Option<Target>) {
+        b.add(line);
+        self.targets.push(target);
+    }
+}
+
+fn open_at(target: Target) {
+    STATE.with(move |s| {
+        let (path, line, range) = target.clone();
+        s.append(Some(path));
+        if let (Some(mut ed), Some(mut buf)) = (s.current_editor(), s.buf()) {
+            let start = buf.skip_lines(0, line as i32 - 1) + range.start as i32;
+            buf.select(start, start + range.len() as i32);
+            ed.set_insert_position(start);
+            ed.show_insert_position();
+        }
+    });
+}
+
+pub struct FindInFilesDialog {
+    win: window::Window,
+}
+
+impl FindInFilesDialog {
+    pub fn new() -> Self {
+        let state = Rc::from(RefCell::from(FindInFiles::default()));
+        let mut win = window::Window::new(0, 0, 500, 400, "Find in Files").with_id("find_in_files");
+        let mut col = group::Flex::default_fill().column();
+        col.set_margin(5);
+        let mut row = group::Flex::default().row();
+        let f = frame::Frame::default().with_label("Find:");
         row.fixed(&f, 60);
-        let mut search = input::Input::default();
-        search.set_trigger(enums::CallbackTrigger::Changed);
+        let mut query = input::Input::default();
+        query.set_trigger(enums::CallbackTrigger::EnterKeyAlways);
         let mut reg = button::ToggleButton::default().with_label(".*");
         reg.set_selection_color(reg.color().lighter());
         reg.set_tooltip("Use regex");
         row.fixed(&reg, 30);
+        let mut find = button::Button::default().with_label("Find");
+        row.fixed(&find, 60);
+        let mut stop = button::Button::default().with_label("Stop");
+        row.fixed(&stop, 60);
         row.end();
+        col.fixed(&row, 30);
         let mut row = group::Flex::default();
         let f = frame::Frame::default().with_label("Replace:");
         row.fixed(&f, 60);
-        let replace = input::Input::default();
-        let mut b = button::Button::default().with_label("@>");
-        b.set_tooltip("Apply");
-        row.fixed(&b, 30);
+        let mut replace = input::Input::default();
+        let mut preview = button::Button::default().with_label("Preview");
+        row.fixed(&preview, 60);
+        let mut apply = button::Button::default().with_label("Apply");
+        apply.deactivate();
+        row.fixed(&apply, 60);
         row.end();
+        col.fixed(&row, 30);
+        let mut results = browser::HoldBrowser::default();
+        let status = frame::Frame::default().with_align(enums::Align::Left | enums::Align::Inside);
+        col.fixed(&status, 20);
         col.end();
         win.end();
-        search.set_callback({
+        win.make_resizable(true);
+
+        let matcher = {
             let reg = reg.clone();
-            move |i| {
-                let val = i.value();
-                let reg_val = reg.value();
-                if reg_val && regex::Regex::new(&val).is_err() {
-                    i.set_text_color(enums::Color::Red);
+            let query = query.clone();
+            move || {
+                let m = search::Matcher::new(&query.value(), reg.value(), true, false);
+                let mut query = query.clone();
+                if m.is_none() && reg.value() && !query.value().is_empty() {
+                    query.set_text_color(enums::Color::Red);
                 } else {
-                    i.set_text_color(enums::Color::Foreground);
+                    query.set_text_color(enums::Color::Foreground);
                 }
+                query.redraw();
+                m
             }
+        };
+        query.set_callback({
+            let mut find = find.clone();
+            move |_| find.do_callback()
         });
-        b.set_callback(move |_| {
-            let search = search.value();
-            let replace = replace.value();
-            let reg_val = reg.value();
-            if reg_val && regex::Regex::new(&search).is_err() {
-                return;
-            }
-            STATE.with({
-                move |s| {
-                    if let Some(buf) = s.buf().as_mut() {
-                        let text = buf.text();
-                        if reg_val {
-                            if let Ok(re) = regex::Regex::new(&search) {
-                                let ntext = re.replace(&text, &replace);
-                                buf.set_text(&ntext);
+        find.set_callback({
+            let state = state.clone();
+            let mut results = results.clone();
+            let mut status = status.clone();
+            let mut apply = apply.clone();
+            let matcher = matcher.clone();
+            move |_| {
+                let matcher = match matcher() {
+                    Some(m) => m,
+                    None => return,
+                };
+                apply.deactivate();
+                let root = STATE.with(|s| s.current_dir.clone());
+                let cancel = Arc::new(AtomicBool::new(false));
+                let gen = {
+                    let mut st = state.borrow_mut();
+                    let gen = st.reset(&mut results);
+                    st.cancel = Some(cancel.clone());
+                    gen
+                };
+                let rx = search::spawn_search(root.clone(), matcher, cancel);
+                status.set_label("Searching...");
+                app::add_timeout3(0.05, {
+                    let state = state.clone();
+                    let mut results = results.clone();
+                    let mut status = status.clone();
+                    move |handle| {
+                        let mut st = state.borrow_mut();
+                        if st.gen != gen {
+                            return;
+                        }
+                        loop {
+                            match rx.try_recv() {
+                                Ok(hit) => {
+                                    let rel = hit.path.strip_prefix(&root).unwrap_or(&hit.path);
+                                    let col = hit.text[..hit.range.start].chars().count() + 1;
+                                    let line = format!(
+                                        "@.{}:{}:{}: {}",
+                                        rel.display(),
+                                        hit.line,
+                                        col,
+                                        hit.text.trim()
+                                    );
+                                    st.push(
+                                        &mut results,
+                                        &line,
+                                        Some((hit.path, hit.line, hit.range)),
+                                    );
+                                }
+                                Err(mpsc::TryRecvError::Empty) => {
+                                    status.set_label(&format!("{} matches...", st.targets.len()));
+                                    app::repeat_timeout3(0.05, handle);
+                                    break;
+                                }
+                                Err(mpsc::TryRecvError::Disconnected) => {
+                                    let stopped = st
+                                        .cancel
+                                        .as_ref()
+                                        .map_or(false, |c| c.load(Ordering::Relaxed));
+                                    status.set_label(&format!(
+                                        "{} matches{}",
+                                        st.targets.len(),
+                                        if stopped { " (stopped)" } else { "" }
+                                    ));
+                                    break;
+                                }
                             }
-                        } else {
-                            let ntext = text.replace(&search, &replace);
-                            buf.set_text(&ntext);
                         }
-                        s.was_modified(true);
                     }
+                });
+            }
+        });
+        stop.set_callback({
+            let state = state.clone();
+            move |_| {
+                if let Some(cancel) = state.borrow().cancel.as_ref() {
+                    cancel.store(true, Ordering::Relaxed);
                 }
-            });
+            }
         });
-        win.handle(|win, ev| match ev {
-            enums::Event::Hide => {
-                win.hide();
-                true
+        preview.set_callback({
+            let state = state.clone();
+            let mut results = results.clone();
+            let mut status = status.clone();
+            let mut apply = apply.clone();
+            move |_| {
+                let matcher = match matcher() {
+                    Some(m) => m,
+                    None => return,
+                };
+                let rep = replace.value();
+                let root = STATE.with(|s| s.current_dir.clone());
+                let gen = state.borrow_mut().reset(&mut results);
+                apply.deactivate();
+                status.set_label("Computing replacements...");
+                let handle = thread::spawn({
+                    let root = root.clone();
+                    move || search::preview_replace(&root, &matcher, &rep)
+                });
+                let mut handle = Some(handle);
+                app::add_timeout3(0.05, {
+                    let state = state.clone();
+                    let mut results = results.clone();
+                    let mut status = status.clone();
+                    let mut apply = apply.clone();
+                    move |timeout| {
+                        let mut st = state.borrow_mut();
+                        if st.gen != gen {
+                            return;
+                        }
+                        if !handle.as_ref().unwrap().is_finished() {
+                            app::repeat_timeout3(0.05, timeout);
+                            return;
+                        }
+                        let changes = handle.take().unwrap().join().unwrap_or_default();
+                        let mut count = 0;
+                        for change in &changes {
+                            let rel = change.path.strip_prefix(&root).unwrap_or(&change.path);
+                            st.push(&mut results, &format!("@b@.{}", rel.display()), None);
+                            for l in &change.lines {
+                                let target = Some((change.path.clone(), l.line, 0..0));
+                                st.push(
+                                    &mut results,
+                                    &format!("@C1@.-{:>6}: {}", l.line, l.old),
+                                    target.clone(),
+                                );
+                                st.push(
+                                    &mut results,
+                                    &format!("@C2@.+{:>6}: {}", l.line, l.new),
+                                    target,
+                                );
+                                count += 1;
+                            }
+                        }
+                        status.set_label(&format!(
+                            "{} lines in {} files will change",
+                            count,
+                            changes.len()
+                        ));
+                        if !changes.is_empty() {
+                            apply.activate();
+                        }
+                        st.changes = changes;
+                    }
+                });
+            }
+        });
+        apply.set_callback({
+            let state = state.clone();
+            let mut results = results.clone();
+            let mut status = status.clone();
+            move |b| {
+                let changes = std::mem::take(&mut state.borrow_mut().changes);
+                let mut st = state.borrow_mut();
+                st.reset(&mut results);
+                // files that couldn't be written are listed with the reason
+                let mut failed = 0;
+                for change in &changes {
+                    if let Err(e) = change.apply() {
+                        let line = format!("@C1@.{}: {}", change.path.display(), e);
+                        st.push(&mut results, &line, Some((change.path.clone(), 1, 0..0)));
+                        failed += 1;
+                    }
+                }
+                status.set_label(&format!(
+                    "Replaced in {} files{}",
+                    changes.len() - failed,
+                    if failed > 0 {
+                        format!(", {} skipped", failed)
+                    } else {
+                        String::new()
+                    }
+                ));
+                b.deactivate();
             }
+        });
+        results.set_callback({
+            let state = state.clone();
+            move |b| {
+                let idx = b.value();
+                if idx > 0 {
+                    let target = state
+                        .borrow()
+                        .targets
+                        .get(idx as usize - 1)
+                        .cloned()
+                        .flatten();
+                    if let Some(target) = target {
+                        open_at(target);
+                    }
+                }
+            }
+        });
+        win.handle(|win, ev| match ev {
             enums::Event::Close => {
                 win.hide();
                 true
@@ -181,19 +886,3 @@ impl ReplaceDialog {
         Self { win }
     }
 }
-
-pub struct ImageDialog {
-    win: window::Window,
-}
-
-impl ImageDialog {
-    pub fn new() -> Self {
-        let mut win = window::Window::default()
-            .with_size(400, 300)
-            .with_id("image_dialog");
-        let mut f = frame::Frame::default_fill();
-        win.end();
-        win.resize_callback(move |_win, _, _, w, h| f.resize(0, 0, w, h));
-        Self { win }
-    }
-}
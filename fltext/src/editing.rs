@@ -0,0 +1,446 @@
+use crate::{
+    lang::{self, Lang},
+    state::STATE,
+};
+use fltk::{
+    app, draw,
+    enums::*,
+    prelude::*,
+    text::{PositionType, TextEditor},
+};
+use std::{cell::RefCell, collections::HashMap};
+
+const BRACKETS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+// Bracket matching doesn't look further than this from the cursor
+const MAX_SCAN: i32 = 100_000;
+
+thread_local! {
+    // The bracket pair outlined per editor, keyed by editor id like State::map
+    static MATCHED: RefCell<HashMap<usize, Option<(i32, i32)>>> = RefCell::new(HashMap::new());
+}
+
+pub fn remove(id: usize) {
+    MATCHED.with(|m| m.borrow_mut().remove(&id));
+}
+
+fn lang_of(ed: &TextEditor) -> Option<&'static Lang> {
+    let id = ed.as_widget_ptr() as usize;
+    let p = STATE.with(move |s| s.path_of(id))?;
+    let buf = ed.buffer()?;
+    let first_line = buf.text_range(0, buf.line_end(0)).unwrap_or_default();
+    lang::detect(&p, &first_line)
+}
+
+// Characters that get closed when typed
+fn pairs(lang: Option<&Lang>) -> Vec<(char, char)> {
+    let name = lang.map(|l| l.name);
+    let mut pairs = BRACKETS.to_vec();
+    pairs.push(('"', '"'));
+    // mostly an apostrophe in prose and a lifetime in rust
+    if !matches!(name, None | Some("rust" | "markdown")) {
+        pairs.push(('\'', '\''));
+    }
+    if matches!(name, Some("markdown" | "shell")) {
+        pairs.push(('`', '`'));
+    }
+    pairs
+}
+
+// Tabs if the file indents with them, otherwise its smallest indentation or 4 spaces
+fn indent_unit(text: &str) -> String {
+    let mut spaces = 0;
+    for line in text.lines().take(1000) {
+        if line.starts_with('\t') {
+            return "\t".to_string();
+        }
+        let n = line.len() - line.trim_start_matches(' ').len();
+        if n >= 2 && n < line.len() && (spaces == 0 || n < spaces) {
+            spaces = n;
+        }
+    }
+    " ".repeat(if spaces > 8 || spaces == 0 { 4 } else { spaces })
+}
+
+fn newline(ed: &mut TextEditor, lang: Option<&Lang>) -> bool {
+    let mut buf = match ed.buffer() {
+        Some(buf) => buf,
+        None => return false,
+    };
+    let pos = ed.insert_position();
+    let (start, end) = match buf.selection_position() {
+        Some((start, end)) if start != end => (start as usize, end as usize),
+        _ => (pos as usize, pos as usize),
+    };
+    let text = buf.text();
+    let line = &text[buf.line_start(start as i32) as usize..start];
+    let current = &line[..line.len() - line.trim_start().len()];
+    // whitespace after the cursor would end up in front of the new line's indentation
+    let end = end + text[end..].len() - text[end..].trim_start_matches([' ', '\t']).len();
+    let before = line.chars().next_back();
+    let after = text[end..].chars().next();
+    let mut insert = if line.trim().is_empty() {
+        // the line's own indentation moves down with it
+        format!("\n{}", line)
+    } else {
+        #[cfg(feature = "highlight")]
+        let level = {
+            let id = ed.as_widget_ptr() as usize;
+            crate::highlight::indent_level(STATE.with(move |s| s.buffer_id(id)), &text, start)
+        };
+        #[cfg(not(feature = "highlight"))]
+        let level: Option<usize> = None;
+        let opens = matches!(line.trim_end().chars().next_back(), Some('(' | '[' | '{'))
+            || (lang.map_or(false, |l| l.name == "python") && line.trim_end().ends_with(':'));
+        match level {
+            Some(level) => format!("\n{}", indent_unit(&text).repeat(level)),
+            None if opens => format!("\n{}{}", current, indent_unit(&text)),
+            None => format!("\n{}", current),
+        }
+    };
+    let cursor = start + insert.len();
+    // "{|}" opens up with the closing bracket on its own line
+    if BRACKETS
+        .iter()
+        .any(|p| (before, after) == (Some(p.0), Some(p.1)))
+    {
+        insert.push('\n');
+        insert.push_str(current);
+    }
+    buf.unselect();
+    buf.replace(start as i32, end as i32, &insert);
+    ed.set_insert_position(cursor as i32);
+    ed.show_insert_position();
+    ed.do_callback();
+    true
+}
+
+// Auto-closes brackets and quotes, wraps the selection in them and steps over a
+// closing character that's already there
+fn type_char(ed: &mut TextEditor, c: char, lang: Option<&Lang>) -> bool {
+    let pairs = pairs(lang);
+    let mut buf = match ed.buffer() {
+        Some(buf) => buf,
+        None => return false,
+    };
+    let pos = ed.insert_position();
+    if let Some((start, end)) = buf.selection_position().filter(|(s, e)| s != e) {
+        let close = match pairs.iter().find(|(open, _)| *open == c) {
+            Some((_, close)) => *close,
+            None => return false,
+        };
+        let selected = buf.selection_text();
+        buf.replace(start, end, &format!("{}{}{}", c, selected, close));
+        buf.select(start + 1, end + 1);
+        ed.set_insert_position(end + 1);
+        ed.do_callback();
+        return true;
+    }
+    let line_start = buf.line_start(pos);
+    let line = buf
+        .text_range(line_start, buf.line_end(pos))
+        .unwrap_or_default();
+    let col = (pos - line_start) as usize;
+    let before = line[..col].chars().next_back();
+    let after = line[col..].chars().next();
+    if after == Some(c) && pairs.iter().any(|(_, close)| *close == c) {
+        ed.set_insert_position(pos + 1);
+        ed.show_insert_position();
+        return true;
+    }
+    let close = match pairs.iter().find(|(open, _)| *open == c) {
+        Some((_, close)) => *close,
+        None => return false,
+    };
+    // not in front of a word, and a quote right after a word is an apostrophe or closes a string
+    if !after.map_or(true, |a| a.is_whitespace() || ")]},;:".contains(a))
+        || (c == close && before.map_or(false, |b| b.is_alphanumeric() || b == c))
+    {
+        return false;
+    }
+    buf.insert(pos, &format!("{}{}", c, close));
+    ed.set_insert_position(pos + 1);
+    ed.show_insert_position();
+    ed.do_callback();
+    true
+}
+
+// Backspace between an empty pair removes both
+fn backspace(ed: &mut TextEditor, lang: Option<&Lang>) -> bool {
+    let mut buf = match ed.buffer() {
+        Some(buf) => buf,
+        None => return false,
+    };
+    if buf.selection_position().map_or(false, |(s, e)| s != e) {
+        return false;
+    }
+    let pos = ed.insert_position();
+    let line_start = buf.line_start(pos);
+    let line = buf
+        .text_range(line_start, buf.line_end(pos))
+        .unwrap_or_default();
+    let col = (pos - line_start) as usize;
+    let pair = (line[..col].chars().next_back(), line[col..].chars().next());
+    if !pairs(lang).iter().any(|p| pair == (Some(p.0), Some(p.1))) {
+        return false;
+    }
+    buf.remove(pos - 1, pos + 1);
+    ed.set_insert_position(pos - 1);
+    ed.show_insert_position();
+    ed.do_callback();
+    true
+}
+
+// Typing helpers for a single caret, see gui::editor_handle
+pub fn handle(ed: &mut TextEditor, ev: Event) -> bool {
+    if !matches!(ev, Event::KeyDown) {
+        return false;
+    }
+    let state = app::event_state();
+    if state.contains(EventState::Ctrl)
+        || state.contains(EventState::Alt)
+        || state.contains(EventState::Meta)
+    {
+        return false;
+    }
+    let lang = lang_of(ed);
+    match app::event_key() {
+        Key::Enter | Key::KPEnter => newline(ed, lang),
+        Key::BackSpace => backspace(ed, lang),
+        _ => {
+            let text = app::event_text();
+            let mut chars = text.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if !c.is_control() => type_char(ed, c, lang),
+                _ => false,
+            }
+        }
+    }
+}
+
+// The bracket at or before pos and the one it pairs with, as offsets into text
+fn matching(text: &str, pos: usize) -> Option<(usize, usize)> {
+    let at = text[pos..].chars().next().map(|c| (pos, c));
+    let before = text[..pos]
+        .chars()
+        .next_back()
+        .map(|c| (pos - c.len_utf8(), c));
+    for (p, c) in at.into_iter().chain(before) {
+        for (open, close) in BRACKETS {
+            let chars: Box<dyn Iterator<Item = (usize, char)>> = if c == open {
+                Box::new(text[p..].char_indices().map(|(i, d)| (p + i, d)))
+            } else if c == close {
+                Box::new(text[..=p].char_indices().rev())
+            } else {
+                continue;
+            };
+            let mut depth = 0;
+            for (i, d) in chars {
+                if d == c {
+                    depth += 1;
+                } else if d == open || d == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some((p, i));
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+// Outlines the bracket next to the cursor and its match, see gui::build_editor
+pub fn draw(ed: &mut TextEditor) {
+    let id = ed.as_widget_ptr() as usize;
+    let pair = ed.buffer().and_then(|buf| {
+        let pos = ed.insert_position();
+        let start = buf.line_start((pos - MAX_SCAN).max(0));
+        let end = buf.line_end((pos + MAX_SCAN).min(buf.length()));
+        let text = buf.text_range(start, end)?;
+        let (a, b) = matching(&text, (pos - start) as usize)?;
+        Some((start + a as i32, start + b as i32))
+    });
+    let last = MATCHED.with(|m| m.borrow_mut().insert(id, pair)).flatten();
+    if last.is_some() && last != pair {
+        // only the lines around the cursor get redrawn, the old outline may be elsewhere
+        app::add_timeout3(0.0, {
+            let mut ed = ed.clone();
+            move |_| {
+                if !ed.was_deleted() {
+                    ed.redraw();
+                }
+            }
+        });
+    }
+    let (buf, (a, b)) = match (ed.buffer(), pair) {
+        (Some(buf), Some(pair)) => (buf, pair),
+        _ => return,
+    };
+    let first = buf.line_start(ed.xy_to_position(ed.x(), ed.y(), PositionType::Cursor));
+    let last =
+        buf.line_end(ed.xy_to_position(ed.x() + ed.w(), ed.y() + ed.h(), PositionType::Cursor));
+    draw::set_font(ed.text_font(), ed.text_size());
+    let lh = draw::height();
+    draw::push_clip(ed.x(), ed.y(), ed.w(), ed.h());
+    draw::set_draw_color(ed.cursor_color());
+    for pos in [a, b] {
+        if pos < first || pos > last {
+            continue;
+        }
+        let (x, y) = ed.position_to_xy(pos);
+        let c = buf.text_range(pos, pos + 1).unwrap_or_default();
+        draw::draw_rect(x, y, draw::width(&c) as i32, lh);
+    }
+    draw::pop_clip();
+}
+
+fn toggle_line_comment(text: &str, token: &str) -> String {
+    let lines: Vec<&str> = text.split('\n').collect();
+    let code = |l: &str| !l.trim().is_empty();
+    let indent = |l: &str| l.len() - l.trim_start().len();
+    let commented = lines
+        .iter()
+        .filter(|l| code(l))
+        .all(|l| l.trim_start().starts_with(token));
+    // new comments line up at the least indented line
+    let column = lines
+        .iter()
+        .filter(|l| code(l))
+        .map(|l| indent(l))
+        .min()
+        .unwrap_or(0);
+    lines
+        .iter()
+        .map(|l| {
+            if !code(l) {
+                l.to_string()
+            } else if commented {
+                let rest = &l[indent(l) + token.len()..];
+                format!(
+                    "{}{}",
+                    &l[..indent(l)],
+                    rest.strip_prefix(' ').unwrap_or(rest)
+                )
+            } else {
+                format!("{}{} {}", &l[..column], token, &l[column..])
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn toggle_block_comment(text: &str, open: &str, close: &str) -> String {
+    let body = text.trim();
+    let lead = &text[..text.len() - text.trim_start().len()];
+    let trail = &text[text.trim_end().len()..];
+    match body.strip_prefix(open).and_then(|b| b.strip_suffix(close)) {
+        Some(inner) => {
+            let inner = inner.strip_prefix(' ').unwrap_or(inner);
+            let inner = inner.strip_suffix(' ').unwrap_or(inner);
+            format!("{}{}{}", lead, inner, trail)
+        }
+        None if body.is_empty() => text.to_string(),
+        None => format!("{}{} {} {}{}", lead, open, body, close, trail),
+    }
+}
+
+// Comments out the selected lines, or uncomments them if they all are, with the
+// file's line comment token or else its block comment
+pub fn toggle_comment(ed: &mut TextEditor) {
+    let lang = match lang_of(ed) {
+        Some(lang) => lang,
+        None => return,
+    };
+    let mut buf = match ed.buffer() {
+        Some(buf) => buf,
+        None => return,
+    };
+    let pos = ed.insert_position();
+    let (start, mut end) = match buf.selection_position() {
+        Some((start, end)) if start != end => (start, end),
+        _ => (pos, pos),
+    };
+    // a selection ending at the start of a line doesn't take that line
+    if end > start && buf.line_start(end) == end {
+        end -= 1;
+    }
+    let (start, end) = (buf.line_start(start), buf.line_end(end));
+    let text = buf.text_range(start, end).unwrap_or_default();
+    let new = match (&lang.line_comment, &lang.block_comment) {
+        (Some(token), _) => toggle_line_comment(&text, token),
+        (None, Some((open, close))) => toggle_block_comment(&text, open, close),
+        _ => return,
+    };
+    if new == text {
+        return;
+    }
+    buf.replace(start, end, &new);
+    let end = start + new.len() as i32;
+    buf.select(start, end);
+    ed.set_insert_position(end);
+    ed.show_insert_position();
+    ed.do_callback();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indentation() {
+        assert_eq!(indent_unit("fn a() {\n\tb();\n}\n"), "\t");
+        assert_eq!(indent_unit("a:\n  b:\n    c: 1\n"), "  ");
+        assert_eq!(indent_unit("if a:\n    b\n        c\n"), "    ");
+        // tabs win over spaces further up
+        assert_eq!(indent_unit("a\n  b\n\tc\n"), "\t");
+        // nothing indented, single spaces and whitespace-only lines don't count
+        assert_eq!(indent_unit("a\nb\n"), "    ");
+        assert_eq!(indent_unit(" * doc\n  \n"), "    ");
+        assert_eq!(indent_unit("a\n            b\n"), "    ");
+    }
+
+    #[test]
+    fn brackets() {
+        let s = "f(a[1], {b}) (";
+        // at or right after a bracket, either way round
+        assert_eq!(matching(s, 1), Some((1, 11)));
+        assert_eq!(matching(s, 12), Some((11, 1)));
+        assert_eq!(matching(s, 3), Some((3, 5)));
+        assert_eq!(matching(s, 9), Some((8, 10)));
+        assert_eq!(matching(s, 10), Some((10, 8)));
+        assert_eq!(matching(s, 0), None);
+        // unbalanced
+        assert_eq!(matching(s, 13), None);
+        assert_eq!(matching(s, 14), None);
+        assert_eq!(matching("a)", 1), None);
+        assert_eq!(matching("(é)", 0), Some((0, 3)));
+        assert_eq!(matching("", 0), None);
+    }
+
+    #[test]
+    fn line_comments() {
+        assert_eq!(toggle_line_comment("a\n  b", "//"), "// a\n//   b");
+        assert_eq!(toggle_line_comment("// a\n//   b", "//"), "a\n  b");
+        // lined up at the least indented line, blank lines are left alone
+        assert_eq!(toggle_line_comment("    a\n\n  b", "#"), "  #   a\n\n  # b");
+        // a mixed selection gets commented, an indented token uncommented
+        assert_eq!(toggle_line_comment("# a\nb", "#"), "# # a\n# b");
+        assert_eq!(toggle_line_comment("  # a\n    #b", "#"), "  a\n    b");
+        assert_eq!(toggle_line_comment("", "#"), "");
+    }
+
+    #[test]
+    fn block_comments() {
+        assert_eq!(
+            toggle_block_comment("  a b\n", "<!--", "-->"),
+            "  <!-- a b -->\n"
+        );
+        assert_eq!(
+            toggle_block_comment("  <!-- a b -->\n", "<!--", "-->"),
+            "  a b\n"
+        );
+        assert_eq!(toggle_block_comment("/*x*/", "/*", "*/"), "x");
+        assert_eq!(toggle_block_comment("/* a\nb */", "/*", "*/"), "a\nb");
+        assert_eq!(toggle_block_comment("  \n", "/*", "*/"), "  \n");
+    }
+}
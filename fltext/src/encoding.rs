@@ -0,0 +1,282 @@
+use std::{fmt::Write, fs, io, path::Path};
+
+// Files above this open read-only without highlighting or a language server
+pub const LARGE_FILE: u64 = 10 * 1024 * 1024;
+// Binary files show at most this many bytes in the hex view
+const MAX_HEX: usize = 1024 * 1024;
+// Bytes looked at when telling text from binary
+const SNIFF_LEN: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    // anything that isn't valid UTF-8 but still looks like text
+    Latin1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+// How a file was stored, so that saving writes it back the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Format {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    #[default]
+    Normal,
+    // above LARGE_FILE: read-only and not highlighted
+    Large,
+    // binary: read-only hex dump
+    Hex,
+}
+
+pub struct Opened {
+    pub text: String,
+    pub format: Format,
+    pub mode: Mode,
+}
+
+pub fn looks_binary(bytes: &[u8]) -> bool {
+    let head = &bytes[..bytes.len().min(SNIFF_LEN)];
+    if head.contains(&0) {
+        return true;
+    }
+    let control = head
+        .iter()
+        .filter(|b| **b < 0x20 && !matches!(**b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+        .count();
+    control * 10 > head.len()
+}
+
+fn decode_utf16(bytes: &[u8], le: bool) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| {
+            if le {
+                u16::from_le_bytes([c[0], c[1]])
+            } else {
+                u16::from_be_bytes([c[0], c[1]])
+            }
+        })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+// Returns None for binary contents. CRLF line endings come back as "\n".
+pub fn decode(bytes: &[u8]) -> Option<(String, Format)> {
+    let (text, encoding) = if let Some(rest) = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]) {
+        (String::from_utf8_lossy(rest).to_string(), Encoding::Utf8Bom)
+    } else if let Some(rest) = bytes.strip_prefix(&[0xff, 0xfe]) {
+        (decode_utf16(rest, true), Encoding::Utf16Le)
+    } else if let Some(rest) = bytes.strip_prefix(&[0xfe, 0xff]) {
+        (decode_utf16(rest, false), Encoding::Utf16Be)
+    } else if looks_binary(bytes) {
+        return None;
+    } else {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => (s.to_string(), Encoding::Utf8),
+            Err(_) => (bytes.iter().map(|b| *b as char).collect(), Encoding::Latin1),
+        }
+    };
+    let crlf = text.matches("\r\n").count();
+    let lf = text.matches('\n').count();
+    // mostly CRLF counts as CRLF, stray LFs get converted on save
+    let line_ending = if crlf > 0 && crlf * 2 >= lf {
+        LineEnding::CrLf
+    } else {
+        LineEnding::Lf
+    };
+    let text = match line_ending {
+        LineEnding::CrLf => text.replace("\r\n", "\n"),
+        LineEnding::Lf => text,
+    };
+    Some((
+        text,
+        Format {
+            encoding,
+            line_ending,
+        },
+    ))
+}
+
+pub fn encode(text: &str, format: &Format) -> Vec<u8> {
+    let text = match format.line_ending {
+        LineEnding::CrLf => text.replace('\n', "\r\n"),
+        LineEnding::Lf => text.to_string(),
+    };
+    match format.encoding {
+        Encoding::Utf8 => text.into_bytes(),
+        Encoding::Utf8Bom => [&[0xef, 0xbb, 0xbf], text.as_bytes()].concat(),
+        Encoding::Utf16Le => [0xff, 0xfe]
+            .into_iter()
+            .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+            .collect(),
+        Encoding::Utf16Be => [0xfe, 0xff]
+            .into_iter()
+            .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+            .collect(),
+        // characters outside Latin-1 can't be stored, they become '?'
+        Encoding::Latin1 => text
+            .chars()
+            .map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' })
+            .collect(),
+    }
+}
+
+// "00000010  48 65 6c 6c 6f 0a 00 00  00 00 00 00 00 00 00 00  |Hello...........|"
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut s = String::new();
+    for (i, chunk) in bytes[..bytes.len().min(MAX_HEX)].chunks(16).enumerate() {
+        write!(s, "{:08x} ", i * 16).ok();
+        for j in 0..16 {
+            if j % 8 == 0 {
+                s.push(' ');
+            }
+            match chunk.get(j) {
+                Some(b) => write!(s, "{:02x} ", b).ok(),
+                None => write!(s, "   ").ok(),
+            };
+        }
+        s.push_str(" |");
+        s.extend(chunk.iter().map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            }
+        }));
+        s.push_str("|\n");
+    }
+    if bytes.len() > MAX_HEX {
+        writeln!(s, "... {} more bytes not shown", bytes.len() - MAX_HEX).ok();
+    }
+    s
+}
+
+pub fn open(p: &Path) -> io::Result<Opened> {
+    let bytes = fs::read(p)?;
+    Ok(match decode(&bytes) {
+        Some((text, format)) => Opened {
+            text,
+            format,
+            mode: if bytes.len() as u64 > LARGE_FILE {
+                Mode::Large
+            } else {
+                Mode::Normal
+            },
+        },
+        None => Opened {
+            text: hex_dump(&bytes),
+            format: Format::default(),
+            mode: Mode::Hex,
+        },
+    })
+}
+
+// The decoded text of p, None if it's unreadable or binary
+pub fn read_text(p: &Path) -> Option<String> {
+    decode(&fs::read(p).ok()?).map(|(text, _)| text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(encoding: Encoding, line_ending: LineEnding) -> Format {
+        Format {
+            encoding,
+            line_ending,
+        }
+    }
+
+    #[test]
+    fn binary() {
+        assert!(looks_binary(b"\x7fELF\x02\x01\x01\0\0\0\0\0"));
+        assert!(looks_binary(&[0; 64]));
+        // a NUL anywhere in the sniffed head
+        assert!(looks_binary(b"text text text\0"));
+        assert!(looks_binary(&[1, 2, 3, 4, b'a', 5, 6]));
+        assert!(!looks_binary(
+            b"fn main() {\r\n\tprintln!(\"\x1b[1m\");\x0c\n}\n"
+        ));
+        assert!(!looks_binary(b""));
+        assert!(!looks_binary("h\u{e9}llo".as_bytes()));
+        // past the sniffed head
+        let mut late = vec![b'a'; SNIFF_LEN];
+        late.push(0);
+        assert!(!looks_binary(&late));
+        assert_eq!(decode(b"a\0b"), None);
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = "h\u{e9}llo\nw\u{f6}rld\n";
+        for encoding in [
+            Encoding::Utf8,
+            Encoding::Utf8Bom,
+            Encoding::Utf16Le,
+            Encoding::Utf16Be,
+            Encoding::Latin1,
+        ] {
+            for line_ending in [LineEnding::Lf, LineEnding::CrLf] {
+                let f = format(encoding, line_ending);
+                let bytes = encode(text, &f);
+                assert_eq!(decode(&bytes), Some((text.to_string(), f)), "{:?}", f);
+            }
+        }
+    }
+
+    #[test]
+    fn encodings() {
+        let utf8 = format(Encoding::Utf8, LineEnding::Lf);
+        assert_eq!(encode("\u{e9}", &utf8), [0xc3, 0xa9]);
+        let bom = format(Encoding::Utf8Bom, LineEnding::Lf);
+        assert_eq!(encode("a", &bom), [0xef, 0xbb, 0xbf, b'a']);
+        let le = format(Encoding::Utf16Le, LineEnding::Lf);
+        assert_eq!(encode("a", &le), [0xff, 0xfe, b'a', 0]);
+        let be = format(Encoding::Utf16Be, LineEnding::Lf);
+        assert_eq!(encode("a", &be), [0xfe, 0xff, 0, b'a']);
+        // invalid UTF-8 is read as Latin-1
+        let latin1 = format(Encoding::Latin1, LineEnding::Lf);
+        assert_eq!(
+            decode(b"caf\xe9\n"),
+            Some(("caf\u{e9}\n".to_string(), latin1))
+        );
+        // what Latin-1 can't store
+        assert_eq!(encode("\u{20ac}1", &latin1), b"?1");
+        // UTF-16 outside the BMP takes surrogate pairs
+        let emoji = encode("\u{1f600}", &le);
+        assert_eq!(emoji.len(), 6);
+        assert_eq!(decode(&emoji).unwrap().0, "\u{1f600}");
+    }
+
+    #[test]
+    fn line_endings() {
+        let lf = format(Encoding::Utf8, LineEnding::Lf);
+        let crlf = format(Encoding::Utf8, LineEnding::CrLf);
+        assert_eq!(decode(b"a\nb\n"), Some(("a\nb\n".to_string(), lf)));
+        assert_eq!(decode(b"a\r\nb\r\n"), Some(("a\nb\n".to_string(), crlf)));
+        assert_eq!(encode("a\nb\n", &crlf), b"a\r\nb\r\n");
+        assert_eq!(decode(b"no newline"), Some(("no newline".to_string(), lf)));
+        // mostly CRLF, the stray LF is written as CRLF
+        let (text, f) = decode(b"a\r\nb\nc\r\n").unwrap();
+        assert_eq!((text.as_str(), f), ("a\nb\nc\n", crlf));
+        assert_eq!(encode(&text, &f), b"a\r\nb\r\nc\r\n");
+        // mostly LF, the CR stays in the text and is written back as is
+        let (text, f) = decode(b"a\nb\nc\r\n").unwrap();
+        assert_eq!((text.as_str(), f), ("a\nb\nc\r\n", lf));
+        assert_eq!(encode(&text, &f), b"a\nb\nc\r\n");
+    }
+}
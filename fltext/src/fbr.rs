@@ -1,77 +1,469 @@
-#![allow(clippy::single_match)]
-
-use crate::{cbs, state::STATE, utils};
-use fltk::{enums::*, prelude::*, *};
-use notify::{event::EventKind, Event, RecursiveMode, Watcher};
+use crate::{
+    cbs,
+    commands::{self, Command},
+    git,
+    state::STATE,
+};
+use fltk::{
+    enums::*,
+    prelude::*,
+    tree::{Tree, TreeItem, TreeItemReselectMode, TreeReason, TreeSelect},
+    *,
+};
+use notify::{event::EventKind, Event, RecommendedWatcher, RecursiveMode, Watcher};
 use std::{
-    env,
+    cell::RefCell,
+    collections::HashSet,
+    fs, io,
     path::{Path, PathBuf},
+    sync::mpsc,
 };
 
-pub fn menu_cb(m: &mut impl MenuExt) {
-    if let Ok(mpath) = m.item_pathname(None) {
-        match mpath.as_str() {
-            "/New File...\t" => cbs::new_file(),
-            "/New Dir...\t" => cbs::new_dir(),
-            _ => (),
+// Child of directories that weren't listed yet, so that they get an open toggle
+const PLACEHOLDER: &str = "...";
+const POLL_INTERVAL: f64 = 0.3;
+// Mouse travel before a press on an item turns into a drag
+const DRAG_DISTANCE: i32 = 5;
+
+struct Browser {
+    root: PathBuf,
+    watcher: Option<RecommendedWatcher>,
+    watched: HashSet<PathBuf>,
+    // dragged path, the press position and whether the mouse moved far enough
+    drag: Option<(PathBuf, i32, bool)>,
+}
+
+thread_local! {
+    static BROWSER: RefCell<Browser> = RefCell::new(Browser {
+        root: PathBuf::new(),
+        watcher: None,
+        watched: HashSet::new(),
+        drag: None,
+    });
+}
+
+pub fn init_menu(m: &mut (impl MenuExt + 'static)) {
+    commands::add_to_menu(m, Command::FILE_BROWSER, false);
+}
+
+fn tree() -> Option<Tree> {
+    app::widget_from_id("fbr")
+}
+
+fn root() -> PathBuf {
+    BROWSER.with(|b| b.borrow().root.clone())
+}
+
+fn item_path(item: &TreeItem) -> PathBuf {
+    let mut names = vec![];
+    let mut item = item.clone();
+    while !item.is_root() {
+        names.push(item.label().unwrap_or_default());
+        item = match item.parent() {
+            Some(parent) => parent,
+            None => break,
+        };
+    }
+    let mut p = root();
+    for name in names.iter().rev() {
+        p.push(name);
+    }
+    p
+}
+
+fn is_loaded(item: &TreeItem) -> bool {
+    !(item.children() == 1 && item.child(0).and_then(|c| c.label()).as_deref() == Some(PLACEHOLDER))
+}
+
+fn children(item: &TreeItem) -> impl Iterator<Item = TreeItem> + '_ {
+    (0..item.children()).filter_map(|i| item.child(i))
+}
+
+// Lists dir's entries under item, directories first. Subdirectories that were open stay open.
+fn load(t: &mut Tree, item: &mut TreeItem) {
+    let dir = item_path(item);
+    let mut entries: Vec<(bool, String)> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name() != ".git")
+            .map(|e| {
+                (
+                    e.path().is_dir(),
+                    e.file_name().to_string_lossy().to_string(),
+                )
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+    entries.sort_by_key(|(is_dir, name)| (!is_dir, name.to_lowercase()));
+    let open: Vec<String> = children(item)
+        .filter(|c| c.is_open() && c.has_children())
+        .filter_map(|c| c.label())
+        .collect();
+    t.clear_children(item);
+    for (is_dir, name) in entries {
+        let mut child = match t.insert(item, &name, item.children()) {
+            Some(child) => child,
+            None => continue,
+        };
+        if is_dir {
+            child.set_label_font(Font::CourierBold);
+            if open.contains(&name) {
+                load(t, &mut child);
+                child.open();
+            } else {
+                t.insert(&child, PLACEHOLDER, 0);
+                child.close();
+            }
         }
     }
+    BROWSER.with(|b| {
+        let mut b = b.borrow_mut();
+        if b.watched.insert(dir.clone()) {
+            if let Some(w) = b.watcher.as_mut() {
+                w.watch(&dir, RecursiveMode::NonRecursive).ok();
+            }
+        }
+    });
 }
 
-pub fn init_menu(m: &mut (impl MenuExt + 'static)) {
-    m.add(
-        "New File...\t",
-        Shortcut::Ctrl | 'n',
-        menu::MenuFlag::Normal,
-        menu_cb,
-    );
-    m.add(
-        "New Dir...\t",
-        Shortcut::Ctrl | Shortcut::Shift | 'n',
-        menu::MenuFlag::Normal,
-        menu_cb,
+// The item for p, listing the directories on the way when load is set
+fn find_item(t: &mut Tree, p: &Path, load_dirs: bool) -> Option<TreeItem> {
+    let rel = p.strip_prefix(root()).ok()?;
+    let mut item = t.root()?;
+    for name in rel.components() {
+        let name = name.as_os_str().to_string_lossy();
+        if !is_loaded(&item) {
+            if !load_dirs {
+                return None;
+            }
+            load(t, &mut item);
+        }
+        item = children(&item).find(|c| c.label().as_deref() == Some(&name))?;
+    }
+    Some(item)
+}
+
+// Re-lists dir if it's shown, e.g. after a file was created or moved there
+pub fn refresh(dir: &Path) {
+    if let Some(mut t) = tree() {
+        if let Some(mut item) = find_item(&mut t, dir, false) {
+            if is_loaded(&item) {
+                load(&mut t, &mut item);
+                git::decorate_fbr();
+                t.redraw();
+            }
+        }
+    }
+}
+
+// Calls f on every listed item with its path
+pub fn for_each_item(mut f: impl FnMut(&mut TreeItem, &Path)) {
+    fn walk(item: &mut TreeItem, p: &Path, f: &mut impl FnMut(&mut TreeItem, &Path)) {
+        f(item, p);
+        if is_loaded(item) {
+            for mut c in children(&item.clone()) {
+                let p = p.join(c.label().unwrap_or_default());
+                walk(&mut c, &p, f);
+            }
+        }
+    }
+    if let Some(mut item) = tree().and_then(|t| t.root()) {
+        walk(&mut item, &root(), &mut f);
+        if let Some(mut t) = tree() {
+            t.redraw();
+        }
+    }
+}
+
+fn selected() -> Option<PathBuf> {
+    tree()?.first_selected_item().map(|item| item_path(&item))
+}
+
+// Where new entries go: the selected directory, the selected file's directory or the root
+pub fn selected_dir() -> PathBuf {
+    match selected() {
+        Some(p) if p.is_dir() => p,
+        Some(p) => p.parent().map_or_else(root, Path::to_path_buf),
+        None => root(),
+    }
+}
+
+fn open_file(path: PathBuf) {
+    let is_image = matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("jpg" | "gif" | "png" | "bmp")
     );
+    if is_image {
+        let img = match image::SharedImage::load(&path) {
+            Ok(img) => img,
+            Err(e) => {
+                dialog::alert_default(&format!("Couldn't open {}: {}", path.display(), e));
+                return;
+            }
+        };
+        let mut win: window::Window = app::widget_from_id("image_dialog").unwrap();
+        win.resize(win.x(), win.y(), img.w(), img.h());
+        win.child(0).unwrap().set_image(Some(img));
+        win.show();
+    } else {
+        match path.canonicalize() {
+            Ok(path) => STATE.with(move |s| s.append(Some(path.clone()))),
+            Err(e) => dialog::alert_default(&format!("Couldn't open {}: {}", path.display(), e)),
+        }
+    }
 }
 
-pub fn fbr_cb(f: &mut browser::FileBrowser, watcher: &mut dyn Watcher) {
-    if let Some(path) = f.text(f.value()) {
-        let path = PathBuf::from(path);
-        if path.exists() {
+fn fbr_cb(t: &mut Tree) {
+    let mut item = match t.callback_item() {
+        Some(item) => item,
+        None => return,
+    };
+    match t.callback_reason() {
+        TreeReason::Opened => {
+            if !is_loaded(&item) {
+                load(t, &mut item);
+                git::decorate_fbr();
+            }
+        }
+        TreeReason::Selected | TreeReason::Reselected => {
+            let path = item_path(&item);
             if path.is_dir() {
-                watcher.watch(&path, RecursiveMode::NonRecursive).unwrap();
-                f.load(&path).expect("Couldn't load directory!");
-                let cwd = env::current_dir().unwrap();
-                env::set_current_dir(cwd.join(path)).unwrap();
-                let mut info: frame::Frame = app::widget_from_id("info").unwrap();
-                info.set_label(&format!(
-                    "Directory: {}",
-                    utils::strip_unc_path(&env::current_dir().unwrap())
-                ));
-                f.set_damage(true);
-            } else {
-                let mut is_image = false;
-                if let Some(ext) = path.extension() {
-                    match ext.to_str().unwrap() {
-                        "jpg" | "gif" | "png" | "bmp" => is_image = true,
-                        _ => (),
+                if item.is_open() && !item.is_root() {
+                    item.close();
+                } else {
+                    if !is_loaded(&item) {
+                        load(t, &mut item);
+                        git::decorate_fbr();
                     }
+                    item.open();
                 }
-                if is_image {
-                    let img = image::SharedImage::load(path).unwrap();
-                    let mut win: window::Window = app::widget_from_id("image_dialog").unwrap();
-                    win.resize(win.x(), win.y(), img.w(), img.h());
-                    win.child(0).unwrap().set_image(Some(img));
-                    win.show();
-                } else {
-                    STATE.with(move |s| {
-                        s.append(Some(path.canonicalize().unwrap()));
-                    });
+                t.redraw();
+            } else if path.exists() {
+                open_file(path);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn fbr_handle(t: &mut Tree, ev: Event) -> bool {
+    match ev {
+        Event::Push if app::event_mouse_button() == app::MouseButton::Right => {
+            if let Some(mut item) = t.find_clicked(false) {
+                t.select_only(&mut item, false).ok();
+            }
+            let mut m: menu::MenuButton = app::widget_from_id("pop1").unwrap();
+            m.popup();
+            true
+        }
+        Event::Push => {
+            let drag = t
+                .find_clicked(false)
+                .filter(|item| !item.is_root())
+                .map(|item| (item_path(&item), app::event_y(), false));
+            BROWSER.with(|b| b.borrow_mut().drag = drag);
+            false
+        }
+        Event::Drag => BROWSER.with(|b| match b.borrow_mut().drag.as_mut() {
+            Some((_, y, moved)) => {
+                if !*moved && (app::event_y() - *y).abs() > DRAG_DISTANCE {
+                    *moved = true;
+                    t.window().unwrap().set_cursor(Cursor::Move);
+                }
+                *moved
+            }
+            None => false,
+        }),
+        Event::Released => match BROWSER.with(|b| b.borrow_mut().drag.take()) {
+            Some((from, _, true)) => {
+                t.window().unwrap().set_cursor(Cursor::Arrow);
+                if let Some(target) = t.find_clicked(false) {
+                    let to = item_path(&target);
+                    let dir = if to.is_dir() {
+                        to
+                    } else {
+                        to.parent().map_or_else(root, Path::to_path_buf)
+                    };
+                    move_into(&from, &dir);
                 }
+                true
+            }
+            _ => false,
+        },
+        Event::KeyDown => match app::event_key() {
+            Key::Delete => {
+                delete_selected();
+                true
             }
+            Key::F2 => {
+                rename_selected();
+                true
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Renames or moves from to to, open buffers follow
+fn rename(from: &Path, to: &Path) -> bool {
+    if to.exists() {
+        dialog::alert_default(&format!("{} already exists.", to.display()));
+        return false;
+    }
+    if let Err(e) = fs::rename(from, to) {
+        dialog::alert_default(&format!("Couldn't move {}: {}", from.display(), e));
+        return false;
+    }
+    let (from, to) = (from.to_path_buf(), to.to_path_buf());
+    STATE.with({
+        let (from, to) = (from.clone(), to.clone());
+        move |s| s.moved(&from, &to)
+    });
+    for dir in [from.parent(), to.parent()].into_iter().flatten() {
+        refresh(dir);
+    }
+    true
+}
+
+fn move_into(from: &Path, dir: &Path) {
+    let to = match from.file_name() {
+        Some(name) => dir.join(name),
+        None => return,
+    };
+    // dropped in place or into itself
+    if to == from || dir.starts_with(from) {
+        return;
+    }
+    rename(from, &to);
+}
+
+pub fn rename_selected() {
+    let from = match selected().filter(|p| *p != root()) {
+        Some(p) => p,
+        None => return,
+    };
+    let name = from.file_name().unwrap().to_string_lossy().to_string();
+    if let Some(new) = dialog::input_default("Rename to", &name) {
+        if !new.is_empty() && new != name {
+            rename(&from, &from.with_file_name(new));
         }
     }
 }
 
+pub fn delete_selected() {
+    let p = match selected().filter(|p| *p != root()) {
+        Some(p) => p,
+        None => return,
+    };
+    let choice = dialog::choice2_default(
+        &format!("Move {} to the trash?", p.display()),
+        "Delete",
+        "Cancel",
+        "",
+    );
+    if choice != Some(0) {
+        return;
+    }
+    match trash::delete(&p) {
+        Ok(()) => {
+            // tabs of the deleted files close, those with changes stay open so that
+            // saving can bring the file back
+            let ids = STATE.with({
+                let p = p.clone();
+                move |s| s.unmodified_below(&p)
+            });
+            for id in ids {
+                cbs::close_tab(id);
+            }
+            if let Some(dir) = p.parent() {
+                refresh(dir);
+            }
+        }
+        Err(e) => dialog::alert_default(&format!("Couldn't delete {}: {}", p.display(), e)),
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let dest = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dest)?;
+        } else {
+            fs::copy(entry.path(), dest)?;
+        }
+    }
+    Ok(())
+}
+
+// "main.rs" becomes "main copy.rs", then "main copy 2.rs"...
+fn copy_name(p: &Path) -> Option<PathBuf> {
+    let stem = p.file_stem()?.to_string_lossy().to_string();
+    let ext = match p.extension() {
+        Some(ext) if p.is_file() => format!(".{}", ext.to_string_lossy()),
+        _ => String::new(),
+    };
+    let stem = if ext.is_empty() {
+        p.file_name()?.to_string_lossy().to_string()
+    } else {
+        stem
+    };
+    (1..)
+        .map(|n| match n {
+            1 => p.with_file_name(format!("{} copy{}", stem, ext)),
+            n => p.with_file_name(format!("{} copy {}{}", stem, n, ext)),
+        })
+        .find(|p| !p.exists())
+}
+
+pub fn duplicate_selected() {
+    let from = match selected().filter(|p| *p != root()) {
+        Some(p) => p,
+        None => return,
+    };
+    let to = match copy_name(&from) {
+        Some(to) => to,
+        None => return,
+    };
+    let res = if from.is_dir() {
+        copy_dir(&from, &to)
+    } else {
+        fs::copy(&from, &to).map(|_| ())
+    };
+    match res {
+        Ok(()) => {
+            if let Some(dir) = to.parent() {
+                refresh(dir);
+            }
+        }
+        Err(e) => dialog::alert_default(&format!("Couldn't duplicate {}: {}", from.display(), e)),
+    }
+}
+
+// Lists and opens the directories down to p, then selects it
+pub fn reveal(p: &Path) {
+    let mut t = match tree() {
+        Some(t) => t,
+        None => return,
+    };
+    let mut item = match find_item(&mut t, p, true) {
+        Some(item) => item,
+        None => return,
+    };
+    let mut parent = item.parent();
+    while let Some(mut p) = parent {
+        p.open();
+        parent = p.parent();
+    }
+    git::decorate_fbr();
+    t.select_only(&mut item, false).ok();
+    t.show_item_middle(&item);
+    t.redraw();
+}
+
 pub struct Fbr {
     g: group::Group,
 }
@@ -79,43 +471,53 @@ pub struct Fbr {
 impl Fbr {
     pub fn new(current_path: &Path) -> Self {
         let mut g = group::Group::default().with_id("fbr_group");
-        let mut fbr = browser::FileBrowser::default()
-            .with_type(browser::BrowserType::Hold)
-            .with_id("fbr");
-        fbr.load(current_path)
-            .expect("Failed to load working directory");
-        fbr.set_color(Color::Background.darker());
+        // created first so that the tree gets the clicks, it pops the menu up itself
         let mut m = menu::MenuButton::default()
             .with_type(menu::MenuButtonType::Popup3)
             .with_id("pop1");
         init_menu(&mut m);
+        let mut fbr = Tree::default().with_id("fbr");
+        fbr.set_color(Color::Background.darker());
+        fbr.set_select_mode(TreeSelect::Single);
+        fbr.set_item_reselect_mode(TreeItemReselectMode::Always);
+        fbr.set_connector_style(tree::TreeConnectorStyle::None);
+        fbr.set_item_label_font(Font::Courier);
+        fbr.set_root_label(&current_path.file_name().map_or_else(
+            || current_path.display().to_string(),
+            |n| n.to_string_lossy().to_string(),
+        ));
         g.end();
-        let mut watcher = notify::recommended_watcher({
-            let mut fbr = fbr.clone();
-            move |res: Result<Event, notify::Error>| match res {
-                Ok(event) => {
-                    let mut needs_update = false;
-                    match event.kind {
-                        EventKind::Create(_) => {
-                            needs_update = true;
-                        }
-                        EventKind::Remove(_) => {
-                            needs_update = true;
+        let (tx, rx) = mpsc::channel();
+        let watcher =
+            notify::recommended_watcher(move |res: Result<Event, notify::Error>| match res {
+                Ok(event) => match event.kind {
+                    EventKind::Create(_)
+                    | EventKind::Remove(_)
+                    | EventKind::Modify(notify::event::ModifyKind::Name(_)) => {
+                        for path in event.paths {
+                            if let Some(dir) = path.parent() {
+                                tx.send(dir.to_path_buf()).ok();
+                            }
                         }
-                        _ => (),
                     }
-                    if needs_update {
-                        fbr.load(env::current_dir().unwrap()).unwrap();
-                    }
-                }
+                    _ => (),
+                },
                 Err(e) => eprintln!("{}", e),
-            }
-        })
-        .unwrap();
-        watcher
-            .watch(current_path, RecursiveMode::NonRecursive)
-            .unwrap();
-        fbr.set_callback(move |f| fbr_cb(f, &mut watcher));
+            })
+            .ok();
+        BROWSER.with(|b| {
+            let mut b = b.borrow_mut();
+            b.root = current_path.to_path_buf();
+            b.watcher = watcher;
+        });
+        if let Some(mut root) = fbr.root() {
+            load(&mut fbr, &mut root);
+            root.open();
+        }
+        fbr.set_callback(fbr_cb);
+        fbr.handle(fbr_handle);
+        app::add_timeout3(POLL_INTERVAL, move |handle| poll(&rx, handle));
+        git::start_polling();
         g.resize_callback(move |_, x, y, w, h| {
             m.resize(x, y, w, h);
             fbr.resize(x, y, w, h);
@@ -125,4 +527,55 @@ impl Fbr {
     }
 }
 
+fn poll(rx: &mpsc::Receiver<PathBuf>, handle: app::TimeoutHandle) {
+    let dirs: HashSet<PathBuf> = rx.try_iter().collect();
+    for dir in dirs {
+        refresh(&dir);
+    }
+    app::repeat_timeout3(POLL_INTERVAL, handle);
+}
+
 fltk::widget_extends!(Fbr, group::Group, g);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fltext-{}-{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn copy_names() {
+        let dir = temp_dir("copy-names");
+        let name = |p: &Path| {
+            copy_name(p)
+                .unwrap()
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        };
+        let file = dir.join("x.rs");
+        fs::write(&file, "").unwrap();
+        assert_eq!(name(&file), "x copy.rs");
+        fs::write(dir.join("x copy.rs"), "").unwrap();
+        assert_eq!(name(&file), "x copy 2.rs");
+        fs::write(dir.join("x copy 2.rs"), "").unwrap();
+        assert_eq!(name(&file), "x copy 3.rs");
+        let dotfile = dir.join(".gitignore");
+        fs::write(&dotfile, "").unwrap();
+        assert_eq!(name(&dotfile), ".gitignore copy");
+        // directories keep their whole name, dots included
+        let sub = dir.join("v1.2");
+        fs::create_dir(&sub).unwrap();
+        assert_eq!(name(&sub), "v1.2 copy");
+        fs::create_dir(dir.join("v1.2 copy")).unwrap();
+        assert_eq!(name(&sub), "v1.2 copy 2");
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,634 @@
+use crate::{fbr, gui, hooks};
+use fltk::{enums::*, prelude::*, *};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::mpsc,
+    thread,
+};
+
+const POLL_INTERVAL: f64 = 2.0;
+const DIFF_DELAY: f64 = 0.3;
+// Beyond this many old x new lines the changed middle is not diffed further
+const MAX_DIFF_CELLS: usize = 1_000_000;
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineMark {
+    Added,
+    Modified,
+    // lines were removed right above this one
+    Deleted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    if out.status.success() {
+        String::from_utf8(out.stdout).ok()
+    } else {
+        None
+    }
+}
+
+// Contents at HEAD, empty for files git doesn't track yet, None outside a work tree
+pub fn head_text(p: &Path) -> Option<String> {
+    let dir = p.parent()?;
+    let name = p.file_name()?.to_str()?;
+    git(dir, &["show", &format!("HEAD:./{}", name)])
+        .or_else(|| git(dir, &["rev-parse", "--is-inside-work-tree"]).map(|_| String::new()))
+}
+
+// Line diff: common prefix and suffix are stripped, the rest goes through an LCS table
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<Op> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+    let mut ops = vec![Op::Equal; prefix];
+    if a.len() * b.len() > MAX_DIFF_CELLS {
+        ops.extend(std::iter::repeat(Op::Delete).take(a.len()));
+        ops.extend(std::iter::repeat(Op::Insert).take(b.len()));
+    } else {
+        // lcs[i][j] is the LCS length of a[i..] and b[j..]
+        let w = b.len() + 1;
+        let mut lcs = vec![0u32; (a.len() + 1) * w];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i * w + j] = if a[i] == b[j] {
+                    lcs[(i + 1) * w + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * w + j].max(lcs[i * w + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                ops.push(Op::Equal);
+                i += 1;
+                j += 1;
+            } else if i < a.len() && (j == b.len() || lcs[(i + 1) * w + j] >= lcs[i * w + j + 1]) {
+                ops.push(Op::Delete);
+                i += 1;
+            } else {
+                ops.push(Op::Insert);
+                j += 1;
+            }
+        }
+    }
+    ops.extend(std::iter::repeat(Op::Equal).take(suffix));
+    ops
+}
+
+// Marks keyed by 0-based line of the new text, sorted by line
+pub fn line_marks(old: &str, new: &str) -> Vec<(usize, LineMark)> {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+    let ops = diff_lines(&old, &new);
+    let mut marks = vec![];
+    let mut line = 0;
+    let mut idx = 0;
+    while idx < ops.len() {
+        if ops[idx] == Op::Equal {
+            line += 1;
+            idx += 1;
+            continue;
+        }
+        let run = ops[idx..].iter().take_while(|op| **op != Op::Equal).count();
+        let deleted = ops[idx..idx + run]
+            .iter()
+            .filter(|op| **op == Op::Delete)
+            .count();
+        let inserted = run - deleted;
+        if inserted == 0 {
+            let at = line.min(new.len().saturating_sub(1));
+            marks.push((at, LineMark::Deleted));
+        }
+        let mark = if deleted > 0 {
+            LineMark::Modified
+        } else {
+            LineMark::Added
+        };
+        for l in line..line + inserted {
+            marks.push((l, mark));
+        }
+        line += inserted;
+        idx += run;
+    }
+    marks.dedup_by_key(|(l, _)| *l);
+    marks
+}
+
+// Unified diff lines ("@@", " ", "-" and "+" prefixed) of new against old
+pub fn unified(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+    let ops = diff_lines(&old, &new);
+    // old and new line index before each op
+    let mut pos = Vec::with_capacity(ops.len());
+    let (mut i, mut j) = (0, 0);
+    for op in &ops {
+        pos.push((i, j));
+        match op {
+            Op::Equal => {
+                i += 1;
+                j += 1;
+            }
+            Op::Delete => i += 1,
+            Op::Insert => j += 1,
+        }
+    }
+    let mut out = vec![];
+    let mut idx = 0;
+    while let Some(first) = ops[idx..].iter().position(|op| *op != Op::Equal) {
+        let start = (idx + first).saturating_sub(CONTEXT);
+        // extend the hunk while changes are at most 2 * CONTEXT lines apart
+        let mut end = idx + first;
+        loop {
+            while end < ops.len() && ops[end] != Op::Equal {
+                end += 1;
+            }
+            let equal = ops[end..].iter().take_while(|op| **op == Op::Equal).count();
+            if end + equal < ops.len() && equal <= 2 * CONTEXT {
+                end += equal;
+            } else {
+                end = (end + equal.min(CONTEXT)).min(ops.len());
+                break;
+            }
+        }
+        let count = |op| ops[start..end].iter().filter(|o| **o != op).count();
+        let (old_start, new_start) = pos[start];
+        out.push(format!(
+            "@@ -{},{} +{},{} @@",
+            old_start + 1,
+            count(Op::Insert),
+            new_start + 1,
+            count(Op::Delete)
+        ));
+        for k in start..end {
+            let (i, j) = pos[k];
+            out.push(match ops[k] {
+                Op::Equal => format!(" {}", new[j]),
+                Op::Delete => format!("-{}", old[i]),
+                Op::Insert => format!("+{}", new[j]),
+            });
+        }
+        idx = end;
+    }
+    out
+}
+
+// None outside a work tree
+pub fn diff(p: &Path, text: &str) -> Option<Vec<String>> {
+    Some(unified(&head_text(p)?, text))
+}
+
+struct Gutter {
+    path: PathBuf,
+    ed: text::TextEditor,
+    buf: text::TextBuffer,
+    head: Option<String>,
+    // false until the background thread read head, and again once HEAD moved
+    head_read: bool,
+    marks: Vec<(usize, LineMark)>,
+    gen: u32,
+}
+
+#[derive(Default)]
+struct Status {
+    // None outside a work tree
+    root: Option<PathBuf>,
+    head: Option<String>,
+    // absolute path -> 'M' modified, 'A' added, 'U' untracked
+    files: HashMap<PathBuf, char>,
+}
+
+#[derive(Default)]
+struct Repo {
+    status: Status,
+    rx: Option<mpsc::Receiver<Status>>,
+    polling: bool,
+}
+
+thread_local! {
+    // Keyed by buffer id like State::map
+    static GUTTERS: RefCell<HashMap<usize, Gutter>> = RefCell::new(HashMap::new());
+    // split pane id -> the buffer id it shows, for draw_gutter
+    static PANES: RefCell<HashMap<usize, usize>> = RefCell::new(HashMap::new());
+    static REPO: RefCell<Repo> = RefCell::new(Repo::default());
+}
+
+// The LCS table can get large, the marks are computed in a background thread
+// and dropped if the buffer changed again in the meantime. HEAD's version is
+// read there too when it isn't known yet.
+fn update_marks(id: usize) {
+    let job = GUTTERS.with(|g| {
+        g.borrow().get(&id).map(|gutter| {
+            (
+                gutter.path.clone(),
+                gutter.head_read.then(|| gutter.head.clone()),
+                gutter.buf.text(),
+                gutter.gen,
+            )
+        })
+    });
+    let (path, head, text, gen) = match job {
+        Some(job) => job,
+        None => return,
+    };
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let head = head.unwrap_or_else(|| head_text(&path));
+        let marks = match &head {
+            Some(head) => line_marks(head, &text),
+            None => vec![],
+        };
+        tx.send((head, marks)).ok();
+    });
+    app::add_timeout3(0.01, move |handle| match rx.try_recv() {
+        Ok((head, marks)) => {
+            let updated = GUTTERS.with(|g| {
+                g.borrow_mut()
+                    .get_mut(&id)
+                    .filter(|gutter| gutter.gen == gen)
+                    .map(|gutter| {
+                        gutter.head = head;
+                        gutter.head_read = true;
+                        gutter.marks = marks;
+                        gutter.ed.redraw();
+                    })
+                    .is_some()
+            });
+            if updated {
+                // split panes draw the same marks
+                let panes: Vec<usize> = PANES.with(|p| {
+                    p.borrow()
+                        .iter()
+                        .filter(|(_, owner)| **owner == id)
+                        .map(|(pane, _)| *pane)
+                        .collect()
+                });
+                for pane in panes {
+                    if let Some(mut pane) = text::TextEditor::from_dyn_widget_ptr(pane as *mut _) {
+                        pane.redraw();
+                    }
+                }
+            }
+        }
+        Err(mpsc::TryRecvError::Empty) => app::repeat_timeout3(0.01, handle),
+        Err(mpsc::TryRecvError::Disconnected) => (),
+    });
+}
+
+pub fn attach(p: &Path, ed: &text::TextEditor, buf: &text::TextBuffer) {
+    let id = ed.as_widget_ptr() as usize;
+    let gutter = Gutter {
+        path: p.to_path_buf(),
+        ed: ed.clone(),
+        buf: buf.clone(),
+        head: None,
+        head_read: false,
+        marks: vec![],
+        gen: 0,
+    };
+    GUTTERS.with(|g| g.borrow_mut().insert(id, gutter));
+    hooks::set(id, "git", move |_, inserted, deleted, _, _| {
+        if inserted > 0 || deleted > 0 {
+            let gen = GUTTERS.with(|g| {
+                g.borrow_mut().get_mut(&id).map(|gutter| {
+                    gutter.gen += 1;
+                    gutter.gen
+                })
+            });
+            if let Some(gen) = gen {
+                app::add_timeout3(DIFF_DELAY, move |_| {
+                    let current = GUTTERS.with(|g| g.borrow().get(&id).map(|g| g.gen));
+                    if current == Some(gen) {
+                        update_marks(id);
+                    }
+                });
+            }
+        }
+    });
+    update_marks(id);
+    start_polling();
+}
+
+pub fn detach(id: usize) {
+    GUTTERS.with(|g| g.borrow_mut().remove(&id));
+}
+
+// A split pane showing buffer id was opened, see gui::create_pane
+pub fn add_pane(pane: usize, id: usize) {
+    PANES.with(|p| p.borrow_mut().insert(pane, id));
+}
+
+pub fn remove_pane(pane: usize) {
+    PANES.with(|p| p.borrow_mut().remove(&pane));
+}
+
+fn status(dir: PathBuf) -> Status {
+    let mut status = Status::default();
+    let root = match git(&dir, &["rev-parse", "--show-toplevel"]) {
+        Some(root) => PathBuf::from(root.trim_end()),
+        None => return status,
+    };
+    status.root = Some(root.clone());
+    status.head = git(&dir, &["rev-parse", "HEAD"]);
+    if let Some(out) = git(&dir, &["status", "--porcelain", "-z"]) {
+        let mut entries = out.split('\0');
+        while let Some(entry) = entries.next() {
+            if entry.len() < 4 {
+                continue;
+            }
+            let (xy, path) = entry.split_at(3);
+            let c = match xy.trim() {
+                "??" => 'U',
+                s if s.contains('A') => 'A',
+                _ => 'M',
+            };
+            // renames are followed by the original path
+            if xy.starts_with('R') || xy.starts_with('C') {
+                entries.next();
+            }
+            let path = root.join(path.trim_end_matches('/'));
+            status.files.insert(path.canonicalize().unwrap_or(path), c);
+        }
+    }
+    status
+}
+
+// Polling stops outside a work tree, opening a file tries again
+pub fn start_polling() {
+    let start = REPO.with(|r| !std::mem::replace(&mut r.borrow_mut().polling, true));
+    if start {
+        app::add_timeout3(0.0, poll);
+    }
+}
+
+fn poll(handle: app::TimeoutHandle) {
+    let received = REPO.with(|r| {
+        let mut r = r.borrow_mut();
+        if r.rx.is_none() {
+            let (tx, rx) = mpsc::channel();
+            let dir = env::current_dir().unwrap();
+            thread::spawn(move || tx.send(status(dir)).ok());
+            r.rx = Some(rx);
+        }
+        match r.rx.as_ref().unwrap().try_recv() {
+            Ok(status) => {
+                r.rx = None;
+                let head_moved = status.head != r.status.head;
+                r.polling = status.root.is_some();
+                r.status = status;
+                Some(head_moved)
+            }
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                r.rx = None;
+                None
+            }
+        }
+    });
+    if let Some(head_moved) = received {
+        // a commit or checkout happened outside the editor
+        if head_moved {
+            let ids: Vec<usize> = GUTTERS.with(|g| {
+                let mut g = g.borrow_mut();
+                for gutter in g.values_mut() {
+                    gutter.head_read = false;
+                }
+                g.keys().copied().collect()
+            });
+            for id in ids {
+                update_marks(id);
+            }
+        }
+        decorate_fbr();
+    }
+    if !REPO.with(|r| r.borrow().polling) {
+        return;
+    }
+    app::repeat_timeout3(
+        if received.is_some() {
+            POLL_INTERVAL
+        } else {
+            0.1
+        },
+        handle,
+    );
+}
+
+// Colors the file browser's entries by their state, directories take the state of the changes below them
+pub fn decorate_fbr() {
+    REPO.with(|r| {
+        let files = &r.borrow().status.files;
+        fbr::for_each_item(|item, path| {
+            let tag = files
+                .get(path)
+                .copied()
+                .or_else(|| files.iter().find(|(p, _)| p.starts_with(path)).map(|_| 'M'));
+            item.set_label_fgcolor(match tag {
+                Some('A') => Color::from_hex(0x859900),
+                Some('U') => Color::from_hex(0x2aa198),
+                Some(_) => Color::from_hex(0xb58900),
+                None => Color::Foreground,
+            });
+        });
+    });
+}
+
+// Draws the change bars over the line-number gutter, see gui::build_editor
+pub fn draw_gutter(ed: &mut text::TextEditor) {
+    let id = ed.as_widget_ptr() as usize;
+    let id = PANES.with(|p| p.borrow().get(&id).copied()).unwrap_or(id);
+    let buf = match ed.buffer() {
+        Some(buf) => buf,
+        None => return,
+    };
+    GUTTERS.with(|g| {
+        let g = g.borrow();
+        let marks = match g.get(&id) {
+            Some(gutter) if !gutter.marks.is_empty() => &gutter.marks,
+            _ => return,
+        };
+        draw::set_font(ed.text_font(), ed.text_size());
+        let lh = draw::height();
+        let top = gui::top_line(ed) as usize;
+        let rows = (ed.h() / lh) as usize + 1;
+        let (_, y0) = ed.position_to_xy(buf.skip_lines(0, top as i32));
+        let x = ed.x() + ed.linenumber_width() - 2;
+        draw::push_clip(ed.x(), ed.y(), ed.linenumber_width() + 4, ed.h());
+        let start = marks.partition_point(|(l, _)| *l < top);
+        for (line, mark) in marks[start..].iter().take_while(|(l, _)| *l < top + rows) {
+            let y = y0 + (line - top) as i32 * lh;
+            match mark {
+                LineMark::Added => {
+                    draw::set_draw_color(Color::from_hex(0x859900));
+                    draw::draw_rectf(x, y, 3, lh);
+                }
+                LineMark::Modified => {
+                    draw::set_draw_color(Color::from_hex(0x268bd2));
+                    draw::draw_rectf(x, y, 3, lh);
+                }
+                LineMark::Deleted => {
+                    draw::set_draw_color(Color::from_hex(0xdc322f));
+                    draw::draw_polygon(x, y - 3, x + 5, y, x, y + 3);
+                }
+            }
+        }
+        draw::pop_clip();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, process};
+
+    #[test]
+    fn diff() {
+        use Op::*;
+        let ops = |old: &str, new: &str| {
+            let old: Vec<_> = old.lines().collect();
+            let new: Vec<_> = new.lines().collect();
+            diff_lines(&old, &new)
+        };
+        assert_eq!(ops("a\nb\nc", "a\nb\nc"), [Equal; 3]);
+        assert_eq!(ops("a\nc", "a\nb\nc"), [Equal, Insert, Equal]);
+        assert_eq!(ops("a\nb\nc", "a\nc"), [Equal, Delete, Equal]);
+        assert_eq!(ops("a\nb\nc", "a\nx\nc"), [Equal, Delete, Insert, Equal]);
+        assert_eq!(ops("", "a"), [Insert]);
+        // the same line moved up
+        assert_eq!(
+            ops("a\nb\nc\nd", "c\na\nb\nd"),
+            [Insert, Equal, Equal, Delete, Equal]
+        );
+    }
+
+    #[test]
+    fn marks() {
+        use LineMark::*;
+        assert_eq!(line_marks("a\nb\nc", "a\nb\nc"), []);
+        assert_eq!(line_marks("a\nc", "a\nb\nc"), [(1, Added)]);
+        assert_eq!(
+            line_marks("a\nb\nc", "a\nx\ny\nc"),
+            [(1, Modified), (2, Modified)]
+        );
+        assert_eq!(line_marks("a\nb\nc", "a\nc"), [(1, Deleted)]);
+        // removed at the end, marked on the last line
+        assert_eq!(line_marks("a\nb", "a"), [(0, Deleted)]);
+        assert_eq!(line_marks("", "a\nb"), [(0, Added), (1, Added)]);
+    }
+
+    #[test]
+    fn unified_hunks() {
+        let old: String = (1..=20).map(|i| format!("{}\n", i)).collect();
+        let new: String = (1..=20)
+            .filter(|i| *i != 18)
+            .map(|i| match i {
+                2 => "two\n".to_string(),
+                i => format!("{}\n", i),
+            })
+            .collect();
+        assert_eq!(
+            unified(&old, &new),
+            [
+                "@@ -1,5 +1,5 @@",
+                " 1",
+                "-2",
+                "+two",
+                " 3",
+                " 4",
+                " 5",
+                "@@ -15,6 +15,5 @@",
+                " 15",
+                " 16",
+                " 17",
+                "-18",
+                " 19",
+                " 20",
+            ]
+        );
+        assert!(unified(&old, &old).is_empty());
+    }
+
+    // git init in a fresh directory under the temp dir
+    fn repo(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("fltext-git-{}-{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        for args in [
+            &["init", "-q"][..],
+            &["config", "user.name", "fltext"],
+            &["config", "user.email", "fltext@localhost"],
+        ] {
+            git(&dir, args).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn work_tree() {
+        let dir = repo("work-tree");
+        let committed = dir.join("committed.txt");
+        fs::write(&committed, "a\nb\n").unwrap();
+        fs::write(dir.join("added.txt"), "").unwrap();
+        git(&dir, &["add", "committed.txt"]).unwrap();
+        git(&dir, &["commit", "-q", "-m", "first"]).unwrap();
+        git(&dir, &["add", "added.txt"]).unwrap();
+        fs::write(dir.join("untracked.txt"), "").unwrap();
+        fs::write(&committed, "a\nc\n").unwrap();
+
+        assert_eq!(head_text(&committed).as_deref(), Some("a\nb\n"));
+        // not committed yet, everything is new
+        assert_eq!(head_text(&dir.join("untracked.txt")).as_deref(), Some(""));
+        assert_eq!(
+            super::diff(&committed, "a\nc\n").unwrap(),
+            ["@@ -1,2 +1,2 @@", " a", "-b", "+c"]
+        );
+
+        let status = status(dir.clone());
+        assert_eq!(status.root.as_ref(), Some(&dir));
+        assert!(status.head.is_some());
+        assert_eq!(status.files.get(&committed), Some(&'M'));
+        assert_eq!(status.files.get(&dir.join("added.txt")), Some(&'A'));
+        assert_eq!(status.files.get(&dir.join("untracked.txt")), Some(&'U'));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn outside_work_tree() {
+        let dir = env::temp_dir().join(format!("fltext-nogit-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file.txt");
+        fs::write(&file, "a\n").unwrap();
+        // the temp dir itself may sit in a repository
+        if git(&dir, &["rev-parse", "--is-inside-work-tree"]).is_none() {
+            assert_eq!(head_text(&file), None);
+            assert!(status(dir.clone()).root.is_none());
+        }
+        fs::remove_dir_all(&dir).ok();
+    }
+}
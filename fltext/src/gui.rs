@@ -1,16 +1,26 @@
 use {
-    crate::{cbs, dialogs, fbr, utils},
+    crate::{
+        cbs,
+        commands::{self, Command},
+        cursors, dialogs, editing, encoding, fbr, git, hooks, outline, preview, script,
+        state::STATE,
+        swap, tasks, theme, utils, watch,
+    },
     fltk::{
         enums::*,
         frame::Frame,
-        menu::{MenuButton, MenuButtonType},
         group::{Flex, Tabs},
+        menu::{MenuButton, MenuButtonType},
         prelude::*,
         window::Window,
         *,
     },
     fltk_theme::{color_themes, ColorTheme},
-    std::path::{Path, PathBuf},
+    std::{
+        cell::RefCell,
+        collections::HashMap,
+        path::{Path, PathBuf},
+    },
 };
 
 #[cfg(feature = "term")]
@@ -19,22 +29,34 @@ use fltk_term as term;
 #[cfg(feature = "highlight")]
 use crate::highlight;
 
+#[cfg(feature = "lsp")]
+use crate::lsp;
+
 const WIDTH: i32 = 800;
 const HEIGHT: i32 = 600;
 const MENU_HEIGHT: i32 = if cfg!(target_os = "macos") { 1 } else { 30 };
 
+thread_local! {
+    // Style buffers of editors without tree-sitter highlighting, keyed by buffer id
+    // like State::map and shared with the buffer's split panes
+    static PLAIN: RefCell<HashMap<usize, text::TextBuffer>> = RefCell::new(HashMap::new());
+}
+
 pub fn init_gui(current_file: &Option<PathBuf>, current_path: &Path) -> app::App {
     let mut buf = text::TextBuffer::default();
     buf.set_tab_distance(4);
 
     let _find_dialog = dialogs::FindDialog::new();
-    let _replace_dialog = dialogs::ReplaceDialog::new();
     let _image_dialog = dialogs::ImageDialog::new();
+    let _find_in_files_dialog = dialogs::FindInFilesDialog::new();
+    let _diff_dialog = dialogs::DiffDialog::new();
+    let _palette_dialog = dialogs::PaletteDialog::new();
+    let _recovery_dialog = swap::RecoveryDialog::new();
 
     let mut popup = MenuButton::default()
         .with_type(MenuButtonType::Popup3)
         .with_id("Popup");
-    init_edit_menu(&mut popup, "");
+    init_edit_menu(&mut popup);
 
     let mut window = window();
 
@@ -56,11 +78,15 @@ pub fn init_gui(current_file: &Option<PathBuf>, current_path: &Path) -> app::App
     row.fixed(&fbr_splitter, 4);
     let mut col = Flex::default().column();
     col.set_pad(0);
+    let reload_bar = watch::ReloadBar::new();
+    col.fixed(&*reload_bar, 0);
     let mut tabs = Tabs::default().with_id("tabs");
     tabs.handle(tabs_handle);
     tabs.handle_overflow(group::TabsOverflow::Pulldown);
     tabs.end();
     tabs.auto_layout();
+    let output = tasks::OutputPanel::new();
+    col.fixed(&*output, 1);
     #[cfg(feature = "term")]
     {
         let mut tab_splitter = frame::Frame::default();
@@ -70,6 +96,13 @@ pub fn init_gui(current_file: &Option<PathBuf>, current_path: &Path) -> app::App
         col.fixed(&*term, 160);
     }
     col.end();
+    let outline = outline::OutlinePanel::new();
+    row.fixed(&*outline, 1);
+    let mut preview_splitter = Frame::default();
+    preview_splitter.handle(cbs::preview_splitter_cb);
+    row.fixed(&preview_splitter, 4);
+    let preview = preview::PreviewPanel::new();
+    row.fixed(&*preview, 1);
     row.end();
     let info = Frame::default()
         .with_label(&format!(
@@ -117,143 +150,239 @@ pub fn tabs_handle(t: &mut group::Tabs, ev: Event) -> bool {
     }
 }
 
-pub fn init_edit_menu(m: &mut (impl MenuExt + 'static), header: &str) {
-    m.add(
-        &format!("{}@#undo  &Undo\t", header),
-        Shortcut::Ctrl | 'z',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        &format!("{}@#redo  &Redo\t", header),
-        Shortcut::Ctrl | 'y',
-        menu::MenuFlag::MenuDivider,
-        cbs::menu_cb,
-    );
-    m.add(
-        &format!("{}Cut\t", header),
-        Shortcut::Ctrl | 'x',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        &format!("{}Copy\t", header),
-        Shortcut::Ctrl | 'c',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        &format!("{}Paste\t", header),
-        Shortcut::Ctrl | 'v',
-        menu::MenuFlag::MenuDivider,
-        cbs::menu_cb,
-    );
-    m.add(
-        &format!("{}@#search  &Find\t", header),
-        Shortcut::Ctrl | 'f',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        &format!("{}Replace\t", header),
-        Shortcut::Ctrl | 'h',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
+pub fn init_edit_menu(m: &mut (impl MenuExt + 'static)) {
+    commands::add_to_menu(m, Command::EDIT, false);
 }
+
 pub fn init_menu(m: &mut (impl MenuExt + 'static), load_dir: bool) {
-    m.add(
-        "&File/@#filenew  &New File...\t",
-        Shortcut::Ctrl | 'n',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        "&File/@#fileopen  New Dir...\t",
-        Shortcut::Ctrl | Shortcut::Shift | 'n',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        "&File/@#fileopen  &Open...\t",
-        Shortcut::Ctrl | 'o',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        "&File/@#filesave  &Save\t",
-        Shortcut::Ctrl | 's',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        "&File/@#filesaveas  Save as...\t",
-        Shortcut::Ctrl | Shortcut::Shift | 'w',
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
-    m.add(
-        "&File/@#filesaveas  Save All\t",
-        Shortcut::None,
-        menu::MenuFlag::MenuDivider,
-        cbs::menu_cb,
-    );
-    let idx = m.add(
-        "&File/@#1+  Quit\t",
-        Shortcut::Ctrl | 'q',
-        menu::MenuFlag::Normal,
-        move |_| {
-            app::handle_main(Event::Close).unwrap();
-        },
-    );
-    m.at(idx)
-        .unwrap()
-        .set_label_color(Color::from_hex(0xdc322f));
-    init_edit_menu(m, "&Edit/");
-    let idx = m.add(
-        "&View/File browser\t",
-        Shortcut::None,
-        menu::MenuFlag::Toggle,
-        cbs::menu_cb,
-    );
-    if load_dir {
-        m.at(idx).unwrap().set();
-    }
-    #[cfg(feature = "term")]
-    {
+    commands::add_to_menu(m, Command::ALL, true);
+    let current = theme::current().name;
+    for t in theme::list() {
         let idx = m.add(
-            "&View/Terminal\t",
+            &format!("&View/Theme/{}\t", t.name),
             Shortcut::None,
-            menu::MenuFlag::Toggle,
-            cbs::menu_cb,
+            menu::MenuFlag::Radio,
+            move |_| select_theme(&t.name),
         );
-        m.at(idx).unwrap().set();
+        if t.name == current {
+            m.at(idx).unwrap().set();
+        }
+    }
+    if let Some(mut item) = m.find_item(&Command::ToggleFileBrowser.menu_path()) {
+        if load_dir {
+            item.set();
+        }
+    }
+    if let Some(mut item) = m.find_item(&Command::ToggleTerminal.menu_path()) {
+        item.set();
+    }
+}
+
+// Also used by the command palette, which bypasses the radio items
+pub fn select_theme(name: &str) {
+    if let Some(t) = theme::list().into_iter().find(|t| t.name == name) {
+        theme::set(t);
+        restyle_all();
+    }
+    if let Some(m) = app::widget_from_id::<menu::SysMenuBar>("menu") {
+        for t in theme::list() {
+            if let Some(mut item) = m.find_item(&format!("&View/Theme/{}\t", t.name)) {
+                if t.name == name {
+                    item.set();
+                } else {
+                    item.clear();
+                }
+            }
+        }
     }
-    m.add(
-        "&Help/About\t",
-        Shortcut::None,
-        menu::MenuFlag::Normal,
-        cbs::menu_cb,
-    );
 }
 
 pub fn build_editor(id: &str) -> text::TextEditor {
     let mut texteditor = text::TextEditor::default().with_id(id);
-    texteditor.set_color(Color::from_hex(0x002b36));
+    apply_theme(&mut texteditor);
     texteditor.set_linenumber_width(40);
     texteditor.set_linenumber_size(12);
-    texteditor.set_linenumber_fgcolor(Color::from_hex(0xb58900));
-    texteditor.set_linenumber_bgcolor(Color::Background.darker());
     texteditor.set_text_font(Font::Courier);
     texteditor.set_trigger(CallbackTrigger::Changed);
     texteditor.set_callback(cbs::editor_cb);
+    texteditor.draw(draw_editor);
+    texteditor.handle(editor_handle);
     texteditor
 }
 
+// Events that would change the buffer, swallowed for read-only editors
+fn is_edit(ev: Event) -> bool {
+    match ev {
+        Event::Paste => true,
+        Event::KeyDown => {
+            let key = app::event_key();
+            if app::is_event_ctrl() || app::is_event_command() {
+                "xvzy".chars().any(|c| key == Key::from_char(c))
+            } else {
+                let text = app::event_text();
+                matches!(
+                    key,
+                    Key::BackSpace | Key::Delete | Key::Enter | Key::KPEnter | Key::Tab
+                ) || (!text.is_empty() && !text.chars().any(char::is_control))
+            }
+        }
+        _ => false,
+    }
+}
+
+fn editor_handle(ed: &mut text::TextEditor, ev: Event) -> bool {
+    let id = ed.as_widget_ptr() as usize;
+    // Push as well, clicking into an editor doesn't always send Focus
+    if matches!(ev, Event::Push | Event::Focus) {
+        STATE.with(move |s| s.focused = Some(id));
+    }
+    if ev == Event::Unfocus {
+        swap::focus_lost();
+    }
+    if is_edit(ev) && STATE.with(move |s| s.read_only(id)) {
+        return true;
+    }
+    script::record(ed, ev);
+    if cursors::handle(ed, ev) || editing::handle(ed, ev) {
+        return true;
+    }
+    #[cfg(feature = "lsp")]
+    {
+        lsp::editor_handle(ed, ev)
+    }
+    #[cfg(not(feature = "lsp"))]
+    false
+}
+
+fn draw_editor(ed: &mut text::TextEditor) {
+    git::draw_gutter(ed);
+    editing::draw(ed);
+    cursors::draw(ed);
+    outline::sync(ed);
+    preview::sync(ed);
+}
+
+fn apply_theme(ed: &mut text::TextEditor) {
+    let t = theme::current();
+    ed.set_color(t.background);
+    ed.set_text_color(t.foreground);
+    ed.set_cursor_color(t.cursor);
+    ed.set_selection_color(t.selection);
+    ed.set_linenumber_fgcolor(t.linenumber);
+    ed.set_linenumber_bgcolor(t.linenumber_background);
+}
+
+// Called after switching themes, every open editor and pane picks up the new colors
+pub fn restyle_all() {
+    let tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
+    let mut editors: Vec<(usize, usize)> = (0..tabs.children())
+        .filter_map(|i| tabs.child(i).and_then(|edrow| edrow.child(0)))
+        .map(|ed| (ed.as_widget_ptr() as usize, ed.as_widget_ptr() as usize))
+        .collect();
+    editors.extend(STATE.with(|s| s.panes.clone()));
+    for (edid, id) in editors {
+        let mut ed = text::TextEditor::from_dyn_widget_ptr(edid as *mut _).unwrap();
+        let buf = STATE.with(move |s| s.map.get(&id).map(|b| b.buf.clone()));
+        apply_theme(&mut ed);
+        #[cfg(feature = "highlight")]
+        {
+            if buf
+                .as_ref()
+                .map_or(false, |buf| highlight::restyle(&mut ed, id, buf))
+            {
+                continue;
+            }
+        }
+        // plain editors only carry diagnostics and search matches
+        if let (Some(sbuf), Some(_)) = (ed.style_buffer(), buf) {
+            let entry = text::StyleTableEntry {
+                color: ed.text_color(),
+                font: ed.text_font(),
+                size: ed.text_size(),
+            };
+            ed.set_highlight_data_ext(sbuf, style_table(&[entry]));
+        }
+    }
+    preview::update();
+    app::redraw();
+}
+
+// Style of search matches, right after the highlighting styles
+pub const MATCH_STYLE: char = '[';
+
+// Style 'A' + n is used for highlighting, 'a' + n is the same style underlined (diagnostics),
+// MATCH_STYLE marks search matches
+pub fn style_table(entries: &[text::StyleTableEntry]) -> Vec<text::StyleTableEntryExt> {
+    let ext = |e: &text::StyleTableEntry, attr| text::StyleTableEntryExt {
+        color: e.color,
+        attr,
+        font: e.font,
+        size: e.size,
+        bgcolor: Color::Red,
+    };
+    let mut table: Vec<_> = entries
+        .iter()
+        .map(|e| ext(e, text::TextAttr::None))
+        .collect();
+    while table.len() < (MATCH_STYLE as u8 - b'A') as usize {
+        table.push(ext(&entries[0], text::TextAttr::None));
+    }
+    table.push(text::StyleTableEntryExt {
+        color: Color::Black,
+        attr: text::TextAttr::BgColor,
+        font: entries[0].font,
+        size: entries[0].size,
+        bgcolor: Color::from_hex(0xb58900),
+    });
+    while table.len() < (b'a' - b'A') as usize {
+        table.push(ext(&entries[0], text::TextAttr::None));
+    }
+    table.extend(entries.iter().map(|e| ext(e, text::TextAttr::Underline)));
+    table
+}
+
+// Editors without tree-sitter highlighting still need a style buffer to carry
+// diagnostics and search matches, id is the buffer id ed shows
+pub fn ensure_style_buffer(id: usize, ed: &mut text::TextEditor, buf: &mut text::TextBuffer) {
+    if ed.style_buffer().is_some() {
+        return;
+    }
+    let sbuf = PLAIN.with(|p| {
+        p.borrow_mut()
+            .entry(id)
+            .or_insert_with(|| {
+                let mut sbuf = text::TextBuffer::default();
+                sbuf.set_text(&"A".repeat(buf.length() as usize));
+                hooks::set(id, "style", {
+                    let mut sbuf = sbuf.clone();
+                    move |pos, ins, del, _, _| {
+                        if del > 0 {
+                            sbuf.remove(pos, pos + del);
+                        }
+                        if ins > 0 {
+                            sbuf.insert(pos, &"A".repeat(ins as usize));
+                        }
+                    }
+                });
+                sbuf
+            })
+            .clone()
+    });
+    ed.set_highlight_data_ext(
+        sbuf,
+        style_table(&[text::StyleTableEntry {
+            color: ed.text_color(),
+            font: ed.text_font(),
+            size: ed.text_size(),
+        }]),
+    );
+}
+
 pub fn create_ed(
     tabs: &mut group::Tabs,
     id: &str,
     current_path: &Option<PathBuf>,
+    opened: Option<&encoding::Opened>,
 ) -> text::TextEditor {
     tabs.begin();
     let mut edrow = group::Flex::default()
@@ -278,11 +407,108 @@ pub fn create_ed(
 
     let mut buf = text::TextBuffer::default();
     buf.set_tab_distance(4);
+    hooks::install(ed.as_widget_ptr() as usize, &mut buf);
     if let Some(p) = current_path.as_ref() {
-        buf.load_file(p).ok();
-        #[cfg(feature = "highlight")]
-        highlight::highlight(p, &mut ed, &mut buf);
+        match opened {
+            Some(opened) => {
+                buf.set_text(&opened.text);
+                match opened.mode {
+                    encoding::Mode::Normal => attach_file(p, &mut ed, &mut buf),
+                    // large files only get reloaded when changed on disk
+                    encoding::Mode::Large => watch::watch(p),
+                    encoding::Mode::Hex => (),
+                }
+            }
+            None => attach_file(p, &mut ed, &mut buf),
+        }
     }
     ed.set_buffer(buf);
     ed
 }
+
+// Adds a splitter and a second editor showing buffer id to a tab, see State::open_pane
+pub fn create_pane(edrow: &mut group::Flex, id: usize, buf: &text::TextBuffer) -> text::TextEditor {
+    edrow.begin();
+    let mut splitter = Frame::default();
+    splitter.handle(cbs::pane_splitter_cb);
+    edrow.fixed(&splitter, 4);
+    let mut ed = build_editor("pane");
+    edrow.end();
+    ed.set_buffer(buf.clone());
+    attach_pane(id, &mut ed);
+    git::add_pane(ed.as_widget_ptr() as usize, id);
+    edrow.layout();
+    ed.take_focus().ok();
+    ed
+}
+
+pub fn remove_pane(id: usize) {
+    let ed = text::TextEditor::from_dyn_widget_ptr(id as *mut _).unwrap();
+    if let Some(parent) = ed.parent() {
+        let mut edrow = group::Flex::from_dyn_widget(&parent).unwrap();
+        let idx = edrow.find(&ed);
+        if let Some(splitter) = edrow.child(idx - 1) {
+            edrow.remove(&splitter);
+            app::delete_widget(splitter);
+        }
+        edrow.remove(&ed);
+        // the tab's own editor takes the whole tab again
+        if let Some(first) = edrow.child(0) {
+            edrow.fixed(&first, 0);
+        }
+        edrow.layout();
+        edrow.redraw();
+    }
+    // highlighting, git marks and modify hooks belong to the buffer
+    git::remove_pane(id);
+    cursors::remove(id);
+    editing::remove(id);
+    app::delete_widget(ed);
+}
+
+// First visible line counting from 0, TextEditor::scroll counts from 1
+pub fn top_line(ed: &text::TextEditor) -> i32 {
+    let pos = ed.xy_to_position(ed.x() + 1, ed.y() + 1, text::PositionType::Character);
+    ed.count_lines(0, pos, true)
+}
+
+// Hooks up everything keyed on the file's path, also used when Save as re-targets a buffer
+pub fn attach_file(p: &Path, ed: &mut text::TextEditor, buf: &mut text::TextBuffer) {
+    watch::watch(p);
+    git::attach(p, ed, buf);
+    #[cfg(feature = "highlight")]
+    highlight::highlight(p, ed, buf);
+    #[cfg(feature = "lsp")]
+    lsp::attach(p, ed, buf);
+}
+
+// Panes show the style buffer of the tab's editor, id, everything else keyed on
+// the file is shared through the buffer id as well
+pub fn attach_pane(id: usize, pane: &mut text::TextEditor) {
+    if pane.style_buffer().is_some() {
+        pane.set_highlight_data_ext(None::<text::TextBuffer>, Vec::new());
+    }
+    #[cfg(feature = "highlight")]
+    if highlight::share(id, pane) {
+        return;
+    }
+    let owner = text::TextEditor::from_dyn_widget_ptr(id as *mut _).unwrap();
+    if let (Some(_), Some(mut buf)) = (owner.style_buffer(), pane.buffer()) {
+        ensure_style_buffer(id, pane, &mut buf);
+    }
+}
+
+pub fn detach_file(id: usize, p: Option<&Path>) {
+    hooks::remove(id);
+    PLAIN.with(|p| p.borrow_mut().remove(&id));
+    git::detach(id);
+    cursors::remove(id);
+    editing::remove(id);
+    #[cfg(feature = "highlight")]
+    highlight::remove(id);
+    if let Some(p) = p {
+        watch::unwatch(p);
+        #[cfg(feature = "lsp")]
+        lsp::detach(p);
+    }
+}
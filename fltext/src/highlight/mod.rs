@@ -1,130 +1,687 @@
+use crate::{
+    gui, hooks, lang,
+    outline::Symbol,
+    theme::{self, Theme},
+};
 use fltk::{
     app,
-    enums::{Color, Font},
+    enums::Font,
     prelude::DisplayExt,
     text::{StyleTableEntry, TextBuffer, TextEditor},
 };
-use std::path::Path;
-use tree_sitter_highlight::HighlightConfiguration;
-use tree_sitter_highlight::HighlightEvent;
-use tree_sitter_highlight::Highlighter;
+use std::{cell::RefCell, collections::HashMap, iter, ops::Range, path::Path};
+use tree_sitter::{InputEdit, Language, Node, Parser, Point, Query, QueryCursor, Tree};
 
-mod colors;
-mod md;
-mod rust;
-mod toml;
+thread_local! {
+    // Keyed by buffer id like State::map, split panes share the tab editor's
+    // highlighter and style buffer. No entry means no grammar applies.
+    static HIGHLIGHTERS: RefCell<HashMap<usize, Highlighter>> = RefCell::new(HashMap::new());
+}
 
 fn translate_style(idx: usize) -> char {
     char::from_u32(65 + idx as u32).unwrap()
 }
 
-fn resolve_styles(v: &[(&'static str, u32)]) -> (Vec<&'static str>, Vec<StyleTableEntry>) {
-    let mut names = Vec::new();
-    let mut styles = Vec::new();
-    for elem in v {
-        names.push(elem.0);
+// Style 0 is the theme's foreground, then one style per capture
+fn resolve_styles(theme: &Theme) -> (Vec<&str>, Vec<StyleTableEntry>) {
+    let mut names = vec!["DEFAULT"];
+    let mut styles = vec![StyleTableEntry {
+        color: theme.foreground,
+        font: Font::Courier,
+        size: app::font_size(),
+    }];
+    for (name, style) in &theme.captures {
+        names.push(name);
         styles.push(StyleTableEntry {
-            color: Color::from_hex(elem.1),
-            font: Font::Courier,
+            color: style.color,
+            font: style.font,
             size: app::font_size(),
         });
     }
     (names, styles)
 }
 
+// Grammars for the languages of the registry in lang.rs
+fn grammar(lang: &str) -> Option<(Language, &'static str)> {
+    Some(match lang {
+        "rust" => (
+            tree_sitter_rust::language(),
+            tree_sitter_rust::HIGHLIGHT_QUERY,
+        ),
+        "toml" => (
+            tree_sitter_toml::language(),
+            tree_sitter_toml::HIGHLIGHT_QUERY,
+        ),
+        "markdown" => (
+            tree_sitter_md::language(),
+            tree_sitter_md::HIGHLIGHT_QUERY_BLOCK,
+        ),
+        "json" => (
+            tree_sitter_json::language(),
+            include_str!("queries/json.scm"),
+        ),
+        "python" => (
+            tree_sitter_python::language(),
+            tree_sitter_python::HIGHLIGHT_QUERY,
+        ),
+        "c" => (tree_sitter_c::language(), tree_sitter_c::HIGHLIGHT_QUERY),
+        "shell" => (
+            tree_sitter_bash::language(),
+            tree_sitter_bash::HIGHLIGHT_QUERY,
+        ),
+        "yaml" => (
+            tree_sitter_yaml::language(),
+            include_str!("queries/yaml.scm"),
+        ),
+        _ => return None,
+    })
+}
+
+// Nodes whose contents are indented one level, used by indent_level
+fn indent_kinds(lang: &str) -> &'static [&'static str] {
+    match lang {
+        "rust" => &[
+            "block",
+            "declaration_list",
+            "field_declaration_list",
+            "field_initializer_list",
+            "enum_variant_list",
+            "match_block",
+            "arguments",
+            "parameters",
+            "array_expression",
+            "tuple_expression",
+            "use_list",
+            "token_tree",
+        ],
+        "toml" => &["array", "inline_table"],
+        "json" => &["object", "array"],
+        "python" => &[
+            "block",
+            "argument_list",
+            "parameters",
+            "list",
+            "dictionary",
+            "set",
+            "tuple",
+        ],
+        "c" => &[
+            "compound_statement",
+            "field_declaration_list",
+            "enumerator_list",
+            "initializer_list",
+            "argument_list",
+            "parameter_list",
+        ],
+        "shell" => &["compound_statement", "do_group", "subshell"],
+        _ => &[],
+    }
+}
+
+// Nodes listed in the outline and the keyword shown in front of their name
+fn symbol_kinds(lang: &str) -> &'static [(&'static str, &'static str)] {
+    match lang {
+        "rust" => &[
+            ("function_item", "fn"),
+            ("function_signature_item", "fn"),
+            ("struct_item", "struct"),
+            ("enum_item", "enum"),
+            ("union_item", "union"),
+            ("trait_item", "trait"),
+            ("impl_item", "impl"),
+            ("mod_item", "mod"),
+            ("macro_definition", "macro_rules!"),
+        ],
+        "python" => &[
+            ("function_definition", "def"),
+            ("class_definition", "class"),
+        ],
+        "c" => &[
+            ("function_definition", "fn"),
+            ("struct_specifier", "struct"),
+            ("enum_specifier", "enum"),
+            ("union_specifier", "union"),
+        ],
+        "shell" => &[("function_definition", "fn")],
+        "markdown" => &[("atx_heading", ""), ("setext_heading", "")],
+        "toml" => &[("table", ""), ("table_array_element", "")],
+        _ => &[],
+    }
+}
+
 pub struct HighlightData {
+    lang: &'static str,
     styles: Vec<StyleTableEntry>,
-    config: HighlightConfiguration,
-    exeption_fn: Option<fn(usize, &str) -> char>,
+    language: Language,
+    query: Query,
+    capture_styles: Vec<usize>,
 }
 
 impl HighlightData {
-    pub fn new(
-        styles: Vec<StyleTableEntry>,
-        config: HighlightConfiguration,
-        exeption_fn: Option<fn(usize, &str) -> char>,
-    ) -> Self {
-        Self {
+    pub fn new(lang: &'static str, theme: &Theme) -> Option<Self> {
+        let (language, query) = grammar(lang)?;
+        let query = match Query::new(language, query) {
+            Ok(query) => query,
+            Err(e) => {
+                eprintln!("{} highlight query: {}", lang, e);
+                return None;
+            }
+        };
+        let (names, styles) = resolve_styles(theme);
+        let capture_styles = query
+            .capture_names()
+            .iter()
+            .map(|capture| capture_style(&names, capture))
+            .collect();
+        Some(Self {
+            lang,
             styles,
-            config,
-            exeption_fn,
-        }
+            language,
+            query,
+            capture_styles,
+        })
     }
 }
 
-fn get_highlight(p: &Path) -> Option<HighlightData> {
-    if let Some(ext) = p.extension() {
-        match ext.to_str().unwrap() {
-            "rs" => Some(rust::lang_data()),
-            "toml" => Some(toml::lang_data()),
-            "md" => Some(md::lang_data()),
-            _ => None,
+// Same rule as tree-sitter-highlight: "function.method.call" takes the style of
+// the longest configured prefix, i.e. "function.method"
+fn capture_style(names: &[&str], capture: &str) -> usize {
+    names
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| {
+            capture == **name
+                || (capture.starts_with(**name) && capture[name.len()..].starts_with('.'))
+        })
+        .max_by_key(|(_, name)| name.len())
+        .map_or(0, |(idx, _)| idx)
+}
+
+fn get_highlight(p: &Path, buf: &TextBuffer) -> Option<HighlightData> {
+    let first_line = buf.text_range(0, buf.line_end(0)).unwrap_or_default();
+    let lang = lang::detect(p, &first_line)?;
+    HighlightData::new(lang.name, &theme::current())
+}
+
+// Row and column of pos counting from a known row at from, fltk counts the
+// lines in between without copying the text out
+fn point(buf: &TextBuffer, from: (usize, usize), pos: usize) -> Point {
+    let pos = pos as i32;
+    Point::new(
+        from.1 + buf.count_lines(from.0 as i32, pos, false) as usize,
+        (pos - buf.line_start(pos)) as usize,
+    )
+}
+
+fn advance(start: Point, s: &str) -> Point {
+    match s.rfind('\n') {
+        Some(idx) => Point::new(start.row + s.matches('\n').count(), s.len() - idx - 1),
+        None => Point::new(start.row, start.column + s.len()),
+    }
+}
+
+fn line_range(buf: &TextBuffer, range: Range<usize>) -> Range<usize> {
+    buf.line_start(range.start as i32) as usize..buf.line_end(range.end as i32) as usize
+}
+
+// Feeds the parser a line at a time, so re-parsing after an edit only reads
+// the parts of the buffer tree-sitter can't reuse
+fn chunk(buf: &TextBuffer, byte: usize) -> Vec<u8> {
+    let len = buf.length() as usize;
+    if byte >= len {
+        return vec![];
+    }
+    let end = (buf.line_end(byte as i32) as usize + 1).min(len);
+    buf.text_range(byte as i32, end as i32)
+        .unwrap_or_default()
+        .into_bytes()
+}
+
+struct Highlighter {
+    parser: Parser,
+    tree: Tree,
+    data: HighlightData,
+    sbuf: TextBuffer,
+}
+
+impl Highlighter {
+    fn new(data: HighlightData, buf: &TextBuffer, sbuf: TextBuffer) -> Option<Self> {
+        let mut parser = Parser::new();
+        parser.set_language(data.language).ok()?;
+        let s = buf.text();
+        let tree = parser.parse(&s, None)?;
+        let mut highlighter = Self {
+            parser,
+            tree,
+            data,
+            sbuf,
+        };
+        let styles = highlighter.styles(&s, 0..s.len());
+        highlighter.sbuf.set_text(&styles);
+        Some(highlighter)
+    }
+
+    // s is the text of range, which starts and ends on a line boundary
+    fn styles(&self, s: &str, range: Range<usize>) -> String {
+        let mut local_buf = vec![translate_style(0) as u8; range.len()];
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(range.clone());
+        let mut last_node = None;
+        // predicates only look at the part of a node inside range
+        let text = |node: Node| {
+            let start = node.start_byte().clamp(range.start, range.end) - range.start;
+            let end = node.end_byte().clamp(range.start, range.end) - range.start;
+            iter::once(&s.as_bytes()[start..end])
+        };
+        for (m, idx) in cursor.captures(&self.data.query, self.tree.root_node(), text) {
+            let capture = m.captures[idx];
+            // When several patterns capture the same node, the first one wins
+            if last_node == Some(capture.node.id()) {
+                continue;
+            }
+            last_node = Some(capture.node.id());
+            let node_range = capture.node.byte_range();
+            let start = node_range.start.max(range.start);
+            let end = node_range.end.min(range.end);
+            if start >= end {
+                continue;
+            }
+            let c = translate_style(self.data.capture_styles[capture.index as usize]);
+            local_buf[start - range.start..end - range.start].fill(c as u8);
         }
-    } else {
-        None
+        String::from_utf8(local_buf).unwrap()
+    }
+
+    // Re-parses incrementally and only restyles the lines touched by the edit
+    // plus whatever ranges tree-sitter reports as structurally changed. Nothing
+    // here copies the whole buffer, a keystroke costs about the same in any file.
+    fn edit(
+        &mut self,
+        buf: &TextBuffer,
+        pos: usize,
+        inserted: usize,
+        deleted: usize,
+        deleted_text: &str,
+    ) {
+        let start_position = point(buf, (0, 0), pos);
+        self.tree.edit(&InputEdit {
+            start_byte: pos,
+            old_end_byte: pos + deleted,
+            new_end_byte: pos + inserted,
+            start_position,
+            old_end_position: advance(start_position, deleted_text),
+            new_end_position: point(buf, (pos, start_position.row), pos + inserted),
+        });
+        if deleted > 0 {
+            self.sbuf.remove(pos as i32, (pos + deleted) as i32);
+        }
+        if inserted > 0 {
+            self.sbuf
+                .insert(pos as i32, &translate_style(0).to_string().repeat(inserted));
+        }
+        let tree = match self
+            .parser
+            .parse_with(&mut |byte, _| chunk(buf, byte), Some(&self.tree))
+        {
+            Some(tree) => tree,
+            None => return,
+        };
+        let len = buf.length() as usize;
+        let mut range = pos..pos + inserted;
+        for changed in self.tree.changed_ranges(&tree) {
+            range.start = range.start.min(changed.start_byte);
+            range.end = range.end.max(changed.end_byte.min(len));
+        }
+        self.tree = tree;
+        let range = line_range(buf, range);
+        let s = buf
+            .text_range(range.start as i32, range.end as i32)
+            .unwrap_or_default();
+        let styles = self.styles(&s, range.clone());
+        self.sbuf
+            .replace(range.start as i32, range.end as i32, &styles);
     }
 }
 
+// Can be called again for the same editor, e.g. after Save as changed the extension.
+// ed is the tab's editor, see share for its panes.
 pub fn highlight(p: &Path, ed: &mut TextEditor, buf: &mut TextBuffer) {
-    if let Some(HighlightData {
-        styles,
-        config,
-        exeption_fn,
-    }) = get_highlight(p)
-    {
-        let mut highlighter = Highlighter::new();
-        let mut sbuf = TextBuffer::default();
-        ed.set_highlight_data(sbuf.clone(), styles);
-        apply(
-            &mut highlighter,
-            &config,
-            &buf.text(),
-            &mut sbuf,
-            &exeption_fn,
-        );
-        buf.add_modify_callback({
-            let buf = buf.clone();
-            move |_, _, _, _, _| {
-                apply(
-                    &mut highlighter,
-                    &config,
-                    &buf.text(),
-                    &mut sbuf,
-                    &exeption_fn,
-                );
+    let id = ed.as_widget_ptr() as usize;
+    let highlighter = get_highlight(p, buf).and_then(|data| {
+        let sbuf = TextBuffer::default();
+        ed.set_highlight_data_ext(sbuf.clone(), gui::style_table(&data.styles));
+        Highlighter::new(data, buf, sbuf)
+    });
+    match highlighter {
+        Some(highlighter) => {
+            HIGHLIGHTERS.with(|h| h.borrow_mut().insert(id, highlighter));
+            hooks::set(id, "highlight", {
+                let buf = buf.clone();
+                move |pos, inserted, deleted, _, deleted_text| {
+                    if inserted > 0 || deleted > 0 {
+                        HIGHLIGHTERS.with(|h| {
+                            if let Some(highlighter) = h.borrow_mut().get_mut(&id) {
+                                highlighter.edit(
+                                    &buf,
+                                    pos as usize,
+                                    inserted as usize,
+                                    deleted as usize,
+                                    deleted_text,
+                                );
+                            }
+                        });
+                    }
+                }
+            });
+        }
+        None => {
+            remove(id);
+            if ed.style_buffer().is_some() {
+                ed.set_highlight_data_ext(None::<TextBuffer>, Vec::new());
             }
-        });
+        }
     }
 }
 
-fn apply(
-    highlighter: &mut Highlighter,
-    config: &HighlightConfiguration,
+// Shows buffer id's highlighting in a split pane, false without a grammar
+pub fn share(id: usize, pane: &mut TextEditor) -> bool {
+    HIGHLIGHTERS.with(|h| match h.borrow().get(&id) {
+        Some(highlighter) => {
+            pane.set_highlight_data_ext(
+                highlighter.sbuf.clone(),
+                gui::style_table(&highlighter.data.styles),
+            );
+            true
+        }
+        None => false,
+    })
+}
+
+// Applies the current theme to an editor showing buffer id, returns false
+// without a grammar. The tab's editor comes before its panes and restyles the
+// shared style buffer.
+pub fn restyle(ed: &mut TextEditor, id: usize, buf: &TextBuffer) -> bool {
+    HIGHLIGHTERS.with(|h| match h.borrow_mut().get_mut(&id) {
+        Some(highlighter) => {
+            if ed.as_widget_ptr() as usize == id {
+                match HighlightData::new(highlighter.data.lang, &theme::current()) {
+                    Some(data) => highlighter.data = data,
+                    None => return false,
+                }
+                let styles = highlighter.styles(&buf.text(), 0..buf.length() as usize);
+                highlighter.sbuf.set_text(&styles);
+            }
+            ed.set_highlight_data_ext(
+                highlighter.sbuf.clone(),
+                gui::style_table(&highlighter.data.styles),
+            );
+            true
+        }
+        None => false,
+    })
+}
+
+// The indentation level of a new line at pos going by the syntax tree: how many
+// lines open a node from indent_kinds that pos is still inside of. None when the
+// buffer has no tree or pos sits in a syntax error, callers then go by the text.
+pub fn indent_level(id: usize, s: &str, pos: usize) -> Option<usize> {
+    HIGHLIGHTERS.with(|h| {
+        let h = h.borrow();
+        let highlighter = h.get(&id)?;
+        let kinds = indent_kinds(highlighter.data.lang);
+        if kinds.is_empty() {
+            return None;
+        }
+        let mut node = highlighter
+            .tree
+            .root_node()
+            .descendant_for_byte_range(pos, pos)?;
+        let mut rows = vec![];
+        loop {
+            if node.is_error() || node.is_missing() {
+                return None;
+            }
+            // a python block ends with its last statement, a bracketed one after the bracket
+            let closed = matches!(
+                s.as_bytes().get(node.end_byte().wrapping_sub(1)),
+                Some(b')' | b']' | b'}')
+            );
+            let inside = node.start_byte() < pos
+                && (pos < node.end_byte() || (pos == node.end_byte() && !closed));
+            let row = node.start_position().row;
+            if inside && kinds.contains(&node.kind()) && !rows.contains(&row) {
+                rows.push(row);
+            }
+            node = match node.parent() {
+                Some(parent) => parent,
+                None => break,
+            };
+        }
+        Some(rows.len())
+    })
+}
+
+fn symbol_name(node: Node, s: &str) -> Option<String> {
+    let text = |n: Node| s.get(n.byte_range()).unwrap_or_default();
+    let name = match node.kind() {
+        "impl_item" => {
+            let ty = text(node.child_by_field_name("type")?);
+            match node.child_by_field_name("trait") {
+                Some(t) => format!("{} for {}", text(t), ty),
+                None => ty.to_string(),
+            }
+        }
+        // "## Title", "[package]"
+        "atx_heading" | "table" | "table_array_element" => text(node).lines().next()?.to_string(),
+        "setext_heading" => format!("# {}", text(node).lines().next()?),
+        _ => {
+            let mut n = node
+                .child_by_field_name("name")
+                .or_else(|| node.child_by_field_name("declarator"))?;
+            // C declarators nest, the name is the innermost one
+            while let Some(d) = n.child_by_field_name("declarator") {
+                n = d;
+            }
+            text(n).to_string()
+        }
+    };
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!name.is_empty()).then_some(name)
+}
+
+fn collect_symbols(
+    node: Node,
     s: &str,
-    sbuf: &mut TextBuffer,
-    exeption_fn: &Option<fn(usize, &str) -> char>,
+    kinds: &[(&str, &'static str)],
+    depth: usize,
+    out: &mut Vec<Symbol>,
 ) {
-    let highlights = highlighter
-        .highlight(config, s.as_bytes(), None, |_| None)
-        .unwrap();
-
-    let mut local_buf = "A".repeat(s.len());
-    let mut curr = 0;
-    for event in highlights {
-        match event.unwrap() {
-            HighlightEvent::HighlightStart(s) => {
-                curr = s.0;
-            }
-            HighlightEvent::Source { start, end } => {
-                let c = if let Some(f) = exeption_fn {
-                    f(curr, &s[start..end])
-                } else {
-                    translate_style(curr)
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        let kind = kinds.iter().find(|(k, _)| *k == child.kind());
+        // C struct and enum specifiers without a body are just uses
+        let body =
+            !child.kind().ends_with("_specifier") || child.child_by_field_name("body").is_some();
+        match (kind, body.then(|| symbol_name(child, s)).flatten()) {
+            (Some((_, keyword)), Some(name)) => {
+                let depth = match child.kind() {
+                    "atx_heading" => name
+                        .chars()
+                        .take_while(|c| *c == '#')
+                        .count()
+                        .saturating_sub(1),
+                    _ => depth,
                 };
-                local_buf.replace_range(start..end, &c.to_string().repeat(end - start));
+                out.push(Symbol {
+                    kind: *keyword,
+                    name,
+                    line: child.start_position().row,
+                    depth,
+                });
+                collect_symbols(child, s, kinds, depth + 1, out);
+            }
+            _ => collect_symbols(child, s, kinds, depth, out),
+        }
+    }
+}
+
+// Definitions and headings in buffer id, in the order they appear
+pub fn symbols(id: usize, s: &str) -> Vec<Symbol> {
+    HIGHLIGHTERS.with(|h| {
+        let h = h.borrow();
+        let highlighter = match h.get(&id) {
+            Some(highlighter) => highlighter,
+            None => return vec![],
+        };
+        let mut out = vec![];
+        let kinds = symbol_kinds(highlighter.data.lang);
+        if !kinds.is_empty() {
+            collect_symbols(highlighter.tree.root_node(), s, kinds, 0, &mut out);
+        }
+        out
+    })
+}
+
+pub fn remove(id: usize) {
+    HIGHLIGHTERS.with(|h| h.borrow_mut().remove(&id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // Ten lines of Rust per block
+    fn source(blocks: usize) -> String {
+        (0..blocks)
+            .map(|i| {
+                format!(
+                    "/// Doc {i}\npub fn f{i}(x: u32) -> u32 {{\n    let s = \"text {i}\";\n    \
+                     if x > {i} {{\n        x * 2\n    }} else {{\n        s.len() as u32\n    \
+                     }}\n}}\n\n"
+                )
+            })
+            .collect()
+    }
+
+    fn highlighter(buf: &TextBuffer) -> Highlighter {
+        let data = HighlightData::new("rust", &theme::current()).unwrap();
+        Highlighter::new(data, buf, TextBuffer::default()).unwrap()
+    }
+
+    // What the modify callback does, for a buffer without one
+    fn insert(h: &mut Highlighter, buf: &mut TextBuffer, pos: usize, text: &str) {
+        buf.insert(pos as i32, text);
+        h.edit(buf, pos, text.len(), 0, "");
+    }
+
+    fn remove(h: &mut Highlighter, buf: &mut TextBuffer, pos: usize, len: usize) {
+        let deleted = buf.text_range(pos as i32, (pos + len) as i32).unwrap();
+        buf.remove(pos as i32, (pos + len) as i32);
+        h.edit(buf, pos, 0, len, &deleted);
+    }
+
+    #[test]
+    fn indent_levels() {
+        let s = "fn f(a: u32) {\n    let v = vec![\n        1,\n    ];\n    \
+                 match a {\n        _ => {}\n    }\n}\n";
+        let mut buf = TextBuffer::default();
+        buf.set_text(s);
+        HIGHLIGHTERS.with(|h| h.borrow_mut().insert(1, highlighter(&buf)));
+        // the level of a new line after each line
+        let levels: Vec<Option<usize>> = s
+            .match_indices('\n')
+            .map(|(pos, _)| indent_level(1, s, pos))
+            .collect();
+        assert_eq!(levels, [1, 2, 2, 1, 2, 2, 1, 0].map(Some));
+        HIGHLIGHTERS.with(|h| h.borrow_mut().remove(&1));
+        // no tree for the buffer
+        assert_eq!(indent_level(1, s, 0), None);
+    }
+
+    fn outline(lang: &str, s: &str) -> Vec<(&'static str, String, usize, usize)> {
+        let mut parser = Parser::new();
+        parser.set_language(grammar(lang).unwrap().0).unwrap();
+        let tree = parser.parse(s, None).unwrap();
+        let mut out = vec![];
+        collect_symbols(tree.root_node(), s, symbol_kinds(lang), 0, &mut out);
+        out.into_iter()
+            .map(|sym| (sym.kind, sym.name, sym.line, sym.depth))
+            .collect()
+    }
+
+    #[test]
+    fn outlines() {
+        let s = "struct Point {\n    x: i32,\n}\n\nimpl Display for Point {\n    fn fmt(&self) {\n        \
+                 fn helper() {}\n    }\n}\n\nmod inner {\n    enum E { A }\n}\n";
+        let expected = [
+            ("struct", "Point", 0, 0),
+            ("impl", "Display for Point", 4, 0),
+            ("fn", "fmt", 5, 1),
+            ("fn", "helper", 6, 2),
+            ("mod", "inner", 10, 0),
+            ("enum", "E", 11, 1),
+        ];
+        assert_eq!(
+            outline("rust", s),
+            expected.map(|(k, n, l, d)| (k, n.to_string(), l, d))
+        );
+
+        let s =
+            "# Title\n\ntext\n\n## Usage\n\n### Flags\n\n```\n# not a heading\n```\n\n## License\n";
+        let expected = [
+            ("# Title", 0, 0),
+            ("## Usage", 4, 1),
+            ("### Flags", 6, 2),
+            ("## License", 12, 1),
+        ];
+        assert_eq!(
+            outline("markdown", s),
+            expected.map(|(n, l, d)| ("", n.to_string(), l, d))
+        );
+        assert!(symbol_kinds("json").is_empty());
+    }
+
+    #[test]
+    fn incremental_matches_full() {
+        let mut buf = TextBuffer::default();
+        buf.set_text(&source(20));
+        let mut h = highlighter(&buf);
+        // into the first function's body, then comment out its doc line
+        insert(&mut h, &mut buf, 37, "    let é = \"\n\";\n");
+        insert(&mut h, &mut buf, 0, "/* ");
+        insert(&mut h, &mut buf, 13, " */");
+        let full = highlighter(&buf);
+        assert_eq!(h.sbuf.text(), full.sbuf.text());
+        remove(&mut h, &mut buf, 13, 3);
+        remove(&mut h, &mut buf, 0, 3);
+        let full = highlighter(&buf);
+        assert_eq!(
+            h.tree.root_node().to_sexp(),
+            full.tree.root_node().to_sexp()
+        );
+        assert_eq!(h.sbuf.text(), full.sbuf.text());
+    }
+
+    // cargo test --release -- --ignored --nocapture keystrokes
+    #[test]
+    #[ignore]
+    fn keystrokes() {
+        let mut buf = TextBuffer::default();
+        buf.set_text(&source(2000));
+        assert_eq!(buf.count_lines(0, buf.length(), false), 20000);
+        let mut h = highlighter(&buf);
+        for (name, start) in [("start", 30), ("middle", buf.length() as usize / 2)] {
+            let pos = buf.line_end(start as i32) as usize;
+            let now = Instant::now();
+            for (i, c) in "let y = x + 1;".chars().cycle().take(500).enumerate() {
+                insert(&mut h, &mut buf, pos + i, &c.to_string());
+            }
+            for i in (0..500).rev() {
+                remove(&mut h, &mut buf, pos + i, 1);
             }
-            HighlightEvent::HighlightEnd => curr = 0,
+            println!("{name}: {:?} per keystroke", now.elapsed() / 1000);
         }
+        assert_eq!(h.sbuf.text(), highlighter(&buf).sbuf.text());
     }
-    sbuf.set_text(&local_buf);
 }
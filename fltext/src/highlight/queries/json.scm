@@ -0,0 +1,23 @@
+(pair
+  key: (string) @property)
+
+(string) @string
+
+(escape_sequence) @escape
+
+(number) @number
+
+[
+  (true)
+  (false)
+  (null)
+] @constant.builtin
+
+[
+  "{"
+  "}"
+  "["
+  "]"
+  ","
+  ":"
+] @punctuation
@@ -0,0 +1,43 @@
+(block_mapping_pair
+  key: (flow_node) @property)
+
+(flow_pair
+  key: (flow_node) @property)
+
+(comment) @comment
+
+[
+  (string_scalar)
+  (double_quote_scalar)
+  (single_quote_scalar)
+  (block_scalar)
+] @string
+
+(escape_sequence) @escape
+
+[
+  (integer_scalar)
+  (float_scalar)
+] @number
+
+[
+  (boolean_scalar)
+  (null_scalar)
+] @constant.builtin
+
+[
+  (anchor_name)
+  (alias_name)
+] @label
+
+(tag) @type
+
+[
+  "-"
+  ":"
+  ","
+  "["
+  "]"
+  "{"
+  "}"
+] @punctuation
@@ -0,0 +1,75 @@
+use fltk::{app, text::TextBuffer};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+
+// fltk can't take a modify callback back, so each buffer gets a single one when its tab
+// is created and the features hook into that by name. Keyed by buffer id like State::map,
+// hooking in again under the same name replaces the old hook.
+type Hook = Rc<RefCell<dyn FnMut(i32, i32, i32, i32, &str)>>;
+
+thread_local! {
+    static HOOKS: RefCell<HashMap<usize, Vec<(&'static str, Hook)>>> =
+        RefCell::new(HashMap::new());
+}
+
+pub fn install(id: usize, buf: &mut TextBuffer) {
+    buf.add_modify_callback(move |pos, inserted, deleted, restyled, deleted_text| {
+        // cloned out so hooks can hook in or out themselves
+        let hooks: Vec<Hook> = HOOKS.with(|h| {
+            h.borrow()
+                .get(&id)
+                .map_or_else(Vec::new, |v| v.iter().map(|(_, f)| f.clone()).collect())
+        });
+        for hook in hooks {
+            if let Ok(mut f) = hook.try_borrow_mut() {
+                f(pos, inserted, deleted, restyled, deleted_text);
+            }
+        }
+    });
+}
+
+pub fn set(id: usize, name: &'static str, f: impl FnMut(i32, i32, i32, i32, &str) + 'static) {
+    let f: Hook = Rc::new(RefCell::new(f));
+    HOOKS.with(|h| {
+        let mut h = h.borrow_mut();
+        let hooks = h.entry(id).or_default();
+        match hooks.iter_mut().find(|(n, _)| *n == name) {
+            Some(hook) => hook.1 = f,
+            None => hooks.push((name, f)),
+        }
+    });
+}
+
+pub fn unset(id: usize, name: &'static str) {
+    HOOKS.with(|h| {
+        if let Some(hooks) = h.borrow_mut().get_mut(&id) {
+            hooks.retain(|(n, _)| *n != name);
+        }
+    });
+}
+
+// Runs f once edits to buffer id pause for delay seconds. Hooked in under name like set,
+// a wait still running when the hook is replaced or the tab closes doesn't run f.
+pub fn debounce(id: usize, name: &'static str, delay: f64, f: impl Fn() + 'static) {
+    let f = Rc::new(f);
+    let gen = Rc::new(Cell::new(0u32));
+    set(id, name, move |_, inserted, deleted, _, _| {
+        if inserted > 0 || deleted > 0 {
+            gen.set(gen.get() + 1);
+            let (f, current, weak) = (f.clone(), gen.get(), Rc::downgrade(&gen));
+            app::add_timeout3(delay, move |_| {
+                if weak.upgrade().map_or(false, |gen| gen.get() == current) {
+                    f();
+                }
+            });
+        }
+    });
+}
+
+// The tab closed, a new editor can get the same id
+pub fn remove(id: usize) {
+    HOOKS.with(|h| h.borrow_mut().remove(&id));
+}
@@ -0,0 +1,196 @@
+use crate::utils;
+use fltk::utils::oncelock::Lazy;
+use std::{fs, path::Path};
+
+pub struct Lang {
+    pub name: &'static str,
+    pub extensions: Vec<String>,
+    pub filenames: Vec<String>,
+    // interpreter names, version suffixes are ignored so python covers python3.11
+    pub shebangs: Vec<String>,
+    // used by Toggle Comment, the block comment only when there's no line comment
+    pub line_comment: Option<String>,
+    pub block_comment: Option<(String, String)>,
+}
+
+fn lang(name: &'static str, extensions: &[&str], filenames: &[&str], shebangs: &[&str]) -> Lang {
+    let owned = |v: &[&str]| v.iter().map(|s| s.to_string()).collect();
+    Lang {
+        name,
+        extensions: owned(extensions),
+        filenames: owned(filenames),
+        shebangs: owned(shebangs),
+        line_comment: None,
+        block_comment: None,
+    }
+}
+
+impl Lang {
+    fn line_comment(mut self, token: &str) -> Self {
+        self.line_comment = Some(token.to_string());
+        self
+    }
+    fn block_comment(mut self, open: &str, close: &str) -> Self {
+        self.block_comment = Some((open.to_string(), close.to_string()));
+        self
+    }
+}
+
+fn defaults() -> Vec<Lang> {
+    vec![
+        lang("rust", &["rs"], &[], &[])
+            .line_comment("//")
+            .block_comment("/*", "*/"),
+        lang("toml", &["toml"], &["Cargo.lock", "Pipfile"], &[]).line_comment("#"),
+        lang("markdown", &["md", "markdown"], &[], &[]).block_comment("<!--", "-->"),
+        lang(
+            "json",
+            &["json", "jsonc"],
+            &[".prettierrc", ".babelrc"],
+            &[],
+        )
+        .line_comment("//"),
+        lang(
+            "python",
+            &["py", "pyw", "pyi"],
+            &["SConstruct", "SConscript"],
+            &["python"],
+        )
+        .line_comment("#"),
+        lang("c", &["c", "h"], &[], &[])
+            .line_comment("//")
+            .block_comment("/*", "*/"),
+        lang(
+            "shell",
+            &["sh", "bash", "zsh"],
+            &[".bashrc", ".bash_profile", ".profile", ".zshrc", "PKGBUILD"],
+            &["sh", "bash", "zsh", "dash", "ksh"],
+        )
+        .line_comment("#"),
+        lang("yaml", &["yaml", "yml"], &[".clang-format"], &[]).line_comment("#"),
+    ]
+}
+
+// Extra associations for the built-in languages can be added in
+// ~/.config/fltext/languages.toml:
+//
+// [python]
+// extensions = ["bzl"]
+// filenames = ["BUILD"]
+// shebangs = ["pypy"]
+//
+// [c]
+// line_comment = "//"
+// block_comment = ["/*", "*/"]
+fn load() -> Vec<Lang> {
+    let mut langs = defaults();
+    let user = utils::config_dir()
+        .and_then(|dir| fs::read_to_string(dir.join("languages.toml")).ok())
+        .and_then(|s| s.parse::<toml::Table>().ok());
+    if let Some(user) = user {
+        for l in &mut langs {
+            if let Some(t) = user.get(l.name).and_then(|v| v.as_table()) {
+                for (key, list) in [
+                    ("extensions", &mut l.extensions),
+                    ("filenames", &mut l.filenames),
+                    ("shebangs", &mut l.shebangs),
+                ] {
+                    if let Some(v) = t.get(key).and_then(|v| v.as_array()) {
+                        list.extend(v.iter().filter_map(|s| s.as_str()).map(String::from));
+                    }
+                }
+                if let Some(token) = t.get("line_comment").and_then(|v| v.as_str()) {
+                    l.line_comment = Some(token.to_string()).filter(|s| !s.is_empty());
+                }
+                let block = t.get("block_comment").and_then(|v| v.as_array());
+                if let Some([open, close]) = block.map(|v| v.as_slice()) {
+                    if let (Some(open), Some(close)) = (open.as_str(), close.as_str()) {
+                        l.block_comment = Some((open.to_string(), close.to_string()));
+                    }
+                }
+            }
+        }
+    }
+    langs
+}
+
+static LANGS: Lazy<Vec<Lang>> = Lazy::new(load);
+
+// "#!/usr/bin/env -S python3 -u" -> "python"
+fn interpreter(first_line: &str) -> Option<&str> {
+    let mut words = first_line.strip_prefix("#!")?.split_whitespace();
+    let mut prog = words.next()?.rsplit('/').next()?;
+    if prog == "env" {
+        prog = words.find(|w| !w.starts_with('-') && !w.contains('='))?;
+    }
+    Some(prog.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.'))
+}
+
+// File name first, then extension, then the shebang of extensionless scripts
+fn find<'a>(langs: &'a [Lang], p: &Path, first_line: &str) -> Option<&'a Lang> {
+    let name = p.file_name()?.to_str()?;
+    if let Some(l) = langs.iter().find(|l| l.filenames.iter().any(|f| f == name)) {
+        return Some(l);
+    }
+    if let Some(ext) = p.extension().and_then(|e| e.to_str()) {
+        if let Some(l) = langs.iter().find(|l| l.extensions.iter().any(|e| e == ext)) {
+            return Some(l);
+        }
+    }
+    let prog = interpreter(first_line)?;
+    langs.iter().find(|l| l.shebangs.iter().any(|s| s == prog))
+}
+
+pub fn detect(p: &Path, first_line: &str) -> Option<&'static Lang> {
+    find(&LANGS, p, first_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpreters() {
+        for (line, prog) in [
+            ("#!/bin/sh", Some("sh")),
+            ("#!/usr/bin/env python3", Some("python")),
+            ("#!/usr/bin/env -S python3.11 -u", Some("python")),
+            ("#!/usr/bin/env FOO=1 bash -e", Some("bash")),
+            ("#! /usr/local/bin/zsh", Some("zsh")),
+            ("#!", None),
+            ("# comment", None),
+            ("", None),
+        ] {
+            assert_eq!(interpreter(line), prog, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn detection() {
+        let langs = defaults();
+        for (path, first_line, name) in [
+            ("src/main.rs", "", Some("rust")),
+            ("Cargo.toml", "", Some("toml")),
+            ("Cargo.lock", "", Some("toml")),
+            ("README.md", "", Some("markdown")),
+            ("a.jsonc", "", Some("json")),
+            (".babelrc", "", Some("json")),
+            ("setup.py", "", Some("python")),
+            ("SConstruct", "", Some("python")),
+            ("x.h", "", Some("c")),
+            (".bashrc", "", Some("shell")),
+            ("ci.yml", "", Some("yaml")),
+            (".clang-format", "", Some("yaml")),
+            ("build", "#!/usr/bin/env python3", Some("python")),
+            ("run", "#!/bin/bash", Some("shell")),
+            // the extension wins over the shebang
+            ("x.rs", "#!/bin/sh", Some("rust")),
+            ("notes.txt", "", None),
+            ("Makefile", "", None),
+            ("run", "#!/usr/bin/perl", None),
+        ] {
+            let found = find(&langs, Path::new(path), first_line).map(|l| l.name);
+            assert_eq!(found, name, "{}", path);
+        }
+    }
+}
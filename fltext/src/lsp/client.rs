@@ -0,0 +1,139 @@
+use super::rpc;
+use fltk::text::TextEditor;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::{self, BufReader},
+    path::Path,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc,
+    thread,
+};
+
+pub enum Pending {
+    Initialize,
+    Hover(TextEditor, i32),
+    Definition,
+}
+
+pub struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    rx: mpsc::Receiver<Value>,
+    next_id: i64,
+    initialized: bool,
+    queue: Vec<Value>,
+    pub pending: HashMap<i64, Pending>,
+}
+
+impl Client {
+    pub fn spawn(cmd: &str, root: &Path) -> io::Result<Self> {
+        let mut words = cmd.split_whitespace();
+        let prog = words
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty server command"))?;
+        let mut child = Command::new(prog)
+            .args(words)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            while let Ok(Some(msg)) = rpc::read_message(&mut reader) {
+                if tx.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut client = Self {
+            child,
+            stdin,
+            rx,
+            next_id: 0,
+            initialized: false,
+            queue: vec![],
+            pending: HashMap::default(),
+        };
+        let root = super::path_to_uri(root);
+        let id = client.next_id();
+        client.pending.insert(id, Pending::Initialize);
+        client.write(&json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "initialize",
+            "params": {
+                "processId": std::process::id(),
+                "rootUri": root,
+                "capabilities": {
+                    "textDocument": {
+                        "hover": { "contentFormat": ["plaintext", "markdown"] },
+                        "definition": { "linkSupport": true },
+                        "publishDiagnostics": {},
+                        "synchronization": { "didSave": false },
+                    }
+                },
+            }
+        }))?;
+        Ok(client)
+    }
+
+    fn next_id(&mut self) -> i64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn write(&mut self, msg: &Value) -> io::Result<()> {
+        rpc::write_message(&mut self.stdin, msg)
+    }
+
+    fn send(&mut self, msg: Value) {
+        if self.initialized {
+            self.write(&msg).ok();
+        } else {
+            self.queue.push(msg);
+        }
+    }
+
+    pub fn on_initialized(&mut self) {
+        self.initialized = true;
+        self.write(&json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }))
+            .ok();
+        for msg in std::mem::take(&mut self.queue) {
+            self.write(&msg).ok();
+        }
+    }
+
+    pub fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    pub fn request(&mut self, method: &str, params: Value, pending: Pending) {
+        let id = self.next_id();
+        self.pending.insert(id, pending);
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+    }
+
+    pub fn respond(&mut self, id: Value, result: Value) {
+        self.write(&json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+            .ok();
+    }
+
+    pub fn try_recv(&self) -> Option<Value> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.write(&json!({ "jsonrpc": "2.0", "id": -1, "method": "shutdown" }))
+            .ok();
+        self.write(&json!({ "jsonrpc": "2.0", "method": "exit" }))
+            .ok();
+        self.child.kill().ok();
+    }
+}
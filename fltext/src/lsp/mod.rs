@@ -0,0 +1,639 @@
+use crate::{gui, hooks, state::STATE};
+use fltk::{
+    app,
+    enums::Event,
+    misc::Tooltip,
+    prelude::*,
+    text::{PositionType, TextBuffer, TextEditor},
+    utils::oncelock::Lazy,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+mod client;
+mod rpc;
+
+use client::{Client, Pending};
+
+// (extension, languageId, default server command)
+// The command can be overridden with e.g. FLTEXT_LSP_RS="/path/to/server --stdio"
+const SERVERS: &[(&str, &str, &str)] = &[
+    ("rs", "rust", "rust-analyzer"),
+    ("c", "c", "clangd"),
+    ("h", "c", "clangd"),
+    ("cpp", "cpp", "clangd"),
+    ("py", "python", "pylsp"),
+];
+
+const POLL_INTERVAL: f64 = 0.05;
+const CHANGE_DELAY: Duration = Duration::from_millis(300);
+const HOVER_DELAY: f64 = 0.6;
+
+static LSP: Lazy<Mutex<Lsp>> = Lazy::new(|| Mutex::new(Lsp::default()));
+static HOVER_GEN: AtomicU32 = AtomicU32::new(0);
+
+struct Diagnostic {
+    start: usize,
+    end: usize,
+    message: String,
+}
+
+struct Doc {
+    server: String,
+    ed: TextEditor,
+    version: i32,
+    edited: Option<Instant>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Default)]
+struct Lsp {
+    clients: HashMap<String, Client>,
+    failed: HashSet<String>,
+    docs: HashMap<PathBuf, Doc>,
+    polling: bool,
+}
+
+impl Lsp {
+    fn client(&mut self, cmd: &str) -> Option<&mut Client> {
+        if !self.clients.contains_key(cmd) && !self.failed.contains(cmd) {
+            match Client::spawn(cmd, &env::current_dir().unwrap()) {
+                Ok(client) => {
+                    self.clients.insert(cmd.to_string(), client);
+                }
+                // reported once, files opened later go without a server
+                Err(e) => {
+                    if self.failed.insert(cmd.to_string()) {
+                        eprintln!("Couldn't start language server {}: {}", cmd, e);
+                    }
+                }
+            }
+        }
+        self.clients.get_mut(cmd)
+    }
+    fn doc_by_editor(&mut self, ed: &TextEditor) -> Option<(&PathBuf, &mut Doc)> {
+        self.docs
+            .iter_mut()
+            .find(|(_, d)| d.ed.as_widget_ptr() == ed.as_widget_ptr())
+    }
+}
+
+fn with<R>(f: impl FnOnce(&mut Lsp) -> R) -> R {
+    f(&mut LSP.lock().unwrap())
+}
+
+fn server_for(p: &Path) -> Option<(&'static str, String)> {
+    let ext = p.extension()?.to_str()?;
+    let (_, lang, cmd) = SERVERS.iter().find(|(e, _, _)| *e == ext)?;
+    let cmd =
+        env::var(format!("FLTEXT_LSP_{}", ext.to_uppercase())).unwrap_or_else(|_| cmd.to_string());
+    Some((lang, cmd))
+}
+
+pub fn path_to_uri(p: &Path) -> String {
+    let p = crate::utils::strip_unc_path(p).replace('\\', "/");
+    if p.starts_with('/') {
+        format!("file://{}", p)
+    } else {
+        format!("file:///{}", p)
+    }
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let p = uri.strip_prefix("file://")?;
+    let bytes = p.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(b) = u8::from_str_radix(&p[i + 1..i + 3], 16) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    let p = String::from_utf8(out).ok()?;
+    if cfg!(target_os = "windows") {
+        Some(PathBuf::from(p.trim_start_matches('/')))
+    } else {
+        Some(PathBuf::from(p))
+    }
+}
+
+// LSP positions count UTF-16 code units, fltk positions are byte offsets
+fn offset_to_position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character = before[line_start..].encode_utf16().count();
+    json!({ "line": line, "character": character })
+}
+
+fn position_to_offset(text: &str, pos: &Value) -> usize {
+    let line = pos["line"].as_u64().unwrap_or(0);
+    let character = pos["character"].as_u64().unwrap_or(0);
+    let mut start = 0;
+    for _ in 0..line {
+        match text[start..].find('\n') {
+            Some(i) => start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let mut units = 0;
+    for (i, c) in text[start..].char_indices() {
+        if units >= character || c == '\n' {
+            return start + i;
+        }
+        units += c.len_utf16() as u64;
+    }
+    text.len()
+}
+
+pub fn attach(p: &Path, ed: &mut TextEditor, buf: &mut TextBuffer) {
+    if let Some((lang, cmd)) = server_for(p) {
+        let path = match p.canonicalize() {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        let opened = with(|lsp| {
+            if let Some(client) = lsp.client(&cmd) {
+                client.notify(
+                    "textDocument/didOpen",
+                    json!({
+                        "textDocument": {
+                            "uri": path_to_uri(&path),
+                            "languageId": lang,
+                            "version": 0,
+                            "text": buf.text(),
+                        }
+                    }),
+                );
+                lsp.docs.insert(
+                    path.clone(),
+                    Doc {
+                        server: cmd.clone(),
+                        ed: ed.clone(),
+                        version: 0,
+                        edited: None,
+                        diagnostics: vec![],
+                    },
+                );
+                let start_polling = !lsp.polling;
+                lsp.polling = true;
+                Some(start_polling)
+            } else {
+                None
+            }
+        });
+        if let Some(start_polling) = opened {
+            gui::ensure_style_buffer(ed.as_widget_ptr() as usize, ed, buf);
+            // set_current_file attaches again, the hook follows the editor, not the path
+            let editor = ed.clone();
+            hooks::set(
+                ed.as_widget_ptr() as usize,
+                "lsp",
+                move |_, ins, del, _, _| {
+                    if ins > 0 || del > 0 {
+                        with(|lsp| {
+                            if let Some((_, doc)) = lsp.doc_by_editor(&editor) {
+                                doc.edited = Some(Instant::now());
+                            }
+                        });
+                    }
+                },
+            );
+            if start_polling {
+                app::add_timeout3(POLL_INTERVAL, poll);
+            }
+        }
+    }
+}
+
+pub fn detach(p: &Path) {
+    with(|lsp| {
+        if let Some(doc) = lsp.docs.remove(p) {
+            if let Some(client) = lsp.clients.get_mut(&doc.server) {
+                client.notify(
+                    "textDocument/didClose",
+                    json!({ "textDocument": { "uri": path_to_uri(p) } }),
+                );
+            }
+        }
+    });
+}
+
+fn flush_changes(lsp: &mut Lsp) {
+    let Lsp { clients, docs, .. } = lsp;
+    for (path, doc) in docs.iter_mut() {
+        if let Some(edited) = doc.edited {
+            if edited.elapsed() < CHANGE_DELAY {
+                continue;
+            }
+            doc.edited = None;
+            doc.version += 1;
+            if let (Some(client), Some(buf)) = (clients.get_mut(&doc.server), doc.ed.buffer()) {
+                client.notify(
+                    "textDocument/didChange",
+                    json!({
+                        "textDocument": { "uri": path_to_uri(path), "version": doc.version },
+                        "contentChanges": [{ "text": buf.text() }],
+                    }),
+                );
+            }
+        }
+    }
+}
+
+enum Action {
+    Hover(TextEditor, i32, Value),
+    Definition(Value),
+    Diagnostics(Value),
+}
+
+fn poll(handle: app::TimeoutHandle) {
+    let actions = with(|lsp| {
+        flush_changes(lsp);
+        let mut actions = vec![];
+        for client in lsp.clients.values_mut() {
+            while let Some(msg) = client.try_recv() {
+                let id = msg["id"].clone();
+                match (msg["method"].as_str(), id.as_i64()) {
+                    (Some("textDocument/publishDiagnostics"), _) => {
+                        actions.push(Action::Diagnostics(msg["params"].clone()));
+                    }
+                    // Server to client requests, we don't support any of them
+                    (Some(_), _) if !id.is_null() => {
+                        client.respond(id, Value::Null);
+                    }
+                    (None, Some(id)) => match client.pending.remove(&id) {
+                        Some(Pending::Initialize) => client.on_initialized(),
+                        Some(Pending::Hover(ed, pos)) => {
+                            actions.push(Action::Hover(ed, pos, msg["result"].clone()))
+                        }
+                        Some(Pending::Definition) => {
+                            actions.push(Action::Definition(msg["result"].clone()))
+                        }
+                        None => (),
+                    },
+                    _ => (),
+                }
+            }
+        }
+        actions
+    });
+    for action in actions {
+        match action {
+            Action::Hover(ed, pos, result) => show_hover(ed, pos, &result),
+            Action::Definition(result) => goto_definition(&result),
+            Action::Diagnostics(params) => publish_diagnostics(&params),
+        }
+    }
+    app::repeat_timeout3(POLL_INTERVAL, handle);
+}
+
+fn publish_diagnostics(params: &Value) {
+    let path = match params["uri"].as_str().and_then(uri_to_path) {
+        Some(path) => path.canonicalize().unwrap_or(path),
+        None => return,
+    };
+    with(|lsp| {
+        if let Some(doc) = lsp.docs.get_mut(&path) {
+            let text = match doc.ed.buffer() {
+                Some(buf) => buf.text(),
+                None => return,
+            };
+            doc.diagnostics = params["diagnostics"]
+                .as_array()
+                .map(|v| {
+                    v.iter()
+                        .map(|d| Diagnostic {
+                            start: position_to_offset(&text, &d["range"]["start"]),
+                            end: position_to_offset(&text, &d["range"]["end"]),
+                            message: d["message"].as_str().unwrap_or_default().to_string(),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            underline(doc);
+        }
+    });
+}
+
+// The underlined variant of style 'A' + n is 'a' + n, see gui::style_table
+fn underline(doc: &Doc) {
+    if let Some(mut sbuf) = doc.ed.style_buffer() {
+        let mut styles = sbuf.text().into_bytes();
+        styles.make_ascii_uppercase();
+        for d in &doc.diagnostics {
+            let end = if d.end > d.start { d.end } else { d.start + 1 };
+            for c in styles.iter_mut().take(end).skip(d.start) {
+                c.make_ascii_lowercase();
+            }
+        }
+        sbuf.set_text(&String::from_utf8(styles).unwrap());
+        let mut ed = doc.ed.clone();
+        ed.redraw();
+    }
+}
+
+fn diagnostics_at(ed: &TextEditor, pos: i32) -> Vec<String> {
+    let pos = pos as usize;
+    with(|lsp| {
+        lsp.doc_by_editor(ed)
+            .map(|(_, doc)| {
+                doc.diagnostics
+                    .iter()
+                    .filter(|d| d.start <= pos && pos <= d.end)
+                    .map(|d| d.message.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+fn show_tooltip(ed: &mut TextEditor, tip: &str) {
+    if !tip.is_empty() {
+        ed.set_tooltip(tip);
+        Tooltip::enter(ed);
+    }
+}
+
+fn hover_text(contents: &Value) -> String {
+    match contents {
+        Value::String(s) => s.clone(),
+        Value::Array(v) => v.iter().map(hover_text).collect::<Vec<_>>().join("\n"),
+        Value::Object(o) => o
+            .get("value")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        _ => String::new(),
+    }
+}
+
+fn show_hover(mut ed: TextEditor, pos: i32, result: &Value) {
+    let mut tip = diagnostics_at(&ed, pos);
+    let hover = hover_text(&result["contents"]);
+    if !hover.trim().is_empty() {
+        tip.push(hover.trim().to_string());
+    }
+    show_tooltip(&mut ed, &tip.join("\n\n"));
+}
+
+fn request(ed: &TextEditor, pos: i32, method: &str, pending: Pending) {
+    with(|lsp| {
+        let (uri, server, position) = match lsp.doc_by_editor(ed) {
+            Some((path, doc)) => match doc.ed.buffer() {
+                Some(buf) => (
+                    path_to_uri(path),
+                    doc.server.clone(),
+                    offset_to_position(&buf.text(), pos as usize),
+                ),
+                None => return,
+            },
+            None => return,
+        };
+        flush_changes(lsp);
+        if let Some(client) = lsp.clients.get_mut(&server) {
+            client.request(
+                method,
+                json!({ "textDocument": { "uri": uri }, "position": position }),
+                pending,
+            );
+        }
+    });
+}
+
+fn hover(ed: &mut TextEditor, x: i32, y: i32) {
+    let pos = ed.xy_to_position(x, y, PositionType::Character);
+    show_tooltip(ed, &diagnostics_at(ed, pos).join("\n\n"));
+    request(
+        ed,
+        pos,
+        "textDocument/hover",
+        Pending::Hover(ed.clone(), pos),
+    );
+}
+
+fn goto_definition(result: &Value) {
+    let loc = match result {
+        Value::Array(v) => v.first(),
+        Value::Object(_) => Some(result),
+        _ => None,
+    };
+    if let Some(loc) = loc {
+        let uri = loc["uri"].as_str().or_else(|| loc["targetUri"].as_str());
+        let range = if loc["range"].is_object() {
+            &loc["range"]
+        } else {
+            &loc["targetSelectionRange"]
+        };
+        if let Some(path) = uri.and_then(uri_to_path) {
+            if !path.exists() {
+                return;
+            }
+            let start = range["start"].clone();
+            STATE.with(move |s| {
+                s.append(Some(path.clone()));
+                if let (Some(mut ed), Some(buf)) = (s.current_editor(), s.buf()) {
+                    let pos = position_to_offset(&buf.text(), &start) as i32;
+                    ed.set_insert_position(pos);
+                    ed.show_insert_position();
+                    let _ = ed.take_focus();
+                }
+            });
+        }
+    }
+}
+
+pub fn editor_handle(ed: &mut TextEditor, ev: Event) -> bool {
+    match ev {
+        Event::Push
+            if app::is_event_ctrl() && app::event_mouse_button() == app::MouseButton::Left =>
+        {
+            let pos = ed.xy_to_position(app::event_x(), app::event_y(), PositionType::Cursor);
+            request(ed, pos, "textDocument/definition", Pending::Definition);
+            true
+        }
+        Event::Move => {
+            ed.set_tooltip("");
+            let gen = HOVER_GEN.fetch_add(1, Ordering::Relaxed) + 1;
+            let (x, y) = app::event_coords();
+            app::add_timeout3(HOVER_DELAY, {
+                let mut ed = ed.clone();
+                move |_| {
+                    if HOVER_GEN.load(Ordering::Relaxed) == gen {
+                        hover(&mut ed, x, y);
+                    }
+                }
+            });
+            false
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, process, thread};
+
+    // Built next to the test binary, cargo test builds the examples too
+    fn stub() -> String {
+        let dir = env::current_exe().unwrap();
+        let stub = dir
+            .parent()
+            .and_then(Path::parent)
+            .unwrap()
+            .join("examples")
+            .join(format!("lsp-stub{}", env::consts::EXE_SUFFIX));
+        assert!(stub.exists(), "run cargo build --examples first");
+        stub.to_string_lossy().to_string()
+    }
+
+    // Waits for the first message f picks, answering the initialize response on the way
+    fn wait<R>(client: &mut Client, mut f: impl FnMut(&mut Client, &Value) -> Option<R>) -> R {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            match client.try_recv() {
+                Some(msg) => {
+                    if let Some(id) = msg["id"].as_i64() {
+                        if matches!(client.pending.get(&id), Some(Pending::Initialize)) {
+                            client.pending.remove(&id);
+                            client.on_initialized();
+                            continue;
+                        }
+                    }
+                    if let Some(r) = f(client, &msg) {
+                        return r;
+                    }
+                }
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        panic!("no answer from the stub server");
+    }
+
+    #[test]
+    fn stub_server() {
+        let root = env::temp_dir().join(format!("fltext-lsp-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("main.rs");
+        let text = "fn main() {\n    // FIXME\n}\n";
+        fs::write(&path, text).unwrap();
+        let uri = path_to_uri(&path);
+
+        let mut client = Client::spawn(&stub(), &root).unwrap();
+        // queued until the server answered initialize
+        client.notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": { "uri": uri, "languageId": "rust", "version": 0, "text": text }
+            }),
+        );
+        let diagnostics = wait(&mut client, |_, msg| {
+            (msg["method"] == "textDocument/publishDiagnostics").then(|| msg["params"].clone())
+        });
+        assert_eq!(diagnostics["uri"], uri.as_str());
+        let d = &diagnostics["diagnostics"][0];
+        assert_eq!(d["message"], "FIXME left in");
+        assert_eq!(position_to_offset(text, &d["range"]["start"]), 19);
+        assert_eq!(position_to_offset(text, &d["range"]["end"]), 24);
+
+        client.request(
+            "textDocument/definition",
+            json!({ "textDocument": { "uri": uri }, "position": offset_to_position(text, 3) }),
+            Pending::Definition,
+        );
+        let location = wait(&mut client, |client, msg| {
+            let id = msg["id"].as_i64()?;
+            match client.pending.remove(&id)? {
+                Pending::Definition => Some(msg["result"].clone()),
+                _ => None,
+            }
+        });
+        assert_eq!(location["uri"].as_str().and_then(uri_to_path), Some(path));
+        assert_eq!(
+            location["range"]["start"],
+            json!({ "line": 0, "character": 0 })
+        );
+        assert!(client.pending.is_empty());
+
+        drop(client);
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn missing_server() {
+        let mut lsp = Lsp::default();
+        let cmd = "fltext-no-such-server --stdio";
+        assert!(lsp.client(cmd).is_none());
+        assert!(lsp.failed.contains(cmd));
+        // not spawned again for the next file
+        assert!(lsp.client(cmd).is_none());
+        assert_eq!(lsp.failed.len(), 1);
+        assert!(lsp.clients.is_empty());
+        assert!(lsp.client("").is_none());
+        assert_eq!(lsp.failed.len(), 2);
+    }
+
+    #[test]
+    fn positions() {
+        // "é" is two bytes but one UTF-16 unit, "𝄞" four bytes and two units
+        let text = "ab\néx𝄞y\n";
+        assert_eq!(
+            offset_to_position(text, 0),
+            json!({ "line": 0, "character": 0 })
+        );
+        assert_eq!(
+            offset_to_position(text, 5),
+            json!({ "line": 1, "character": 1 })
+        );
+        assert_eq!(
+            offset_to_position(text, 10),
+            json!({ "line": 1, "character": 4 })
+        );
+        assert_eq!(
+            offset_to_position(text, 100),
+            json!({ "line": 2, "character": 0 })
+        );
+        for offset in [0, 2, 3, 5, 6, 10, 11, 12] {
+            assert_eq!(
+                position_to_offset(text, &offset_to_position(text, offset)),
+                offset
+            );
+        }
+        // past the end of a line stops at its newline
+        assert_eq!(
+            position_to_offset(text, &json!({ "line": 0, "character": 9 })),
+            2
+        );
+        assert_eq!(
+            position_to_offset(text, &json!({ "line": 7, "character": 0 })),
+            text.len()
+        );
+    }
+
+    #[test]
+    fn uris() {
+        let path = Path::new("/tmp/a dir/ü.rs");
+        assert_eq!(
+            uri_to_path("file:///tmp/a%20dir/%C3%BC.rs").as_deref(),
+            Some(path)
+        );
+        assert_eq!(uri_to_path(&path_to_uri(path)).as_deref(), Some(path));
+        assert_eq!(uri_to_path("https://example.com"), None);
+    }
+}
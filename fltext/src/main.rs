@@ -1,18 +1,39 @@
 use std::env;
 
 mod cbs;
+mod commands;
+mod cursors;
 mod dialogs;
+mod editing;
+mod encoding;
 mod fbr;
+mod git;
 mod gui;
+mod hooks;
+mod lang;
+mod outline;
+mod preview;
+mod script;
+mod search;
+mod session;
 mod state;
+mod swap;
+mod tasks;
+mod theme;
 mod utils;
+mod watch;
 
 #[cfg(feature = "highlight")]
 mod highlight;
 
+#[cfg(feature = "lsp")]
+mod lsp;
+
 fn main() {
-    let (current_file, current_path) = utils::init_args(env::args());
+    let (current_file, current_path, session) = utils::init_args(env::args());
     let a = gui::init_gui(&current_file, &current_path);
+    session::set_enabled(session);
     state::init_state(current_file, current_path);
+    swap::start();
     a.run().unwrap();
 }
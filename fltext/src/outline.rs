@@ -0,0 +1,203 @@
+use crate::{hooks, search, state::STATE};
+use fltk::{app, browser, enums::*, group, input, prelude::*, text::TextEditor};
+use std::cell::RefCell;
+
+// Typing pauses this long before the outline is rebuilt
+const DELAY: f64 = 0.5;
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    // "fn", "struct", ... empty for headings
+    pub kind: &'static str,
+    pub name: String,
+    // 0-based
+    pub line: usize,
+    pub depth: usize,
+}
+
+impl Symbol {
+    pub fn label(&self) -> String {
+        if self.kind.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} {}", self.kind, self.name)
+        }
+    }
+}
+
+#[derive(Default)]
+struct Outline {
+    // the buffer listed and its symbols
+    shown: Option<usize>,
+    symbols: Vec<Symbol>,
+    // browser line -> symbol
+    targets: Vec<usize>,
+}
+
+thread_local! {
+    static OUTLINE: RefCell<Outline> = RefCell::new(Outline::default());
+}
+
+fn outline_browser() -> browser::HoldBrowser {
+    app::widget_from_id("outline").unwrap()
+}
+
+fn visible() -> bool {
+    app::widget_from_id::<group::Group>("outline_group").map_or(false, |g| g.w() > 1)
+}
+
+// The symbols of the current editor, empty without a grammar
+pub fn current_symbols() -> Vec<Symbol> {
+    #[cfg(feature = "highlight")]
+    {
+        if let Some((id, buf)) = STATE.with(|s| Some((s.current_id()?, s.buf()?))) {
+            return crate::highlight::symbols(id, &buf.text());
+        }
+    }
+    vec![]
+}
+
+// Moves the current editor's cursor to the start of line (0-based)
+pub fn jump(line: usize) {
+    let current = STATE.with(|s| Some((s.current_editor()?, s.buf()?)));
+    if let Some((mut ed, buf)) = current {
+        let pos = buf.skip_lines(0, line as i32);
+        ed.set_insert_position(pos);
+        ed.show_insert_position();
+        ed.take_focus().ok();
+    }
+}
+
+fn fill(query: &str) {
+    let mut b = outline_browser();
+    b.clear();
+    OUTLINE.with(|o| {
+        let mut o = o.borrow_mut();
+        let mut targets = vec![];
+        for (idx, sym) in o.symbols.iter().enumerate() {
+            let label = sym.label();
+            if query.is_empty() || search::fuzzy_score(query, &label).is_some() {
+                b.add(&format!("@.{}{}", "  ".repeat(sym.depth), label));
+                targets.push(idx);
+            }
+        }
+        o.targets = targets;
+    });
+}
+
+// Lists the current buffer's symbols if the outline is open
+pub fn update() {
+    if !visible() {
+        return;
+    }
+    let shown = STATE.with(|s| s.buf().and(s.current_id()));
+    if let Some(id) = shown {
+        hooks::debounce(id, "outline", DELAY, move || {
+            if OUTLINE.with(|o| o.borrow().shown == Some(id)) {
+                update();
+            }
+        });
+    }
+    let symbols = current_symbols();
+    OUTLINE.with(|o| {
+        let mut o = o.borrow_mut();
+        o.shown = shown;
+        o.symbols = symbols;
+    });
+    let filter: input::Input = app::widget_from_id("outline_filter").unwrap();
+    fill(&filter.value());
+}
+
+// Follows tab switches, see gui::draw_editor
+pub fn sync(ed: &TextEditor) {
+    if !visible() {
+        return;
+    }
+    let id = ed.as_widget_ptr() as usize;
+    let current = STATE.with(|s| {
+        let focused = s.current_editor().map(|e| e.as_widget_ptr() as usize) == Some(id);
+        focused.then(|| s.current_id())
+    });
+    let switched = match current {
+        Some(current) => OUTLINE.with(|o| {
+            let mut o = o.borrow_mut();
+            let switched = o.shown != current;
+            o.shown = current;
+            switched
+        }),
+        None => false,
+    };
+    if switched {
+        app::add_timeout3(0.0, |_| update());
+    }
+}
+
+fn open_line(line: i32) {
+    let idx = (line as usize)
+        .checked_sub(1)
+        .and_then(|line| OUTLINE.with(|o| o.borrow().targets.get(line).copied()));
+    let sym_line =
+        idx.and_then(|idx| OUTLINE.with(|o| o.borrow().symbols.get(idx).map(|s| s.line)));
+    if let Some(line) = sym_line {
+        jump(line);
+    }
+}
+
+pub struct OutlinePanel {
+    g: group::Group,
+}
+
+impl OutlinePanel {
+    pub fn new() -> Self {
+        let mut g = group::Group::default().with_id("outline_group");
+        let mut col = group::Flex::default().column();
+        col.set_pad(2);
+        let mut filter = input::Input::default().with_id("outline_filter");
+        filter.set_trigger(CallbackTrigger::Changed);
+        filter.set_tooltip("Filter symbols");
+        col.fixed(&filter, 25);
+        let mut b = browser::HoldBrowser::default().with_id("outline");
+        b.set_color(Color::Background.darker());
+        col.end();
+        g.end();
+        g.resize_callback(move |_, x, y, w, h| col.resize(x, y, w, h));
+        filter.set_callback(|i| fill(&i.value()));
+        filter.handle({
+            let mut b = b.clone();
+            move |_, ev| {
+                if ev != Event::KeyDown {
+                    return false;
+                }
+                match app::event_key() {
+                    Key::Down if b.size() > 0 => {
+                        b.select(1);
+                        b.take_focus().ok();
+                        true
+                    }
+                    Key::Enter | Key::KPEnter if b.size() > 0 => {
+                        open_line(b.value().max(1));
+                        true
+                    }
+                    _ => false,
+                }
+            }
+        });
+        // arrow keys only move the selection, Enter or a click jumps
+        b.set_callback(|b| {
+            if app::event() != Event::KeyDown {
+                open_line(b.value());
+            }
+        });
+        b.handle(|b, ev| {
+            if ev == Event::KeyDown && matches!(app::event_key(), Key::Enter | Key::KPEnter) {
+                open_line(b.value());
+                true
+            } else {
+                false
+            }
+        });
+        Self { g }
+    }
+}
+
+fltk::widget_extends!(OutlinePanel, group::Group, g);
@@ -0,0 +1,265 @@
+use crate::{gui, hooks, lang, state::STATE, theme};
+use fltk::{app, group, misc::HelpView, prelude::*, text::TextEditor};
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+};
+
+// Typing pauses this long before the preview is rendered again
+const DELAY: f64 = 0.3;
+
+#[derive(Default)]
+struct Preview {
+    // the buffer last looked at, whether it's markdown and the source line of
+    // each top-level block
+    shown: Option<usize>,
+    markdown: bool,
+    lines: Vec<usize>,
+    // the editor's top line the preview was last scrolled to
+    top: Option<i32>,
+}
+
+thread_local! {
+    static PREVIEW: RefCell<Preview> = RefCell::new(Preview::default());
+}
+
+fn view() -> Option<HelpView> {
+    app::widget_from_id("preview")
+}
+
+fn visible() -> bool {
+    app::widget_from_id::<group::Group>("preview_group").map_or(false, |g| g.w() > 1)
+}
+
+fn is_markdown(p: &Path, text: &str) -> bool {
+    let first_line = text.lines().next().unwrap_or_default();
+    lang::detect(p, first_line).map_or(false, |l| l.name == "markdown")
+}
+
+// Relative links and image sources point next to the file, HelpView only gets a string
+fn resolve<'a>(dir: &Path, url: CowStr<'a>) -> CowStr<'a> {
+    if url.contains("://") || url.starts_with('#') || Path::new(url.as_ref()).is_absolute() {
+        url
+    } else {
+        dir.join(url.as_ref()).display().to_string().into()
+    }
+}
+
+// Renders markdown to the HTML subset HelpView understands. Every top-level block
+// gets an anchor named after its source line, returned along with the HTML.
+pub fn to_html(text: &str, dir: &Path) -> (String, Vec<usize>) {
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let mut lines = vec![];
+    let mut events = vec![];
+    let mut depth = 0;
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    for (event, range) in Parser::new_ext(text, options).into_offset_iter() {
+        let event = match event {
+            Event::Start(tag) => {
+                if depth == 0 {
+                    let line = line_starts.partition_point(|s| *s <= range.start) - 1;
+                    lines.push(line);
+                    events.push(Event::Html(format!("<a name=\"l{}\"></a>", line).into()));
+                }
+                depth += 1;
+                Event::Start(match tag {
+                    Tag::Image(kind, url, title) => Tag::Image(kind, resolve(dir, url), title),
+                    Tag::Link(kind, url, title) => Tag::Link(kind, resolve(dir, url), title),
+                    tag => tag,
+                })
+            }
+            Event::End(tag) => {
+                depth -= 1;
+                Event::End(tag)
+            }
+            event => event,
+        };
+        events.push(event);
+    }
+    let t = theme::current();
+    let mut s = format!(
+        "<html><body bgcolor=\"{}\" text=\"{}\" link=\"{}\">",
+        t.background.to_hex_str(),
+        t.foreground.to_hex_str(),
+        t.cursor.to_hex_str()
+    );
+    html::push_html(&mut s, events.into_iter());
+    s.push_str("</body></html>");
+    (s, lines)
+}
+
+// Renders the current buffer if the preview is open
+pub fn update() {
+    if !visible() {
+        return;
+    }
+    let mut view = match view() {
+        Some(view) => view,
+        None => return,
+    };
+    let current = STATE.with(|s| {
+        let id = s.current_id()?;
+        let mybuf = s.map.get(&id)?;
+        Some((id, mybuf.buf.clone(), mybuf.current_file.clone()?))
+    });
+    let (id, buf, path) = match current {
+        Some(current) => current,
+        None => {
+            PREVIEW.with(|p| p.borrow_mut().markdown = false);
+            view.set_value("<p><i>Not a Markdown file</i></p>");
+            return;
+        }
+    };
+    let text = buf.text();
+    let markdown = is_markdown(&path, &text);
+    PREVIEW.with(|p| {
+        let mut p = p.borrow_mut();
+        p.shown = Some(id);
+        p.markdown = markdown;
+    });
+    if !markdown {
+        view.set_value("<p><i>Not a Markdown file</i></p>");
+        return;
+    }
+    hooks::debounce(id, "preview", DELAY, move || {
+        let current = PREVIEW.with(|p| {
+            let p = p.borrow();
+            p.markdown && p.shown == Some(id)
+        });
+        if current {
+            update();
+        }
+    });
+    let dir = path.parent().map_or_else(PathBuf::new, Path::to_path_buf);
+    let (html, lines) = to_html(&text, &dir);
+    PREVIEW.with(|p| {
+        let mut p = p.borrow_mut();
+        p.lines = lines;
+        p.top = None;
+    });
+    view.set_value(&html);
+    if let Some(ed) = STATE.with(|s| s.current_editor()) {
+        sync(&ed);
+    }
+}
+
+// Scrolls the preview to the block at the editor's top line and follows tab
+// switches, see gui::draw_editor
+pub fn sync(ed: &TextEditor) {
+    if !visible() {
+        return;
+    }
+    let id = ed.as_widget_ptr() as usize;
+    let current = STATE.with(|s| {
+        let focused = s.current_editor().map(|e| e.as_widget_ptr() as usize) == Some(id);
+        focused.then(|| s.current_id())
+    });
+    let current = match current {
+        Some(current) => current,
+        None => return,
+    };
+    let (switched, markdown) = PREVIEW.with(|p| {
+        let mut p = p.borrow_mut();
+        let switched = p.shown != current;
+        p.shown = current;
+        (switched, p.markdown)
+    });
+    if switched {
+        app::add_timeout3(0.0, |_| update());
+        return;
+    }
+    if !markdown {
+        return;
+    }
+    let top = gui::top_line(ed);
+    let anchor = PREVIEW.with(|p| {
+        let mut p = p.borrow_mut();
+        if p.top == Some(top) {
+            return None;
+        }
+        p.top = Some(top);
+        let idx = p.lines.partition_point(|l| *l <= top as usize);
+        Some(idx.checked_sub(1).map(|idx| p.lines[idx]))
+    });
+    // deferred, the editor is usually in the middle of drawing
+    if let Some(anchor) = anchor {
+        app::add_timeout3(0.0, move |_| {
+            if let Some(mut view) = view() {
+                match anchor {
+                    Some(line) => view.set_topline(&format!("l{}", line)),
+                    None => view.set_top_line(0),
+                }
+            }
+        });
+    }
+}
+
+pub struct PreviewPanel {
+    g: group::Group,
+}
+
+impl PreviewPanel {
+    pub fn new() -> Self {
+        let mut g = group::Group::default().with_id("preview_group");
+        let mut view = HelpView::default().with_id("preview");
+        view.set_text_size(app::font_size());
+        g.end();
+        g.resize_callback(move |_, x, y, w, h| view.resize(x, y, w, h));
+        Self { g }
+    }
+}
+
+fltk::widget_extends!(PreviewPanel, group::Group, g);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links() {
+        let dir = Path::new("/docs");
+        let resolved = |url: &'static str| resolve(dir, url.into()).to_string();
+        assert_eq!(
+            resolved("img/logo.png"),
+            Path::new("/docs")
+                .join("img/logo.png")
+                .display()
+                .to_string()
+        );
+        assert_eq!(resolved("#usage"), "#usage");
+        assert_eq!(
+            resolved("https://example.com/a.md"),
+            "https://example.com/a.md"
+        );
+        assert_eq!(resolved("file:///etc/motd"), "file:///etc/motd");
+        #[cfg(unix)]
+        assert_eq!(resolved("/abs/a.png"), "/abs/a.png");
+    }
+
+    #[test]
+    fn block_anchors() {
+        let text = "# Title\n\nSome *text*\nwrapped\n\n- a\n- b\n\n```\ncode\n```\n";
+        let (html, lines) = to_html(text, Path::new("/docs"));
+        assert_eq!(lines, [0, 2, 5, 8]);
+        for line in lines {
+            assert!(html.contains(&format!("<a name=\"l{}\"></a>", line)));
+        }
+        // nested blocks don't get anchors of their own
+        assert!(!html.contains("name=\"l6\""));
+        let anchor = html.find("name=\"l2\"").unwrap();
+        assert!(anchor < html.find("<p>Some <em>text</em>").unwrap());
+        assert!(html.starts_with("<html><body"));
+        assert!(html.ends_with("</body></html>"));
+
+        let (html, lines) = to_html("![logo](logo.png) [up](../README.md)", Path::new("/docs"));
+        assert_eq!(lines, [0]);
+        for url in ["logo.png", "../README.md"] {
+            let resolved = Path::new("/docs").join(url).display().to_string();
+            assert!(html.contains(&format!("\"{}\"", resolved)));
+        }
+        assert_eq!(to_html("", Path::new("/docs")).1, Vec::<usize>::new());
+    }
+}
@@ -0,0 +1,716 @@
+use crate::{encoding, hooks, state::STATE};
+use fltk::{
+    app, dialog,
+    enums::{Event, Key},
+    frame,
+    prelude::*,
+    text::{TextBuffer, TextEditor},
+};
+use regex::Regex;
+use rhai::{Engine, EvalAltResult};
+use std::{cell::RefCell, fs, io::Read, path::Path, rc::Rc};
+
+// Keeps a runaway script from hanging the editor
+const MAX_OPERATIONS: u64 = 50_000_000;
+
+// What a script works on: the text, and the selection as byte offsets. The cursor
+// sits at end, start == end when nothing is selected.
+#[derive(Debug, Clone, Default)]
+pub struct Doc {
+    pub text: String,
+    pub path: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Doc {
+    pub fn new(text: String, path: String) -> Self {
+        Self {
+            text,
+            path,
+            start: 0,
+            end: 0,
+        }
+    }
+
+    fn clamp(&self, pos: i64) -> usize {
+        let mut pos = pos.clamp(0, self.text.len() as i64) as usize;
+        while !self.text.is_char_boundary(pos) {
+            pos -= 1;
+        }
+        pos
+    }
+
+    fn set_cursor(&mut self, pos: i64) {
+        self.end = self.clamp(pos);
+        self.start = self.end;
+    }
+
+    fn select(&mut self, start: i64, end: i64) {
+        let (a, b) = (self.clamp(start), self.clamp(end));
+        self.start = a.min(b);
+        self.end = a.max(b);
+    }
+
+    fn selected(&self) -> String {
+        self.text[self.start..self.end].to_string()
+    }
+
+    // Replaces the selection, like typing
+    fn insert(&mut self, s: &str) {
+        self.text.replace_range(self.start..self.end, s);
+        self.start += s.len();
+        self.end = self.start;
+    }
+
+    fn insert_at(&mut self, pos: i64, s: &str) {
+        let pos = self.clamp(pos);
+        self.text.insert_str(pos, s);
+        if self.start >= pos {
+            self.start += s.len();
+        }
+        if self.end >= pos {
+            self.end += s.len();
+        }
+    }
+
+    fn prev(&self, pos: usize) -> usize {
+        self.text[..pos]
+            .chars()
+            .next_back()
+            .map_or(pos, |c| pos - c.len_utf8())
+    }
+
+    fn next(&self, pos: usize) -> usize {
+        self.text[pos..]
+            .chars()
+            .next()
+            .map_or(pos, |c| pos + c.len_utf8())
+    }
+
+    fn backspace(&mut self) {
+        if self.start == self.end {
+            self.start = self.prev(self.end);
+        }
+        self.insert("");
+    }
+
+    fn delete(&mut self) {
+        if self.start == self.end {
+            self.end = self.next(self.start);
+        }
+        self.insert("");
+    }
+
+    // Selects n characters from the cursor on, or before it when n is negative
+    fn select_by(&mut self, n: i64) {
+        let anchor = self.end;
+        self.move_by(n);
+        self.select(anchor as i64, self.end as i64);
+    }
+
+    // Moves the cursor by n characters, dropping the selection
+    fn move_by(&mut self, n: i64) {
+        let mut pos = self.end;
+        for _ in 0..n.unsigned_abs() {
+            pos = if n < 0 {
+                self.prev(pos)
+            } else {
+                self.next(pos)
+            };
+        }
+        self.start = pos;
+        self.end = pos;
+    }
+
+    fn line_start(&self, pos: usize) -> usize {
+        self.text[..pos].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self, pos: usize) -> usize {
+        self.text[pos..]
+            .find('\n')
+            .map_or(self.text.len(), |i| pos + i)
+    }
+
+    // Moves the cursor n lines down (up if negative), keeping the column
+    fn move_lines(&mut self, n: i64) {
+        let start = self.line_start(self.end);
+        let column = self.text[start..self.end].chars().count();
+        let mut line = start;
+        for _ in 0..n.unsigned_abs() {
+            line = if n < 0 {
+                if line == 0 {
+                    break;
+                }
+                self.line_start(line - 1)
+            } else {
+                match self.text[line..].find('\n') {
+                    Some(i) => line + i + 1,
+                    None => break,
+                }
+            };
+        }
+        let end = self.line_end(line);
+        let pos = self.text[line..end]
+            .char_indices()
+            .nth(column)
+            .map_or(end, |(i, _)| line + i);
+        self.start = pos;
+        self.end = pos;
+    }
+
+    // Selects the next occurrence of s after the cursor, -1 if there's none
+    fn find(&mut self, s: &str) -> i64 {
+        match self.text[self.end..].find(s) {
+            Some(i) => {
+                self.start = self.end + i;
+                self.end = self.start + s.len();
+                self.start as i64
+            }
+            None => -1,
+        }
+    }
+
+    // Keeps the cursor in bounds after the whole text changed
+    fn replace_text(&mut self, text: String) {
+        self.text = text;
+        let end = self.clamp(self.end as i64);
+        self.start = end;
+        self.end = end;
+    }
+
+    fn replace_all(&mut self, from: &str, to: &str) -> i64 {
+        if from.is_empty() {
+            return 0;
+        }
+        let count = self.text.matches(from).count();
+        if count > 0 {
+            self.replace_text(self.text.replace(from, to));
+        }
+        count as i64
+    }
+
+    // $1 and ${name} in rep refer to capture groups
+    fn regex_replace(&mut self, pattern: &str, rep: &str) -> Result<i64, String> {
+        let re = Regex::new(pattern).map_err(|e| e.to_string())?;
+        let count = re.find_iter(&self.text).count();
+        if count > 0 {
+            self.replace_text(re.replace_all(&self.text, rep).into_owned());
+        }
+        Ok(count as i64)
+    }
+}
+
+fn engine(doc: &Rc<RefCell<Doc>>) -> Engine {
+    let mut e = Engine::new();
+    e.set_max_operations(MAX_OPERATIONS);
+    macro_rules! with_doc {
+        ($name:expr, |$d:ident $(, $arg:ident: $t:ty)*| $body:expr) => {{
+            let doc = doc.clone();
+            e.register_fn($name, move |$($arg: $t),*| {
+                let mut doc = doc.borrow_mut();
+                let $d = &mut *doc;
+                $body
+            });
+        }};
+    }
+    with_doc!("text", |d| d.text.clone());
+    with_doc!("set_text", |d, s: &str| d.replace_text(s.to_string()));
+    with_doc!("path", |d| d.path.clone());
+    with_doc!("len", |d| d.text.len() as i64);
+    with_doc!("cursor", |d| d.end as i64);
+    with_doc!("set_cursor", |d, pos: i64| d.set_cursor(pos));
+    with_doc!("select", |d, start: i64, end: i64| d.select(start, end));
+    with_doc!("select_all", |d| d.select(0, d.text.len() as i64));
+    with_doc!("selection_start", |d| d.start as i64);
+    with_doc!("selection_end", |d| d.end as i64);
+    with_doc!("selected", |d| d.selected());
+    with_doc!("insert", |d, s: &str| d.insert(s));
+    with_doc!("insert_at", |d, pos: i64, s: &str| d.insert_at(pos, s));
+    with_doc!("backspace", |d| d.backspace());
+    with_doc!("delete", |d| d.delete());
+    with_doc!("move_by", |d, n: i64| d.move_by(n));
+    with_doc!("select_by", |d, n: i64| d.select_by(n));
+    with_doc!("move_lines", |d, n: i64| d.move_lines(n));
+    with_doc!("line_start", |d| d.set_cursor(d.line_start(d.end) as i64));
+    with_doc!("line_end", |d| d.set_cursor(d.line_end(d.end) as i64));
+    with_doc!("find", |d, s: &str| d.find(s));
+    with_doc!("replace_all", |d, from: &str, to: &str| d
+        .replace_all(from, to));
+    let d = doc.clone();
+    e.register_fn(
+        "regex_replace",
+        move |pattern: &str, rep: &str| -> Result<i64, Box<EvalAltResult>> {
+            d.borrow_mut()
+                .regex_replace(pattern, rep)
+                .map_err(|e| e.into())
+        },
+    );
+    e
+}
+
+// Runs script against doc, returning the changed doc
+pub fn run(script: &str, doc: Doc) -> Result<Doc, String> {
+    let doc = Rc::new(RefCell::new(doc));
+    engine(&doc).run(script).map_err(|e| e.to_string())?;
+    let doc = doc.borrow().clone();
+    Ok(doc)
+}
+
+// fltext --script file.rhai [files]: applies the script to each file and writes back
+// those it changed, or filters stdin to stdout without files. Returns the exit code.
+pub fn run_batch(script: &Path, files: &[String]) -> i32 {
+    let script = match fs::read_to_string(script) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}: {}", script.display(), e);
+            return 1;
+        }
+    };
+    if files.is_empty() {
+        let mut text = String::new();
+        if let Err(e) = std::io::stdin().read_to_string(&mut text) {
+            eprintln!("stdin: {}", e);
+            return 1;
+        }
+        return match run(&script, Doc::new(text, String::new())) {
+            Ok(doc) => {
+                print!("{}", doc.text);
+                0
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                1
+            }
+        };
+    }
+    let mut code = 0;
+    for f in files {
+        let p = Path::new(f);
+        let opened = match encoding::open(p) {
+            Ok(o) if o.mode != encoding::Mode::Hex => o,
+            Ok(_) => {
+                eprintln!("{}: binary file skipped", f);
+                code = 1;
+                continue;
+            }
+            Err(e) => {
+                eprintln!("{}: {}", f, e);
+                code = 1;
+                continue;
+            }
+        };
+        match run(&script, Doc::new(opened.text.clone(), f.clone())) {
+            Ok(doc) if doc.text != opened.text => {
+                if let Err(e) = fs::write(p, encoding::encode(&doc.text, &opened.format)) {
+                    eprintln!("{}: {}", f, e);
+                    code = 1;
+                }
+            }
+            Ok(_) => (),
+            Err(e) => {
+                eprintln!("{}: {}", f, e);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+// Runs script on the editor's buffer, the change is a single undo step
+pub fn apply(ed: &mut TextEditor, script: &str) {
+    let mut buf = match ed.buffer() {
+        Some(buf) => buf,
+        None => return,
+    };
+    let id = ed.as_widget_ptr() as usize;
+    let path = STATE
+        .with(move |s| s.path_of(id))
+        .map(|p| p.display().to_string())
+        .unwrap_or_default();
+    let text = buf.text();
+    let mut doc = Doc::new(text.clone(), path);
+    match buf.selection_position() {
+        Some((start, end)) if start != end => {
+            doc.start = start as usize;
+            doc.end = end as usize;
+        }
+        _ => doc.set_cursor(ed.insert_position() as i64),
+    }
+    let doc = match run(script, doc) {
+        Ok(doc) => doc,
+        Err(e) => {
+            dialog::alert_default(&format!("Script error: {}", e));
+            return;
+        }
+    };
+    // only the part that differs is replaced, keeping the rest of the buffer's state
+    let prefix = text
+        .bytes()
+        .zip(doc.text.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let max_suffix = text.len().min(doc.text.len()) - prefix;
+    let mut suffix = text
+        .bytes()
+        .rev()
+        .zip(doc.text.bytes().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    // keep both ends on character boundaries
+    let mut prefix = prefix;
+    while !text.is_char_boundary(prefix) || !doc.text.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    while !text.is_char_boundary(text.len() - suffix)
+        || !doc.text.is_char_boundary(doc.text.len() - suffix)
+    {
+        suffix -= 1;
+    }
+    if prefix + suffix < text.len().max(doc.text.len()) {
+        buf.replace(
+            prefix as i32,
+            (text.len() - suffix) as i32,
+            &doc.text[prefix..doc.text.len() - suffix],
+        );
+    }
+    if doc.start != doc.end {
+        buf.select(doc.start as i32, doc.end as i32);
+    } else {
+        buf.unselect();
+    }
+    ed.set_insert_position(doc.end as i32);
+    ed.show_insert_position();
+}
+
+// Runs a script file on the current editor
+pub fn run_file(p: &Path) {
+    let script = match fs::read_to_string(p) {
+        Ok(s) => s,
+        Err(e) => {
+            dialog::alert_default(&format!("{}: {}", p.display(), e));
+            return;
+        }
+    };
+    let ed = STATE.with(|s| {
+        s.current_editor()
+            .filter(|e| !s.read_only(e.as_widget_ptr() as usize))
+    });
+    if let Some(mut ed) = ed {
+        apply(&mut ed, &script);
+    }
+}
+
+#[derive(Default)]
+struct Recorder {
+    // the buffer being recorded
+    id: Option<usize>,
+    // the recorded script lines, typed text is collected before it's added
+    lines: Vec<String>,
+    typed: String,
+    // where the script's cursor is, None after a recorded key until the editor moved
+    // its own. Differs from the editor's after auto-close and the like.
+    cursor: Option<i32>,
+    // the last finished recording and the info label it replaced
+    last: Option<String>,
+    label: String,
+}
+
+// Characters from cursor to pos, negative when pos comes first, counted in the text
+// before the edit at pos that removed deleted_text
+fn distance(buf: &TextBuffer, cursor: i32, pos: i32, inserted: i32, deleted_text: &str) -> i64 {
+    let chars = |a: i32, b: i32| buf.text_range(a, b).map_or(0, |s| s.chars().count()) as i64;
+    let deleted = deleted_text.len() as i32;
+    if cursor <= pos {
+        chars(cursor, pos)
+    } else if cursor <= pos + deleted {
+        -(deleted_text
+            .get(..(cursor - pos) as usize)
+            .map_or(0, |s| s.chars().count()) as i64)
+    } else {
+        -(deleted_text.chars().count() as i64 + chars(pos + inserted, cursor - deleted + inserted))
+    }
+}
+
+impl Recorder {
+    fn flush(&mut self) {
+        if !self.typed.is_empty() {
+            let line = format!("insert({:?});", self.typed);
+            self.lines.push(line);
+            self.typed.clear();
+        }
+    }
+
+    fn push(&mut self, line: &str) {
+        self.flush();
+        self.lines.push(line.to_string());
+    }
+
+    // An edit to the recorded buffer, as the buffer's modify hook sees it. Edits made
+    // by auto-indent, auto-close, cut and the like are recorded like typing.
+    fn edited(
+        &mut self,
+        buf: &TextBuffer,
+        pos: i32,
+        inserted: i32,
+        deleted: i32,
+        deleted_text: &str,
+    ) {
+        if inserted == 0 && deleted == 0 {
+            return;
+        }
+        let cursor = self.cursor.unwrap_or(pos);
+        let moved = distance(buf, cursor, pos, inserted, deleted_text);
+        let text = buf.text_range(pos, pos + inserted).unwrap_or_default();
+        let one_char = inserted == 0 && deleted_text.chars().count() == 1;
+        if deleted == 0 && moved == 0 {
+            self.typed.push_str(&text);
+        } else if one_char && pos + deleted == cursor {
+            self.push("backspace();");
+        } else if one_char && pos == cursor {
+            self.push("delete();");
+        } else {
+            if moved != 0 {
+                self.push(&format!("move_by({});", moved));
+            }
+            if deleted > 0 {
+                self.push(&format!("select_by({});", deleted_text.chars().count()));
+                self.push(&format!("insert({:?});", text));
+            } else {
+                self.typed.push_str(&text);
+            }
+        }
+        self.cursor = Some(pos + inserted);
+    }
+
+    // A key that moves the cursor, cursor is the editor's before the key. The script's
+    // cursor catches up with it first.
+    fn moved(&mut self, buf: &TextBuffer, cursor: i32, line: &str) {
+        if let Some(from) = self.cursor.filter(|from| *from != cursor) {
+            let n = distance(buf, from, cursor, 0, "");
+            self.push(&format!("move_by({});", n));
+        }
+        self.push(line);
+        self.cursor = None;
+    }
+}
+
+thread_local! {
+    static RECORDER: RefCell<Recorder> = RefCell::new(Recorder::default());
+}
+
+pub fn recording() -> bool {
+    RECORDER.with(|r| r.borrow().id.is_some())
+}
+
+// Starts recording a macro in the current buffer, or stops and keeps it for replay
+pub fn toggle_recording() {
+    let mut info: frame::Frame = match app::widget_from_id("info") {
+        Some(f) => f,
+        None => return,
+    };
+    let stopped = RECORDER.with(|r| {
+        let mut r = r.borrow_mut();
+        let id = r.id.take()?;
+        r.flush();
+        let lines = std::mem::take(&mut r.lines);
+        if !lines.is_empty() {
+            r.last = Some(lines.join("\n"));
+        }
+        info.set_label(&r.label);
+        Some(id)
+    });
+    match stopped {
+        Some(id) => hooks::unset(id, "macro"),
+        None => {
+            let current = STATE.with(|s| {
+                let ed = s.current_editor()?;
+                Some((s.buffer_id(ed.as_widget_ptr() as usize), ed.buffer()?))
+            });
+            let (id, buf) = match current {
+                Some(current) => current,
+                None => return,
+            };
+            RECORDER.with(|r| {
+                let mut r = r.borrow_mut();
+                r.id = Some(id);
+                r.lines.clear();
+                r.typed.clear();
+                r.cursor = None;
+                r.label = info.label();
+            });
+            hooks::set(
+                id,
+                "macro",
+                move |pos, inserted, deleted, _, deleted_text| {
+                    RECORDER.with(|r| {
+                        r.borrow_mut()
+                            .edited(&buf, pos, inserted, deleted, deleted_text)
+                    })
+                },
+            );
+            info.set_label("Recording macro... (Ctrl+Shift+R to stop)");
+        }
+    }
+    info.redraw();
+}
+
+// Records the keys that move the cursor, edits come from the buffer's modify hook.
+// Called before the editor handles ev, see gui::editor_handle.
+pub fn record(ed: &TextEditor, ev: Event) {
+    let recorded = match RECORDER.with(|r| r.borrow().id) {
+        Some(recorded) => recorded,
+        None => return,
+    };
+    let id = ed.as_widget_ptr() as usize;
+    let buf = match ed.buffer() {
+        Some(buf) if STATE.with(move |s| s.buffer_id(id)) == recorded => buf,
+        _ => return,
+    };
+    let line = match ev {
+        Event::KeyDown if !app::is_event_ctrl() && !app::is_event_alt() => match app::event_key() {
+            Key::Left => Some("move_by(-1);"),
+            Key::Right => Some("move_by(1);"),
+            Key::Up => Some("move_lines(-1);"),
+            Key::Down => Some("move_lines(1);"),
+            Key::Home => Some("line_start();"),
+            Key::End => Some("line_end();"),
+            _ => None,
+        },
+        _ => None,
+    };
+    let cursor = ed.insert_position();
+    RECORDER.with(|r| {
+        let mut r = r.borrow_mut();
+        match line {
+            Some(line) => r.moved(&buf, cursor, line),
+            // the editor has handled the last recorded key by now
+            None if r.cursor.is_none() => r.cursor = Some(cursor),
+            None => (),
+        }
+    });
+}
+
+pub fn play_macro() {
+    if recording() {
+        return;
+    }
+    let script = match RECORDER.with(|r| r.borrow().last.clone()) {
+        Some(s) => s,
+        None => return,
+    };
+    let ed = STATE.with(|s| {
+        s.current_editor()
+            .filter(|e| !s.read_only(e.as_widget_ptr() as usize))
+    });
+    if let Some(mut ed) = ed {
+        apply(&mut ed, &script);
+    }
+}
+
+// The last recorded macro as a script, for Save Macro
+pub fn last_macro() -> Option<String> {
+    RECORDER.with(|r| r.borrow().last.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn doc(text: &str, cursor: usize) -> Doc {
+        let mut doc = Doc::new(text.to_string(), String::new());
+        doc.set_cursor(cursor as i64);
+        doc
+    }
+
+    #[test]
+    fn doc_editing() {
+        let mut d = doc("héllo\nwo\nworld", 0);
+        d.move_by(2);
+        assert_eq!(d.end, 3);
+        d.backspace();
+        assert_eq!(d.text, "hllo\nwo\nworld");
+        d.move_lines(2);
+        assert_eq!(d.end, "hllo\nwo\nw".len());
+        d.move_lines(-1);
+        assert_eq!(d.end, "hllo\nw".len());
+        d.select_by(-1);
+        assert_eq!(d.selected(), "w");
+        d.insert("W");
+        assert_eq!(d.text, "hllo\nWo\nworld");
+        assert_eq!(d.find("wor"), "hllo\nWo\n".len() as i64);
+        assert_eq!(d.find("wor"), -1);
+        assert_eq!(d.replace_all("o", "0"), 3);
+        assert_eq!(d.regex_replace(r"(\w)0", "${1}o"), Ok(3));
+        assert_eq!(d.text, "hllo\nWo\nworld");
+        d.set_cursor(100);
+        assert_eq!(d.end, d.text.len());
+    }
+
+    #[test]
+    fn run_script() {
+        let d = run(
+            "move_by(2); select_by(2); insert(\"X\"); line_end(); insert(\"!\");",
+            doc("abcdef\ngh", 0),
+        )
+        .unwrap();
+        assert_eq!(d.text, "abXef!\ngh");
+        assert!(run("nope();", doc("", 0)).is_err());
+    }
+
+    fn edit(r: &mut Recorder, buf: &mut TextBuffer, pos: i32, deleted: i32, s: &str) {
+        let deleted_text = buf.text_range(pos, pos + deleted).unwrap_or_default();
+        buf.replace(pos, pos + deleted, s);
+        r.edited(buf, pos, s.len() as i32, deleted, &deleted_text);
+    }
+
+    // Edits as fltext's helpers make them, the recording replayed from the same
+    // cursor has to give the same text
+    #[test]
+    fn recorded_edits_replay() {
+        let start = "fn f() {}\nlet é = 1;\n";
+        let mut buf = TextBuffer::default();
+        buf.set_text(start);
+        let mut r = Recorder::default();
+        // auto-close, typing inside, then typing past the skipped over )
+        edit(&mut r, &mut buf, 5, 0, "()");
+        edit(&mut r, &mut buf, 6, 0, "x");
+        edit(&mut r, &mut buf, 8, 0, "?");
+        // Backspace, then cutting "fn"
+        edit(&mut r, &mut buf, 8, 1, "");
+        edit(&mut r, &mut buf, 0, 2, "");
+        // Enter with auto-indent
+        edit(&mut r, &mut buf, 0, 0, "\n    ");
+        // Down onto é, the editor has moved by the next event, then Delete
+        r.moved(&buf, 5, "move_lines(1);");
+        let e = buf.text().find('é').unwrap() as i32;
+        r.cursor = Some(e);
+        edit(&mut r, &mut buf, e, "é".len() as i32, "");
+        r.flush();
+        let recorded = r.lines.join("\n");
+        assert!(recorded.contains("backspace();") && recorded.contains("delete();"));
+        let d = run(&recorded, doc(start, 5)).unwrap();
+        assert_eq!(d.text, buf.text(), "{}", recorded);
+    }
+
+    #[test]
+    fn batch() {
+        let dir = env::temp_dir().join(format!("fltext-script-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("upper.rhai");
+        fs::write(&script, "replace_all(\"l\", \"L\");").unwrap();
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        fs::write(&a, "hello\n").unwrap();
+        fs::write(&b, "abc\n").unwrap();
+        let files = [&a, &dir.join("missing.txt"), &b].map(|p| p.display().to_string());
+        assert_eq!(run_batch(&script, &files), 1);
+        assert_eq!(fs::read_to_string(&a).unwrap(), "heLLo\n");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "abc\n");
+        assert_eq!(run_batch(&script, &files[..1]), 0);
+        assert_eq!(run_batch(&dir.join("missing.rhai"), &[]), 1);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,417 @@
+use crate::encoding;
+use ignore::WalkBuilder;
+use regex::{Regex, RegexBuilder};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+};
+
+// Plain queries are escaped into a regex so that both modes share the
+// case and whole-word options
+#[derive(Clone)]
+pub struct Matcher {
+    re: Regex,
+    literal: bool,
+}
+
+impl Matcher {
+    pub fn new(query: &str, regex: bool, case_sensitive: bool, whole_word: bool) -> Option<Self> {
+        if query.is_empty() {
+            return None;
+        }
+        let mut pattern = if regex {
+            query.to_string()
+        } else {
+            regex::escape(query)
+        };
+        if whole_word {
+            pattern = format!(r"\b(?:{})\b", pattern);
+        }
+        RegexBuilder::new(&pattern)
+            .case_insensitive(!case_sensitive)
+            .build()
+            .ok()
+            .map(|re| Matcher {
+                re,
+                literal: !regex,
+            })
+    }
+    pub fn find_iter(&self, s: &str) -> Vec<Range<usize>> {
+        self.re
+            .find_iter(s)
+            .map(|m| m.range())
+            .filter(|r| !r.is_empty())
+            .collect()
+    }
+    // Regex replacements may use capture groups, e.g. $1. Exactly the matches
+    // find_iter reports are replaced.
+    pub fn replace_all(&self, s: &str, rep: &str) -> String {
+        let matches = self.find_iter(s);
+        match (matches.first(), matches.last()) {
+            (Some(first), Some(last)) => format!(
+                "{}{}{}",
+                &s[..first.start],
+                self.replace_matches(s, &matches, rep),
+                &s[last.end..]
+            ),
+            _ => s.to_string(),
+        }
+    }
+    // Replacement for s from the first match to the end of the last one. Each match is
+    // expanded against the whole of s, so anchors and word boundaries see the same
+    // context as find_iter did, the text between matches is kept.
+    pub fn replace_matches(&self, s: &str, matches: &[Range<usize>], rep: &str) -> String {
+        let mut dst = String::new();
+        let mut last = match matches.first() {
+            Some(first) => first.start,
+            None => return dst,
+        };
+        for r in matches {
+            dst.push_str(&s[last..r.start]);
+            dst.push_str(&self.expand(s, r.clone(), rep));
+            last = r.end;
+        }
+        dst
+    }
+    // Replacement text for the match starting at range.start
+    pub fn expand(&self, s: &str, range: Range<usize>, rep: &str) -> String {
+        if self.literal {
+            return rep.to_string();
+        }
+        let mut dst = String::new();
+        if let Some(caps) = self.re.captures_at(s, range.start) {
+            caps.expand(rep, &mut dst);
+        }
+        dst
+    }
+}
+
+pub struct Hit {
+    pub path: PathBuf,
+    // 1-based line number
+    pub line: usize,
+    // byte range of the match within the line
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+pub struct LineChange {
+    pub line: usize,
+    pub old: String,
+    pub new: String,
+}
+
+pub struct FileChange {
+    pub path: PathBuf,
+    pub lines: Vec<LineChange>,
+    // hash of the text the preview was computed from
+    original: u64,
+    contents: String,
+}
+
+fn hash(s: &str) -> u64 {
+    let mut h = DefaultHasher::new();
+    s.hash(&mut h);
+    h.finish()
+}
+
+impl FileChange {
+    // A file that changed since the preview is left alone
+    pub fn apply(&self) -> io::Result<()> {
+        if hash(&fs::read_to_string(&self.path)?) != self.original {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "changed since the preview",
+            ));
+        }
+        fs::write(&self.path, &self.contents)
+    }
+}
+
+// Files under root, honoring .gitignore and friends
+pub fn walk(root: &Path) -> impl Iterator<Item = PathBuf> {
+    WalkBuilder::new(root)
+        .build()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().map_or(false, |t| t.is_file()))
+        .map(|e| e.into_path())
+}
+
+// Text files under root, binary ones are skipped
+fn files(root: &Path) -> impl Iterator<Item = (PathBuf, String)> {
+    walk(root).filter_map(|p| {
+        let contents = fs::read_to_string(&p).ok()?;
+        if encoding::looks_binary(contents.as_bytes()) {
+            return None;
+        }
+        Some((p, contents))
+    })
+}
+
+// Case-insensitive subsequence match, None when a query character is missing.
+// Runs of consecutive characters and matches at word starts score higher,
+// shorter candidates win ties.
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i32> {
+    let cand: Vec<char> = candidate.chars().collect();
+    let lower: Vec<char> = cand
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect();
+    let mut score = 0;
+    let mut idx = 0;
+    let mut prev: Option<usize> = None;
+    for q in query.chars().filter(|c| !c.is_whitespace()) {
+        let q = q.to_lowercase().next().unwrap_or(q);
+        idx += lower[idx..].iter().position(|c| *c == q)?;
+        score += 1;
+        if prev.map_or(false, |p| p + 1 == idx) {
+            score += 5;
+        }
+        if idx == 0
+            || !cand[idx - 1].is_alphanumeric()
+            || (cand[idx - 1].is_lowercase() && cand[idx].is_uppercase())
+        {
+            score += 3;
+        }
+        prev = Some(idx);
+        idx += 1;
+    }
+    Some(score * 16 - cand.len().min(255) as i32 / 16)
+}
+
+// Searches in a background thread, hits are sent as they're found.
+// Setting cancel stops the walk at the next file.
+pub fn spawn_search(
+    root: PathBuf,
+    matcher: Matcher,
+    cancel: Arc<AtomicBool>,
+) -> mpsc::Receiver<Hit> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for (path, contents) in files(&root) {
+            if cancel.load(Ordering::Relaxed) {
+                return;
+            }
+            for (idx, line) in contents.lines().enumerate() {
+                for range in matcher.find_iter(line) {
+                    let hit = Hit {
+                        path: path.clone(),
+                        line: idx + 1,
+                        range,
+                        text: line.to_string(),
+                    };
+                    if tx.send(hit).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    rx
+}
+
+// Computes replacements line by line, nothing is written until FileChange::apply
+pub fn preview_replace(root: &Path, matcher: &Matcher, rep: &str) -> Vec<FileChange> {
+    let mut changes = vec![];
+    for (path, contents) in files(root) {
+        let mut lines = vec![];
+        let mut new_contents = String::with_capacity(contents.len());
+        for (idx, line) in contents.split_inclusive('\n').enumerate() {
+            let (body, ending) = match line.strip_suffix('\n') {
+                Some(body) => match body.strip_suffix('\r') {
+                    Some(body) => (body, "\r\n"),
+                    None => (body, "\n"),
+                },
+                None => (line, ""),
+            };
+            if matcher.find_iter(body).is_empty() {
+                new_contents.push_str(line);
+                continue;
+            }
+            let new = matcher.replace_all(body, rep);
+            new_contents.push_str(&new);
+            new_contents.push_str(ending);
+            if new != body {
+                lines.push(LineChange {
+                    line: idx + 1,
+                    old: body.to_string(),
+                    new,
+                });
+            }
+        }
+        if !lines.is_empty() {
+            changes.push(FileChange {
+                path,
+                lines,
+                original: hash(&contents),
+                contents: new_contents,
+            });
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fltext-{}-{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn hits(root: &Path, query: &str, cancel: bool) -> Vec<(String, usize, Range<usize>)> {
+        let matcher = Matcher::new(query, false, true, false).unwrap();
+        let rx = spawn_search(
+            root.to_path_buf(),
+            matcher,
+            Arc::new(AtomicBool::new(cancel)),
+        );
+        let mut hits: Vec<_> = rx
+            .iter()
+            .map(|h| {
+                let rel = h.path.strip_prefix(root).unwrap();
+                (rel.display().to_string(), h.line, h.range)
+            })
+            .collect();
+        hits.sort_by_key(|(path, line, range)| (path.clone(), *line, range.start));
+        hits
+    }
+
+    #[test]
+    fn fuzzy() {
+        let rank = |query: &str, candidates: &[&'static str]| {
+            let mut scored: Vec<(i32, &str)> = candidates
+                .iter()
+                .filter_map(|c| Some((fuzzy_score(query, c)?, *c)))
+                .collect();
+            scored.sort_by_key(|(score, c)| (-score, *c));
+            scored.into_iter().map(|(_, c)| c).collect::<Vec<_>>()
+        };
+        // prefixes and consecutive runs first, then word starts, then scattered letters
+        assert_eq!(
+            rank(
+                "main",
+                &["domain.rs", "src/main.rs", "my_admin.rs", "main.rs"]
+            ),
+            ["main.rs", "src/main.rs", "domain.rs", "my_admin.rs"]
+        );
+        assert_eq!(
+            rank("fb", &["src/fbr.rs", "FileBrowser.rs", "buffer.rs"]),
+            ["src/fbr.rs", "FileBrowser.rs"]
+        );
+        assert!(fuzzy_score("sr", "some_rs") > fuzzy_score("sr", "assert"));
+        // case and whitespace in the query don't matter
+        assert_eq!(
+            fuzzy_score("MAIN", "main.rs"),
+            fuzzy_score("main", "main.rs")
+        );
+        assert_eq!(
+            fuzzy_score("ma in", "Main.rs"),
+            fuzzy_score("main", "main.rs")
+        );
+        // shorter candidates win ties
+        assert!(fuzzy_score("a", "a.rs") > fuzzy_score("a", "a_very_long_name_indeed.rs"));
+        assert_eq!(fuzzy_score("", "anything"), Some(0));
+        assert_eq!(fuzzy_score("xyz", "main.rs"), None);
+        assert_eq!(fuzzy_score("nm", "main"), None);
+        assert_eq!(fuzzy_score("a", ""), None);
+    }
+
+    #[test]
+    fn find_in_files() {
+        let dir = temp_dir("search-walk");
+        // .gitignore only counts inside a repository
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(dir.join(".gitignore"), "target/\n*.log\n").unwrap();
+        fs::write(dir.join("a.rs"), "fn needle() {}\nlet x = needle;\n").unwrap();
+        fs::write(dir.join("b.txt"), "no match\n").unwrap();
+        fs::write(dir.join("target/out.rs"), "needle").unwrap();
+        fs::write(dir.join("run.log"), "needle").unwrap();
+        fs::write(dir.join(".hidden"), "needle").unwrap();
+        fs::write(dir.join("blob.bin"), b"needle\0\0\0\0").unwrap();
+        fs::write(dir.join("latin1.txt"), b"needle \xe9").unwrap();
+        assert_eq!(
+            hits(&dir, "needle", false),
+            [
+                ("a.rs".to_string(), 1, 3..9),
+                ("a.rs".to_string(), 2, 8..14)
+            ]
+        );
+        // cancelled before the first file
+        assert!(hits(&dir, "needle", true).is_empty());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn preview_and_apply() {
+        let dir = temp_dir("search-replace");
+        fs::write(dir.join("a.txt"), "foo bar\r\nbar\r\nfoo").unwrap();
+        fs::write(dir.join("b.txt"), "foo\n").unwrap();
+        fs::write(dir.join("c.txt"), "nothing\n").unwrap();
+        let matcher = Matcher::new("foo", false, true, false).unwrap();
+        let mut changes = preview_replace(&dir, &matcher, "baz");
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        let lines: Vec<Vec<(usize, &str, &str)>> = changes
+            .iter()
+            .map(|c| {
+                c.lines
+                    .iter()
+                    .map(|l| (l.line, l.old.as_str(), l.new.as_str()))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                vec![(1, "foo bar", "baz bar"), (3, "foo", "baz")],
+                vec![(1, "foo", "baz")],
+            ]
+        );
+        // nothing is written before apply
+        assert_eq!(fs::read_to_string(dir.join("b.txt")).unwrap(), "foo\n");
+        // a file edited since the preview is skipped
+        fs::write(dir.join("b.txt"), "foo foo\n").unwrap();
+        assert!(changes[0].apply().is_ok());
+        assert!(changes[1].apply().is_err());
+        assert_eq!(
+            fs::read_to_string(dir.join("a.txt")).unwrap(),
+            "baz bar\r\nbar\r\nbaz"
+        );
+        assert_eq!(fs::read_to_string(dir.join("b.txt")).unwrap(), "foo foo\n");
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    fn replace(query: &str, regex: bool, s: &str, rep: &str) -> (String, usize) {
+        let m = Matcher::new(query, regex, true, false).unwrap();
+        (m.replace_all(s, rep), m.find_iter(s).len())
+    }
+
+    #[test]
+    fn replace_all() {
+        assert_eq!(replace("a.", false, "a.b a.", "$1"), ("$1b $1".into(), 2));
+        assert_eq!(
+            replace(r"(\w+)=(\w+)", true, "a=1, b=2", "$2=$1"),
+            ("1=a, 2=b".into(), 2)
+        );
+        // empty matches aren't counted, so they aren't replaced either
+        assert_eq!(replace(r"\d*", true, "1ab2", "-"), ("-ab-".into(), 2));
+        // the context before the first match still counts
+        assert_eq!(replace(r"\Bb", true, "ab b", "X"), ("aX b".into(), 1));
+        assert_eq!(replace("^a", true, "a a", "X"), ("X a".into(), 1));
+        assert_eq!(replace("z", false, "abc", "X"), ("abc".into(), 0));
+    }
+}
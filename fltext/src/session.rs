@@ -0,0 +1,248 @@
+use crate::{
+    commands::{self, Command},
+    gui,
+    state::State,
+    utils,
+};
+use fltk::{app, group, prelude::*, text};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub insert_position: i32,
+    pub top_line: i32,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Session {
+    pub fbr_width: Option<i32>,
+    pub term_height: Option<i32>,
+    pub fbr_visible: Option<bool>,
+    pub term_visible: Option<bool>,
+    pub active: usize,
+    pub entries: Vec<Entry>,
+}
+
+impl Session {
+    pub fn parse(s: &str) -> Self {
+        let mut session = Session::default();
+        for line in s.lines() {
+            if let Some((key, value)) = line.split_once('=') {
+                match key {
+                    "fbr_width" => session.fbr_width = value.parse().ok(),
+                    "term_height" => session.term_height = value.parse().ok(),
+                    "fbr_visible" => session.fbr_visible = Some(value == "1"),
+                    "term_visible" => session.term_visible = Some(value == "1"),
+                    "active" => session.active = value.parse().unwrap_or_default(),
+                    "file" => {
+                        let mut fields = value.splitn(3, '\t');
+                        if let (Some(pos), Some(top), Some(path)) =
+                            (fields.next(), fields.next(), fields.next())
+                        {
+                            session.entries.push(Entry {
+                                path: PathBuf::from(path),
+                                insert_position: pos.parse().unwrap_or_default(),
+                                top_line: top.parse().unwrap_or_default(),
+                            });
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        session
+    }
+}
+
+impl std::fmt::Display for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(w) = self.fbr_width {
+            writeln!(f, "fbr_width={}", w)?;
+        }
+        if let Some(h) = self.term_height {
+            writeln!(f, "term_height={}", h)?;
+        }
+        if let Some(v) = self.fbr_visible {
+            writeln!(f, "fbr_visible={}", v as u8)?;
+        }
+        if let Some(v) = self.term_visible {
+            writeln!(f, "term_visible={}", v as u8)?;
+        }
+        writeln!(f, "active={}", self.active)?;
+        for e in &self.entries {
+            writeln!(
+                f,
+                "file={}\t{}\t{}",
+                e.insert_position,
+                e.top_line,
+                e.path.display()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn set_enabled(flag: bool) {
+    ENABLED.store(flag, Ordering::Relaxed);
+}
+
+fn session_file(dir: &Path) -> Option<PathBuf> {
+    Some(
+        utils::config_dir()?
+            .join("sessions")
+            .join(utils::path_key(dir)),
+    )
+}
+
+pub fn save(state: &State) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut session = Session::default();
+    if let Some(fbr) = app::widget_from_id::<group::Group>("fbr_group") {
+        session.fbr_width = Some(fbr.w());
+    }
+    if let Some(term) = app::widget_from_id::<group::Group>("term_group") {
+        session.term_height = Some(term.h());
+    }
+    session.fbr_visible = commands::menu_item(Command::ToggleFileBrowser).map(|i| i.value());
+    session.term_visible = commands::menu_item(Command::ToggleTerminal).map(|i| i.value());
+    let tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
+    let current = state.tab_id();
+    for i in 0..tabs.children() {
+        if let Some(ed) = tabs.child(i).and_then(|edrow| edrow.child(0)) {
+            let id = ed.as_widget_ptr() as usize;
+            if let Some(mybuf) = state.map.get(&id) {
+                if let Some(path) = mybuf.current_file.as_ref() {
+                    if current == Some(id) {
+                        session.active = session.entries.len();
+                    }
+                    let ed = text::TextEditor::from_dyn_widget(&ed).unwrap();
+                    session.entries.push(Entry {
+                        path: path.clone(),
+                        insert_position: ed.insert_position(),
+                        top_line: gui::top_line(&ed),
+                    });
+                }
+            }
+        }
+    }
+    if let Some(file) = session_file(&state.current_dir) {
+        fs::create_dir_all(file.parent().unwrap()).ok();
+        fs::write(file, session.to_string()).ok();
+    }
+}
+
+fn toggle(cmd: Command, widget: &str, visible: Option<bool>, size: Option<i32>) {
+    if let (Some(mut item), Some(w)) = (
+        commands::menu_item(cmd),
+        app::widget_from_id::<group::Group>(widget),
+    ) {
+        let mut parent = group::Flex::from_dyn_widget(&w.parent().unwrap()).unwrap();
+        let visible = visible.unwrap_or(item.value());
+        if visible {
+            item.set();
+            if let Some(size) = size.filter(|size| *size > 1) {
+                parent.fixed(&w, size);
+            }
+        } else {
+            item.clear();
+            parent.fixed(&w, 1);
+        }
+    }
+}
+
+// Returns whether any buffer was reopened
+pub fn restore(state: &mut State) -> bool {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let session = match session_file(&state.current_dir).and_then(|f| fs::read_to_string(f).ok()) {
+        Some(s) => Session::parse(&s),
+        None => return false,
+    };
+    toggle(
+        Command::ToggleFileBrowser,
+        "fbr_group",
+        session.fbr_visible,
+        session.fbr_width,
+    );
+    toggle(
+        Command::ToggleTerminal,
+        "term_group",
+        session.term_visible,
+        session.term_height,
+    );
+    let mut opened = vec![];
+    // active counts the saved entries, missing files are skipped here
+    let mut active = None;
+    for (i, e) in session.entries.iter().enumerate() {
+        if !e.path.exists() {
+            continue;
+        }
+        state.append(Some(e.path.clone()));
+        if let (Some(mut ed), Some(buf)) = (state.current_editor(), state.buf()) {
+            ed.set_insert_position(e.insert_position.min(buf.length()));
+            ed.scroll(e.top_line + 1, 0);
+            if i == session.active {
+                active = Some(ed.clone());
+            }
+            opened.push(ed);
+        }
+    }
+    if let Some(ed) = active {
+        let mut tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
+        tabs.set_value(&ed.parent().unwrap()).ok();
+    }
+    app::redraw();
+    !opened.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let session = Session {
+            fbr_width: Some(200),
+            term_height: None,
+            fbr_visible: Some(false),
+            term_visible: Some(true),
+            active: 1,
+            entries: vec![
+                Entry {
+                    path: PathBuf::from("/tmp/a.rs"),
+                    insert_position: 12,
+                    top_line: 3,
+                },
+                Entry {
+                    path: PathBuf::from("/tmp/key=value\tand tab.txt"),
+                    insert_position: 0,
+                    top_line: 0,
+                },
+            ],
+        };
+        let s = session.to_string();
+        assert_eq!(Session::parse(&s), session);
+        assert_eq!(Session::parse(""), Session::default());
+        // unknown keys and broken lines are skipped
+        let parsed = Session::parse("active=x\nfile=1\tno path\ncolor=red\nfile=1\t2\t/a\n");
+        assert_eq!(parsed.active, 0);
+        assert_eq!(
+            parsed.entries,
+            [Entry {
+                path: PathBuf::from("/a"),
+                insert_position: 1,
+                top_line: 2,
+            }]
+        );
+    }
+}
@@ -1,14 +1,19 @@
 #![allow(dead_code)]
 
-use crate::gui;
-use fltk::{app, group, prelude::*, text, utils::oncelock::Lazy};
+use crate::{
+    encoding::{self, Format, Mode},
+    gui, session, swap, watch,
+};
+use fltk::{app, dialog, group, prelude::*, text, utils::oncelock::Lazy};
 use std::collections::HashMap;
 use std::{
-    path::PathBuf,
+    fs,
+    path::{Path, PathBuf},
     sync::atomic::{AtomicU32, Ordering},
 };
 
 static COUNT: AtomicU32 = AtomicU32::new(0);
+static UNTITLED: AtomicU32 = AtomicU32::new(1);
 
 #[derive(Clone, Debug)]
 pub struct MyBuffer {
@@ -16,24 +21,83 @@ pub struct MyBuffer {
     pub id: String,
     pub buf: text::TextBuffer,
     pub current_file: Option<PathBuf>,
+    pub untitled: u32,
+    pub format: Format,
+    pub mode: Mode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseChoice {
+    Save,
+    Discard,
+    Cancel,
+}
+
+// Asks about every dirty buffer in order, returns the ids to save before closing,
+// or None if the user cancelled. Kept free of widgets so it can run headless.
+pub fn confirm_close(
+    dirty: &[(usize, String)],
+    mut ask: impl FnMut(&str) -> CloseChoice,
+) -> Option<Vec<usize>> {
+    let mut to_save = vec![];
+    for (id, name) in dirty {
+        match ask(name) {
+            CloseChoice::Save => to_save.push(*id),
+            CloseChoice::Discard => (),
+            CloseChoice::Cancel => return None,
+        }
+    }
+    Some(to_save)
+}
+
+// Where file ends up when from is moved to to, None if it isn't at or below from
+fn retarget(file: &Path, from: &Path, to: &Path) -> Option<PathBuf> {
+    let rest = file.strip_prefix(from).ok()?;
+    if rest.as_os_str().is_empty() {
+        Some(to.to_path_buf())
+    } else {
+        Some(to.join(rest))
+    }
+}
+
+// Whether editor id sits in the tab edrow
+fn in_tab(id: usize, edrow: &impl WidgetExt) -> bool {
+    text::TextEditor::from_dyn_widget_ptr(id as *mut _)
+        .and_then(|ed| ed.parent())
+        .map_or(false, |p| p.as_widget_ptr() == edrow.as_widget_ptr())
 }
 
 pub struct State {
     pub map: HashMap<usize, MyBuffer>,
     pub current_dir: PathBuf,
+    // Split panes, keyed by the pane's editor id, to the id of the buffer they show
+    pub panes: HashMap<usize, usize>,
+    // Last editor or pane that got focus
+    pub focused: Option<usize>,
 }
 
 impl State {
     pub fn new(current_dir: PathBuf) -> Self {
         let map = HashMap::default();
-        State { map, current_dir }
+        State {
+            map,
+            current_dir,
+            panes: HashMap::default(),
+            focused: None,
+        }
     }
+    // Opening a file while a split pane has focus shows it in that pane
     pub fn append(&mut self, current_path: Option<PathBuf>) {
         let mut tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
+        let pane = self
+            .focused_in_tab()
+            .filter(|id| self.panes.contains_key(id));
+        let tab = tabs.value();
+        let current_path = current_path.map(|p| p.canonicalize().unwrap_or(p));
         let mut open = false;
         let mut edid = 0;
         for (k, v) in &self.map {
-            if v.current_file == current_path {
+            if current_path.is_some() && v.current_file == current_path {
                 open = true;
                 edid = *k;
                 break;
@@ -43,15 +107,47 @@ impl State {
             let old_count = COUNT.load(Ordering::Relaxed);
             let id = format!("edrow{}", old_count);
             COUNT.store(old_count + 1, Ordering::Relaxed);
-            let ed = gui::create_ed(&mut tabs, &id, &current_path);
+            let opened = current_path
+                .as_ref()
+                .filter(|p| p.is_file())
+                .and_then(|p| encoding::open(p).ok());
+            let ed = gui::create_ed(&mut tabs, &id, &current_path, opened.as_ref());
+            let (format, mode) =
+                opened.map_or((Format::default(), Mode::Normal), |o| (o.format, o.mode));
+            if mode == Mode::Large {
+                let name = current_path
+                    .as_ref()
+                    .and_then(|p| p.file_name())
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                // shown once STATE is released
+                app::add_timeout3(0.0, move |_| {
+                    dialog::message_default(&format!(
+                        "{} is larger than {} MB, it was opened read-only without highlighting.",
+                        name,
+                        encoding::LARGE_FILE / 1024 / 1024
+                    ))
+                });
+            }
+            let untitled = match current_path {
+                Some(_) => 0,
+                None => UNTITLED.fetch_add(1, Ordering::Relaxed),
+            };
             let mybuf = MyBuffer {
                 modified: false,
                 id,
                 buf: ed.buffer().unwrap(),
-                current_file: current_path.map(|p| p.canonicalize().unwrap()),
+                current_file: current_path,
+                untitled,
+                format,
+                mode,
             };
-            self.map.insert(ed.as_widget_ptr() as usize, mybuf);
-        } else {
+            edid = ed.as_widget_ptr() as usize;
+            self.map.insert(edid, mybuf);
+            if untitled > 0 {
+                self.set_modified(edid, false);
+            }
+        } else if pane.is_none() {
             tabs.set_value(
                 &text::TextEditor::from_dyn_widget_ptr(edid as *mut _)
                     .unwrap()
@@ -61,8 +157,73 @@ impl State {
             .ok();
             tabs.set_damage(true);
         }
+        if let (Some(pane), Some(tab)) = (pane, tab) {
+            tabs.set_value(&tab).ok();
+            tabs.set_damage(true);
+            if self.panes.get(&pane) != Some(&edid) {
+                let mut edrow = group::Flex::from_dyn_widget(&tab).unwrap();
+                self.close_pane(pane);
+                self.open_pane(&mut edrow, edid);
+            }
+        }
+    }
+    // The focused editor or pane, if it's in the visible tab
+    fn focused_in_tab(&self) -> Option<usize> {
+        let tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
+        let edrow = tabs.value()?;
+        // checked against the maps first, closed editors are deleted
+        let id = self
+            .focused
+            .filter(|id| self.map.contains_key(id) || self.panes.contains_key(id))?;
+        if in_tab(id, &edrow) {
+            Some(id)
+        } else {
+            None
+        }
     }
+    // The split pane of the visible tab
+    pub fn current_pane(&self) -> Option<usize> {
+        let tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
+        let edrow = tabs.value()?;
+        self.panes.keys().copied().find(|id| in_tab(*id, &edrow))
+    }
+    // Adds a pane showing buffer id to edrow and focuses it
+    pub fn open_pane(&mut self, edrow: &mut group::Flex, id: usize) {
+        if let Some(mybuf) = self.map.get(&id) {
+            let pane = gui::create_pane(edrow, id, &mybuf.buf);
+            let pane = pane.as_widget_ptr() as usize;
+            self.panes.insert(pane, id);
+            self.focused = Some(pane);
+        }
+    }
+    pub fn close_pane(&mut self, pane: usize) {
+        if self.panes.remove(&pane).is_some() {
+            gui::remove_pane(pane);
+            if self.focused == Some(pane) {
+                self.focused = None;
+            }
+        }
+    }
+    // Closes the panes inside edrow and those showing buffer id, before the tab goes away
+    pub fn close_panes_of(&mut self, id: usize, edrow: &impl WidgetExt) {
+        let panes: Vec<usize> = self
+            .panes
+            .iter()
+            .filter(|(pane, owner)| **owner == id || in_tab(**pane, edrow))
+            .map(|(pane, _)| *pane)
+            .collect();
+        for pane in panes {
+            self.close_pane(pane);
+        }
+    }
+    // Buffer id of the focused pane, which may belong to another tab
     pub fn current_id(&self) -> Option<usize> {
+        self.focused_in_tab()
+            .map(|id| self.panes.get(&id).copied().unwrap_or(id))
+            .or_else(|| self.tab_id())
+    }
+    // Buffer id of the visible tab
+    pub fn tab_id(&self) -> Option<usize> {
         let tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
         if tabs.children() == 0 {
             return None;
@@ -72,26 +233,75 @@ impl State {
             .child(0)
             .map(|ed| ed.as_widget_ptr() as usize)
     }
-    pub fn was_modified(&mut self, flag: bool) {
-        let mut tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
-        if tabs.children() == 0 {
-            return;
-        }
-        let mut edrow = tabs.value().unwrap();
-        if let Some(c) = edrow.child(0) {
-            let id = c.as_widget_ptr() as usize;
-            let mybuf = self.map.get_mut(&id).unwrap();
+    pub fn ids(&self) -> Vec<usize> {
+        let tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
+        (0..tabs.children())
+            .filter_map(|i| tabs.child(i).and_then(|edrow| edrow.child(0)))
+            .map(|ed| ed.as_widget_ptr() as usize)
+            .collect()
+    }
+    pub fn name(&self, id: usize) -> String {
+        self.map
+            .get(&id)
+            .and_then(|mybuf| mybuf.current_file.as_ref())
+            .and_then(|f| f.file_name())
+            .map_or_else(
+                || format!("untitled-{}", self.map.get(&id).map_or(0, |b| b.untitled)),
+                |f| f.to_string_lossy().to_string(),
+            )
+    }
+    pub fn dirty(&self, ids: &[usize]) -> Vec<(usize, String)> {
+        ids.iter()
+            .filter(|id| self.map.get(id).map_or(false, |mybuf| mybuf.modified))
+            .map(|id| (*id, self.name(*id)))
+            .collect()
+    }
+    pub fn set_modified(&mut self, id: usize, flag: bool) {
+        let mode = if let Some(mybuf) = self.map.get_mut(&id) {
             mybuf.modified = flag;
-            if let Some(f) = mybuf.current_file.as_ref() {
-                if flag {
-                    edrow.set_label(&format!("\t{} *", f.file_name().unwrap().to_str().unwrap()));
-                } else {
-                    edrow.set_label(&format!("\t{}", f.file_name().unwrap().to_str().unwrap()));
-                }
-                tabs.redraw();
+            mybuf.mode
+        } else {
+            return;
+        };
+        let name = match mode {
+            Mode::Normal => self.name(id),
+            Mode::Large => format!("{} [read-only]", self.name(id)),
+            Mode::Hex => format!("{} [hex]", self.name(id)),
+        };
+        let ed = text::TextEditor::from_dyn_widget_ptr(id as *mut _).unwrap();
+        if let Some(mut edrow) = ed.parent() {
+            if flag {
+                edrow.set_label(&format!("\t{} *", name));
+            } else {
+                edrow.set_label(&format!("\t{}", name));
             }
+            app::widget_from_id::<group::Tabs>("tabs").unwrap().redraw();
         }
     }
+    pub fn was_modified(&mut self, flag: bool) {
+        if let Some(id) = self.current_id() {
+            self.set_modified(id, flag);
+        }
+    }
+    // Returns false for buffers without a file or when writing fails
+    pub fn save(&mut self, id: usize) -> bool {
+        let written = match self.map.get(&id) {
+            // the hex view is never written back
+            Some(MyBuffer {
+                current_file: Some(f),
+                buf,
+                format,
+                mode: Mode::Normal | Mode::Large,
+                ..
+            }) => fs::write(f, encoding::encode(&buf.text(), format)).is_ok(),
+            _ => false,
+        };
+        if written {
+            self.set_modified(id, false);
+            swap::discard(id);
+        }
+        written
+    }
     pub fn modified(&self) -> bool {
         if let Some(current_id) = self.current_id() {
             let mybuf = self.map.get(&current_id).unwrap();
@@ -116,21 +326,105 @@ impl State {
             None
         }
     }
-    pub fn set_current_file(&mut self, path: PathBuf) {
-        if let Some(current_id) = self.current_id() {
-            let mybuf = self.map.get_mut(&current_id).unwrap();
-            mybuf.current_file = Some(path)
+    // Re-targets buffer id to path: highlighting, language server and tab label follow
+    pub fn set_current_file(&mut self, id: usize, path: PathBuf) {
+        if let Some(mybuf) = self.map.get_mut(&id) {
+            let path = path.canonicalize().unwrap_or(path);
+            let old = mybuf.current_file.replace(path.clone());
+            let mut buf = mybuf.buf.clone();
+            let mut ed = text::TextEditor::from_dyn_widget_ptr(id as *mut _).unwrap();
+            if let Some(old) = old.as_ref() {
+                watch::unwatch(old);
+                #[cfg(feature = "lsp")]
+                crate::lsp::detach(old);
+            }
+            gui::attach_file(&path, &mut ed, &mut buf);
+            for (pane, _) in self.panes.iter().filter(|(_, owner)| **owner == id) {
+                let mut pane = text::TextEditor::from_dyn_widget_ptr(*pane as *mut _).unwrap();
+                gui::attach_pane(id, &mut pane);
+            }
+            let modified = mybuf.modified;
+            self.set_modified(id, modified);
         }
     }
-    pub fn current_editor(&self) -> Option<text::TextEditor> {
-        let tabs: group::Tabs = app::widget_from_id("tabs").unwrap();
-        if tabs.children() == 0 {
-            return None;
+    // Follows a rename or move in the file browser, buffers at or below from are re-targeted
+    pub fn moved(&mut self, from: &Path, to: &Path) {
+        let moved: Vec<(usize, PathBuf)> = self
+            .map
+            .iter()
+            .filter_map(|(id, mybuf)| {
+                Some((*id, retarget(mybuf.current_file.as_ref()?, from, to)?))
+            })
+            .collect();
+        for (id, path) in moved {
+            self.set_current_file(id, path);
         }
-        tabs.value()
-            .unwrap()
-            .child(0)
-            .map(|c| text::TextEditor::from_dyn_widget(&c).unwrap())
+    }
+    // Tabs without changes whose file is p or below it
+    pub fn unmodified_below(&self, p: &Path) -> Vec<usize> {
+        self.map
+            .iter()
+            .filter(|(_, mybuf)| !mybuf.modified)
+            .filter(|(_, mybuf)| {
+                mybuf
+                    .current_file
+                    .as_ref()
+                    .map_or(false, |f| f.starts_with(p))
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+    // The buffer editor id shows, split panes show their tab editor's
+    pub fn buffer_id(&self, id: usize) -> usize {
+        self.panes.get(&id).copied().unwrap_or(id)
+    }
+    // Large and binary files can't be edited, id can also be a split pane
+    pub fn read_only(&self, id: usize) -> bool {
+        let id = self.buffer_id(id);
+        self.map.get(&id).map_or(false, |b| b.mode != Mode::Normal)
+    }
+    // The file shown in editor id, which can also be a split pane
+    pub fn path_of(&self, id: usize) -> Option<PathBuf> {
+        let id = self.buffer_id(id);
+        self.map.get(&id)?.current_file.clone()
+    }
+    pub fn id_of(&self, path: &Path) -> Option<usize> {
+        self.map
+            .iter()
+            .find(|(_, v)| v.current_file.as_deref() == Some(path))
+            .map(|(k, _)| *k)
+    }
+    // Replaces the buffer's contents with what's on disk, keeping the cursor and scroll position
+    pub fn reload(&mut self, id: usize, contents: &str) {
+        if let Some(mybuf) = self.map.get_mut(&id) {
+            let mut ed = text::TextEditor::from_dyn_widget_ptr(id as *mut _).unwrap();
+            let pos = ed.insert_position();
+            let top_line = gui::top_line(&ed);
+            mybuf.buf.set_text(contents);
+            ed.set_insert_position(pos.min(mybuf.buf.length()));
+            ed.scroll(top_line + 1, 0);
+            self.set_modified(id, false);
+        }
+    }
+    pub fn save_as(&mut self, id: usize, path: PathBuf) -> bool {
+        let written = match self.map.get(&id) {
+            Some(mybuf) if mybuf.mode != Mode::Hex => {
+                fs::write(&path, encoding::encode(&mybuf.buf.text(), &mybuf.format)).is_ok()
+            }
+            _ => false,
+        };
+        if written {
+            self.set_current_file(id, path);
+            self.set_modified(id, false);
+            swap::discard(id);
+        }
+        written
+    }
+    // The focused pane, or the visible tab's editor
+    pub fn current_editor(&self) -> Option<text::TextEditor> {
+        self.focused_in_tab()
+            .or_else(|| self.tab_id())
+            .and_then(|id| text::TextEditor::from_dyn_widget_ptr(id as *mut _))
     }
 }
 
@@ -138,6 +432,123 @@ pub static STATE: Lazy<app::GlobalState<State>> = Lazy::new(app::GlobalState::<S
 
 pub fn init_state(current_file: Option<PathBuf>, current_path: PathBuf) {
     let mut state = State::new(current_path);
-    state.append(current_file);
+    let restored = session::restore(&mut state);
+    if current_file.is_some() || !restored {
+        state.append(current_file);
+    }
     app::GlobalState::new(state);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn dirty() -> Vec<(usize, String)> {
+        vec![
+            (1, "a.rs".to_string()),
+            (2, "b.rs".to_string()),
+            (3, "untitled-1".to_string()),
+        ]
+    }
+
+    // Answers in order, recording who was asked
+    fn answers<'a>(
+        choices: &'a [CloseChoice],
+        asked: &'a mut Vec<String>,
+    ) -> impl FnMut(&str) -> CloseChoice + 'a {
+        let mut choices = choices.iter();
+        move |name| {
+            asked.push(name.to_string());
+            *choices.next().unwrap()
+        }
+    }
+
+    #[test]
+    fn save() {
+        let mut asked = vec![];
+        let ask = answers(&[CloseChoice::Save; 3], &mut asked);
+        assert_eq!(confirm_close(&dirty(), ask), Some(vec![1, 2, 3]));
+        assert_eq!(asked, ["a.rs", "b.rs", "untitled-1"]);
+    }
+
+    #[test]
+    fn discard() {
+        let mut asked = vec![];
+        let ask = answers(&[CloseChoice::Discard; 3], &mut asked);
+        assert_eq!(confirm_close(&dirty(), ask), Some(vec![]));
+        assert_eq!(asked.len(), 3);
+    }
+
+    #[test]
+    fn mixed() {
+        let mut asked = vec![];
+        let choices = [
+            CloseChoice::Discard,
+            CloseChoice::Save,
+            CloseChoice::Discard,
+        ];
+        let ask = answers(&choices, &mut asked);
+        assert_eq!(confirm_close(&dirty(), ask), Some(vec![2]));
+    }
+
+    #[test]
+    fn cancel() {
+        let mut asked = vec![];
+        let choices = [CloseChoice::Save, CloseChoice::Cancel];
+        let ask = answers(&choices, &mut asked);
+        // nothing gets saved, and nobody after the cancel is asked
+        assert_eq!(confirm_close(&dirty(), ask), None);
+        assert_eq!(asked, ["a.rs", "b.rs"]);
+    }
+
+    #[test]
+    fn nothing_dirty() {
+        let ask = |_: &str| -> CloseChoice { panic!("nothing to ask about") };
+        assert_eq!(confirm_close(&[], ask), Some(vec![]));
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fltext-{}-{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn retargeting() {
+        let dir = temp_dir("retarget");
+        let (from, to) = (dir.join("src"), dir.join("lib"));
+        fs::create_dir_all(from.join("nested")).unwrap();
+        for f in ["main.rs", "nested/mod.rs"] {
+            fs::write(from.join(f), "").unwrap();
+        }
+        fs::write(dir.join("src.rs"), "").unwrap();
+        fs::rename(&from, &to).unwrap();
+        let files = [
+            from.clone(),
+            from.join("main.rs"),
+            from.join("nested/mod.rs"),
+        ];
+        for f in &files {
+            let moved = retarget(f, &from, &to).unwrap();
+            assert!(moved.exists(), "{}", moved.display());
+            assert!(moved.starts_with(&to));
+        }
+        assert_eq!(
+            retarget(&files[2], &from, &to),
+            Some(to.join("nested/mod.rs"))
+        );
+        // siblings sharing the name as a prefix stay put
+        assert_eq!(retarget(&dir.join("src.rs"), &from, &to), None);
+        assert_eq!(retarget(&dir.join("srcs/a.rs"), &from, &to), None);
+        // renaming a single file
+        let file = to.join("main.rs");
+        fs::rename(&file, to.join("app.rs")).unwrap();
+        assert_eq!(
+            retarget(&file, &file, &to.join("app.rs")),
+            Some(to.join("app.rs"))
+        );
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,486 @@
+use crate::{
+    cbs,
+    commands::{self, Command},
+    encoding::{self, Mode},
+    git,
+    state::{MyBuffer, State, STATE},
+    utils,
+};
+use fltk::{prelude::*, *};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Seconds between swap file writes
+const INTERVAL: f64 = 30.0;
+const HEADER: &str = "fltext swap 1";
+
+static AUTOSAVE: AtomicBool = AtomicBool::new(false);
+
+// The contents of a modified buffer as of the last write, left behind when fltext
+// doesn't quit normally
+#[derive(Debug, Clone)]
+pub struct Swap {
+    pub file: PathBuf,
+    pub pid: u32,
+    // None for untitled buffers
+    pub path: Option<PathBuf>,
+    pub text: String,
+}
+
+thread_local! {
+    // buffer id -> swap file and the hash of what was last written to it
+    static WRITTEN: RefCell<HashMap<usize, (PathBuf, u64)>> = RefCell::new(HashMap::new());
+    // swaps offered in the recovery dialog
+    static PENDING: RefCell<Vec<Swap>> = RefCell::new(vec![]);
+}
+
+fn swap_dir() -> Option<PathBuf> {
+    Some(utils::cache_dir()?.join("swap"))
+}
+
+fn swap_name(mybuf: &MyBuffer) -> String {
+    let key = match &mybuf.current_file {
+        Some(p) => utils::path_key(p),
+        None => format!("untitled{}", mybuf.untitled),
+    };
+    format!("{}-{}", process::id(), key)
+}
+
+fn hash(text: &str) -> u64 {
+    let mut h = DefaultHasher::new();
+    text.hash(&mut h);
+    h.finish()
+}
+
+// The swap file for this instance's buffer of path
+fn contents(path: Option<&Path>, text: &str) -> String {
+    let mut s = format!("{}\npid={}\n", HEADER, process::id());
+    if let Some(p) = path {
+        s.push_str(&format!("path={}\n", p.display()));
+    }
+    s.push('\n');
+    s.push_str(text);
+    s
+}
+
+fn parse(file: &Path) -> Option<Swap> {
+    let s = fs::read_to_string(file).ok()?;
+    let (header, text) = s.split_once("\n\n")?;
+    let mut lines = header.lines();
+    if lines.next()? != HEADER {
+        return None;
+    }
+    let mut swap = Swap {
+        file: file.to_path_buf(),
+        pid: 0,
+        path: None,
+        text: text.to_string(),
+    };
+    for line in lines {
+        match line.split_once('=') {
+            Some(("pid", pid)) => swap.pid = pid.parse().ok()?,
+            Some(("path", path)) => swap.path = Some(PathBuf::from(path)),
+            _ => (),
+        }
+    }
+    Some(swap)
+}
+
+// Writes the swap files of modified buffers and removes those of saved or closed ones
+pub fn write_all(state: &State) {
+    let dir = match swap_dir() {
+        Some(dir) => dir,
+        None => return,
+    };
+    WRITTEN.with(|w| {
+        let mut w = w.borrow_mut();
+        w.retain(|id, (file, _)| {
+            let open = state.map.contains_key(id);
+            if !open {
+                fs::remove_file(file).ok();
+            }
+            open
+        });
+        for (id, mybuf) in &state.map {
+            let file = dir.join(swap_name(mybuf));
+            let keep = mybuf.modified && mybuf.mode == Mode::Normal;
+            // saved, or re-targeted by Save as or a rename
+            if w.get(id).map_or(false, |(old, _)| !keep || *old != file) {
+                if let Some((old, _)) = w.remove(id) {
+                    fs::remove_file(old).ok();
+                }
+            }
+            if !keep {
+                continue;
+            }
+            let text = mybuf.buf.text();
+            let h = hash(&text);
+            if w.get(id).map(|(_, old)| *old) == Some(h) {
+                continue;
+            }
+            let s = contents(mybuf.current_file.as_deref(), &text);
+            fs::create_dir_all(&dir).ok();
+            match fs::write(&file, s) {
+                Ok(_) => {
+                    w.insert(*id, (file, h));
+                }
+                Err(e) => eprintln!("{}: {}", file.display(), e),
+            }
+        }
+    });
+}
+
+// Called once buffer id was saved or closed
+pub fn discard(id: usize) {
+    if let Some((file, _)) = WRITTEN.with(|w| w.borrow_mut().remove(&id)) {
+        fs::remove_file(file).ok();
+    }
+}
+
+// On a normal quit nothing needs recovering
+pub fn discard_all() {
+    let ids: Vec<usize> = WRITTEN.with(|w| w.borrow().keys().copied().collect());
+    for id in ids {
+        discard(id);
+    }
+}
+
+// Swaps of an instance that's still running aren't offered for recovery. When
+// the check itself fails the process counts as running, the swap is offered next time.
+fn running(pid: u32) -> bool {
+    if pid == process::id() {
+        return true;
+    }
+    if cfg!(target_os = "linux") {
+        return Path::new(&format!("/proc/{}", pid)).exists();
+    }
+    let pid = pid.to_string();
+    if cfg!(windows) {
+        process::Command::new("tasklist")
+            .args(["/NH", "/FI", &format!("PID eq {}", pid)])
+            .output()
+            .map_or(true, |out| {
+                String::from_utf8_lossy(&out.stdout)
+                    .split_whitespace()
+                    .any(|w| w == pid)
+            })
+    } else {
+        // signal 0 only checks that the process exists
+        process::Command::new("kill")
+            .args(["-0", &pid])
+            .output()
+            .map_or(true, |out| out.status.success())
+    }
+}
+
+fn recoverable() -> Vec<Swap> {
+    match swap_dir() {
+        Some(dir) => recoverable_in(&dir),
+        None => vec![],
+    }
+}
+
+// Swaps in dir left behind by instances that are gone, those matching the file on
+// disk are removed
+fn recoverable_in(dir: &Path) -> Vec<Swap> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut swaps = vec![];
+    for entry in entries.flatten() {
+        let swap = match parse(&entry.path()) {
+            Some(swap) => swap,
+            None => continue,
+        };
+        if running(swap.pid) {
+            continue;
+        }
+        let on_disk = swap.path.as_deref().and_then(encoding::read_text);
+        if on_disk.as_deref() == Some(swap.text.as_str()) {
+            fs::remove_file(&swap.file).ok();
+            continue;
+        }
+        swaps.push(swap);
+    }
+    swaps.sort_by(|a, b| a.path.cmp(&b.path));
+    swaps
+}
+
+// Offers recovery of what a crashed instance left behind, then writes swap files
+// every INTERVAL seconds
+pub fn start() {
+    let saved = autosave_file().and_then(|f| fs::read_to_string(f).ok());
+    apply_autosave(saved.map_or(false, |s| s.trim() == "1"));
+    app::add_timeout3(0.0, |_| {
+        let swaps = recoverable();
+        if !swaps.is_empty() {
+            PENDING.with(|p| *p.borrow_mut() = swaps);
+            show_recovery();
+        }
+    });
+    app::add_timeout3(INTERVAL, |handle| {
+        STATE.with(|s| write_all(s));
+        app::repeat_timeout3(INTERVAL, handle);
+    });
+}
+
+pub fn autosave() -> bool {
+    AUTOSAVE.load(Ordering::Relaxed)
+}
+
+// The setting is the same in every directory, so it's kept next to the sessions
+// rather than in one
+fn autosave_file() -> Option<PathBuf> {
+    Some(utils::config_dir()?.join("autosave"))
+}
+
+pub fn set_autosave(flag: bool) {
+    apply_autosave(flag);
+    if let Some(f) = autosave_file() {
+        let written = f
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&f, if flag { "1\n" } else { "0\n" }));
+        if let Err(e) = written {
+            eprintln!("{}: {}", f.display(), e);
+        }
+    }
+}
+
+fn apply_autosave(flag: bool) {
+    AUTOSAVE.store(flag, Ordering::Relaxed);
+    if let Some(mut item) = commands::menu_item(Command::ToggleAutosave) {
+        if flag {
+            item.set();
+        } else {
+            item.clear();
+        }
+    }
+}
+
+// An editor lost focus, if no other fltext widget took it the window was left
+pub fn focus_lost() {
+    if autosave() {
+        app::add_timeout3(0.1, |_| {
+            if app::focus().is_none() {
+                cbs::save_all();
+            }
+        });
+    }
+}
+
+fn label(swap: &Swap) -> String {
+    match &swap.path {
+        Some(p) => p.display().to_string(),
+        None => "Untitled".to_string(),
+    }
+}
+
+fn show_diff(line: i32) {
+    let mut b: browser::Browser = app::widget_from_id("recovery_diff").unwrap();
+    b.clear();
+    let swap = (line as usize)
+        .checked_sub(1)
+        .and_then(|idx| PENDING.with(|p| p.borrow().get(idx).cloned()));
+    let swap = match swap {
+        Some(swap) => swap,
+        None => return,
+    };
+    let on_disk = swap
+        .path
+        .as_deref()
+        .and_then(encoding::read_text)
+        .unwrap_or_default();
+    for line in git::unified(&on_disk, &swap.text) {
+        let color = match line.chars().next() {
+            Some('+') => "@C2",
+            Some('-') => "@C1",
+            Some('@') => "@C4",
+            _ => "",
+        };
+        b.add(&format!("{}@.{}", color, line));
+    }
+}
+
+fn show_recovery() {
+    let mut dlg: window::Window = app::widget_from_id("recovery_dialog").unwrap();
+    let mut list: browser::HoldBrowser = app::widget_from_id("recovery_list").unwrap();
+    list.clear();
+    PENDING.with(|p| {
+        for swap in p.borrow().iter() {
+            list.add(&format!("@.{}", label(swap)));
+        }
+    });
+    if list.size() == 0 {
+        dlg.hide();
+        return;
+    }
+    list.select(1);
+    show_diff(1);
+    dlg.show();
+}
+
+// Opens the selected swap's contents as a modified buffer, or throws it away
+fn resolve(recover: bool) {
+    let list: browser::HoldBrowser = app::widget_from_id("recovery_list").unwrap();
+    let idx = match (list.value() as usize).checked_sub(1) {
+        Some(idx) => idx,
+        None => return,
+    };
+    let swap = match PENDING.with(|p| {
+        let mut p = p.borrow_mut();
+        (idx < p.len()).then(|| p.remove(idx))
+    }) {
+        Some(swap) => swap,
+        None => return,
+    };
+    if recover {
+        STATE.with(|s| {
+            s.append(swap.path.clone());
+            if let (Some(id), Some(mut buf)) = (s.current_id(), s.buf()) {
+                buf.set_text(&swap.text);
+                s.set_modified(id, true);
+            }
+        });
+    }
+    fs::remove_file(&swap.file).ok();
+    show_recovery();
+}
+
+pub struct RecoveryDialog {
+    win: window::Window,
+}
+
+impl RecoveryDialog {
+    pub fn new() -> Self {
+        let mut win = window::Window::default()
+            .with_size(600, 400)
+            .with_label("Recover Unsaved Changes")
+            .with_id("recovery_dialog");
+        let mut col = group::Flex::default_fill().column();
+        col.set_margin(5);
+        let info = frame::Frame::default()
+            .with_label("These buffers weren't saved when fltext last quit:")
+            .with_align(enums::Align::Left | enums::Align::Inside);
+        col.fixed(&info, 20);
+        let mut list = browser::HoldBrowser::default().with_id("recovery_list");
+        col.fixed(&list, 100);
+        let mut diff = browser::Browser::default().with_id("recovery_diff");
+        diff.set_text_font(enums::Font::Courier);
+        let mut row = group::Flex::default().row();
+        frame::Frame::default();
+        let mut recover = button::Button::default().with_label("Recover");
+        let mut discard = button::Button::default().with_label("Discard");
+        let mut later = button::Button::default().with_label("Later");
+        for b in [&recover, &discard, &later] {
+            row.fixed(b, 90);
+        }
+        row.end();
+        col.fixed(&row, 30);
+        col.end();
+        win.end();
+        win.make_resizable(true);
+        list.set_callback(|b| show_diff(b.value()));
+        recover.set_callback(|_| resolve(true));
+        recover.set_tooltip("Open the unsaved contents as a modified buffer");
+        discard.set_callback(|_| resolve(false));
+        later.set_callback({
+            let mut win = win.clone();
+            move |_| win.hide()
+        });
+        later.set_tooltip("Ask again next time");
+        Self { win }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fltext-{}-{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // The pid of a process that already exited
+    fn dead_pid() -> u32 {
+        let mut child = if cfg!(windows) {
+            process::Command::new("cmd").args(["/C", "exit"]).spawn()
+        } else {
+            process::Command::new("true").spawn()
+        }
+        .unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = temp_dir("swap-parse");
+        let path = dir.join("a=b.rs");
+        let file = dir.join("swap");
+        fs::write(&file, contents(Some(&path), "fn main() {}\n\npid=1\n")).unwrap();
+        let swap = parse(&file).unwrap();
+        assert_eq!(swap.pid, process::id());
+        assert_eq!(swap.path, Some(path));
+        assert_eq!(swap.text, "fn main() {}\n\npid=1\n");
+        fs::write(&file, contents(None, "")).unwrap();
+        let swap = parse(&file).unwrap();
+        assert_eq!((swap.path, swap.text), (None, String::new()));
+        fs::write(&file, "something else\n\ntext").unwrap();
+        assert!(parse(&file).is_none());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn liveness() {
+        assert!(running(process::id()));
+        assert!(!running(dead_pid()));
+    }
+
+    #[test]
+    fn recovery() {
+        let dir = temp_dir("swap-recover");
+        let (same, edited) = (dir.join("same.txt"), dir.join("edited.txt"));
+        fs::write(&same, "saved\n").unwrap();
+        fs::write(&edited, "saved\n").unwrap();
+        let swaps = dir.join("swap");
+        fs::create_dir_all(&swaps).unwrap();
+        let dead = dead_pid();
+        let left = |path: Option<&Path>, text: &str| {
+            contents(path, text)
+                .replace(&format!("pid={}", process::id()), &format!("pid={}", dead))
+        };
+        fs::write(swaps.join("same"), left(Some(&same), "saved\n")).unwrap();
+        fs::write(swaps.join("edited"), left(Some(&edited), "unsaved\n")).unwrap();
+        fs::write(swaps.join("untitled"), left(None, "scratch")).unwrap();
+        // this instance's own swap
+        fs::write(swaps.join("own"), contents(Some(&edited), "mine")).unwrap();
+        fs::write(swaps.join("junk"), "not a swap").unwrap();
+        let found: Vec<(Option<PathBuf>, String)> = recoverable_in(&swaps)
+            .into_iter()
+            .map(|s| (s.path, s.text))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (None, "scratch".to_string()),
+                (Some(edited), "unsaved\n".to_string()),
+            ]
+        );
+        // the one matching the file on disk is gone
+        assert!(!swaps.join("same").exists());
+        assert!(swaps.join("own").exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,503 @@
+use crate::{cbs, state::STATE};
+use fltk::{app, browser, enums::*, group, prelude::*, utils::oncelock::Lazy};
+use regex::Regex;
+use std::{
+    cell::RefCell,
+    fs,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+};
+
+#[derive(Debug, Clone)]
+pub struct Task {
+    pub name: String,
+    pub command: String,
+    pub cwd: PathBuf,
+    // the project directory, output locations not found under cwd are looked up here
+    pub root: PathBuf,
+    // positional parameters, command refers to them as "$1" and on
+    pub args: Vec<String>,
+}
+
+// Commands for Run current file by extension, {file} is the file's path and {stem}
+// its name without the extension, both already quoted. Can be overridden under [run] in .fltext.toml.
+const RUNNERS: &[(&str, &str)] = &[
+    ("rs", "cargo run"),
+    ("py", "python3 {file}"),
+    ("sh", "sh {file}"),
+    ("js", "node {file}"),
+    ("rb", "ruby {file}"),
+    ("lua", "lua {file}"),
+    ("go", "go run {file}"),
+    ("c", "cc {file} -o {stem} && ./{stem}"),
+    ("cpp", "c++ {file} -o {stem} && ./{stem}"),
+];
+
+// Output lines shown per run, the rest is dropped
+const MAX_LINES: usize = 20000;
+
+fn config(dir: &Path) -> Option<toml::Table> {
+    let s = fs::read_to_string(dir.join(".fltext.toml")).ok()?;
+    match s.parse::<toml::Table>() {
+        Ok(table) => Some(table),
+        Err(e) => {
+            eprintln!("{}: {}", dir.join(".fltext.toml").display(), e);
+            None
+        }
+    }
+}
+
+// Tasks from .fltext.toml in dir:
+//
+// [tasks]
+// build = "cargo build"
+// test = { command = "cargo test", cwd = "crates/core" }
+pub fn load(dir: &Path) -> Vec<Task> {
+    let table = match config(dir) {
+        Some(table) => table,
+        None => return vec![],
+    };
+    let tasks = match table.get("tasks").and_then(|t| t.as_table()) {
+        Some(tasks) => tasks,
+        None => return vec![],
+    };
+    tasks
+        .iter()
+        .filter_map(|(name, v)| {
+            let (command, cwd) = match v {
+                toml::Value::String(s) => (s.clone(), None),
+                toml::Value::Table(t) => (
+                    t.get("command")?.as_str()?.to_string(),
+                    t.get("cwd").and_then(|c| c.as_str()),
+                ),
+                _ => return None,
+            };
+            Some(Task {
+                name: name.clone(),
+                command,
+                cwd: cwd.map_or_else(|| dir.to_path_buf(), |c| dir.join(c)),
+                root: dir.to_path_buf(),
+                args: vec![],
+            })
+        })
+        .collect()
+}
+
+// The directory of the nearest Cargo.toml above p, cargo run has to start there
+fn cargo_root(p: &Path) -> Option<&Path> {
+    p.ancestors()
+        .skip(1)
+        .find(|d| d.join("Cargo.toml").is_file())
+}
+
+// The Run current file task for p, looked up by its extension
+pub fn runner(dir: &Path, p: &Path) -> Option<Task> {
+    let ext = p.extension()?.to_str()?;
+    let configured =
+        config(dir).and_then(|table| table.get("run")?.get(ext)?.as_str().map(|s| s.to_string()));
+    let template = configured.or_else(|| {
+        RUNNERS
+            .iter()
+            .find(|(e, _)| *e == ext)
+            .map(|(_, cmd)| cmd.to_string())
+    })?;
+    let stem = p.file_stem()?.to_string_lossy();
+    // sh gets the names as parameters, a file named $(...) is not run
+    let command = if cfg!(windows) {
+        template
+            .replace("{file}", &format!("\"{}\"", p.display()))
+            .replace("{stem}", &format!("\"{}\"", stem))
+    } else {
+        template
+            .replace("{file}", "\"$1\"")
+            .replace("{stem}", "\"$2\"")
+    };
+    let cwd = match ext {
+        "rs" => cargo_root(p).or(p.parent())?,
+        _ => p.parent()?,
+    };
+    Some(Task {
+        name: p.file_name()?.to_string_lossy().to_string(),
+        command,
+        cwd: cwd.to_path_buf(),
+        root: dir.to_path_buf(),
+        args: vec![p.display().to_string(), stem.to_string()],
+    })
+}
+
+// A file position in the output, the line and column are 1-based
+pub type Location = (PathBuf, i32, i32);
+
+// rustc's "--> src/main.rs:3:5", gcc and clang's "main.c:3:5: error: ..."
+// and python's "File "main.py", line 3"
+static LOCATION: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"File "([^"]+)", line (\d+)|((?:[A-Za-z]:)?[^\s:"'()<>\[\]]+):(\d+)(?::(\d+))?"#)
+        .unwrap()
+});
+
+// Relative paths are looked up in dirs in order, cargo reports them relative
+// to the workspace, which can be above the crate it runs in
+pub fn parse_location(line: &str, dirs: &[PathBuf]) -> Option<Location> {
+    LOCATION.captures_iter(line).find_map(|c| {
+        let (file, line, col) = match (c.get(1), c.get(3)) {
+            (Some(file), _) => (file, c.get(2)?, None),
+            (_, Some(file)) => (file, c.get(4)?, c.get(5)),
+            _ => return None,
+        };
+        let path = dirs
+            .iter()
+            .map(|d| d.join(file.as_str()))
+            .find(|p| p.is_file())?;
+        let line = line.as_str().parse().ok()?;
+        let col = col.map_or(Some(1), |c| c.as_str().parse().ok())?;
+        Some((path.canonicalize().unwrap_or(path), line, col))
+    })
+}
+
+#[derive(Default)]
+struct Output {
+    child: Option<Child>,
+    // where locations in the output are looked up
+    dirs: Vec<PathBuf>,
+    // bumped on every run so that the previous run's timeout stops
+    gen: u32,
+    // browser line -> location to open
+    targets: Vec<Option<Location>>,
+}
+
+impl Output {
+    fn push(&mut self, b: &mut browser::HoldBrowser, line: &str, target: Option<Location>) {
+        if target.is_some() {
+            b.add(&format!("@C4@u@.{}", line));
+        } else {
+            b.add(&format!("@.{}", line));
+        }
+        self.targets.push(target);
+    }
+}
+
+thread_local! {
+    static OUTPUT: RefCell<Output> = RefCell::new(Output::default());
+}
+
+fn output_browser() -> browser::HoldBrowser {
+    app::widget_from_id("output").unwrap()
+}
+
+fn spawn_reader(r: impl Read + Send + 'static, tx: mpsc::Sender<String>) {
+    thread::spawn(move || {
+        for line in BufReader::new(r).lines() {
+            match line {
+                Ok(line) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+fn shell(task: &Task) -> Command {
+    if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(&task.command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(&task.command).arg("sh").args(&task.args);
+        // its own process group, so that stop reaches what the shell started
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        cmd
+    }
+}
+
+// Kills the shell along with the commands it started
+fn kill(child: &mut Child) {
+    let pid = child.id().to_string();
+    if cfg!(windows) {
+        Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid])
+            .output()
+            .ok();
+    } else {
+        Command::new("kill")
+            .args(["-KILL", "--", &format!("-{}", pid)])
+            .output()
+            .ok();
+    }
+    child.kill().ok();
+    child.wait().ok();
+}
+
+// The terminal panel's display, task output is copied there as well
+#[cfg(feature = "term")]
+fn terminal() -> Option<fltk::text::SimpleTerminal> {
+    let g: group::Group = app::widget_from_id("term_group")?;
+    (0..g.children())
+        .filter_map(|i| g.child(i))
+        .find_map(|w| fltk::text::SimpleTerminal::from_dyn_widget(&w))
+}
+
+fn echo(line: &str) {
+    #[cfg(feature = "term")]
+    if let Some(mut term) = terminal() {
+        term.append(line);
+        term.append("\n");
+    }
+    #[cfg(not(feature = "term"))]
+    let _ = line;
+}
+
+// Runs task with its output in the Output panel and the terminal, a task that's still
+// running is killed first
+pub fn run(task: Task) {
+    stop();
+    cbs::show_output();
+    #[cfg(feature = "term")]
+    cbs::show_term();
+    let mut b = output_browser();
+    b.clear();
+    let child = shell(&task)
+        .current_dir(&task.cwd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let gen = OUTPUT.with(|o| {
+        let mut o = o.borrow_mut();
+        o.gen += 1;
+        o.targets.clear();
+        o.dirs = vec![task.cwd.clone(), task.root.clone()];
+        o.push(&mut b, &format!("$ {}", task.command), None);
+        o.gen
+    });
+    echo(&format!("$ {}", task.command));
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            OUTPUT.with(|o| o.borrow_mut().push(&mut b, &e.to_string(), None));
+            echo(&e.to_string());
+            return;
+        }
+    };
+    let (tx, rx) = mpsc::channel();
+    spawn_reader(child.stdout.take().unwrap(), tx.clone());
+    spawn_reader(child.stderr.take().unwrap(), tx);
+    OUTPUT.with(|o| o.borrow_mut().child = Some(child));
+    app::add_timeout3(0.05, move |handle| {
+        OUTPUT.with(|o| {
+            let mut o = o.borrow_mut();
+            if o.gen != gen {
+                return;
+            }
+            let mut b = output_browser();
+            let lines = o.targets.len();
+            loop {
+                match rx.try_recv() {
+                    Ok(line) => {
+                        if o.targets.len() < MAX_LINES {
+                            let target = parse_location(&line, &o.dirs);
+                            o.push(&mut b, &line, target);
+                            echo(&line);
+                        }
+                    }
+                    Err(mpsc::TryRecvError::Empty) => {
+                        app::repeat_timeout3(0.05, handle);
+                        break;
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        match o.child.as_mut().map(|c| c.try_wait()) {
+                            Some(Ok(Some(status))) => {
+                                o.child = None;
+                                let line = match status.code() {
+                                    Some(0) => "[finished]".to_string(),
+                                    Some(code) => format!("[exited with code {}]", code),
+                                    None => "[terminated]".to_string(),
+                                };
+                                o.push(&mut b, &line, None);
+                                echo(&line);
+                            }
+                            Some(Ok(None)) => app::repeat_timeout3(0.05, handle),
+                            _ => o.child = None,
+                        }
+                        break;
+                    }
+                }
+            }
+            if o.targets.len() != lines {
+                b.bottom_line(b.size());
+            }
+        });
+    });
+}
+
+pub fn stop() {
+    OUTPUT.with(|o| {
+        let mut o = o.borrow_mut();
+        if let Some(mut child) = o.child.take() {
+            kill(&mut child);
+            o.gen += 1;
+            o.push(&mut output_browser(), "[stopped]", None);
+            echo("[stopped]");
+        }
+    });
+}
+
+fn open_at((path, line, col): Location) {
+    STATE.with(move |s| {
+        s.append(Some(path.clone()));
+        if let (Some(mut ed), Some(buf)) = (s.current_editor(), s.buf()) {
+            let start = buf.skip_lines(0, line - 1);
+            let pos = (start + col - 1).min(buf.line_end(start));
+            ed.set_insert_position(pos);
+            ed.show_insert_position();
+            ed.take_focus().ok();
+        }
+    });
+}
+
+pub struct OutputPanel {
+    g: group::Group,
+}
+
+impl OutputPanel {
+    pub fn new() -> Self {
+        let mut g = group::Group::default().with_id("output_group");
+        let mut b = browser::HoldBrowser::default().with_id("output");
+        b.set_text_font(Font::Courier);
+        b.set_color(Color::Background.darker());
+        b.set_callback(|b| {
+            let line = match (b.value() as usize).checked_sub(1) {
+                Some(line) => line,
+                None => return,
+            };
+            let target = OUTPUT.with(|o| o.borrow().targets.get(line).cloned().flatten());
+            if let Some(target) = target {
+                open_at(target);
+            }
+        });
+        g.end();
+        g.resize_callback(move |_, x, y, w, h| b.resize(x, y, w, h));
+        Self { g }
+    }
+}
+
+fltk::widget_extends!(OutputPanel, group::Group, g);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fltext-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn rust_runs_in_the_crate() {
+        let dir = temp_dir("tasks-cargo");
+        fs::create_dir_all(dir.join("core/src/bin")).unwrap();
+        fs::write(dir.join("core/Cargo.toml"), "").unwrap();
+        let p = dir.join("core/src/bin/x.rs");
+        fs::write(&p, "").unwrap();
+        let task = runner(&dir, &p).unwrap();
+        assert_eq!(task.cwd, dir.join("core"));
+        assert_eq!(task.root, dir);
+        // without a Cargo.toml the file's own directory
+        fs::remove_file(dir.join("core/Cargo.toml")).unwrap();
+        assert_eq!(runner(&dir, &p).unwrap().cwd, dir.join("core/src/bin"));
+        let py = dir.join("core/x.py");
+        assert_eq!(runner(&dir, &py).unwrap().cwd, dir.join("core"));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn locations() {
+        let dir = temp_dir("tasks-locations");
+        fs::create_dir_all(dir.join("core/src")).unwrap();
+        for f in ["core/src/main.rs", "core/main.c", "core/x.py"] {
+            fs::write(dir.join(f), "").unwrap();
+        }
+        let dirs = [dir.join("core"), dir.clone()];
+        for (line, found) in [
+            ("  --> src/main.rs:3:5", Some(("core/src/main.rs", 3, 5))),
+            (
+                "main.c:12:7: error: expected ';'",
+                Some(("core/main.c", 12, 7)),
+            ),
+            ("main.c:12: warning", Some(("core/main.c", 12, 1))),
+            (
+                "  File \"x.py\", line 4, in <module>",
+                Some(("core/x.py", 4, 1)),
+            ),
+            // relative to the workspace above the crate
+            (
+                "  --> core/src/main.rs:1:2",
+                Some(("core/src/main.rs", 1, 2)),
+            ),
+            ("  --> src/lib.rs:3:5", None),
+            ("error: could not compile", None),
+        ] {
+            let expected = found.map(|(f, l, c)| (dir.join(f), l, c));
+            assert_eq!(parse_location(line, &dirs), expected, "{}", line);
+        }
+        let abs = format!("{}:2:3: note", dir.join("core/main.c").display());
+        assert_eq!(
+            parse_location(&abs, &dirs[..1]),
+            Some((dir.join("core/main.c"), 2, 3))
+        );
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn windows_drive_paths() {
+        let c = LOCATION.captures(r"C:\src\main.c:3:5: error: x").unwrap();
+        assert_eq!(&c[3], r"C:\src\main.c");
+        assert_eq!(&c[4], "3");
+        assert_eq!(&c[5], "5");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runner_quoting() {
+        let dir = std::env::temp_dir().join(format!("fltext-tasks-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let p = dir.join("a $(touch pwned) 'b\".sh");
+        fs::write(&p, "echo ran").unwrap();
+        let task = runner(&dir, &p).unwrap();
+        assert_eq!(task.command, "sh \"$1\"");
+        let out = shell(&task).current_dir(&task.cwd).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "ran\n");
+        assert!(!dir.join("pwned").exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_kills_the_group() {
+        let task = Task {
+            name: "sleep".into(),
+            command: "sleep 30 & sleep 30; echo done".into(),
+            cwd: std::env::temp_dir(),
+            root: std::env::temp_dir(),
+            args: vec![],
+        };
+        let mut child = shell(&task).stdout(Stdio::piped()).spawn().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let start = std::time::Instant::now();
+        kill(&mut child);
+        // the pipe only closes once the backgrounded sleep is gone too
+        let mut out = String::new();
+        BufReader::new(stdout).read_to_string(&mut out).unwrap();
+        assert!(out.is_empty());
+        assert!(start.elapsed().as_secs() < 10);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::utils;
+use fltk::{
+    enums::{Color, Font},
+    utils::oncelock::Lazy,
+};
+use std::{fs, sync::Mutex};
+
+const BUILTIN: &[&str] = &[
+    include_str!("../themes/solarized-dark.toml"),
+    include_str!("../themes/solarized-light.toml"),
+];
+
+// The style table has room for 26 highlight styles 'A'..='Z' (their underlined
+// variants are the lowercase letters), the first one is the foreground
+pub const MAX_CAPTURES: usize = 25;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Style {
+    pub color: Color,
+    pub font: Font,
+}
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub name: String,
+    pub background: Color,
+    pub foreground: Color,
+    pub cursor: Color,
+    pub selection: Color,
+    pub linenumber: Color,
+    pub linenumber_background: Color,
+    pub captures: Vec<(String, Style)>,
+}
+
+fn color(v: Option<&toml::Value>) -> Option<Color> {
+    let hex = v?.as_str()?.strip_prefix('#')?;
+    u32::from_str_radix(hex, 16).ok().map(Color::from_hex)
+}
+
+fn font(name: &str) -> Font {
+    match name {
+        "bold" => Font::CourierBold,
+        "italic" => Font::CourierItalic,
+        "bold italic" | "italic bold" => Font::CourierBoldItalic,
+        _ => Font::Courier,
+    }
+}
+
+impl Theme {
+    pub fn parse(s: &str) -> Option<Self> {
+        let t: toml::Table = s.parse().ok()?;
+        let editor = t.get("editor")?.as_table()?;
+        let foreground = color(editor.get("foreground"))?;
+        let background = color(editor.get("background"))?;
+        let mut captures = vec![];
+        let name = t.get("name")?.as_str()?.replace('/', " ");
+        if let Some(c) = t.get("captures").and_then(|c| c.as_table()) {
+            if c.len() > MAX_CAPTURES {
+                let dropped: Vec<&str> = c.keys().skip(MAX_CAPTURES).map(String::as_str).collect();
+                eprintln!(
+                    "Theme {}: only {} captures fit the style table, ignoring {}",
+                    name,
+                    MAX_CAPTURES,
+                    dropped.join(", ")
+                );
+            }
+            for (name, v) in c.iter().take(MAX_CAPTURES) {
+                let style = match v {
+                    toml::Value::Table(v) => Style {
+                        color: color(v.get("color")).unwrap_or(foreground),
+                        font: font(v.get("font").and_then(|f| f.as_str()).unwrap_or_default()),
+                    },
+                    v => Style {
+                        color: color(Some(v)).unwrap_or(foreground),
+                        font: Font::Courier,
+                    },
+                };
+                captures.push((name.clone(), style));
+            }
+        }
+        Some(Self {
+            name,
+            background,
+            foreground,
+            cursor: color(editor.get("cursor")).unwrap_or(foreground),
+            selection: color(editor.get("selection")).unwrap_or(Color::Selection),
+            linenumber: color(editor.get("linenumber")).unwrap_or(foreground),
+            linenumber_background: color(editor.get("linenumber_background")).unwrap_or(background),
+            captures,
+        })
+    }
+}
+
+// Built-in themes followed by the ones in ~/.config/fltext/themes,
+// a user theme with the same name replaces the built-in one
+pub fn list() -> Vec<Theme> {
+    let mut themes: Vec<Theme> = BUILTIN.iter().filter_map(|s| Theme::parse(s)).collect();
+    let user = utils::config_dir().and_then(|dir| fs::read_dir(dir.join("themes")).ok());
+    if let Some(entries) = user {
+        let mut paths: Vec<_> = entries
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|p| p.extension().map_or(false, |e| e == "toml"))
+            .collect();
+        paths.sort();
+        for p in paths {
+            match fs::read_to_string(&p).ok().and_then(|s| Theme::parse(&s)) {
+                Some(theme) => match themes.iter_mut().find(|t| t.name == theme.name) {
+                    Some(t) => *t = theme,
+                    None => themes.push(theme),
+                },
+                None => eprintln!("Invalid theme {}", p.display()),
+            }
+        }
+    }
+    themes
+}
+
+static CURRENT: Lazy<Mutex<Theme>> = Lazy::new(|| Mutex::new(list().into_iter().next().unwrap()));
+
+pub fn current() -> Theme {
+    CURRENT.lock().unwrap().clone()
+}
+
+pub fn set(theme: Theme) {
+    *CURRENT.lock().unwrap() = theme;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_themes_parse() {
+        let names: Vec<String> = BUILTIN
+            .iter()
+            .map(|s| Theme::parse(s).unwrap().name)
+            .collect();
+        assert_eq!(names, ["Solarized Dark", "Solarized Light"]);
+    }
+
+    #[test]
+    fn parse_theme() {
+        let t = Theme::parse(
+            r##"
+name = "a/b"
+[editor]
+background = "#000000"
+foreground = "#ffffff"
+selection = "#123456"
+[captures]
+comment = { color = "#00ff00", font = "italic" }
+keyword = { font = "bold" }
+string = "#ff0000"
+number = "red"
+"##,
+        )
+        .unwrap();
+        assert_eq!(t.name, "a b");
+        assert_eq!(t.background, Color::from_hex(0x000000));
+        assert_eq!(t.foreground, Color::from_hex(0xffffff));
+        assert_eq!(t.selection, Color::from_hex(0x123456));
+        // unset editor colors fall back to the foreground and background
+        assert_eq!(t.cursor, t.foreground);
+        assert_eq!(t.linenumber, t.foreground);
+        assert_eq!(t.linenumber_background, t.background);
+        let captures: Vec<(&str, Color, Font)> = t
+            .captures
+            .iter()
+            .map(|(n, s)| (n.as_str(), s.color, s.font))
+            .collect();
+        assert_eq!(
+            captures,
+            [
+                ("comment", Color::from_hex(0x00ff00), Font::CourierItalic),
+                ("keyword", t.foreground, Font::CourierBold),
+                ("number", t.foreground, Font::Courier),
+                ("string", Color::from_hex(0xff0000), Font::Courier),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_themes() {
+        let editor = "[editor]\nbackground = \"#000000\"\nforeground = \"#ffffff\"\n";
+        assert!(Theme::parse(&format!("name = \"x\"\n{}", editor)).is_some());
+        // no name
+        assert!(Theme::parse(editor).is_none());
+        // no foreground
+        assert!(Theme::parse("name = \"x\"\n[editor]\nbackground = \"#000000\"").is_none());
+        // not a color
+        assert!(Theme::parse(&format!(
+            "name = \"x\"\n{}",
+            editor.replace("#ffffff", "white")
+        ))
+        .is_none());
+        assert!(Theme::parse("name = ").is_none());
+    }
+
+    #[test]
+    fn captures_are_capped() {
+        let mut s = String::from(
+            "name = \"x\"\n[editor]\nbackground = \"#000000\"\nforeground = \"#ffffff\"\n[captures]\n",
+        );
+        for i in 0..MAX_CAPTURES + 3 {
+            s += &format!("c{:02} = \"#{:06x}\"\n", i, i);
+        }
+        let t = Theme::parse(&s).unwrap();
+        assert_eq!(t.captures.len(), MAX_CAPTURES);
+        assert_eq!(
+            t.captures.last().unwrap().0,
+            format!("c{:02}", MAX_CAPTURES - 1)
+        );
+    }
+}
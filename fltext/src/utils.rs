@@ -1,7 +1,8 @@
+use crate::script;
 use std::{
     env,
     path::{Path, PathBuf},
-    process::Command,
+    process::{self, Command},
 };
 
 pub fn strip_unc_path(p: &Path) -> String {
@@ -13,6 +14,37 @@ pub fn strip_unc_path(p: &Path) -> String {
     }
 }
 
+// ~/.config/fltext, shared by sessions, themes and the language registry
+pub fn config_dir() -> Option<PathBuf> {
+    let home = env::var("HOME").or_else(|_| env::var("USERPROFILE")).ok()?;
+    Some(PathBuf::from(home).join(".config").join("fltext"))
+}
+
+// ~/.cache/fltext, for swap files
+pub fn cache_dir() -> Option<PathBuf> {
+    let home = env::var("HOME").or_else(|_| env::var("USERPROFILE")).ok()?;
+    Some(PathBuf::from(home).join(".cache").join("fltext"))
+}
+
+// A file name standing for p, the same across runs: p's own name for reading
+// followed by a FNV-1a hash of the whole path, so /a/b_c and /a/b/c don't collide
+pub fn path_key(p: &Path) -> String {
+    let hash = p
+        .to_string_lossy()
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+    let name: String = p
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}-{:016x}", name, hash)
+}
+
 #[allow(dead_code)]
 pub fn has_program(prog: &str) -> bool {
     // hacky
@@ -22,8 +54,21 @@ pub fn has_program(prog: &str) -> bool {
     }
 }
 
-pub fn init_args(args: env::Args) -> (Option<PathBuf>, PathBuf) {
-    let args: Vec<_> = args.collect();
+pub fn init_args(args: env::Args) -> (Option<PathBuf>, PathBuf, bool) {
+    let mut args: Vec<_> = args.collect();
+    // fltext --script file.rhai [files]: runs headless and exits
+    if let Some(idx) = args.iter().position(|a| a == "--script") {
+        let script = match args.get(idx + 1) {
+            Some(s) => PathBuf::from(s),
+            None => {
+                eprintln!("--script needs a script file");
+                process::exit(2);
+            }
+        };
+        process::exit(script::run_batch(&script, &args[idx + 2..]));
+    }
+    let session = !args.iter().any(|a| a == "--no-session");
+    args.retain(|a| a != "--no-session");
     let mut current_file: Option<PathBuf> = None;
     // fix our working dir
     if args.len() > 1 {
@@ -46,7 +91,7 @@ pub fn init_args(args: env::Args) -> (Option<PathBuf>, PathBuf) {
     };
 
     let current_path = env::current_dir().unwrap().canonicalize().unwrap();
-    (current_file, current_path)
+    (current_file, current_path, session)
 }
 
 #[allow(dead_code)]
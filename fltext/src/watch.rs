@@ -0,0 +1,187 @@
+use crate::{encoding, state::STATE};
+use fltk::{enums::*, prelude::*, utils::oncelock::Lazy, *};
+use notify::{event::EventKind, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+};
+
+const POLL_INTERVAL: f64 = 0.2;
+const BAR_HEIGHT: i32 = 30;
+
+// Directories are watched rather than the files themselves, so that files
+// replaced by rename (git checkout, most editors' atomic saves) keep being tracked
+struct Watch {
+    watcher: RecommendedWatcher,
+    rx: mpsc::Receiver<PathBuf>,
+    dirs: HashMap<PathBuf, usize>,
+    files: HashSet<PathBuf>,
+    conflicts: VecDeque<PathBuf>,
+    polling: bool,
+}
+
+static WATCH: Lazy<Mutex<Watch>> = Lazy::new(|| {
+    let (tx, rx) = mpsc::channel();
+    let watcher = notify::recommended_watcher(move |res: Result<Event, notify::Error>| match res {
+        Ok(event) => match event.kind {
+            EventKind::Create(_) | EventKind::Modify(_) => {
+                for path in event.paths {
+                    tx.send(path).ok();
+                }
+            }
+            _ => (),
+        },
+        Err(e) => eprintln!("{}", e),
+    })
+    .unwrap();
+    Mutex::new(Watch {
+        watcher,
+        rx,
+        dirs: HashMap::default(),
+        files: HashSet::default(),
+        conflicts: VecDeque::default(),
+        polling: false,
+    })
+});
+
+pub fn watch(p: &Path) {
+    let start_polling = {
+        let mut w = WATCH.lock().unwrap();
+        if let Some(dir) = p.parent() {
+            if w.files.insert(p.to_path_buf()) {
+                let count = w.dirs.entry(dir.to_path_buf()).or_insert(0);
+                *count += 1;
+                if *count == 1 {
+                    w.watcher.watch(dir, RecursiveMode::NonRecursive).ok();
+                }
+            }
+        }
+        let start_polling = !w.polling;
+        w.polling = true;
+        start_polling
+    };
+    if start_polling {
+        app::add_timeout3(POLL_INTERVAL, poll);
+    }
+}
+
+pub fn unwatch(p: &Path) {
+    let mut w = WATCH.lock().unwrap();
+    if w.files.remove(p) {
+        if let Some(dir) = p.parent() {
+            if let Some(count) = w.dirs.get_mut(dir) {
+                *count -= 1;
+                if *count == 0 {
+                    w.dirs.remove(dir);
+                    w.watcher.unwatch(dir).ok();
+                }
+            }
+        }
+    }
+    w.conflicts.retain(|c| c != p);
+}
+
+fn poll(handle: app::TimeoutHandle) {
+    let changed: HashSet<PathBuf> = {
+        let w = WATCH.lock().unwrap();
+        w.rx.try_iter().filter(|p| w.files.contains(p)).collect()
+    };
+    let mut conflicts = vec![];
+    for path in changed {
+        // Our own saves land here too, they're filtered out by comparing contents
+        if let Some(text) = encoding::read_text(&path) {
+            let conflict = STATE.with({
+                let path = path.clone();
+                move |s| match s.id_of(&path) {
+                    Some(id) if s.map[&id].buf.text() != text => {
+                        if s.map[&id].modified {
+                            true
+                        } else {
+                            s.reload(id, &text);
+                            false
+                        }
+                    }
+                    _ => false,
+                }
+            });
+            if conflict {
+                conflicts.push(path);
+            }
+        }
+    }
+    if !conflicts.is_empty() {
+        let mut w = WATCH.lock().unwrap();
+        for path in conflicts {
+            if !w.conflicts.contains(&path) {
+                w.conflicts.push_back(path);
+            }
+        }
+        drop(w);
+        update_bar();
+    }
+    app::repeat_timeout3(POLL_INTERVAL, handle);
+}
+
+fn update_bar() {
+    let current = WATCH.lock().unwrap().conflicts.front().cloned();
+    let mut bar: group::Flex = app::widget_from_id("reload_bar").unwrap();
+    let mut parent = group::Flex::from_dyn_widget(&bar.parent().unwrap()).unwrap();
+    if let Some(path) = current {
+        let mut label: frame::Frame = app::widget_from_id("reload_label").unwrap();
+        label.set_label(&format!(
+            "{} changed on disk.",
+            path.file_name().unwrap().to_string_lossy()
+        ));
+        parent.fixed(&bar, BAR_HEIGHT);
+        bar.show();
+    } else {
+        parent.fixed(&bar, 0);
+        bar.hide();
+    }
+    parent.layout();
+    app::redraw();
+}
+
+fn resolve(reload: bool) {
+    let path = WATCH.lock().unwrap().conflicts.pop_front();
+    if let Some(path) = path {
+        if reload {
+            if let Some(text) = encoding::read_text(&path) {
+                STATE.with(move |s| {
+                    if let Some(id) = s.id_of(&path) {
+                        s.reload(id, &text);
+                    }
+                });
+            }
+        }
+    }
+    update_bar();
+}
+
+pub struct ReloadBar {
+    g: group::Flex,
+}
+
+impl ReloadBar {
+    pub fn new() -> Self {
+        let mut g = group::Flex::default().row().with_id("reload_bar");
+        g.set_margin(2);
+        g.set_color(Color::from_hex(0xb58900));
+        g.set_frame(FrameType::FlatBox);
+        frame::Frame::default()
+            .with_align(Align::Left | Align::Inside)
+            .with_id("reload_label");
+        let mut reload = button::Button::default().with_label("Reload");
+        reload.set_callback(|_| resolve(true));
+        g.fixed(&reload, 80);
+        let mut keep = button::Button::default().with_label("Keep mine");
+        keep.set_callback(|_| resolve(false));
+        g.fixed(&keep, 80);
+        g.end();
+        g.hide();
+        Self { g }
+    }
+}
+
+fltk::widget_extends!(ReloadBar, group::Flex, g);